@@ -0,0 +1,245 @@
+use bloomfilter::Bloom;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use tokio::sync::RwLock;
+
+const FILE_MAGIC: &[u8; 4] = b"ABLM";
+/// Version 1 held a single filter; version 2 holds length-prefixed shards,
+/// each encoded in the version 1 layout.
+const FILE_VERSION_SINGLE: u8 = 1;
+const FILE_VERSION_SHARDED: u8 = 2;
+const SINGLE_HEADER_LEN: usize = 4 + 1 + 8 + 4 + 4 * 8;
+const SHARDED_HEADER_LEN: usize = 4 + 1 + 4;
+
+/// Bloom filter split into shards keyed by the first byte of the content
+/// hash, each behind its own lock, so concurrent stores of different content
+/// rarely wait on one another.
+pub struct ShardedBloom {
+    shards: Vec<RwLock<Bloom<str>>>,
+    /// Items the filter was sized for at its false-positive rate.
+    capacity: AtomicUsize,
+    /// Items set so far, repeats included.
+    len: AtomicUsize,
+    /// Shard locks taken by checks and sets, for `/metrics`.
+    locks_taken: AtomicU64,
+}
+
+impl ShardedBloom {
+    /// Creates `shard_count` empty filters sharing a budget of `items`.
+    pub fn new(shard_count: usize, items: usize, fp_rate: f64) -> Self {
+        let shard_count = shard_count.clamp(1, 256);
+        let per_shard = items.div_ceil(shard_count).max(1);
+        ShardedBloom {
+            shards: (0..shard_count)
+                .map(|_| RwLock::new(Bloom::new_for_fp_rate(per_shard, fp_rate)))
+                .collect(),
+            capacity: AtomicUsize::new(per_shard * shard_count),
+            len: AtomicUsize::new(0),
+            locks_taken: AtomicU64::new(0),
+        }
+    }
+    
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+    
+    pub fn capacity(&self) -> usize {
+        self.capacity.load(Ordering::Relaxed)
+    }
+    
+    pub fn items(&self) -> usize {
+        self.len.load(Ordering::Relaxed)
+    }
+    
+    pub fn locks_taken(&self) -> u64 {
+        self.locks_taken.load(Ordering::Relaxed)
+    }
+    
+    /// Sets the item count of a loaded filter, whose file doesn't record it.
+    pub fn set_items(&self, items: usize) {
+        self.len.store(items, Ordering::Relaxed);
+    }
+    
+    /// Swaps in `other`'s contents shard by shard. Both must have the same
+    /// number of shards, since the shard count decides where a hash goes.
+    pub async fn replace(&self, other: ShardedBloom) {
+        assert_eq!(self.shards.len(), other.shards.len(), "shard counts differ");
+        for (shard, replacement) in self.shards.iter().zip(other.shards) {
+            *shard.write().await = replacement.into_inner();
+        }
+        self.capacity.store(other.capacity.into_inner(), Ordering::Relaxed);
+        self.len.store(other.len.into_inner(), Ordering::Relaxed);
+    }
+    
+    fn shard_index(&self, hash: &str) -> usize {
+        let digest = hash.rsplit(':').next().unwrap_or(hash);
+        let prefix = digest.get(..2)
+            .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+            .unwrap_or(0);
+        prefix as usize % self.shards.len()
+    }
+    
+    /// Indexes into `hashes`, grouped by the shard each falls in.
+    fn by_shard(&self, hashes: &[&str]) -> BTreeMap<usize, Vec<usize>> {
+        let mut groups: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+        for (index, hash) in hashes.iter().enumerate() {
+            groups.entry(self.shard_index(hash)).or_default().push(index);
+        }
+        groups
+    }
+    
+    pub async fn check(&self, hash: &str) -> bool {
+        self.locks_taken.fetch_add(1, Ordering::Relaxed);
+        self.shards[self.shard_index(hash)].read().await.check(hash)
+    }
+    
+    pub async fn set(&self, hash: &str) {
+        self.locks_taken.fetch_add(1, Ordering::Relaxed);
+        self.shards[self.shard_index(hash)].write().await.set(hash);
+        self.len.fetch_add(1, Ordering::Relaxed);
+    }
+    
+    /// `check` for each of `hashes`, read-locking each shard they fall in
+    /// once rather than once per hash.
+    pub async fn check_many(&self, hashes: &[&str]) -> Vec<bool> {
+        let mut found = vec![false; hashes.len()];
+        for (shard, indexes) in self.by_shard(hashes) {
+            self.locks_taken.fetch_add(1, Ordering::Relaxed);
+            let bloom = self.shards[shard].read().await;
+            for index in indexes {
+                found[index] = bloom.check(hashes[index]);
+            }
+        }
+        found
+    }
+    
+    /// `set` for each of `hashes`, write-locking each shard they fall in once.
+    pub async fn set_many(&self, hashes: &[&str]) {
+        for (shard, indexes) in self.by_shard(hashes) {
+            self.locks_taken.fetch_add(1, Ordering::Relaxed);
+            let mut bloom = self.shards[shard].write().await;
+            for index in indexes {
+                bloom.set(hashes[index]);
+            }
+        }
+        self.len.fetch_add(hashes.len(), Ordering::Relaxed);
+    }
+    
+    /// Serializes every shard. All shards are read-locked before `on_locked`
+    /// runs, so no insert can land between it and the snapshot.
+    pub async fn encode(&self, on_locked: impl FnOnce()) -> Vec<u8> {
+        let mut guards = Vec::with_capacity(self.shards.len());
+        for shard in &self.shards {
+            guards.push(shard.read().await);
+        }
+        on_locked();
+        
+        let mut out = Vec::new();
+        out.extend_from_slice(FILE_MAGIC);
+        out.push(FILE_VERSION_SHARDED);
+        out.extend_from_slice(&(guards.len() as u32).to_le_bytes());
+        for bloom in &guards {
+            let encoded = encode_single(bloom);
+            out.extend_from_slice(&(encoded.len() as u64).to_le_bytes());
+            out.extend_from_slice(&encoded);
+        }
+        out
+    }
+    
+    /// Parses a sharded filter file saved at `fp_rate`. Returns `None` for
+    /// anything unreadable, including single-filter files, which can't be
+    /// split back into shards.
+    pub fn decode(data: &[u8], fp_rate: f64) -> Option<Self> {
+        if data.len() < SHARDED_HEADER_LEN || &data[..4] != FILE_MAGIC || data[4] != FILE_VERSION_SHARDED {
+            return None;
+        }
+        let shard_count = u32::from_le_bytes(data[5..9].try_into().unwrap()) as usize;
+        if shard_count == 0 {
+            return None;
+        }
+        
+        let mut shards = Vec::with_capacity(shard_count);
+        let mut capacity = 0;
+        let mut rest = &data[SHARDED_HEADER_LEN..];
+        for _ in 0..shard_count {
+            let len = u64::from_le_bytes(rest.get(..8)?.try_into().unwrap()) as usize;
+            let encoded = rest.get(8..8usize.checked_add(len)?)?;
+            let bloom = decode_single(encoded)?;
+            capacity += sized_for(&bloom, fp_rate);
+            shards.push(RwLock::new(bloom));
+            rest = &rest[8 + len..];
+        }
+        Some(ShardedBloom {
+            shards,
+            capacity: AtomicUsize::new(capacity),
+            len: AtomicUsize::new(0),
+            locks_taken: AtomicU64::new(0),
+        })
+    }
+    
+    /// Whether `data` is a single-filter file from before sharding.
+    pub fn is_single_filter_file(data: &[u8]) -> bool {
+        data.len() >= 5 && &data[..4] == FILE_MAGIC && data[4] == FILE_VERSION_SINGLE
+    }
+}
+
+/// Items a filter was sized for, recovered from its bitmap: n items at
+/// false-positive rate p take m = -n ln p / (ln 2)^2 bits.
+fn sized_for(bloom: &Bloom<str>, fp_rate: f64) -> usize {
+    let bits = bloom.number_of_bits() as f64;
+    (bits * std::f64::consts::LN_2.powi(2) / -fp_rate.ln()) as usize
+}
+
+fn encode_single(bloom: &Bloom<str>) -> Vec<u8> {
+    let bitmap = bloom.bitmap();
+    let mut out = Vec::with_capacity(SINGLE_HEADER_LEN + bitmap.len());
+    out.extend_from_slice(FILE_MAGIC);
+    out.push(FILE_VERSION_SINGLE);
+    out.extend_from_slice(&bloom.number_of_bits().to_le_bytes());
+    out.extend_from_slice(&bloom.number_of_hash_functions().to_le_bytes());
+    for (k0, k1) in bloom.sip_keys() {
+        out.extend_from_slice(&k0.to_le_bytes());
+        out.extend_from_slice(&k1.to_le_bytes());
+    }
+    out.extend_from_slice(&bitmap);
+    out
+}
+
+fn decode_single(data: &[u8]) -> Option<Bloom<str>> {
+    if data.len() < SINGLE_HEADER_LEN || &data[..4] != FILE_MAGIC || data[4] != FILE_VERSION_SINGLE {
+        return None;
+    }
+    let u64_at = |offset: usize| u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap());
+    let bitmap_bits = u64_at(5);
+    let k_num = u32::from_le_bytes(data[13..17].try_into().unwrap());
+    let sip_keys = [(u64_at(17), u64_at(25)), (u64_at(33), u64_at(41))];
+    
+    let bitmap = &data[SINGLE_HEADER_LEN..];
+    if (bitmap.len() as u64) * 8 < bitmap_bits {
+        return None;
+    }
+    Some(Bloom::from_existing(bitmap, bitmap_bits, k_num, sip_keys))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    
+    #[tokio::test]
+    async fn stores_in_other_shards_proceed_while_one_is_locked() {
+        let bloom = ShardedBloom::new(16, 1000, 0.01);
+        let locked = format!("sha256:00{}", "a".repeat(62));
+        let other = format!("sha256:01{}", "a".repeat(62));
+        
+        let guard = bloom.shards[bloom.shard_index(&locked)].write().await;
+        tokio::time::timeout(Duration::from_secs(1), bloom.set(&other)).await
+            .expect("a store in another shard waited on the held lock");
+        assert!(tokio::time::timeout(Duration::from_millis(50), bloom.set(&locked)).await.is_err());
+        drop(guard);
+        
+        bloom.set(&locked).await;
+        assert!(bloom.check(&locked).await && bloom.check(&other).await);
+        assert_eq!(bloom.items(), 2);
+    }
+}
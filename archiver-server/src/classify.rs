@@ -0,0 +1,227 @@
+use crate::storage::RedactionMarker;
+use regex::Regex;
+use std::collections::BTreeMap;
+use std::ops::Range;
+
+/// A byte range of a body to redact and the kind of data it holds.
+#[derive(Debug, Clone)]
+pub struct Finding {
+    pub span: Range<usize>,
+    pub category: String,
+}
+
+/// Finds sensitive data in response bodies before they're stored. Spans
+/// must fall on character boundaries; they may overlap.
+pub trait Classifier: Send + Sync {
+    fn classify(&self, text: &str) -> Vec<Finding>;
+}
+
+/// Default classifier: email addresses and phone numbers, by pattern.
+pub struct RegexClassifier {
+    patterns: Vec<(&'static str, Regex)>,
+}
+
+impl Default for RegexClassifier {
+    fn default() -> Self {
+        let pattern = |re: &str| Regex::new(re).expect("built-in pattern is valid");
+        RegexClassifier {
+            patterns: vec![
+                ("email", pattern(r"[A-Za-z0-9._%+-]+@[A-Za-z0-9-]+(?:\.[A-Za-z0-9-]+)*\.[A-Za-z]{2,}")),
+                // Separators are required so plain numbers (IDs, timestamps)
+                // aren't taken for phone numbers
+                ("phone", pattern(r"(?:\+\d{1,3}[\s.-]?)?(?:\(\d{3}\)\s?|\b\d{3}[\s.-])\d{3}[\s.-]\d{4}\b")),
+            ],
+        }
+    }
+}
+
+impl Classifier for RegexClassifier {
+    fn classify(&self, text: &str) -> Vec<Finding> {
+        self.patterns.iter()
+            .flat_map(|(category, regex)| regex.find_iter(text).map(|m| Finding {
+                span: m.range(),
+                category: category.to_string(),
+            }))
+            .collect()
+    }
+}
+
+/// Finds long, random-looking tokens (API keys, JWTs, session secrets) that
+/// weren't hashed by the client. A token is a run of base64, base64url, and
+/// JWT characters; it's flagged when it's at least `min_length` long, mixes
+/// upper and lower case letters with digits, and has enough Shannon entropy
+/// per character: `min_bits_per_char`, or for shorter tokens what a random
+/// base64 string of that length usually reaches, if lower. Prose,
+/// identifiers, hex digests and UUIDs fail one test or another.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EntropyClassifier {
+    pub min_length: usize,
+    pub min_bits_per_char: f64,
+}
+
+impl EntropyClassifier {
+    pub const DEFAULT_MIN_LENGTH: usize = 32;
+    pub const DEFAULT_MIN_BITS_PER_CHAR: f64 = 4.5;
+    
+    fn is_secret(&self, token: &str) -> bool {
+        token.len() >= self.min_length
+            && !ENTROPY_EXEMPT_PREFIXES.iter().any(|prefix| token.starts_with(prefix))
+            && token.bytes().any(|b| b.is_ascii_uppercase())
+            && token.bytes().any(|b| b.is_ascii_lowercase())
+            && token.bytes().any(|b| b.is_ascii_digit())
+            && shannon_entropy(token) >= self.required_bits(token.len())
+    }
+    
+    /// A string can't have more distinct characters than its length, so
+    /// the entropy of a random one climbs with length towards the alphabet's
+    /// 6 bits; a 32-character key averages about 4.56. The bar is set under
+    /// that curve, where about 99 in 100 random tokens clear it.
+    fn required_bits(&self, len: usize) -> f64 {
+        self.min_bits_per_char.min(random_token_entropy(len) - RANDOM_ENTROPY_SLACK)
+    }
+}
+
+impl Default for EntropyClassifier {
+    fn default() -> Self {
+        EntropyClassifier {
+            min_length: Self::DEFAULT_MIN_LENGTH,
+            min_bits_per_char: Self::DEFAULT_MIN_BITS_PER_CHAR,
+        }
+    }
+}
+
+/// How far under a random token's expected entropy the bar sits.
+const RANDOM_ENTROPY_SLACK: f64 = 0.35;
+
+/// Expected Shannon entropy per character of `len` characters drawn
+/// uniformly from the 64 base64 symbols. Past 256 characters it's within a
+/// fraction of a bit of 6 and no longer matters.
+fn random_token_entropy(len: usize) -> f64 {
+    const SYMBOLS: f64 = 64.0;
+    let n = len.clamp(1, 256);
+    let p = 1.0 / SYMBOLS;
+    // E[sum over symbols of c·log2(c)], c being a symbol's binomial count
+    let mut probability = (1.0 - p).powi(n as i32);
+    let mut expected = 0.0;
+    for count in 1..=n {
+        probability *= (n - count + 1) as f64 / count as f64 * p / (1.0 - p);
+        expected += probability * count as f64 * (count as f64).log2();
+    }
+    (n as f64).log2() - SYMBOLS * expected / n as f64
+}
+
+/// Tokens starting with these are inline data or integrity digests, not
+/// secrets.
+const ENTROPY_EXEMPT_PREFIXES: &[&str] = &["sha256-", "sha384-", "sha512-"];
+
+impl Classifier for EntropyClassifier {
+    fn classify(&self, text: &str) -> Vec<Finding> {
+        let is_token_byte = |b: u8| b.is_ascii_alphanumeric() || matches!(b, b'+' | b'/' | b'=' | b'-' | b'_' | b'.');
+        let bytes = text.as_bytes();
+        let mut findings = Vec::new();
+        let mut start = 0;
+        while start < bytes.len() {
+            if !is_token_byte(bytes[start]) {
+                start += 1;
+                continue;
+            }
+            let end = bytes[start..].iter().position(|&b| !is_token_byte(b)).map_or(bytes.len(), |len| start + len);
+            // Sentence punctuation and path separators around a token aren't part of it
+            let run = &text[start..end];
+            let trimmed = run.trim_start_matches(['.', '/', '-']);
+            let token_start = start + run.len() - trimmed.len();
+            let token = trimmed.trim_end_matches(['.', '/', '-']);
+            // `data:` URIs carry images and fonts inline
+            let inline_data = text[..start].ends_with("base64,");
+            if !inline_data && self.is_secret(token) {
+                findings.push(Finding {
+                    span: token_start..token_start + token.len(),
+                    category: "high_entropy".to_string(),
+                });
+            }
+            start = end;
+        }
+        findings
+    }
+}
+
+/// Bits of Shannon entropy per byte of `token`.
+fn shannon_entropy(token: &str) -> f64 {
+    let mut counts = [0usize; 256];
+    for b in token.bytes() {
+        counts[b as usize] += 1;
+    }
+    let len = token.len() as f64;
+    counts.iter()
+        .filter(|&&count| count > 0)
+        .map(|&count| {
+            let p = count as f64 / len;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+/// Runs a classifier over each part of a URL between `/`, `?`, `&`, `=`,
+/// and `#`, so a token in a path segment or query value isn't read as one
+/// with the host and path around it.
+pub struct UrlParts<'a, C: Classifier>(pub &'a C);
+
+impl<C: Classifier> Classifier for UrlParts<'_, C> {
+    fn classify(&self, text: &str) -> Vec<Finding> {
+        let mut findings = Vec::new();
+        let mut start = 0;
+        for part in text.split(['/', '?', '&', '=', '#']) {
+            findings.extend(self.0.classify(part).into_iter().map(|finding| Finding {
+                span: start + finding.span.start..start + finding.span.end,
+                category: finding.category,
+            }));
+            start += part.len() + 1;
+        }
+        findings
+    }
+}
+
+/// Several classifiers run over the same text, their findings pooled.
+pub struct Combined(pub Vec<Box<dyn Classifier>>);
+
+impl Classifier for Combined {
+    fn classify(&self, text: &str) -> Vec<Finding> {
+        self.0.iter().flat_map(|classifier| classifier.classify(text)).collect()
+    }
+}
+
+/// Replaces everything `classifier` finds in `text` with `marker`, merging
+/// overlapping spans. Also returns how many findings of each category were
+/// redacted.
+pub fn redact(text: &str, classifier: &dyn Classifier, marker: &RedactionMarker) -> (String, BTreeMap<String, usize>) {
+    let mut findings = classifier.classify(text);
+    findings.retain(|finding| {
+        finding.span.start < finding.span.end
+            && text.is_char_boundary(finding.span.start)
+            && text.is_char_boundary(finding.span.end)
+    });
+    if findings.is_empty() {
+        return (text.to_string(), BTreeMap::new());
+    }
+    findings.sort_by_key(|finding| (finding.span.start, finding.span.end));
+    
+    let mut categories = BTreeMap::new();
+    let mut spans: Vec<Range<usize>> = Vec::new();
+    for finding in findings {
+        *categories.entry(finding.category).or_insert(0) += 1;
+        match spans.last_mut() {
+            Some(last) if finding.span.start <= last.end => last.end = last.end.max(finding.span.end),
+            _ => spans.push(finding.span),
+        }
+    }
+    
+    let mut redacted = String::with_capacity(text.len());
+    let mut copied = 0;
+    for span in spans {
+        redacted.push_str(&text[copied..span.start]);
+        redacted.push_str(&marker.replacement(&text[span.clone()]));
+        copied = span.end;
+    }
+    redacted.push_str(&text[copied..]);
+    (redacted, categories)
+}
@@ -0,0 +1,150 @@
+use crate::storage::{remove_file_if_exists, write_atomic, StorageError};
+use std::path::PathBuf;
+use tokio::fs;
+
+/// Where compressed objects live. Keys are hex content hashes; metadata and
+/// reference counts stay in sled whichever store holds the bytes.
+#[axum::async_trait]
+pub trait ContentStore: Send + Sync {
+    /// Stores `data` under `key`, replacing any object already there. A
+    /// reader never sees a partly written object.
+    async fn put(&self, key: &str, data: &[u8]) -> Result<(), StorageError>;
+    
+    /// The object under `key`, or `None` if there isn't one.
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, StorageError>;
+    
+    /// Removes the object under `key`, returning the bytes freed (0 if there
+    /// was nothing to remove).
+    async fn delete(&self, key: &str) -> Result<u64, StorageError>;
+    
+    /// The object's file, for stores that keep objects on the local
+    /// filesystem, so callers can map or move it directly.
+    fn local_path(&self, _key: &str) -> Option<PathBuf> {
+        None
+    }
+}
+
+/// Objects as `{key}.zst` files under `root`, nested in `fanout_depth`
+/// levels of two-hex-character directories.
+pub struct LocalStore {
+    root: PathBuf,
+    fanout_depth: usize,
+    /// Depths objects may also have been written at, looked under in order
+    /// when an object isn't at `fanout_depth`.
+    fallback_depths: Vec<usize>,
+}
+
+impl LocalStore {
+    pub fn new(root: PathBuf, fanout_depth: usize) -> Self {
+        LocalStore { root, fanout_depth, fallback_depths: Vec::new() }
+    }
+    
+    /// Also finds objects written at `depths`, such as ones stored before the
+    /// fanout depth changed. New objects still go to `fanout_depth`.
+    pub fn with_fallback_depths(mut self, depths: &[usize]) -> Self {
+        self.fallback_depths = depths.iter().copied()
+            .filter(|&depth| depth != self.fanout_depth)
+            .collect();
+        self
+    }
+    
+    fn path(&self, key: &str) -> PathBuf {
+        self.path_at(key, self.fanout_depth)
+    }
+    
+    fn path_at(&self, key: &str, depth: usize) -> PathBuf {
+        let mut path = self.root.clone();
+        for level in 0..depth {
+            path = path.join(&key[level * 2..level * 2 + 2]);
+        }
+        path.join(format!("{}.zst", key))
+    }
+    
+    /// Every place the object could be, the configured depth first.
+    fn candidate_paths<'a>(&'a self, key: &'a str) -> impl Iterator<Item = PathBuf> + 'a {
+        std::iter::once(self.fanout_depth)
+            .chain(self.fallback_depths.iter().copied())
+            .map(move |depth| self.path_at(key, depth))
+    }
+}
+
+#[axum::async_trait]
+impl ContentStore for LocalStore {
+    async fn put(&self, key: &str, data: &[u8]) -> Result<(), StorageError> {
+        write_atomic(&self.path(key), data).await
+    }
+    
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, StorageError> {
+        for path in self.candidate_paths(key) {
+            match fs::read(path).await {
+                Ok(data) => return Ok(Some(data)),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(None)
+    }
+    
+    /// Removes the object from every depth it's at; rewriting an object
+    /// puts it at the configured depth, leaving any older copy behind.
+    async fn delete(&self, key: &str) -> Result<u64, StorageError> {
+        let mut freed = 0;
+        for path in self.candidate_paths(key) {
+            freed += remove_file_if_exists(&path).await?;
+        }
+        Ok(freed)
+    }
+    
+    /// Where the object goes at the configured depth, whether or not it's
+    /// there yet.
+    fn local_path(&self, key: &str) -> Option<PathBuf> {
+        Some(self.path(key))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    
+    /// What every store must do with an object's lifetime, run against each
+    /// backend.
+    pub(crate) async fn round_trip(store: &dyn ContentStore) {
+        let key = "ab12cd34ef";
+        assert_eq!(store.get(key).await.unwrap(), None);
+        assert_eq!(store.delete(key).await.unwrap(), 0);
+        
+        store.put(key, b"first version").await.unwrap();
+        assert_eq!(store.get(key).await.unwrap().as_deref(), Some(&b"first version"[..]));
+        store.put(key, b"second").await.unwrap();
+        assert_eq!(store.get(key).await.unwrap().as_deref(), Some(&b"second"[..]));
+        
+        store.put("ff00", b"neighbour").await.unwrap();
+        assert_eq!(store.delete(key).await.unwrap(), 6);
+        assert_eq!(store.get(key).await.unwrap(), None);
+        assert_eq!(store.get("ff00").await.unwrap().as_deref(), Some(&b"neighbour"[..]));
+    }
+    
+    /// A store whose disk has no space left: every write fails with ENOSPC.
+    pub(crate) struct FullDisk;
+    
+    #[axum::async_trait]
+    impl ContentStore for FullDisk {
+        async fn put(&self, _key: &str, _data: &[u8]) -> Result<(), StorageError> {
+            Err(std::io::Error::from_raw_os_error(28).into())
+        }
+        
+        async fn get(&self, _key: &str) -> Result<Option<Vec<u8>>, StorageError> {
+            Ok(None)
+        }
+        
+        async fn delete(&self, _key: &str) -> Result<u64, StorageError> {
+            Ok(0)
+        }
+    }
+    
+    #[tokio::test]
+    async fn local_store_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        round_trip(&LocalStore::new(dir.path().to_path_buf(), 2)).await;
+    }
+}
@@ -0,0 +1,270 @@
+use crate::storage::{ArchivedRequest, HashAlgorithm};
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+/// Headers that differ on every fetch and say nothing about API drift.
+const VOLATILE_HEADERS: &[&str] = &["date", "age", "expires", "x-request-id"];
+/// Cap on reported JSON changes, so a rewritten document stays readable.
+const MAX_BODY_CHANGES: usize = 100;
+
+/// What a live fetch returned for an archived request's URL.
+pub struct LiveResponse {
+    pub status_code: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DriftReport {
+    pub request_id: String,
+    pub url: String,
+    pub fetched_at: chrono::DateTime<chrono::Utc>,
+    /// True if anything below changed.
+    pub drifted: bool,
+    pub status: StatusDrift,
+    pub headers: HeaderDrift,
+    pub body: BodyDrift,
+}
+
+#[derive(Debug, Serialize)]
+pub struct StatusDrift {
+    pub archived: Option<u16>,
+    pub live: u16,
+    pub changed: bool,
+}
+
+/// Header differences keyed by lowercased name.
+#[derive(Debug, Default, Serialize)]
+pub struct HeaderDrift {
+    pub added: BTreeMap<String, String>,
+    pub removed: BTreeMap<String, String>,
+    pub changed: BTreeMap<String, ValueChange<String>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ValueChange<T> {
+    pub archived: T,
+    pub live: T,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BodyDrift {
+    pub archived_hash: Option<String>,
+    pub live_hash: String,
+    pub archived_size: Option<usize>,
+    pub live_size: usize,
+    pub changed: bool,
+    /// Per-path changes when both bodies are JSON, using `$.a[0].b` paths.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub json_changes: Vec<JsonChange>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct JsonChange {
+    pub path: String,
+    pub archived: Option<Value>,
+    pub live: Option<Value>,
+}
+
+/// Compares an archived exchange against a live fetch of the same URL.
+/// `archived_body` is the stored response body, if there was one.
+pub fn build_report(request: &ArchivedRequest, archived_body: Option<&[u8]>, live: &LiveResponse) -> DriftReport {
+    let response = request.response.as_ref();
+    
+    let status = StatusDrift {
+        archived: response.map(|r| r.status_code),
+        live: live.status_code,
+        changed: response.map(|r| r.status_code) != Some(live.status_code),
+    };
+    
+    let archived_headers = response.map(|r| header_map(r.headers.iter())).unwrap_or_default();
+    let live_headers = header_map(live.headers.iter().map(|(name, value)| (name.as_str(), value.as_str())));
+    let mut headers = HeaderDrift::default();
+    for (name, archived) in &archived_headers {
+        match live_headers.get(name) {
+            None => {
+                headers.removed.insert(name.clone(), archived.clone());
+            }
+            Some(live) if live != archived => {
+                headers.changed.insert(name.clone(), ValueChange {
+                    archived: archived.clone(),
+                    live: live.clone(),
+                });
+            }
+            Some(_) => {}
+        }
+    }
+    for (name, live) in live_headers {
+        if !archived_headers.contains_key(&name) {
+            headers.added.insert(name, live);
+        }
+    }
+    
+    let archived_hash = response.and_then(|r| r.body_hash.clone());
+    // Hashed the way the archived body was, so an unchanged body matches
+    let algorithm = archived_hash.as_deref().and_then(HashAlgorithm::of).unwrap_or_default();
+    let live_hash = algorithm.hash(&live.body);
+    let changed = match &archived_hash {
+        Some(hash) => *hash != live_hash,
+        // Empty bodies aren't stored
+        None => !live.body.is_empty(),
+    };
+    
+    let mut json_changes = Vec::new();
+    if changed {
+        let archived_json = archived_body.and_then(|b| serde_json::from_slice::<Value>(b).ok());
+        let live_json = serde_json::from_slice::<Value>(&live.body).ok();
+        if let (Some(archived), Some(live)) = (archived_json, live_json) {
+            diff_json("$", Some(&archived), Some(&live), &mut json_changes);
+        }
+    }
+    
+    let body = BodyDrift {
+        archived_hash,
+        live_hash,
+        archived_size: response.and_then(|r| r.body_size),
+        live_size: live.body.len(),
+        changed,
+        json_changes,
+    };
+    
+    DriftReport {
+        request_id: request.request_id.clone(),
+        url: request.url.clone(),
+        fetched_at: chrono::Utc::now(),
+        drifted: status.changed
+            || !headers.added.is_empty()
+            || !headers.removed.is_empty()
+            || !headers.changed.is_empty()
+            || body.changed,
+        status,
+        headers,
+        body,
+    }
+}
+
+fn header_map<'a>(headers: impl Iterator<Item = (&'a str, &'a str)>) -> BTreeMap<String, String> {
+    let mut map: BTreeMap<String, String> = BTreeMap::new();
+    for (name, value) in headers {
+        let name = name.to_lowercase();
+        if VOLATILE_HEADERS.contains(&name.as_str()) {
+            continue;
+        }
+        // Repeated headers compare as one comma-joined value
+        map.entry(name)
+            .and_modify(|existing| {
+                existing.push_str(", ");
+                existing.push_str(value);
+            })
+            .or_insert_with(|| value.to_string());
+    }
+    map
+}
+
+/// Records where two JSON values differ, descending into objects and arrays.
+fn diff_json(path: &str, archived: Option<&Value>, live: Option<&Value>, out: &mut Vec<JsonChange>) {
+    if out.len() >= MAX_BODY_CHANGES || archived == live {
+        return;
+    }
+    
+    match (archived, live) {
+        (Some(Value::Object(a)), Some(Value::Object(b))) => {
+            let keys: std::collections::BTreeSet<&String> = a.keys().chain(b.keys()).collect();
+            for key in keys {
+                diff_json(&format!("{}.{}", path, key), a.get(key), b.get(key), out);
+            }
+        }
+        (Some(Value::Array(a)), Some(Value::Array(b))) => {
+            for index in 0..a.len().max(b.len()) {
+                diff_json(&format!("{}[{}]", path, index), a.get(index), b.get(index), out);
+            }
+        }
+        _ => out.push(JsonChange {
+            path: path.to_string(),
+            archived: archived.cloned(),
+            live: live.cloned(),
+        }),
+    }
+}
+
+/// Request headers not replayed: reqwest sets these itself, and dropping
+/// `accept-encoding` keeps live bodies comparable to the decoded archive.
+const SKIPPED_REQUEST_HEADERS: &[&str] = &["host", "content-length", "connection", "accept-encoding", "transfer-encoding"];
+const LIVE_FETCH_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+const MAX_REDIRECTS: usize = 10;
+
+/// Re-issues archived requests against their origin, restricted to an
+/// allowlist of hosts. An empty allowlist disables live fetches.
+#[derive(Clone)]
+pub struct LiveFetcher {
+    client: reqwest::Client,
+    allowed_hosts: std::sync::Arc<Vec<String>>,
+}
+
+impl LiveFetcher {
+    pub fn new(allowed_hosts: Vec<String>) -> Result<Self, reqwest::Error> {
+        let allowed_hosts: std::sync::Arc<Vec<String>> = std::sync::Arc::new(allowed_hosts.into_iter()
+            .map(|host| host.trim().to_lowercase())
+            .filter(|host| !host.is_empty())
+            .collect());
+        
+        // Redirects must stay on allowed hosts too
+        let redirect_hosts = allowed_hosts.clone();
+        let policy = reqwest::redirect::Policy::custom(move |attempt| {
+            if attempt.previous().len() >= MAX_REDIRECTS {
+                attempt.error("too many redirects")
+            } else if host_allowed(&redirect_hosts, attempt.url()) {
+                attempt.follow()
+            } else {
+                attempt.stop()
+            }
+        });
+        
+        let client = reqwest::Client::builder()
+            .redirect(policy)
+            .timeout(LIVE_FETCH_TIMEOUT)
+            .build()?;
+        Ok(LiveFetcher { client, allowed_hosts })
+    }
+    
+    pub fn is_enabled(&self) -> bool {
+        !self.allowed_hosts.is_empty()
+    }
+    
+    pub fn is_allowed(&self, url: &str) -> bool {
+        reqwest::Url::parse(url).is_ok_and(|url| host_allowed(&self.allowed_hosts, &url))
+    }
+    
+    /// Replays `request` with its archived method, headers and body.
+    pub async fn fetch(&self, request: &ArchivedRequest, body: Option<Vec<u8>>) -> Result<LiveResponse, reqwest::Error> {
+        let method = reqwest::Method::from_bytes(request.method.as_bytes())
+            .unwrap_or(reqwest::Method::GET);
+        let mut builder = self.client.request(method, &request.url);
+        for (name, value) in request.request_headers.iter() {
+            if !SKIPPED_REQUEST_HEADERS.contains(&name.to_lowercase().as_str()) {
+                builder = builder.header(name, value);
+            }
+        }
+        if let Some(body) = body {
+            builder = builder.body(body);
+        }
+        
+        let response = builder.send().await?;
+        let status_code = response.status().as_u16();
+        let headers = response.headers().iter()
+            .map(|(name, value)| (name.to_string(), String::from_utf8_lossy(value.as_bytes()).into_owned()))
+            .collect();
+        let body = response.bytes().await?.to_vec();
+        Ok(LiveResponse { status_code, headers, body })
+    }
+}
+
+/// Matches a host exactly or as a subdomain of an allowed entry.
+fn host_allowed(allowed_hosts: &[String], url: &reqwest::Url) -> bool {
+    let Some(host) = url.host_str().map(str::to_lowercase) else {
+        return false;
+    };
+    matches!(url.scheme(), "http" | "https")
+        && allowed_hosts.iter().any(|allowed| host == *allowed || host.ends_with(&format!(".{}", allowed)))
+}
@@ -0,0 +1,105 @@
+use axum::extract::Request;
+use axum::http::{header, HeaderValue, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use schemars::JsonSchema;
+use serde::Serialize;
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+/// Most of a plain-text error body kept as the envelope's message.
+const MAX_ERROR_TEXT: usize = 64 * 1024;
+
+/// ID assigned to each request, in its extensions, its tracing span, and
+/// the `X-Request-ID` response header.
+#[derive(Debug, Clone)]
+pub struct RequestId(pub String);
+
+/// What clients get for an error without a JSON body of its own.
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct ErrorBody {
+    pub error: String,
+    /// Snake-case form of the status, e.g. `not_found`.
+    pub code: String,
+    pub request_id: String,
+}
+
+/// Outermost middleware: assigns the request ID, then rewrites error
+/// responses with an empty or plain-text body into an `ErrorBody`, keeping
+/// their status and headers. JSON object error bodies (`/archive`'s, say)
+/// keep their fields and gain `code` and `request_id`.
+pub async fn envelope(mut request: Request, next: Next) -> Response {
+    let request_id = uuid::Uuid::new_v4().to_string();
+    request.extensions_mut().insert(RequestId(request_id.clone()));
+    
+    let mut response = next.run(request).await;
+    if let Ok(value) = HeaderValue::from_str(&request_id) {
+        response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+    let status = response.status();
+    let is_json = response.headers().get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/json"));
+    if !(status.is_client_error() || status.is_server_error()) {
+        return response;
+    }
+    if is_json {
+        return with_error_fields(response, status, request_id).await;
+    }
+    
+    let (mut parts, body) = response.into_parts();
+    let text = axum::body::to_bytes(body, MAX_ERROR_TEXT).await
+        .map(|bytes| String::from_utf8_lossy(&bytes).trim().to_string())
+        .unwrap_or_default();
+    let error = if text.is_empty() {
+        status.canonical_reason().unwrap_or("Error").to_string()
+    } else {
+        text
+    };
+    // Replaced by the JSON body's own
+    parts.headers.remove(header::CONTENT_TYPE);
+    parts.headers.remove(header::CONTENT_LENGTH);
+    (parts, Json(ErrorBody { error, code: status_code(status), request_id })).into_response()
+}
+
+/// Adds `code` and `request_id` to a JSON object error body, unless it
+/// already has them. Anything else is passed through.
+async fn with_error_fields(response: Response, status: StatusCode, request_id: String) -> Response {
+    let (mut parts, body) = response.into_parts();
+    let bytes = match axum::body::to_bytes(body, usize::MAX).await {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            tracing::error!("Failed to read JSON error body: {}", e);
+            return (parts, axum::body::Body::empty()).into_response();
+        }
+    };
+    let Ok(serde_json::Value::Object(mut fields)) = serde_json::from_slice(&bytes) else {
+        return (parts, bytes).into_response();
+    };
+    fields.entry("code").or_insert_with(|| status_code(status).into());
+    fields.entry("request_id").or_insert_with(|| request_id.into());
+    parts.headers.remove(header::CONTENT_LENGTH);
+    (parts, Json(fields)).into_response()
+}
+
+/// Answers a panicking handler with a 500 (which `envelope` then fills in),
+/// logging what it panicked with.
+pub fn panic_response(panic: Box<dyn std::any::Any + Send + 'static>) -> Response {
+    let message = panic.downcast_ref::<String>().map(String::as_str)
+        .or_else(|| panic.downcast_ref::<&str>().copied())
+        .unwrap_or("unknown panic");
+    tracing::error!("Handler panicked: {}", message);
+    StatusCode::INTERNAL_SERVER_ERROR.into_response()
+}
+
+fn status_code(status: StatusCode) -> String {
+    status.canonical_reason()
+        .unwrap_or("error")
+        .chars()
+        .filter_map(|c| match c {
+            c if c.is_ascii_alphanumeric() => Some(c.to_ascii_lowercase()),
+            ' ' | '-' => Some('_'),
+            _ => None,
+        })
+        .collect()
+}
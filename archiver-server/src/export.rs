@@ -0,0 +1,386 @@
+use base64::Engine;
+use crate::storage::{ArchivedRequest, ArchivedResponse, FrameDirection, Headers, PageFetchIndex, WebSocketFrame};
+use serde_json::json;
+use std::collections::HashMap;
+
+/// A captured body ready to embed in an export.
+pub struct Resource {
+    pub url: String,
+    pub content_type: String,
+    pub body: Vec<u8>,
+}
+
+/// Rebuilds the HTTP/1.1 request message for `request`: request line,
+/// headers (with `Host` added if it wasn't captured), blank line, body.
+pub fn raw_request(request: &ArchivedRequest, body: Option<&[u8]>) -> String {
+    let parsed = ::url::Url::parse(&request.url).ok();
+    let target = match &parsed {
+        Some(url) => match url.query() {
+            Some(query) => format!("{}?{}", url.path(), query),
+            None => url.path().to_string(),
+        },
+        None => request.url.clone(),
+    };
+    
+    let mut out = format!("{} {} HTTP/1.1\r\n", request.method, target);
+    let has_host = request.request_headers.contains("host");
+    if let (false, Some(host)) = (has_host, parsed.as_ref().and_then(|url| url.host_str())) {
+        match parsed.as_ref().and_then(|url| url.port()) {
+            Some(port) => out.push_str(&format!("Host: {}:{}\r\n", host, port)),
+            None => out.push_str(&format!("Host: {}\r\n", host)),
+        }
+    }
+    push_message(&mut out, &request.request_headers, body);
+    out
+}
+
+/// Rebuilds the response message for `response` in HTTP/1.1 syntax: status
+/// line with the captured version and reason phrase, headers, blank line, body.
+pub fn raw_response(response: &ArchivedResponse, body: Option<&[u8]>) -> String {
+    let mut out = format!("{} {} {}\r\n", response.version(), response.status_code, response.reason());
+    push_message(&mut out, &response.headers, body);
+    out
+}
+
+fn push_message(out: &mut String, headers: &Headers, body: Option<&[u8]>) {
+    for (name, value) in headers.iter() {
+        out.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    out.push_str("\r\n");
+    if let Some(body) = body {
+        out.push_str(&String::from_utf8_lossy(body));
+    }
+}
+
+/// rrweb release the extension records with, loaded by `export.html` when no
+/// local build is configured.
+const RRWEB_CDN: &str = "https://cdn.jsdelivr.net/npm/rrweb@2.0.0-alpha.4/dist";
+
+/// The rrweb build an HTML export replays with.
+pub enum ReplayPlayer {
+    /// Embedded in the file, so it replays offline.
+    Inline { script: String, stylesheet: Option<String> },
+    /// Loaded from `RRWEB_CDN` when the file is opened.
+    Cdn,
+}
+
+/// Builds a standalone HTML page that replays `events` with rrweb's
+/// `Replayer`. The events are embedded as JSON that can't close its
+/// `<script>` element early; a session note becomes the page description.
+pub fn build_replay_html(title: &str, note: Option<&str>, events: &[serde_json::Value], player: &ReplayPlayer) -> String {
+    let events = serde_json::to_string(events).unwrap_or_else(|_| "[]".to_string())
+        // `<` only occurs inside JSON strings, where the escape means the same
+        .replace('<', "\\u003c");
+    let (script, stylesheet) = match player {
+        ReplayPlayer::Inline { script, stylesheet } => (
+            format!("<script>{}</script>", script.replace("</script", "<\\/script")),
+            stylesheet.as_ref()
+                .map(|css| format!("<style>{}</style>", css.replace("</style", "<\\/style")))
+                .unwrap_or_default(),
+        ),
+        ReplayPlayer::Cdn => (
+            format!("<script src=\"{}/rrweb.min.js\"></script>", RRWEB_CDN),
+            format!("<link rel=\"stylesheet\" href=\"{}/rrweb.min.css\">", RRWEB_CDN),
+        ),
+    };
+    
+    format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>{title}</title>
+{description}{stylesheet}
+<style>body {{ margin: 0; background: #f4f4f4; }} #archiver-player {{ display: flex; justify-content: center; padding: 16px; }}</style>
+</head>
+<body>
+<div id="archiver-player"></div>
+<script type="application/json" id="archiver-events">{events}</script>
+{script}
+<script id="archiver-bootstrap">
+const events = JSON.parse(document.getElementById("archiver-events").textContent);
+const replayer = new rrweb.Replayer(events, {{ root: document.getElementById("archiver-player") }});
+replayer.play();
+</script>
+</body>
+</html>
+"#,
+        title = escape_html(title),
+        description = note
+            .map(|note| format!("<meta name=\"description\" content=\"{}\">\n", escape_html(note)))
+            .unwrap_or_default(),
+    )
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// A `Content-Disposition` value offering the export as `{stem}.{extension}`.
+/// Characters of `stem` outside `[A-Za-z0-9._-]` become `_`, so a client ID
+/// can't close the quoted filename or break the header.
+pub fn attachment(stem: &str, extension: &str) -> String {
+    let stem: String = stem.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-') { c } else { '_' })
+        .collect();
+    format!("attachment; filename=\"{}.{}\"", stem, extension)
+}
+
+/// Builds a `multipart/related` MHTML document that browsers open offline.
+/// `document` becomes the root part; absolute references to each resource
+/// inside it are rewritten to that resource's `cid:`.
+pub fn build_mhtml(page: &PageFetchIndex, document: Resource, resources: &[Resource]) -> String {
+    let boundary = format!("----ArchiverBoundary-{}", page.navigation_id);
+    let content_id = |index: usize| format!("resource-{}@{}", index, page.navigation_id);
+    
+    let mut html = String::from_utf8_lossy(&document.body).into_owned();
+    for (index, resource) in resources.iter().enumerate() {
+        html = html.replace(&resource.url, &format!("cid:{}", content_id(index)));
+    }
+    
+    let date = chrono::DateTime::from_timestamp_millis(page.timestamp)
+        .unwrap_or_else(chrono::Utc::now)
+        .to_rfc2822();
+    
+    let mut out = String::new();
+    out.push_str("From: <Saved by Archiver>\r\n");
+    out.push_str(&format!("Snapshot-Content-Location: {}\r\n", document.url));
+    out.push_str(&format!("Subject: {}\r\n", page.page_url));
+    out.push_str(&format!("Date: {}\r\n", date));
+    out.push_str("MIME-Version: 1.0\r\n");
+    out.push_str(&format!(
+        "Content-Type: multipart/related;\r\n\ttype=\"text/html\";\r\n\tboundary=\"{}\"\r\n\r\n",
+        boundary
+    ));
+    
+    push_part(&mut out, &boundary, &document.content_type, &format!("document@{}", page.navigation_id), &document.url, html.as_bytes());
+    for (index, resource) in resources.iter().enumerate() {
+        push_part(&mut out, &boundary, &resource.content_type, &content_id(index), &resource.url, &resource.body);
+    }
+    out.push_str(&format!("--{}--\r\n", boundary));
+    
+    out
+}
+
+fn push_part(out: &mut String, boundary: &str, content_type: &str, content_id: &str, location: &str, body: &[u8]) {
+    out.push_str(&format!("--{}\r\n", boundary));
+    out.push_str(&format!("Content-Type: {}\r\n", content_type));
+    out.push_str(&format!("Content-ID: <{}>\r\n", content_id));
+    out.push_str("Content-Transfer-Encoding: base64\r\n");
+    out.push_str(&format!("Content-Location: {}\r\n\r\n", location));
+    
+    let encoded = base64::engine::general_purpose::STANDARD.encode(body);
+    // MIME caps line length at 76 characters
+    for line in encoded.as_bytes().chunks(76) {
+        out.push_str(std::str::from_utf8(line).unwrap_or_default());
+        out.push_str("\r\n");
+    }
+    out.push_str("\r\n");
+}
+
+/// Builds a HAR 1.2 log for the given page fetches. `bodies` maps content
+/// hashes to their bytes; bodies missing from it are exported empty. The
+/// session's note, if any, is the log's `comment`.
+pub fn build_har(page_fetches: &[PageFetchIndex], bodies: &HashMap<String, Vec<u8>>, note: Option<&str>) -> serde_json::Value {
+    let iso = |millis: i64| {
+        chrono::DateTime::from_timestamp_millis(millis)
+            .unwrap_or_default()
+            .to_rfc3339_opts(chrono::SecondsFormat::Millis, true)
+    };
+    let har_headers = |headers: &Headers| {
+        headers.iter()
+            .map(|(name, value)| json!({ "name": name, "value": value }))
+            .collect::<Vec<_>>()
+    };
+    
+    let mut pages = Vec::new();
+    let mut entries = Vec::new();
+    
+    for page_fetch in page_fetches {
+        pages.push(json!({
+            "startedDateTime": iso(page_fetch.timestamp),
+            "id": page_fetch.navigation_id,
+            "title": page_fetch.page_url,
+            "pageTimings": {},
+        }));
+        
+        let page_start = entries.len();
+        for request in &page_fetch.requests {
+            let query_string: Vec<_> = request.url.split_once('?')
+                .map(|(_, query)| query.split('&')
+                    .filter(|pair| !pair.is_empty())
+                    .map(|pair| {
+                        let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+                        json!({ "name": name, "value": value })
+                    })
+                    .collect())
+                .unwrap_or_default();
+            
+            let mut har_request = json!({
+                "method": request.method,
+                "url": request.url,
+                // The request went out over the protocol the response came back on
+                "httpVersion": request.response.as_ref().map_or("HTTP/1.1", |r| r.version()),
+                "cookies": [],
+                "headers": har_headers(&request.request_headers),
+                "queryString": query_string,
+                "headersSize": -1,
+                "bodySize": request.request_body_size.map(|s| s as i64).unwrap_or(0),
+            });
+            if let Some(body) = request.request_body_hash.as_ref().and_then(|h| bodies.get(h)) {
+                har_request["postData"] = json!({
+                    "mimeType": request.request_headers.get("content-type").unwrap_or_default(),
+                    "text": String::from_utf8_lossy(body),
+                });
+            }
+            
+            let har_response = match &request.response {
+                Some(response) => {
+                    let mut content = json!({
+                        "size": response.body_size.unwrap_or(0),
+                        "mimeType": response.body_type.clone().unwrap_or_default(),
+                    });
+                    // A 304 shows the cached body it revalidated
+                    if let Some(body) = response.served_body_hash().and_then(|h| bodies.get(h)) {
+                        match std::str::from_utf8(body) {
+                            Ok(text) => content["text"] = json!(text),
+                            Err(_) => {
+                                content["text"] = json!(base64::engine::general_purpose::STANDARD.encode(body));
+                                content["encoding"] = json!("base64");
+                            }
+                        }
+                    }
+                    json!({
+                        "status": response.status_code,
+                        "statusText": response.reason(),
+                        "httpVersion": response.version(),
+                        "cookies": [],
+                        "headers": har_headers(&response.headers),
+                        "content": content,
+                        "redirectURL": response.header("location").unwrap_or_default(),
+                        "headersSize": -1,
+                        "bodySize": response.body_size.map(|s| s as i64).unwrap_or(-1),
+                    })
+                }
+                // HAR has no notion of a missing response; status 0 marks it
+                None => json!({
+                    "status": 0,
+                    "statusText": "",
+                    "httpVersion": "",
+                    "cookies": [],
+                    "headers": [],
+                    "content": { "size": 0, "mimeType": "" },
+                    "redirectURL": "",
+                    "headersSize": -1,
+                    "bodySize": -1,
+                }),
+            };
+            
+            let mut entry = json!({
+                "pageref": page_fetch.navigation_id,
+                "startedDateTime": iso(request.timestamp),
+                "time": request.duration_ms.unwrap_or(0.0),
+                "request": har_request,
+                "response": har_response,
+                "cache": {},
+                // Only the total is known; it's all attributed to waiting
+                "timings": { "send": 0, "wait": request.duration_ms.unwrap_or(0.0), "receive": 0 },
+            });
+            // Chrome DevTools' custom fields for resource type and priority
+            if let Some(resource_type) = &request.resource_type {
+                entry["_resourceType"] = json!(resource_type);
+            }
+            if let Some(priority) = &request.priority {
+                entry["_priority"] = json!(priority);
+            }
+            entries.push(entry);
+        }
+        
+        // Each socket's frames go on its handshake's entry, or on an entry
+        // standing in for it when the handshake wasn't captured
+        let mut sockets: Vec<(&str, Vec<&WebSocketFrame>)> = Vec::new();
+        for frame in &page_fetch.websocket_frames {
+            match sockets.iter_mut().find(|(url, _)| *url == frame.url) {
+                Some((_, frames)) => frames.push(frame),
+                None => sockets.push((&frame.url, vec![frame])),
+            }
+        }
+        for (url, frames) in sockets {
+            let messages: Vec<_> = frames.iter()
+                .map(|frame| {
+                    let data = frame.payload_hash.as_ref()
+                        .and_then(|hash| bodies.get(hash))
+                        .map(|body| match std::str::from_utf8(body) {
+                            Ok(text) if frame.opcode == 1 => text.to_string(),
+                            _ => base64::engine::general_purpose::STANDARD.encode(body),
+                        })
+                        .unwrap_or_default();
+                    json!({
+                        "type": match frame.direction {
+                            FrameDirection::Outbound => "send",
+                            FrameDirection::Inbound => "receive",
+                        },
+                        "time": frame.timestamp as f64 / 1000.0,
+                        "opcode": frame.opcode,
+                        "data": data,
+                    })
+                })
+                .collect();
+            
+            let handshake = entries[page_start..].iter_mut()
+                .find(|entry| entry["request"]["url"] == url);
+            match handshake {
+                Some(entry) => {
+                    entry["_resourceType"] = json!("websocket");
+                    entry["_webSocketMessages"] = json!(messages);
+                }
+                None => entries.push(json!({
+                    "pageref": page_fetch.navigation_id,
+                    "startedDateTime": iso(frames[0].timestamp),
+                    "time": 0,
+                    "request": {
+                        "method": "GET",
+                        "url": url,
+                        "httpVersion": "HTTP/1.1",
+                        "cookies": [],
+                        "headers": [],
+                        "queryString": [],
+                        "headersSize": -1,
+                        "bodySize": 0,
+                    },
+                    "response": {
+                        "status": 101,
+                        "statusText": "Switching Protocols",
+                        "httpVersion": "HTTP/1.1",
+                        "cookies": [],
+                        "headers": [],
+                        "content": { "size": 0, "mimeType": "" },
+                        "redirectURL": "",
+                        "headersSize": -1,
+                        "bodySize": 0,
+                    },
+                    "cache": {},
+                    "timings": { "send": 0, "wait": 0, "receive": 0 },
+                    "_resourceType": "websocket",
+                    "_webSocketMessages": messages,
+                })),
+            }
+        }
+    }
+    
+    let mut har = json!({
+        "log": {
+            "version": "1.2",
+            "creator": { "name": "archiver", "version": env!("CARGO_PKG_VERSION") },
+            "pages": pages,
+            "entries": entries,
+        }
+    });
+    if let Some(note) = note {
+        har["log"]["comment"] = json!(note);
+    }
+    har
+}
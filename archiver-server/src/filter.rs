@@ -0,0 +1,83 @@
+use sha2::{Digest, Sha256};
+
+/// Which exchanges `/archive` stores, by response content type and request
+/// host. Patterns are case-insensitive globs where `*` matches any run of
+/// characters and `?` any one. A deny match always skips; a non-empty allow
+/// list skips everything it doesn't match.
+#[derive(Debug, Clone, Default)]
+pub struct IngestFilter {
+    pub allow_content_types: Vec<String>,
+    pub deny_content_types: Vec<String>,
+    pub allow_hosts: Vec<String>,
+    pub deny_hosts: Vec<String>,
+}
+
+impl IngestFilter {
+    /// Reads `ARCHIVER_{ALLOW,DENY}_{CONTENT_TYPES,HOSTS}`, each a
+    /// comma-separated list of globs.
+    pub fn from_env() -> Self {
+        let list = |name: &str| -> Vec<String> {
+            std::env::var(name).unwrap_or_default()
+                .split(',')
+                .map(|pattern| pattern.trim().to_lowercase())
+                .filter(|pattern| !pattern.is_empty())
+                .collect()
+        };
+        IngestFilter {
+            allow_content_types: list("ARCHIVER_ALLOW_CONTENT_TYPES"),
+            deny_content_types: list("ARCHIVER_DENY_CONTENT_TYPES"),
+            allow_hosts: list("ARCHIVER_ALLOW_HOSTS"),
+            deny_hosts: list("ARCHIVER_DENY_HOSTS"),
+        }
+    }
+    
+    pub fn allows_host(&self, host: &str) -> bool {
+        allowed(&self.allow_hosts, &self.deny_hosts, &host.to_lowercase())
+    }
+    
+    /// Parameters such as `; charset=utf-8` are ignored.
+    pub fn allows_content_type(&self, content_type: &str) -> bool {
+        let essence = content_type.split(';').next().unwrap_or_default().trim().to_lowercase();
+        allowed(&self.allow_content_types, &self.deny_content_types, &essence)
+    }
+}
+
+/// Whether sampling at `rate` (0.0 to 1.0) keeps requests to `url`. The
+/// decision comes from a hash of the URL, so it's the same on every batch.
+pub fn sampled_in(url: &str, rate: f64) -> bool {
+    let digest = Sha256::digest(url.as_bytes());
+    let bucket = u64::from_be_bytes(digest[..8].try_into().expect("SHA-256 is 32 bytes"));
+    (bucket as f64) < rate * u64::MAX as f64
+}
+
+fn allowed(allow: &[String], deny: &[String], value: &str) -> bool {
+    !deny.iter().any(|pattern| glob_match(pattern, value))
+        && (allow.is_empty() || allow.iter().any(|pattern| glob_match(pattern, value)))
+}
+
+/// Matches `text` against `pattern`, both already lowercased, backtracking
+/// only to the most recent `*`.
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = star {
+            // Let the last `*` absorb one more character
+            p = star_p + 1;
+            t = star_t + 1;
+            star = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
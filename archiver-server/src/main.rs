@@ -1,30 +1,116 @@
+mod bloom;
+mod classify;
+mod content_store;
+mod drift;
+mod errors;
+mod export;
+mod filter;
+mod metrics;
+mod msgpack;
+mod openapi;
+mod provenance;
+#[cfg(feature = "replay")]
+mod replay;
+mod rrweb;
+#[cfg(feature = "s3")]
+mod s3;
+mod schema;
+mod sniff;
 mod storage;
+#[cfg(test)]
+mod tests;
+mod url;
+mod webhook;
 
 use axum::{
-    extract::State,
-    http::Method,
-    response::Json,
-    routing::{get, post},
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        DefaultBodyLimit, FromRequestParts, Path, Query,
+    },
+    http::{request::Parts, Method, StatusCode},
+    http::header,
+    response::{IntoResponse, Json, Response},
+    routing::{delete, get, post},
     Router,
 };
+use base64::Engine;
+use classify::EntropyClassifier;
+use clap::Parser;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::hash::BuildHasher;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
-use storage::{ArchivedRequest, ArchivedResponse, PageFetchIndex, Storage};
-use tokio::sync::Mutex;
-use tower_http::cors::{Any, CorsLayer};
+use storage::{ArchivedRequest, ArchivedResponse, ClientInfo, HashAlgorithm, ImageKind, PageFetchIndex, RedactionMarker, Storage, StorageConfig, StorageError};
+use tokio::sync::{broadcast, Mutex};
+use tower::ServiceBuilder;
+use tower_http::catch_panic::CatchPanicLayer;
+use tower_http::cors::{AllowHeaders, AllowOrigin, Any, CorsLayer};
+use tower_http::limit::RequestBodyLimitLayer;
 use tower_http::trace::TraceLayer;
 use tracing::{info, debug};
-use tracing_subscriber;
 use uuid::Uuid;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Parser)]
+#[command(about = "Archiver storage server")]
+struct Cli {
+    /// Directory holding archived content, sessions, and metadata
+    #[arg(long, env = "ARCHIVER_DATA_DIR", default_value = "./archiver-data")]
+    data_dir: std::path::PathBuf,
+    
+    /// Address to listen on
+    #[arg(long, env = "ARCHIVER_BIND", default_value = "127.0.0.1:41788")]
+    bind: std::net::SocketAddr,
+    
+    /// Zstd compression level for stored content
+    #[arg(long, env = "ARCHIVER_COMPRESSION_LEVEL", default_value_t = 3,
+        value_parser = clap::value_parser!(i32).range(1..=22))]
+    compression_level: i32,
+    
+    /// Tracing filter directive, e.g. `info` or `archiver_server=debug`
+    #[arg(long, env = "RUST_LOG", default_value = "info")]
+    log_level: String,
+    
+    /// Hosts that archived requests may be re-fetched from live, comma separated.
+    /// Subdomains match; live fetches are disabled when empty
+    #[arg(long, env = "ARCHIVER_LIVE_FETCH_ALLOWLIST", value_delimiter = ',')]
+    live_fetch_allowlist: Vec<String>,
+    
+    /// URL that receives a JSON summary of each stored page fetch
+    #[arg(long, env = "WEBHOOK_URL")]
+    webhook_url: Option<reqwest::Url>,
+    
+    /// Chromium binary launched to replay recordings
+    #[cfg(feature = "replay")]
+    #[arg(long, env = "ARCHIVER_CHROME_PATH", default_value = "chromium")]
+    chrome_path: std::path::PathBuf,
+}
+
+const RETENTION_SWEEP_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+const LIVE_EVENT_BUFFER: usize = 256;
+/// How long `/ready` reuses a storage probe's result.
+const READY_CACHE_TTL: std::time::Duration = std::time::Duration::from_secs(5);
+const TENANT_HEADER: &str = "x-archiver-tenant";
+const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+/// Set on a response repeated for a retried `Idempotency-Key`.
+const IDEMPOTENT_REPLAYED_HEADER: &str = "idempotent-replayed";
+const MAX_IDEMPOTENCY_KEY_LEN: usize = 255;
+/// Header value naming the tenant used when the header is absent.
+const DEFAULT_TENANT: &str = "default";
+const DEFAULT_TOP_CONTENT: usize = 20;
+const MAX_TOP_CONTENT: usize = 1000;
+/// Longest session note, in bytes.
+const MAX_NOTE_BYTES: usize = 64 * 1024;
+const BODY_HASH_MISMATCH: &str = "body doesn't match its supplied SHA-256";
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 struct HttpHeader {
     name: String,
     value: String,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 #[serde(tag = "type", rename_all = "snake_case")]
 enum ArchiveEntry {
     Request {
@@ -34,6 +120,16 @@ enum ArchiveEntry {
         method: String,
         request_headers: Option<Vec<HttpHeader>>,
         request_body: Option<serde_json::Value>,
+        /// Client's SHA-256 of the body, checked on receipt. A string body is
+        /// hashed as its text; any other JSON as its compact serialization.
+        #[serde(default)]
+        request_body_sha256: Option<String>,
+        /// Browser resource type, e.g. `document`, `script`, `xhr`.
+        #[serde(default)]
+        resource_type: Option<String>,
+        /// Browser fetch priority, e.g. `High`, `Low`.
+        #[serde(default)]
+        priority: Option<String>,
     },
     Response {
         id: String,
@@ -41,12 +137,53 @@ enum ArchiveEntry {
         url: String,
         method: String,
         status_code: Option<u16>,
+        /// Reason phrase from the status line, e.g. `Non-Authoritative Information`.
+        #[serde(default)]
+        status_text: Option<String>,
+        /// Protocol version, e.g. `HTTP/1.1`, or an ALPN ID such as `h2`.
+        #[serde(default)]
+        http_version: Option<String>,
         response_headers: Option<Vec<HttpHeader>>,
         response_body: Option<String>,
+        #[serde(default)]
+        body_encoding: BodyEncoding,
+        /// Client's SHA-256 of the body, checked on receipt. A base64 body is
+        /// hashed as its decoded bytes.
+        #[serde(default)]
+        response_body_sha256: Option<String>,
+        /// Milliseconds from the request starting to the response, as the
+        /// client timed it. Taken from the two timestamps when absent.
+        #[serde(default)]
+        duration_ms: Option<f64>,
+    },
+    /// A message sent or received on a WebSocket, filed with the page the
+    /// socket's host groups under.
+    WebSocketFrame {
+        id: String,
+        timestamp: i64,
+        url: String,
+        direction: storage::FrameDirection,
+        /// 1 for text, 2 for binary.
+        opcode: u8,
+        #[serde(default)]
+        payload: Option<String>,
+        #[serde(default)]
+        payload_encoding: BodyEncoding,
     },
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// How a response body is carried in JSON.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+enum BodyEncoding {
+    #[default]
+    Text,
+    /// Standard base64 of the body's bytes, for images, fonts, and other
+    /// bodies that aren't text.
+    Base64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 struct PasswordHash {
     id: String,
     timestamp: i64,
@@ -55,31 +192,241 @@ struct PasswordHash {
     hash: String,
 }
 
+/// One tenant's state, resolved for each request from `X-Archiver-Tenant`.
 #[derive(Clone)]
 struct AppState {
     storage: Arc<Storage>,
-    active_sessions: Arc<Mutex<HashMap<String, PageFetchIndex>>>,
+    active_sessions: Arc<Mutex<HashMap<String, ActiveSession>>>,
     rrweb_sessions: Arc<Mutex<HashMap<String, RrwebSession>>>,
+    live_events: broadcast::Sender<LiveEvent>,
+    live_fetcher: drift::LiveFetcher,
+    /// Redacts sensitive data from response bodies before storage; `None`
+    /// stores them as sent.
+    classifier: Option<Arc<dyn classify::Classifier>>,
+    /// Shared by every tenant; `None` when no webhook is configured.
+    webhook: Option<webhook::Webhook>,
+    counters: Arc<metrics::IngestCounters>,
+    /// Last storage probe made for `/ready`, shared by every tenant.
+    readiness: Arc<Mutex<Option<Readiness>>>,
+    /// Shared by every tenant; replays recordings for `/sessions/{id}/replay`.
+    #[cfg(feature = "replay")]
+    browser: Option<Arc<dyn replay::BrowserDriver>>,
+    /// Shared by every tenant; replays run one at a time, in the order
+    /// they were queued.
+    #[cfg(feature = "replay")]
+    replay_queue: Option<tokio::sync::mpsc::UnboundedSender<QueuedReplay>>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+impl AppState {
+    fn new(
+        storage: Storage,
+        live_fetcher: drift::LiveFetcher,
+        classifier: Option<Arc<dyn classify::Classifier>>,
+        webhook: Option<webhook::Webhook>,
+    ) -> Self {
+        AppState {
+            storage: Arc::new(storage),
+            active_sessions: Arc::new(Mutex::new(HashMap::new())),
+            rrweb_sessions: Arc::new(Mutex::new(HashMap::new())),
+            live_events: broadcast::channel(LIVE_EVENT_BUFFER).0,
+            live_fetcher,
+            classifier,
+            webhook,
+            counters: Arc::new(metrics::IngestCounters::default()),
+            readiness: Arc::new(Mutex::new(None)),
+            #[cfg(feature = "replay")]
+            browser: None,
+            #[cfg(feature = "replay")]
+            replay_queue: None,
+        }
+    }
+}
+
+struct Readiness {
+    checked_at: std::time::Instant,
+    /// Why storage failed the probe; `None` when it passed.
+    failure: Option<String>,
+}
+
+/// Router state: the default tenant plus every other tenant opened so far.
+#[derive(Clone)]
+struct Tenants {
+    default: AppState,
+    others: Arc<Mutex<HashMap<String, AppState>>>,
+}
+
+impl Tenants {
+    /// Returns a tenant's state, opening its namespace on first use.
+    async fn get(&self, tenant: &str) -> Result<AppState, StorageError> {
+        if tenant == DEFAULT_TENANT {
+            return Ok(self.default.clone());
+        }
+        let mut others = self.others.lock().await;
+        if let Some(state) = others.get(tenant) {
+            return Ok(state.clone());
+        }
+        let storage = self.default.storage.open_tenant(tenant).await?;
+        info!("Opened tenant {}", tenant);
+        let mut state = AppState::new(
+            storage,
+            self.default.live_fetcher.clone(),
+            self.default.classifier.clone(),
+            self.default.webhook.clone(),
+        );
+        state.readiness = self.default.readiness.clone();
+        #[cfg(feature = "replay")]
+        {
+            state.browser = self.default.browser.clone();
+            state.replay_queue = self.default.replay_queue.clone();
+        }
+        others.insert(tenant.to_string(), state.clone());
+        Ok(state)
+    }
+    
+    async fn all(&self) -> Vec<AppState> {
+        let others = self.others.lock().await;
+        std::iter::once(self.default.clone())
+            .chain(others.values().cloned())
+            .collect()
+    }
+}
+
+#[axum::async_trait]
+impl FromRequestParts<Tenants> for AppState {
+    type Rejection = (StatusCode, String);
+    
+    async fn from_request_parts(parts: &mut Parts, tenants: &Tenants) -> Result<Self, Self::Rejection> {
+        let Some(value) = parts.headers.get(TENANT_HEADER) else {
+            return Ok(tenants.default.clone());
+        };
+        let tenant = value.to_str().ok()
+            .filter(|tenant| *tenant == DEFAULT_TENANT || storage::is_valid_tenant(tenant))
+            .ok_or_else(|| (StatusCode::BAD_REQUEST, format!("Invalid {} header", TENANT_HEADER)))?;
+        tenants.get(tenant).await.map_err(|e| {
+            tracing::error!("Failed to open tenant {}: {}", tenant, e);
+            (storage_status(&e), format!("Failed to open tenant: {}", e))
+        })
+    }
+}
+
+/// Page fetches being appended to for one session.
+#[derive(Debug, Default)]
+struct ActiveSession {
+    /// Navigation used for batches that don't name one.
+    default_navigation: Option<String>,
+    pages: HashMap<String, PageFetchIndex>,
+    /// What `/stats` counts in each of `pages`, kept by `insert_page` so
+    /// stats needn't walk every request while holding the lock.
+    tallies: HashMap<String, PageTally>,
+}
+
+impl ActiveSession {
+    fn insert_page(&mut self, page_fetch: PageFetchIndex) {
+        self.tallies.insert(page_fetch.navigation_id.clone(), PageTally::of(&page_fetch));
+        self.pages.insert(page_fetch.navigation_id.clone(), page_fetch);
+    }
+}
+
+/// One in-memory page's share of `/stats`.
+#[derive(Debug, Clone, Default)]
+struct PageTally {
+    requests: usize,
+    responses: usize,
+    third_party: usize,
+    /// Sum of the requests' `duration_ms`, over `timed` of them.
+    duration_ms: f64,
+    timed: usize,
+    password_hashes: Vec<String>,
+}
+
+impl PageTally {
+    fn of(page_fetch: &PageFetchIndex) -> Self {
+        let durations = page_fetch.requests.iter().filter_map(|r| r.duration_ms);
+        PageTally {
+            requests: page_fetch.requests.len(),
+            responses: page_fetch.requests.iter().filter(|r| r.response.is_some()).count(),
+            third_party: page_fetch.requests.iter().filter(|r| r.third_party).count(),
+            duration_ms: durations.clone().sum(),
+            timed: durations.count(),
+            password_hashes: page_fetch.password_hashes.clone(),
+        }
+    }
+}
+
+/// Pushed to `/ws` subscribers whenever ingest persists something.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum LiveEvent {
+    Archive {
+        session_id: String,
+        request_count: usize,
+        bytes_stored: usize,
+    },
+    Recording {
+        session_id: String,
+        event_count: usize,
+        bytes_stored: usize,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
 struct ArchiveRequest {
-    entries: Vec<ArchiveEntry>,
+    /// `ArchiveEntry` objects, parsed one at a time so a malformed entry
+    /// doesn't cost the rest of the batch.
+    #[schemars(with = "Vec<ArchiveEntry>")]
+    entries: Vec<serde_json::Value>,
     password_hashes: Vec<String>,
+    /// Client-assigned page identifier; batches sharing one merge into a
+    /// single `PageFetchIndex`. Generated server-side when absent.
+    #[serde(default)]
+    navigation_id: Option<String>,
+    /// Roll back everything stored by this batch if any part of it fails.
+    #[serde(default)]
+    atomic: bool,
+    /// Version of the capturing extension, recorded in provenance records.
+    /// Defaults to `client_info`'s version.
+    #[serde(default)]
+    client_version: Option<String>,
+    /// The client sending the batch, kept on each page fetch it writes.
+    #[serde(default)]
+    client_info: Option<ClientInfo>,
+    /// The page as the client last saw it, as base64 PNG or a base64 `data:`
+    /// URL. Attached to the page fetch of the batch's first request.
+    #[serde(default)]
+    screenshot: Option<String>,
+    /// The page's icon, in the same forms as `screenshot`.
+    #[serde(default)]
+    favicon: Option<String>,
+    /// URL of the top-level page the batch was captured on, which requests
+    /// are compared against to tag them third-party. Defaults to each page
+    /// fetch's `page_url`.
+    #[serde(default)]
+    page_url: Option<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
 struct PasswordHashRequest {
     hashes: Vec<PasswordHash>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 struct RrwebRecordingRequest {
     session_id: String,
     url: String,
     timestamp: i64,
     events: Vec<serde_json::Value>,
     password_hashes: Vec<String>,
+    /// Position of this batch in the session, counting from 0. Batches that
+    /// arrive out of order are slotted in by it.
+    #[serde(default)]
+    batch_seq: Option<u64>,
+    /// As in `ArchiveRequest`, attached to this batch.
+    #[serde(default)]
+    screenshot: Option<String>,
+    #[serde(default)]
+    favicon: Option<String>,
+    #[serde(default)]
+    client_info: Option<ClientInfo>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -89,16 +436,239 @@ struct RrwebSession {
     timestamp: i64,
     events: Vec<serde_json::Value>,
     password_hashes: HashSet<String>,
+    /// Sequence numbers below the highest received that haven't arrived.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    missing_batches: Vec<u64>,
+    /// Event count per sequence number, in `events` order.
+    #[serde(skip)]
+    batch_sizes: BTreeMap<u64, usize>,
+    /// Sequence numbers clients actually sent.
+    #[serde(skip)]
+    received_seqs: BTreeSet<u64>,
+    /// When a batch last arrived, in milliseconds, for picking which
+    /// session to drop from memory.
+    #[serde(skip)]
+    updated_at: i64,
+}
+
+impl RrwebSession {
+    /// Reassembles a session from its stored batches, oldest first.
+    fn from_batches(batches: Vec<storage::RecordingBatch>) -> Option<Self> {
+        let first = batches.first()?;
+        let mut session = RrwebSession {
+            session_id: first.session_id.clone(),
+            url: first.url.clone(),
+            timestamp: first.timestamp,
+            events: Vec::new(),
+            password_hashes: HashSet::new(),
+            missing_batches: Vec::new(),
+            batch_sizes: BTreeMap::new(),
+            received_seqs: BTreeSet::new(),
+            updated_at: chrono::Utc::now().timestamp_millis(),
+        };
+        for batch in batches {
+            session.add_batch(batch.batch_seq, batch.events, batch.password_hashes);
+        }
+        Some(session)
+    }
+    
+    /// Slots a batch's events in after every batch with a lower or equal
+    /// sequence number. A batch without one goes after everything so far.
+    fn add_batch(&mut self, batch_seq: Option<u64>, events: Vec<serde_json::Value>, password_hashes: Vec<String>) {
+        let seq = batch_seq.unwrap_or_else(|| self.batch_sizes.keys().next_back().map_or(0, |last| last + 1));
+        let at: usize = self.batch_sizes.range(..=seq).map(|(_, size)| size).sum();
+        *self.batch_sizes.entry(seq).or_default() += events.len();
+        self.events.splice(at..at, events);
+        self.password_hashes.extend(password_hashes);
+        
+        if let Some(seq) = batch_seq {
+            self.received_seqs.insert(seq);
+            let highest = self.received_seqs.last().copied().unwrap_or_default();
+            self.missing_batches = (0..highest)
+                .filter(|seq| !self.received_seqs.contains(seq))
+                .collect();
+        }
+    }
+}
+
+/// One step of a session's timeline: an archived exchange or a stored
+/// rrweb batch.
+#[derive(Debug, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum ManifestEntry {
+    Request {
+        timestamp: i64,
+        navigation_id: String,
+        request_id: String,
+        method: String,
+        url: String,
+        status_code: Option<u16>,
+        request_body_hash: Option<String>,
+        response_body_hash: Option<String>,
+    },
+    Recording {
+        timestamp: i64,
+        end_timestamp: i64,
+        event_count: usize,
+        path: String,
+    },
+}
+
+impl ManifestEntry {
+    fn timestamp(&self) -> i64 {
+        match self {
+            ManifestEntry::Request { timestamp, .. } | ManifestEntry::Recording { timestamp, .. } => *timestamp,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct SessionManifest {
+    session_id: String,
+    entries: Vec<ManifestEntry>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SessionTtlRequest {
+    /// `None` clears the override so the global retention applies again.
+    ttl_secs: Option<u64>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+struct SessionNoteRequest {
+    /// `None` or empty clears the note.
+    text: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RecompressQuery {
+    level: i32,
+}
+
+#[derive(Debug, Deserialize)]
+struct SchemaQuery {
+    url: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct SearchQuery {
+    resource_type: Option<String>,
+    session_id: Option<String>,
+}
+
+/// `durable=true` makes an ingest route flush the metadata database before
+/// answering, so what it reports as stored survives a crash.
+#[derive(Debug, Default, Deserialize)]
+struct DurableQuery {
+    #[serde(default)]
+    durable: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct TopContentQuery {
+    n: Option<usize>,
+    /// Objects to skip, for paging through the list.
+    #[serde(default)]
+    offset: usize,
+    /// Inclusive bounds on `compressed_size`.
+    min_size: Option<u64>,
+    max_size: Option<u64>,
+}
+
+/// Filters on the clients that wrote a session's pages.
+#[derive(Debug, Deserialize)]
+struct SessionListQuery {
+    client_name: Option<String>,
+    client_version: Option<String>,
+}
+
+/// Inclusive bounds on request timestamps, in milliseconds.
+#[derive(Debug, Deserialize)]
+struct TimeRangeQuery {
+    from: Option<i64>,
+    to: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+struct SearchMatch {
+    session_id: String,
+    navigation_id: String,
+    request: ArchivedRequest,
+}
+
+#[derive(Debug, Serialize)]
+struct ErrorResponseGroup {
+    status_code: u16,
+    url: String,
+    count: usize,
+    requests: Vec<ErrorResponseEntry>,
+}
+
+#[derive(Debug, Serialize)]
+struct ErrorResponseEntry {
+    request_id: String,
+    timestamp: i64,
+    method: String,
+    /// Response body decoded lossily as UTF-8.
+    body: Option<String>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+struct ContentExistsRequest {
+    hashes: Vec<String>,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+struct ContentExistsResponse {
+    existing: Vec<String>,
+    missing: Vec<String>,
 }
 
 #[derive(Debug, Serialize)]
+struct SchemaResponse {
+    session_id: String,
+    url: String,
+    /// Responses for the URL whose bodies were not valid JSON.
+    skipped: usize,
+    schema: schema::InferredSchema,
+}
+
+/// An entry that didn't parse as an `ArchiveEntry`, by its position in the batch.
+#[derive(Debug, Serialize, JsonSchema)]
+struct EntryError {
+    index: usize,
+    error: String,
+}
+
+#[derive(Debug, Default, Serialize, JsonSchema)]
 struct ArchiveResponse {
     success: bool,
     message: String,
     count: usize,
+    /// Entries that were dropped or only partly stored.
+    failed: usize,
+    /// Entries left out by the ingest filter.
+    skipped: usize,
+    /// With sampling on, exchanges whose URL was sampled in, and those left
+    /// out (their entries aren't counted anywhere else).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sampled_in: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sampled_out: Option<usize>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    errors: Vec<String>,
+    /// Navigation each session's entries were filed under, keyed by session.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    navigations: BTreeMap<String, String>,
+    /// For a recording, batch sequence numbers not yet received.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    missing_batches: Vec<u64>,
+    /// Entries left out because they didn't parse; also counted in `failed`.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    entry_errors: Vec<EntryError>,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, JsonSchema)]
 struct StatsResponse {
     total_archives: usize,
     total_password_hashes: usize,
@@ -106,6 +676,11 @@ struct StatsResponse {
     responses: usize,
     sessions: usize,
     events: usize,
+    /// Mean `duration_ms` of those responses; `None` before any.
+    avg_duration_ms: Option<f64>,
+    /// `requests` split by whether they went to the page's own site.
+    first_party_requests: usize,
+    third_party_requests: usize,
     storage: storage::StorageStats,
 }
 
@@ -113,45 +688,354 @@ async fn health() -> &'static str {
     "OK"
 }
 
-fn strip_password_hashes(text: &str, hashes: &HashSet<String>) -> String {
+async fn get_openapi() -> Json<serde_json::Value> {
+    Json(openapi::document())
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+struct ReadyResponse {
+    ready: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+}
+
+/// 200 when storage takes writes, otherwise 503 with the reason. Probes at
+/// most once per `READY_CACHE_TTL`, whatever the traffic.
+async fn ready(state: AppState) -> (StatusCode, Json<ReadyResponse>) {
+    let mut readiness = state.readiness.lock().await;
+    let cached = readiness.as_ref()
+        .filter(|readiness| readiness.checked_at.elapsed() < READY_CACHE_TTL)
+        .map(|readiness| readiness.failure.clone());
+    let failure = match cached {
+        Some(failure) => failure,
+        None => {
+            let failure = state.storage.probe().await.err().map(|e| e.to_string());
+            if let Some(reason) = &failure {
+                tracing::warn!("Storage failed its readiness probe: {}", reason);
+            }
+            *readiness = Some(Readiness {
+                checked_at: std::time::Instant::now(),
+                failure: failure.clone(),
+            });
+            failure
+        }
+    };
+    
+    match failure {
+        None => (StatusCode::OK, Json(ReadyResponse { ready: true, reason: None })),
+        Some(reason) => (StatusCode::SERVICE_UNAVAILABLE, Json(ReadyResponse { ready: false, reason: Some(reason) })),
+    }
+}
+
+fn strip_password_hashes(text: &str, hashes: &HashSet<String>, marker: &RedactionMarker) -> String {
     let mut result = text.to_string();
     for hash in hashes {
-        result = result.replace(hash, "[REDACTED]");
+        result = result.replace(hash, &marker.replacement(hash));
     }
     result
 }
 
+/// `strip_password_hashes`, then, with entropy redaction on, any
+/// high-entropy tokens left in `text`.
+fn scrub(text: &str, hashes: &HashSet<String>, marker: &RedactionMarker, entropy: Option<&EntropyClassifier>) -> String {
+    let stripped = strip_password_hashes(text, hashes, marker);
+    match entropy {
+        Some(entropy) => classify::redact(&stripped, entropy, marker).0,
+        None => stripped,
+    }
+}
+
+/// `scrub` for a URL, checking its path segments and query parts one by one.
+fn scrub_url(url: &str, hashes: &HashSet<String>, marker: &RedactionMarker, entropy: Option<&EntropyClassifier>) -> String {
+    let stripped = strip_password_hashes(url, hashes, marker);
+    match entropy {
+        Some(entropy) => classify::redact(&stripped, &classify::UrlParts(entropy), marker).0,
+        None => stripped,
+    }
+}
+
+/// `strip_password_hashes` over every string nested in `value`. Object keys
+/// are left alone.
+fn strip_password_hashes_in(value: &mut serde_json::Value, hashes: &HashSet<String>, marker: &RedactionMarker) {
+    match value {
+        serde_json::Value::String(text) if hashes.iter().any(|hash| text.contains(hash.as_str())) => {
+            *text = strip_password_hashes(text, hashes, marker);
+        }
+        serde_json::Value::Array(items) => {
+            for item in items {
+                strip_password_hashes_in(item, hashes, marker);
+            }
+        }
+        serde_json::Value::Object(fields) => {
+            for field in fields.values_mut() {
+                strip_password_hashes_in(field, hashes, marker);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// A batch as logged to the write-ahead log, with what's needed to archive
+/// it again as the request would have.
+#[derive(Debug, Serialize, Deserialize)]
+struct WalEntry<B> {
+    idempotency_key: Option<String>,
+    batch: B,
+}
+
+/// Archives a batch, unless it's a retry: a batch whose `Idempotency-Key`
+/// was already used with the same batch is answered with the first
+/// response instead of being stored again. With the write-ahead log on, the
+/// batch is logged before anything else is done with it.
 async fn archive_entries(
-    State(state): State<AppState>,
+    state: AppState,
+    headers: axum::http::HeaderMap,
+    query: Query<DurableQuery>,
+    Json(mut payload): Json<ArchiveRequest>,
+) -> Response {
+    fill_user_agent(&mut payload.client_info, &headers);
+    let key = match headers.get(IDEMPOTENCY_KEY_HEADER) {
+        Some(_) if state.storage.config().idempotency_ttl_secs.is_none() => None,
+        Some(value) => match value.to_str().ok().filter(|key| is_valid_idempotency_key(key)) {
+            Some(key) => Some(key.to_string()),
+            None => return (StatusCode::BAD_REQUEST, "Invalid Idempotency-Key header").into_response(),
+        },
+        None => None,
+    };
+    
+    let wal_id = if state.storage.config().write_ahead_log {
+        let entry = WalEntry { idempotency_key: key.clone(), batch: &payload };
+        let logged = match serde_json::to_vec(&entry) {
+            Ok(data) => state.storage.append_wal(&data).await,
+            Err(e) => Err(e.into()),
+        };
+        match logged {
+            Ok(id) => Some(id),
+            Err(e) => {
+                tracing::error!("Failed to log batch to the write-ahead log: {}", e);
+                return (storage_status(&e), "Failed to log batch to the write-ahead log").into_response();
+            }
+        }
+    } else {
+        None
+    };
+    let response = archive_idempotent(state.clone(), key, query, payload).await;
+    // Off the response path: completing waits for a database flush
+    if let Some(id) = wal_id {
+        tokio::spawn(async move {
+            if let Err(e) = state.storage.complete_wal(id).await {
+                tracing::error!("Failed to complete write-ahead log entry {}: {}", id, e);
+            }
+        });
+    }
+    response
+}
+
+/// Archives batches left in the write-ahead log by a process that stopped
+/// before finishing them, as their requests would have been, then drops
+/// their entries. A batch that finished but whose entry wasn't dropped
+/// yet is archived again, unless it carried an `Idempotency-Key`. One
+/// that fails with a server error keeps its entry for the next start.
+async fn replay_write_ahead_log(state: &AppState) -> Result<usize, StorageError> {
+    let mut replayed = 0;
+    for (id, data) in state.storage.pending_wal().await? {
+        match serde_json::from_slice::<WalEntry<ArchiveRequest>>(&data) {
+            Ok(entry) => {
+                let response = archive_idempotent(state.clone(), entry.idempotency_key, Query(DurableQuery::default()), entry.batch).await;
+                let status = response.status();
+                if !status.is_success() && !status.is_client_error() {
+                    // Storage still can't take it; keep the entry for the
+                    // next start rather than lose the batch.
+                    tracing::warn!("Keeping write-ahead log entry {}: replay failed with {}", id, status);
+                    continue;
+                }
+                info!("Replayed write-ahead log entry {}: {}", id, status);
+                replayed += 1;
+            }
+            Err(e) => tracing::error!("Dropping unreadable write-ahead log entry {}: {}", id, e),
+        }
+        state.storage.complete_wal(id).await?;
+    }
+    Ok(replayed)
+}
+
+/// `archive_batch`, answering a retried `Idempotency-Key` from its record.
+async fn archive_idempotent(
+    state: AppState,
+    key: Option<String>,
+    query: Query<DurableQuery>,
+    payload: ArchiveRequest,
+) -> Response {
+    let Some(key) = key else {
+        return archive_batch(state, query, Json(payload)).await.into_response();
+    };
+    
+    let fingerprint = Storage::compute_hash(&serde_json::to_vec(&payload).unwrap_or_default());
+    match state.storage.claim_idempotency_key(&key, &fingerprint) {
+        Ok(storage::IdempotencyClaim::Claimed) => {}
+        Ok(storage::IdempotencyClaim::InProgress) => {
+            return (StatusCode::CONFLICT, "A batch with this Idempotency-Key is still being archived").into_response();
+        }
+        Ok(storage::IdempotencyClaim::Mismatch) => {
+            return (StatusCode::UNPROCESSABLE_ENTITY, "Idempotency-Key was already used with a different batch").into_response();
+        }
+        Ok(storage::IdempotencyClaim::Completed(response)) => {
+            debug!("Answering a retry of Idempotency-Key {} with its first response", key);
+            let status = StatusCode::from_u16(response.status).unwrap_or(StatusCode::OK);
+            return (status, [(IDEMPOTENT_REPLAYED_HEADER, "true")], Json(response.body)).into_response();
+        }
+        Err(e) => {
+            tracing::error!("Failed to claim Idempotency-Key {}: {}", key, e);
+            return storage_status(&e).into_response();
+        }
+    }
+    
+    let (status, Json(response)) = archive_batch(state.clone(), query, Json(payload)).await;
+    // Server errors such as a full disk are worth retrying for real
+    let recorded = if status.is_server_error() {
+        state.storage.release_idempotency_key(&key)
+    } else {
+        serde_json::to_value(&response)
+            .map_err(StorageError::from)
+            .and_then(|body| state.storage.complete_idempotency_key(&key, &fingerprint, storage::StoredResponse {
+                status: status.as_u16(),
+                body,
+            }))
+    };
+    if let Err(e) = recorded {
+        tracing::error!("Failed to record Idempotency-Key {}: {}", key, e);
+    }
+    (status, Json(response)).into_response()
+}
+
+/// Takes a client's user agent from the request when it didn't send one.
+fn fill_user_agent(client_info: &mut Option<ClientInfo>, headers: &axum::http::HeaderMap) {
+    if let Some(client) = client_info.as_mut().filter(|client| client.user_agent.is_none()) {
+        client.user_agent = headers.get(axum::http::header::USER_AGENT)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+    }
+}
+
+/// Visible ASCII, up to `MAX_IDEMPOTENCY_KEY_LEN` characters.
+fn is_valid_idempotency_key(key: &str) -> bool {
+    !key.is_empty() && key.len() <= MAX_IDEMPOTENCY_KEY_LEN && key.bytes().all(|b| b.is_ascii_graphic())
+}
+
+async fn archive_batch(
+    state: AppState,
+    Query(query): Query<DurableQuery>,
     Json(payload): Json<ArchiveRequest>,
-) -> Json<ArchiveResponse> {
+) -> (StatusCode, Json<ArchiveResponse>) {
     let count = payload.entries.len();
     let password_hashes: HashSet<String> = payload.password_hashes.into_iter().collect();
+    let mut navigations = BTreeMap::new();
+    let mut errors = Vec::new();
+    let mut failed = 0;
+    
+    let mut entries = Vec::with_capacity(count);
+    let mut entry_errors = Vec::new();
+    let mut invalid_requests = HashSet::new();
+    for (index, value) in payload.entries.into_iter().enumerate() {
+        // Kept so the response to a malformed request counts as failed quietly
+        let request_id = (value.get("type").and_then(|t| t.as_str()) == Some("request"))
+            .then(|| value.get("id").and_then(|id| id.as_str()).map(str::to_string))
+            .flatten();
+        match serde_json::from_value::<ArchiveEntry>(value) {
+            Ok(entry) => entries.push(entry),
+            Err(e) => {
+                entry_errors.push(EntryError { index, error: e.to_string() });
+                invalid_requests.extend(request_id);
+            }
+        }
+    }
+    if !entry_errors.is_empty() {
+        if payload.atomic {
+            return (StatusCode::UNPROCESSABLE_ENTITY, Json(ArchiveResponse {
+                success: false,
+                message: format!("Rejected batch: {} malformed entries", entry_errors.len()),
+                failed: count,
+                entry_errors,
+                ..Default::default()
+            }));
+        }
+        failed += entry_errors.len();
+    }
+    // Answered with 507 so clients back off and retry instead of dropping data
+    let mut disk_full = false;
+    let mut too_large = false;
     
     // Group entries by session/page
     let mut page_requests: HashMap<String, Vec<(ArchiveEntry, Option<ArchiveEntry>)>> = HashMap::new();
-    let mut pending_requests: HashMap<String, ArchiveEntry> = HashMap::new();
+    // Request ID -> session ID
+    let mut pending_requests: HashMap<String, String> = HashMap::new();
+    let mut skipped_requests = HashSet::new();
+    let mut skipped = 0;
+    // Where the batch's screenshot and favicon go
+    let mut image_session = None;
+    let mut sampled_out_requests = HashSet::new();
+    let (mut sampled_in, mut sampled_out_entries) = (0, 0);
+    let strip_params = &state.storage.config().strip_query_params;
+    let filter = &state.storage.config().ingest_filter;
+    let sample_rate = state.storage.config().sample_rate;
+    let marker = &state.storage.config().redaction_marker;
+    let entropy = state.storage.config().entropy_redaction.as_ref();
     
-    for entry in payload.entries {
+    for entry in entries {
         match &entry {
             ArchiveEntry::Request { id, url, .. } => {
-                // Extract session ID from URL or use a default
-                let session_id = extract_session_id(url);
-                let entry_clone = entry.clone();
-                pending_requests.insert(id.clone(), entry_clone.clone());
+                let normalized = match url::normalize(url, strip_params) {
+                    Ok(normalized) => normalized,
+                    Err(e) => {
+                        failed += 1;
+                        errors.push(format!("Request {} has an invalid URL: {}", id, e));
+                        invalid_requests.insert(id.clone());
+                        continue;
+                    }
+                };
+                if !filter.allows_host(normalized.host_str().unwrap_or_default()) {
+                    skipped += 1;
+                    skipped_requests.insert(id.clone());
+                    continue;
+                }
+                if let Some(rate) = sample_rate {
+                    if !filter::sampled_in(normalized.as_str(), rate) {
+                        sampled_out_entries += 1;
+                        sampled_out_requests.insert(id.clone());
+                        continue;
+                    }
+                    sampled_in += 1;
+                }
+                let session_id = url::session_id(&normalized);
+                image_session.get_or_insert_with(|| session_id.clone());
+                pending_requests.insert(id.clone(), session_id.clone());
                 
                 page_requests.entry(session_id)
-                    .or_insert_with(Vec::new)
-                    .push((entry_clone, None));
+                    .or_default()
+                    .push((entry.clone(), None));
             }
-            ArchiveEntry::Response { id, .. } => {
+            ArchiveEntry::Response { id, response_headers, .. } => {
                 // Find matching request
                 let request_id = id.trim_end_matches("_response");
-                if let Some(request) = pending_requests.get(request_id) {
-                    let session_id = extract_session_id(&match request {
-                        ArchiveEntry::Request { url, .. } => url.clone(),
-                        _ => String::new(),
-                    });
+                if let Some(session_id) = pending_requests.get(request_id).cloned() {
+                    let content_type = response_headers.iter().flatten()
+                        .find(|header| header.name.trim().eq_ignore_ascii_case("content-type"))
+                        .map(|header| header.value.as_str());
+                    if content_type.is_some_and(|content_type| !filter.allows_content_type(content_type)) {
+                        // The request goes too; exchanges are stored whole or not at all
+                        pending_requests.remove(request_id);
+                        skipped_requests.insert(request_id.to_string());
+                        if let Some(requests) = page_requests.get_mut(&session_id) {
+                            requests.retain(|(request, _)| {
+                                !matches!(request, ArchiveEntry::Request { id, .. } if id == request_id)
+                            });
+                            if requests.is_empty() {
+                                page_requests.remove(&session_id);
+                            }
+                        }
+                        skipped += 2;
+                        continue;
+                    }
                     
                     // Update the page requests with the response
                     if let Some(requests) = page_requests.get_mut(&session_id) {
@@ -164,262 +1048,2907 @@ async fn archive_entries(
                             }
                         }
                     }
+                } else if skipped_requests.contains(request_id) {
+                    skipped += 1;
+                } else if sampled_out_requests.contains(request_id) {
+                    sampled_out_entries += 1;
+                } else if invalid_requests.contains(request_id) {
+                    // Already reported with its request
+                    failed += 1;
+                } else {
+                    failed += 1;
+                    errors.push(format!("Response {} has no matching request", id));
+                }
+            }
+            ArchiveEntry::WebSocketFrame { id, url, .. } => {
+                let normalized = match url::normalize(url, strip_params) {
+                    Ok(normalized) => normalized,
+                    Err(e) => {
+                        failed += 1;
+                        errors.push(format!("WebSocket frame {} has an invalid URL: {}", id, e));
+                        continue;
+                    }
+                };
+                if !filter.allows_host(normalized.host_str().unwrap_or_default()) {
+                    skipped += 1;
+                    continue;
+                }
+                if sample_rate.is_some_and(|rate| !filter::sampled_in(normalized.as_str(), rate)) {
+                    sampled_out_entries += 1;
+                    continue;
                 }
+                // Frames ride along with the session's exchanges, unpaired
+                page_requests.entry(url::session_id(&normalized))
+                    .or_default()
+                    .push((entry.clone(), None));
             }
         }
     }
     
+    // Content references taken by this batch, for rolling back atomic batches
+    let mut references = Vec::new();
+    let mut bodies = BatchBodies::default();
+    let mut failure = None;
+    let mut prepared = Vec::new();
+    
     // Process each page's requests
-    for (session_id, requests) in page_requests {
-        let mut page_fetch = {
-            let mut sessions = state.active_sessions.lock().await;
-            sessions.entry(session_id.clone())
-                .or_insert_with(|| PageFetchIndex {
-                    session_id: session_id.clone(),
-                    page_url: String::new(),
-                    timestamp: chrono::Utc::now().timestamp_millis(),
-                    navigation_id: Uuid::new_v4().to_string(),
-                    requests: Vec::new(),
-                    password_hashes: password_hashes.iter().cloned().collect(),
-                })
-                .clone()
-        };
+    'sessions: for (session_id, requests) in page_requests {
+        let request_count = requests.iter()
+            .filter(|(request, _)| matches!(request, ArchiveEntry::Request { .. }))
+            .count();
+        let frame_count = requests.len() - request_count;
+        let entry_count = requests.len() + requests.iter().filter(|(_, response)| response.is_some()).count();
+        if let Some(error) = quota_error(&state, &session_id, &requests) {
+            tracing::warn!("Rejected batch: {}", error);
+            too_large = true;
+            if payload.atomic {
+                failure = Some(error);
+                break 'sessions;
+            }
+            failed += entry_count;
+            errors.push(error);
+            continue;
+        }
+        let mut failed_entries = 0;
+        let mut bytes_stored = 0;
+        let first_timestamp = requests.iter()
+            .find_map(|(request, _)| match request {
+                ArchiveEntry::Request { timestamp, .. } | ArchiveEntry::WebSocketFrame { timestamp, .. } => Some(*timestamp),
+                ArchiveEntry::Response { .. } => None,
+            });
+        let mut page_fetch = active_page_fetch(
+            &state,
+            &session_id,
+            payload.navigation_id.as_deref(),
+            &password_hashes,
+            first_timestamp,
+        ).await;
+        // Later batches for the page may redact hashes the first didn't
+        for hash in &password_hashes {
+            if !page_fetch.password_hashes.contains(hash) {
+                page_fetch.password_hashes.push(hash.clone());
+            }
+        }
+        let original = page_fetch.clone();
+        // Stored pages of this session, loaded on the first 304 or repeat that needs them
+        let mut session_history = None;
+        // Stored pages this batch's repeats were collapsed into, as they were before
+        let mut repeated_pages = BTreeMap::new();
+        let mut hosts = storage::HostTally::default();
+        // Bodies are hashed as they're read and stored together after the loop
+        let mut pending = PendingBodies { algorithm: state.storage.config().hash_algorithm, ..Default::default() };
         
         // Process each request/response pair
         for (request, response) in requests {
-            if let ArchiveEntry::Request { url, method, request_headers, request_body, timestamp, .. } = request {
+            if let ArchiveEntry::Request { url, method, request_headers, request_body, request_body_sha256, timestamp, resource_type, priority, .. } = request {
+                // Validated while grouping
+                let normalized_url = url::normalize(&url, strip_params)
+                    .map(|normalized| scrub_url(normalized.as_str(), &password_hashes, marker, entropy))
+                    .ok();
                 // Set page URL if not set
                 if page_fetch.page_url.is_empty() {
-                    page_fetch.page_url = strip_password_hashes(&url, &password_hashes);
+                    page_fetch.page_url = normalized_url.clone().unwrap_or_default();
                 }
                 
                 let mut archived_request = ArchivedRequest {
                     request_id: Uuid::new_v4().to_string(),
                     timestamp,
                     method,
-                    url: strip_password_hashes(&url, &password_hashes),
-                    request_headers: convert_headers(request_headers, &password_hashes),
+                    url: scrub_url(&url, &password_hashes, marker, entropy),
+                    normalized_url,
+                    request_headers: convert_headers(request_headers, &password_hashes, marker, entropy),
                     request_body_hash: None,
                     request_body_size: None,
+                    request_body_hash_mismatch: false,
+                    resource_type: resource_type.map(|t| t.to_lowercase()),
+                    priority,
                     response: None,
+                    occurrences: 1,
+                    occurrence_timestamps: Vec::new(),
+                    response_timestamp: None,
+                    duration_ms: None,
+                    third_party: false,
                 };
+                // Counted once the exchange is known not to be a repeat
+                let mut body_bytes_stored = 0;
+                let top_level = payload.page_url.as_deref().unwrap_or(&page_fetch.page_url);
+                archived_request.third_party = url::is_third_party(&url, top_level);
                 
                 // Store request body if present
                 if let Some(body) = request_body {
                     let body_str = serde_json::to_string(&body).unwrap_or_default();
-                    let cleaned_body = strip_password_hashes(&body_str, &password_hashes);
+                    let cleaned_body = scrub(&body_str, &password_hashes, marker, entropy);
                     let body_bytes = cleaned_body.as_bytes();
                     
                     if !body_bytes.is_empty() {
-                        match state.storage.store_content(body_bytes).await {
+                        let content_type = archived_request.request_headers.get("content-type")
+                            .map(str::to_string);
+                        let mismatch = request_body_sha256.is_some_and(|expected| {
+                            let received = match &body {
+                                serde_json::Value::String(text) => text.as_bytes(),
+                                _ => body_str.as_bytes(),
+                            };
+                            !body_hash_matches(&expected, received)
+                        });
+                        let flag_mismatch = state.storage.config().flag_body_hash_mismatches;
+                        archived_request.request_body_hash_mismatch = mismatch && flag_mismatch;
+                        let stored = if mismatch && !flag_mismatch {
+                            Err(BODY_HASH_MISMATCH.into())
+                        } else {
+                            Ok(pending.queue(&bodies, BodyKind::Request, body_bytes, content_type))
+                        };
+                        match stored {
                             Ok(hash) => {
                                 archived_request.request_body_hash = Some(hash);
                                 archived_request.request_body_size = Some(body_bytes.len());
+                                body_bytes_stored += body_bytes.len();
                             }
                             Err(e) => {
                                 tracing::error!("Failed to store request body: {}", e);
+                                disk_full |= matches!(e, StorageError::DiskFull(_));
+                                too_large |= matches!(e, StorageError::TooLarge { .. });
+                                if payload.atomic {
+                                    failure = Some(format!("Failed to store request body: {}", e));
+                                    break 'sessions;
+                                }
+                                failed_entries += 1;
+                                errors.push(format!("Failed to store request body for {}: {}", archived_request.url, e));
                             }
                         }
                     }
                 }
                 
                 // Process response if present
-                if let Some(ArchiveEntry::Response { status_code, response_headers, response_body, .. }) = response {
+                if let Some(ArchiveEntry::Response { timestamp: response_timestamp, status_code, status_text, http_version, response_headers, response_body, body_encoding, response_body_sha256, duration_ms, .. }) = response {
+                    archived_request.response_timestamp = Some(response_timestamp);
+                    archived_request.duration_ms = duration_ms
+                        .filter(|duration| duration.is_finite() && *duration >= 0.0)
+                        .or_else(|| Some(response_timestamp.saturating_sub(timestamp).max(0) as f64));
                     let mut archived_response = ArchivedResponse {
                         status_code: status_code.unwrap_or(0),
-                        headers: convert_headers(response_headers, &password_hashes),
+                        // A line break would let the phrase inject headers into raw exports
+                        status_text: status_text
+                            .map(|text| text.trim().to_string())
+                            .filter(|text| !text.is_empty() && !text.chars().any(|c| c.is_ascii_control())),
+                        http_version: http_version.as_deref().and_then(storage::normalize_http_version),
+                        headers: convert_headers(response_headers, &password_hashes, marker, entropy),
                         body_hash: None,
                         body_size: None,
                         body_type: None,
+                        body_hash_mismatch: false,
+                        revalidates: None,
+                        cached_body_hash: None,
+                        redacted_categories: BTreeMap::new(),
                     };
                     
-                    // Detect content type
-                    for (name, value) in &archived_response.headers {
-                        if name.to_lowercase() == "content-type" {
-                            archived_response.body_type = Some(value.clone());
-                            break;
-                        }
-                    }
+                    // Bodies get sniffed once they're decoded, below
+                    archived_response.body_type = archived_response.header("content-type").map(str::to_string);
                     
                     // Store response body if present
                     if let Some(body) = response_body {
-                        let cleaned_body = strip_password_hashes(&body, &password_hashes);
-                        let body_bytes = cleaned_body.as_bytes();
+                        let body_bytes = match body_encoding {
+                            BodyEncoding::Text => {
+                                let mut cleaned_body = strip_password_hashes(&body, &password_hashes, marker);
+                                if let Some(classifier) = &state.classifier {
+                                    let (redacted, categories) = classify::redact(&cleaned_body, classifier.as_ref(), marker);
+                                    cleaned_body = redacted;
+                                    archived_response.redacted_categories = categories;
+                                }
+                                Ok(cleaned_body.into_bytes())
+                            }
+                            // Binary can't be searched for password hashes or
+                            // classified as text, so it's stored as sent
+                            BodyEncoding::Base64 => base64::engine::general_purpose::STANDARD.decode(body.trim())
+                                .map_err(|e| format!("body isn't valid base64: {}", e)),
+                        };
+                        // What the client hashed: the text as sent, or the decoded bytes
+                        let received = match (&body_encoding, &body_bytes) {
+                            (BodyEncoding::Base64, Ok(bytes)) => bytes.as_slice(),
+                            _ => body.as_bytes(),
+                        };
+                        let body_size = body_bytes.as_ref().map_or(0, Vec::len);
+                        if let Some(bytes) = body_bytes.as_ref().ok().filter(|bytes| !bytes.is_empty()) {
+                            archived_response.body_type = sniff_body_type(state.storage.config(), archived_response.header("content-type"), bytes, &archived_request.url);
+                        }
                         
-                        if !body_bytes.is_empty() {
-                            match state.storage.store_content(body_bytes).await {
+                        if !matches!(&body_bytes, Ok(bytes) if bytes.is_empty()) {
+                            let mismatch = response_body_sha256
+                                .is_some_and(|expected| !body_hash_matches(&expected, received));
+                            let flag_mismatch = state.storage.config().flag_body_hash_mismatches;
+                            archived_response.body_hash_mismatch = mismatch && flag_mismatch;
+                            let stored = match &body_bytes {
+                                Err(e) => Err(StorageError::Invalid(e.clone())),
+                                Ok(_) if mismatch && !flag_mismatch => Err(BODY_HASH_MISMATCH.into()),
+                                Ok(bytes) => Ok(pending.queue(&bodies, BodyKind::Response, bytes, archived_response.body_type.clone())),
+                            };
+                            match stored {
                                 Ok(hash) => {
                                     archived_response.body_hash = Some(hash);
-                                    archived_response.body_size = Some(body_bytes.len());
+                                    archived_response.body_size = Some(body_size);
+                                    body_bytes_stored += body_size;
                                 }
                                 Err(e) => {
                                     tracing::error!("Failed to store response body: {}", e);
+                                    disk_full |= matches!(e, StorageError::DiskFull(_));
+                                    too_large |= matches!(e, StorageError::TooLarge { .. });
+                                    if payload.atomic {
+                                        failure = Some(format!("Failed to store response body: {}", e));
+                                        break 'sessions;
+                                    }
+                                    failed_entries += 1;
+                                    errors.push(format!("Failed to store response body for {}: {}", archived_request.url, e));
                                 }
                             }
                         }
                     }
                     
+                    let not_modified = archived_response.status_code == 304 && archived_response.body_hash.is_none();
                     archived_request.response = Some(archived_response);
+                    if not_modified {
+                        link_revalidation(&state, &page_fetch, &mut archived_request, &mut session_history).await;
+                    }
+                }
+                
+                if state.storage.config().collapse_repeated_requests
+                    && collapse_repeat(&state, &mut page_fetch, &mut session_history, &mut repeated_pages, &archived_request).await
+                {
+                    // The entry it repeats holds the same bodies
+                    pending.discard();
+                    continue;
                 }
                 
+                bytes_stored += body_bytes_stored;
+                hosts.add(&archived_request);
+                pending.place(page_fetch.requests.len());
                 page_fetch.requests.push(archived_request);
+            } else if let ArchiveEntry::WebSocketFrame { timestamp, url, direction, opcode, payload: frame_payload, payload_encoding, .. } = request {
+                if page_fetch.page_url.is_empty() {
+                    page_fetch.page_url = url::normalize(&url, strip_params)
+                        .map(|normalized| scrub_url(normalized.as_str(), &password_hashes, marker, entropy))
+                        .unwrap_or_default();
+                }
+                let mut frame = storage::WebSocketFrame {
+                    frame_id: Uuid::new_v4().to_string(),
+                    timestamp,
+                    url: scrub_url(&url, &password_hashes, marker, entropy),
+                    direction,
+                    opcode,
+                    payload_hash: None,
+                    payload_size: None,
+                };
+                let frame_payload = frame_payload.unwrap_or_default();
+                let payload_bytes = match payload_encoding {
+                    BodyEncoding::Text => Ok(scrub(&frame_payload, &password_hashes, marker, entropy).into_bytes()),
+                    // Stored as sent, like binary bodies
+                    BodyEncoding::Base64 => base64::engine::general_purpose::STANDARD.decode(frame_payload.trim())
+                        .map_err(|e| format!("payload isn't valid base64: {}", e)),
+                };
+                let content_type = if opcode == 1 { "text/plain" } else { "application/octet-stream" };
+                let stored = match &payload_bytes {
+                    Ok(bytes) if bytes.is_empty() => None,
+                    Ok(bytes) => Some(Ok(pending.queue(&bodies, BodyKind::Frame, bytes, Some(content_type.to_string())))),
+                    Err(e) => Some(Err(StorageError::Invalid(e.clone()))),
+                };
+                match stored {
+                    None => {}
+                    Some(Ok(hash)) => {
+                        let size = payload_bytes.as_ref().map_or(0, Vec::len);
+                        frame.payload_hash = Some(hash);
+                        frame.payload_size = Some(size);
+                        bytes_stored += size;
+                    }
+                    Some(Err(e)) => {
+                        tracing::error!("Failed to store WebSocket payload: {}", e);
+                        disk_full |= matches!(e, StorageError::DiskFull(_));
+                        too_large |= matches!(e, StorageError::TooLarge { .. });
+                        if payload.atomic {
+                            failure = Some(format!("Failed to store WebSocket payload: {}", e));
+                            break 'sessions;
+                        }
+                        failed_entries += 1;
+                        errors.push(format!("Failed to store WebSocket payload for {}: {}", frame.url, e));
+                    }
+                }
+                pending.place(page_fetch.websocket_frames.len());
+                page_fetch.websocket_frames.push(frame);
             }
         }
         
-        // Store the page fetch index
-        match state.storage.store_page_fetch(&session_id, &page_fetch).await {
-            Ok(path) => {
-                info!("Stored page fetch at: {:?}", path);
-                
-                // Update active sessions
-                let mut sessions = state.active_sessions.lock().await;
-                sessions.insert(session_id, page_fetch);
-            }
-            Err(e) => {
-                tracing::error!("Failed to store page fetch: {}", e);
+        let stored = store_pending(&state, &mut bodies, pending, &session_id, payload.atomic.then_some(&mut references)).await;
+        for (kind, index, result) in stored {
+            let Err(e) = result else {
+                continue;
+            };
+            // Take back what the body was counted as
+            let url = match kind {
+                BodyKind::Request | BodyKind::Response => {
+                    let request = &mut page_fetch.requests[index];
+                    let size = match kind {
+                        BodyKind::Request => {
+                            request.request_body_hash = None;
+                            request.request_body_size.take()
+                        }
+                        _ => request.response.as_mut().and_then(|response| {
+                            response.body_hash = None;
+                            response.body_size.take()
+                        }),
+                    };
+                    bytes_stored -= size.unwrap_or(0);
+                    hosts.remove_bytes(request, size.unwrap_or(0));
+                    request.url.clone()
+                }
+                BodyKind::Frame => {
+                    let frame = &mut page_fetch.websocket_frames[index];
+                    frame.payload_hash = None;
+                    bytes_stored -= frame.payload_size.take().unwrap_or(0);
+                    frame.url.clone()
+                }
+            };
+            tracing::error!("Failed to store {}: {}", kind, e);
+            disk_full |= matches!(e, StorageError::DiskFull(_));
+            too_large |= matches!(e, StorageError::TooLarge { .. });
+            if payload.atomic {
+                failure = Some(format!("Failed to store {}: {}", kind, e));
+                break 'sessions;
             }
+            failed_entries += 1;
+            errors.push(format!("Failed to store {} for {}: {}", kind, url, e));
         }
-    }
-    
+        
+        // References to images this batch replaces, dropped once the page is written
+        let mut replaced_images = Vec::new();
+        if image_session.as_ref() == Some(&session_id) {
+            let images = [
+                (ImageKind::Screenshot, &payload.screenshot),
+                (ImageKind::Favicon, &payload.favicon),
+            ];
+            for (kind, image) in images {
+                let Some(image) = image else {
+                    continue;
+                };
+                let stored = match decode_image(image) {
+                    Ok((bytes, content_type)) => store_batch_content(
+                        &state,
+                        &mut bodies,
+                        &bytes,
+                        Some(&content_type),
+                        &session_id,
+                        payload.atomic.then_some(&mut references),
+                    ).await,
+                    Err(e) => Err(StorageError::Invalid(e)),
+                };
+                match stored {
+                    Ok(hash) => {
+                        let slot = match kind {
+                            ImageKind::Screenshot => &mut page_fetch.screenshot_hash,
+                            ImageKind::Favicon => &mut page_fetch.favicon_hash,
+                        };
+                        replaced_images.extend(slot.replace(hash));
+                    }
+                    Err(e) => {
+                        tracing::error!("Failed to store {}: {}", kind, e);
+                        disk_full |= matches!(e, StorageError::DiskFull(_));
+                        too_large |= matches!(e, StorageError::TooLarge { .. });
+                        if payload.atomic {
+                            failure = Some(format!("Failed to store {}: {}", kind, e));
+                            break 'sessions;
+                        }
+                        errors.push(format!("Failed to store {} for {}: {}", kind, session_id, e));
+                    }
+                }
+            }
+        }
+        
+        if payload.client_info.is_some() {
+            page_fetch.client_info = payload.client_info.clone();
+        }
+        if let Some(key) = &state.storage.config().provenance_key {
+            let client_version = payload.client_version.clone()
+                .or_else(|| payload.client_info.as_ref().map(|client| client.version.clone()));
+            page_fetch.provenance = Some(provenance::sign(key, &page_fetch, client_version));
+        }
+        
+        for (navigation_id, original) in repeated_pages {
+            let Some(repeated) = session_history.iter().flatten().find(|page| page.navigation_id == navigation_id) else {
+                continue;
+            };
+            prepared.push(PreparedPage {
+                session_id: session_id.clone(),
+                page_fetch: repeated.clone(),
+                original,
+                repeats_only: true,
+                request_count: 0,
+                frame_count: 0,
+                entry_count: 0,
+                failed_entries: 0,
+                bytes_stored: 0,
+                hosts: storage::HostTally::default(),
+                replaced_images: Vec::new(),
+            });
+        }
+        prepared.push(PreparedPage {
+            session_id,
+            page_fetch,
+            original,
+            repeats_only: false,
+            request_count,
+            frame_count,
+            entry_count,
+            failed_entries,
+            bytes_stored,
+            hosts,
+            replaced_images,
+        });
+    }
+    
+    // Store the page fetch indexes once every body is stored
+    let mut written = Vec::new();
+    if failure.is_none() {
+        for page in prepared {
+            match state.storage.store_page_fetch(&page.session_id, &page.page_fetch).await {
+                Ok(path) => {
+                    info!("Stored page fetch at: {:?}", path);
+                    failed += page.failed_entries;
+                    written.push((page, path));
+                }
+                Err(e) => {
+                    tracing::error!("Failed to store page fetch: {}", e);
+                    disk_full |= matches!(e, StorageError::DiskFull(_));
+                    if payload.atomic {
+                        failure = Some(format!("Failed to store page fetch: {}", e));
+                        break;
+                    }
+                    failed += page.entry_count;
+                    errors.push(format!("Failed to store page fetch for {}: {}", page.session_id, e));
+                }
+            }
+        }
+    }
+    
+    if let Some(error) = failure {
+        rollback_batch(&state, written, references).await;
+        return (write_failure_status(disk_full, too_large), Json(ArchiveResponse {
+            success: false,
+            message: format!("Rolled back batch: {}", error),
+            failed: count,
+            errors: vec![error],
+            ..Default::default()
+        }));
+    }
+    
+    for (PreparedPage { session_id, page_fetch, repeats_only, request_count, frame_count, entry_count, bytes_stored, hosts, replaced_images, .. }, _) in written {
+        if repeats_only {
+            state.active_sessions.lock().await
+                .entry(session_id)
+                .or_default()
+                .insert_page(page_fetch);
+            continue;
+        }
+        for hash in &replaced_images {
+            if let Err(e) = state.storage.release_replaced(hash).await {
+                tracing::error!("Failed to release replaced image {}: {}", hash, e);
+            }
+        }
+        state.counters.requests.fetch_add(request_count as u64, Ordering::Relaxed);
+        state.counters.responses.fetch_add((entry_count - request_count - frame_count) as u64, Ordering::Relaxed);
+        if let Err(e) = state.storage.record_hosts(&hosts) {
+            tracing::error!("Failed to update hosts index: {}", e);
+        }
+        if let Some(webhook) = &state.webhook {
+            webhook.send(webhook::PageSummary::new(state.storage.tenant(), &page_fetch));
+        }
+        
+        // Update active sessions
+        navigations.insert(session_id.clone(), page_fetch.navigation_id.clone());
+        let mut sessions = state.active_sessions.lock().await;
+        sessions.entry(session_id.clone())
+            .or_default()
+            .insert_page(page_fetch);
+        drop(sessions);
+        
+        // No subscribers is not an error
+        let _ = state.live_events.send(LiveEvent::Archive {
+            session_id,
+            request_count,
+            bytes_stored,
+        });
+    }
+    
+    let stored = count - failed - skipped - sampled_out_entries;
+    let mut message = if failed == 0 {
+        format!("Archived {} entries", stored)
+    } else {
+        format!("Archived {} of {} entries; {} failed", stored, count, failed)
+    };
+    if skipped > 0 {
+        message.push_str(&format!("; {} skipped by filters", skipped));
+    }
+    if !sampled_out_requests.is_empty() {
+        message.push_str(&format!("; {} exchanges sampled out", sampled_out_requests.len()));
+    }
+    let mut status = write_failure_status(disk_full, too_large);
+    let mut durable = true;
+    if query.durable {
+        if let Err(e) = state.storage.sync_db().await {
+            tracing::error!("Failed to flush database: {}", e);
+            // Written, but not known to be on disk
+            status = storage_status(&e);
+            durable = false;
+            message.push_str("; not flushed to disk");
+            errors.push(format!("Failed to flush database: {}", e));
+        }
+    }
+    (status, Json(ArchiveResponse {
+        success: failed == 0 && durable,
+        message,
+        count: stored,
+        failed,
+        skipped,
+        sampled_in: sample_rate.map(|_| sampled_in),
+        sampled_out: sample_rate.map(|_| sampled_out_requests.len()),
+        errors,
+        navigations,
+        entry_errors,
+        ..Default::default()
+    }))
+}
+
+/// Why adding `requests` would take the session past a configured quota, if
+/// it would. Bodies count at the size they arrived, before redaction.
+fn quota_error(state: &AppState, session_id: &str, requests: &[(ArchiveEntry, Option<ArchiveEntry>)]) -> Option<String> {
+    let config = state.storage.config();
+    if config.max_session_requests.is_none() && config.max_session_bytes.is_none() {
+        return None;
+    }
+    let usage = state.storage.session_usage(session_id).unwrap_or_else(|e| {
+        tracing::error!("Failed to read usage of session {}: {}", session_id, e);
+        storage::SessionUsage::default()
+    });
+    let added = requests.iter().fold(storage::SessionUsage::default(), |total, (request, response)| {
+        total.plus(storage::SessionUsage {
+            requests: u64::from(matches!(request, ArchiveEntry::Request { .. })),
+            bytes: (entry_body_len(request) + response.as_ref().map_or(0, entry_body_len)) as u64,
+        })
+    });
+    
+    if let Some(max) = config.max_session_requests.filter(|max| usage.requests + added.requests > *max) {
+        return Some(format!("Session {} is over its quota of {} requests ({} stored, {} in this batch)",
+            session_id, max, usage.requests, added.requests));
+    }
+    if let Some(max) = config.max_session_bytes.filter(|max| usage.bytes + added.bytes > *max) {
+        return Some(format!("Session {} is over its quota of {} bytes ({} stored, {} in this batch)",
+            session_id, max, usage.bytes, added.bytes));
+    }
+    None
+}
+
+/// Size of an entry's body as it will be stored, before redaction.
+fn entry_body_len(entry: &ArchiveEntry) -> usize {
+    match entry {
+        ArchiveEntry::Request { request_body, .. } => request_body.as_ref()
+            .map_or(0, |body| serde_json::to_string(body).map_or(0, |text| text.len())),
+        ArchiveEntry::Response { response_body: Some(body), body_encoding: BodyEncoding::Base64, .. }
+        | ArchiveEntry::WebSocketFrame { payload: Some(body), payload_encoding: BodyEncoding::Base64, .. } => {
+            let encoded = body.trim();
+            let padding = encoded.bytes().rev().take_while(|&b| b == b'=').count();
+            (encoded.len() / 4 * 3).saturating_sub(padding)
+        }
+        ArchiveEntry::Response { response_body: body, .. }
+        | ArchiveEntry::WebSocketFrame { payload: body, .. } => body.as_ref().map_or(0, String::len),
+    }
+}
+
+/// Status for a failed storage operation; callers log the error themselves.
+fn storage_status(error: &StorageError) -> StatusCode {
+    match error {
+        StorageError::NotFound(_) => StatusCode::NOT_FOUND,
+        StorageError::Invalid(_) => StatusCode::BAD_REQUEST,
+        StorageError::DiskFull(_) => StatusCode::INSUFFICIENT_STORAGE,
+        StorageError::TooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+        StorageError::Busy(_) => StatusCode::CONFLICT,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+/// 507 when a write failed for lack of space, so the client retries later;
+/// 413 when a body was over the size cap or a session over its quota, so it
+/// doesn't; other failures are reported in the body of a 200.
+fn write_failure_status(disk_full: bool, too_large: bool) -> StatusCode {
+    if disk_full {
+        StatusCode::INSUFFICIENT_STORAGE
+    } else if too_large {
+        StatusCode::PAYLOAD_TOO_LARGE
+    } else {
+        StatusCode::OK
+    }
+}
+
+/// Links a 304 to the most recent earlier exchange it revalidated, looking in
+/// the page being built and then the session's other stored pages, so its
+/// body can be served in place of the empty one.
+async fn link_revalidation(
+    state: &AppState,
+    page_fetch: &PageFetchIndex,
+    request: &mut ArchivedRequest,
+    session_history: &mut Option<Vec<PageFetchIndex>>,
+) {
+    let mut earlier = page_fetch.requests.iter().rev()
+        .find(|earlier| earlier.is_revalidated_by(request))
+        .cloned();
+    if earlier.is_none() {
+        earlier = load_session_history(state, &page_fetch.session_id, session_history).await.iter()
+            // The page being built supersedes its stored copy
+            .filter(|page| page.navigation_id != page_fetch.navigation_id)
+            .flat_map(|page| &page.requests)
+            .filter(|earlier| earlier.is_revalidated_by(request))
+            .max_by_key(|earlier| earlier.timestamp)
+            .cloned();
+    }
+    
+    let (Some(earlier), Some(response)) = (earlier, request.response.as_mut()) else {
+        debug!("No earlier response found for 304 on {}", request.url);
+        return;
+    };
+    response.revalidates = Some(earlier.request_id);
+    response.cached_body_hash = earlier.response.and_then(|r| r.body_hash);
+}
+
+/// Folds `request` into an identical exchange already in its session: one
+/// on the page being built, else the latest on the session's other stored
+/// pages. A stored page changed this way is kept, changed, in
+/// `session_history`, and as it was in `repeated_pages` under its
+/// navigation ID. Returns false if the exchange isn't a repeat.
+async fn collapse_repeat(
+    state: &AppState,
+    page_fetch: &mut PageFetchIndex,
+    session_history: &mut Option<Vec<PageFetchIndex>>,
+    repeated_pages: &mut BTreeMap<String, PageFetchIndex>,
+    request: &ArchivedRequest,
+) -> bool {
+    if let Some(existing) = page_fetch.requests.iter_mut().find(|existing| existing.is_repeat_of(request)) {
+        existing.add_occurrence(request.timestamp);
+        return true;
+    }
+    
+    let history = load_session_history(state, &page_fetch.session_id, session_history).await;
+    let earlier = history.iter().enumerate()
+        // The page being built supersedes its stored copy
+        .filter(|(_, page)| page.navigation_id != page_fetch.navigation_id)
+        .flat_map(|(page_index, page)| page.requests.iter().enumerate()
+            .map(move |(index, existing)| (page_index, index, existing)))
+        .filter(|(_, _, existing)| existing.is_repeat_of(request))
+        .max_by_key(|(_, _, existing)| existing.timestamp)
+        .map(|(page_index, index, _)| (page_index, index));
+    let Some((page_index, index)) = earlier else {
+        return false;
+    };
+    let page = &mut history[page_index];
+    repeated_pages.entry(page.navigation_id.clone()).or_insert_with(|| page.clone());
+    page.requests[index].add_occurrence(request.timestamp);
+    true
+}
+
+/// The session's stored page fetches, loaded into `session_history` on
+/// first use. A session that fails to load is treated as empty.
+async fn load_session_history<'a>(
+    state: &AppState,
+    session_id: &str,
+    session_history: &'a mut Option<Vec<PageFetchIndex>>,
+) -> &'a mut Vec<PageFetchIndex> {
+    if session_history.is_none() {
+        *session_history = Some(match state.storage.load_session(session_id).await {
+            Ok(pages) => pages.unwrap_or_default(),
+            Err(e) => {
+                tracing::warn!("Failed to load session {}: {}", session_id, e);
+                Vec::new()
+            }
+        });
+    }
+    session_history.get_or_insert_with(Vec::new)
+}
+
+/// Compares a client-supplied SHA-256, prefixed or bare hex, with `body`.
+fn body_hash_matches(expected: &str, body: &[u8]) -> bool {
+    let expected = expected.strip_prefix("sha256:").unwrap_or(expected);
+    Storage::compute_hash(body)
+        .strip_prefix("sha256:")
+        .is_some_and(|actual| actual.eq_ignore_ascii_case(expected.trim()))
+}
+
+/// A page fetch built from a batch, ready to be written.
+struct PreparedPage {
+    session_id: String,
+    page_fetch: PageFetchIndex,
+    /// The page fetch as it was before this batch, for rollback.
+    original: PageFetchIndex,
+    /// An earlier page of the session whose only change is repeats this
+    /// batch collapsed into it, so it isn't reported as archived.
+    repeats_only: bool,
+    request_count: usize,
+    frame_count: usize,
+    /// Requests plus their responses, and WebSocket frames.
+    entry_count: usize,
+    /// Entries whose bodies couldn't be stored.
+    failed_entries: usize,
+    bytes_stored: usize,
+    /// This batch's exchanges, for the hosts index once the page is written.
+    hosts: storage::HostTally,
+    /// Screenshot and favicon hashes the batch replaced on the page.
+    replaced_images: Vec<String>,
+}
+
+/// A response body's type by the configured sniffing steps, or the
+/// configured default.
+fn sniff_body_type(config: &StorageConfig, header: Option<&str>, body: &[u8], url: &str) -> Option<String> {
+    sniff::content_type(&config.content_type_sniffing, header, body, url)
+        .or_else(|| config.default_content_type.clone())
+}
+
+/// Served for content stored without a type.
+fn fallback_content_type(state: &AppState) -> String {
+    state.storage.config().default_content_type.clone()
+        .unwrap_or_else(|| "application/octet-stream".to_string())
+}
+
+/// Decodes an attached image sent as bare base64, taken to be PNG, or as a
+/// base64 `data:` URL, returning its bytes and content type.
+fn decode_image(image: &str) -> Result<(Vec<u8>, String), String> {
+    let image = image.trim();
+    let (content_type, data) = match image.strip_prefix("data:") {
+        Some(url) => {
+            let (meta, data) = url.split_once(',').ok_or("data URL has no ','")?;
+            let content_type = meta.strip_suffix(";base64").ok_or("data URL isn't base64")?;
+            (content_type, data)
+        }
+        None => ("", image),
+    };
+    let bytes = base64::engine::general_purpose::STANDARD.decode(data)
+        .map_err(|e| format!("image isn't valid base64: {}", e))?;
+    let content_type = match content_type {
+        "" => "image/png",
+        content_type => content_type,
+    };
+    Ok((bytes, content_type.to_string()))
+}
+
+/// A content reference taken while archiving a batch, undone if an atomic
+/// batch fails.
+struct BatchReference {
+    hash: String,
+    session_id: String,
+    /// The session already referenced the object before this batch.
+    had_session: bool,
+}
+
+/// Bodies a batch has stored so far, so repeats of one (beacons, polling)
+/// only take a reference instead of being hashed and looked up again. Keyed
+/// by a fast keyed hash, with the bytes compared on a hit; each distinct
+/// body is copied once.
+#[derive(Default)]
+struct BatchBodies {
+    hasher: std::collections::hash_map::RandomState,
+    stored: HashMap<u64, (Vec<u8>, String)>,
+}
+
+impl BatchBodies {
+    fn hash_of(&self, data: &[u8]) -> Option<String> {
+        self.stored.get(&self.hasher.hash_one(data))
+            .filter(|(stored, _)| stored.as_slice() == data)
+            .map(|(_, hash)| hash.clone())
+    }
+    
+    fn insert(&mut self, data: &[u8], hash: &str) {
+        // On a collision the first body keeps the slot
+        self.stored.entry(self.hasher.hash_one(data))
+            .or_insert_with(|| (data.to_vec(), hash.to_string()));
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BodyKind {
+    Request,
+    Response,
+    Frame,
+}
+
+impl std::fmt::Display for BodyKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            BodyKind::Request => "request body",
+            BodyKind::Response => "response body",
+            BodyKind::Frame => "WebSocket payload",
+        })
+    }
+}
+
+/// A body waiting to be stored, and the request or frame it belongs to.
+struct PendingBody {
+    kind: BodyKind,
+    /// Into the page's `requests` or `websocket_frames`.
+    index: usize,
+    data: Vec<u8>,
+    content_type: Option<String>,
+    hash: String,
+}
+
+/// A page's bodies, hashed as the batch is read and stored together by
+/// `store_pending`.
+#[derive(Default)]
+struct PendingBodies {
+    bodies: Vec<PendingBody>,
+    /// Bodies at the end of `bodies` whose request or frame isn't placed yet.
+    unplaced: usize,
+    algorithm: HashAlgorithm,
+}
+
+impl PendingBodies {
+    /// Queues a body, returning its hash. A body the batch already stored
+    /// isn't hashed again.
+    fn queue(&mut self, stored: &BatchBodies, kind: BodyKind, data: &[u8], content_type: Option<String>) -> String {
+        let hash = stored.hash_of(data).unwrap_or_else(|| self.algorithm.hash(data));
+        self.bodies.push(PendingBody {
+            kind,
+            index: usize::MAX,
+            data: data.to_vec(),
+            content_type,
+            hash: hash.clone(),
+        });
+        self.unplaced += 1;
+        hash
+    }
+    
+    /// Places the bodies queued since the last call at `index`.
+    fn place(&mut self, index: usize) {
+        let start = self.bodies.len() - self.unplaced;
+        for body in &mut self.bodies[start..] {
+            body.index = index;
+        }
+        self.unplaced = 0;
+    }
+    
+    /// Drops the bodies queued since the last `place`.
+    fn discard(&mut self) {
+        self.bodies.truncate(self.bodies.len() - self.unplaced);
+        self.unplaced = 0;
+    }
+}
+
+/// Stores a page's queued bodies with `Storage::store_content_batch`,
+/// recording each reference in `references` when the batch may need
+/// rolling back. Returns where each body belongs and whether it was stored.
+async fn store_pending(
+    state: &AppState,
+    stored: &mut BatchBodies,
+    pending: PendingBodies,
+    session_id: &str,
+    mut references: Option<&mut Vec<BatchReference>>,
+) -> Vec<(BodyKind, usize, Result<(), StorageError>)> {
+    let mut results = Vec::with_capacity(pending.bodies.len());
+    let mut batch = Vec::with_capacity(pending.bodies.len());
+    let mut had_sessions = Vec::with_capacity(pending.bodies.len());
+    for body in &pending.bodies {
+        if references.is_some() {
+            let had_session = state.storage.content_metadata(&body.hash).map(|metadata| {
+                metadata.and_then(|metadata| metadata.sessions)
+                    .is_some_and(|sessions| sessions.contains(session_id))
+            });
+            match had_session {
+                Ok(had_session) => had_sessions.push(had_session),
+                Err(e) => {
+                    results.push((body.kind, body.index, Err(e)));
+                    continue;
+                }
+            }
+        }
+        batch.push(body);
+    }
+    
+    let items: Vec<_> = batch.iter()
+        .map(|body| storage::ContentItem {
+            data: &body.data,
+            content_type: body.content_type.as_deref(),
+            hash: body.hash.clone(),
+        })
+        .collect();
+    let outcomes = state.storage.store_content_batch(&items, session_id).await;
+    for (index, (body, outcome)) in batch.into_iter().zip(outcomes).enumerate() {
+        let result = outcome.into_result().map(|hash| {
+            stored.insert(&body.data, &hash);
+            if let Some(references) = references.as_deref_mut() {
+                references.push(BatchReference {
+                    hash,
+                    session_id: session_id.to_string(),
+                    had_session: had_sessions[index],
+                });
+            }
+        });
+        results.push((body.kind, body.index, result));
+    }
+    results
+}
+
+/// Stores a body, or references it again if the batch already stored it,
+/// recording the reference in `references` when the batch may need rolling
+/// back.
+async fn store_batch_content(
+    state: &AppState,
+    bodies: &mut BatchBodies,
+    data: &[u8],
+    content_type: Option<&str>,
+    session_id: &str,
+    references: Option<&mut Vec<BatchReference>>,
+) -> Result<String, StorageError> {
+    let known = bodies.hash_of(data);
+    let had_session = match &references {
+        Some(_) => {
+            let hash = known.clone().unwrap_or_else(|| state.storage.content_hash(data));
+            state.storage.content_metadata(&hash)?
+                .and_then(|metadata| metadata.sessions)
+                .is_some_and(|sessions| sessions.contains(session_id))
+        }
+        None => false,
+    };
+    
+    let hash = match known {
+        // Unless something deleted it since
+        Some(hash) if state.storage.reference_content(&hash, content_type, session_id)? => hash,
+        _ => {
+            let hash = state.storage.store_content(data, content_type, session_id).await?;
+            bodies.insert(data, &hash);
+            hash
+        }
+    };
+    if let Some(references) = references {
+        references.push(BatchReference {
+            hash: hash.clone(),
+            session_id: session_id.to_string(),
+            had_session,
+        });
+    }
+    Ok(hash)
+}
+
+/// Undoes a failed atomic batch: page fetches written so far go back to
+/// their state before the batch, then content references are released
+/// newest first, freeing objects the batch created.
+async fn rollback_batch(
+    state: &AppState,
+    written: Vec<(PreparedPage, std::path::PathBuf)>,
+    references: Vec<BatchReference>,
+) {
+    for (page, path) in written {
+        let restored = if page.original.requests.is_empty() {
+            state.storage.remove_page_fetch(&page.session_id, &page.page_fetch.navigation_id, &path).await
+        } else {
+            state.storage.store_page_fetch(&page.session_id, &page.original).await.map(|_| ())
+        };
+        if let Err(e) = restored {
+            tracing::error!("Failed to roll back page fetch {:?}: {}", path, e);
+        }
+    }
+    
+    for reference in references.into_iter().rev() {
+        let released = state.storage
+            .rollback_reference(&reference.hash, &reference.session_id, reference.had_session)
+            .await;
+        if let Err(e) = released {
+            tracing::error!("Failed to roll back content {}: {}", reference.hash, e);
+        }
+    }
+}
+
+/// Returns the page fetch that new entries for `session_id` extend: the named
+/// navigation (from memory, else from disk so it merges), or the session's
+/// default navigation when the client didn't name one. A new page is dated
+/// by `first_timestamp`, as `StorageConfig::page_timestamp` allows.
+async fn active_page_fetch(
+    state: &AppState,
+    session_id: &str,
+    navigation_id: Option<&str>,
+    password_hashes: &HashSet<String>,
+    first_timestamp: Option<i64>,
+) -> PageFetchIndex {
+    {
+        let sessions = state.active_sessions.lock().await;
+        if let Some(active) = sessions.get(session_id) {
+            let navigation_id = navigation_id.or(active.default_navigation.as_deref());
+            if let Some(page_fetch) = navigation_id.and_then(|id| active.pages.get(id)) {
+                return page_fetch.clone();
+            }
+        }
+    }
+    
+    if let Some(navigation_id) = navigation_id {
+        match state.storage.find_page_fetch(session_id, navigation_id).await {
+            Ok(Some(page_fetch)) => return page_fetch,
+            Ok(None) => {}
+            Err(e) => tracing::error!("Failed to look up navigation {}: {}", navigation_id, e),
+        }
+    }
+    
+    let page_fetch = PageFetchIndex {
+        session_id: session_id.to_string(),
+        page_url: String::new(),
+        timestamp: state.storage.config().page_timestamp(first_timestamp),
+        navigation_id: navigation_id.map(str::to_string)
+            .unwrap_or_else(|| Uuid::new_v4().to_string()),
+        requests: Vec::new(),
+        password_hashes: password_hashes.iter().cloned().collect(),
+        provenance: None,
+        screenshot_hash: None,
+        favicon_hash: None,
+        websocket_frames: Vec::new(),
+        client_info: None,
+    };
+    
+    if navigation_id.is_none() {
+        let mut sessions = state.active_sessions.lock().await;
+        sessions.entry(session_id.to_string())
+            .or_default()
+            .default_navigation = Some(page_fetch.navigation_id.clone());
+    }
+    
+    page_fetch
+}
+
+fn convert_headers(
+    headers: Option<Vec<HttpHeader>>,
+    password_hashes: &HashSet<String>,
+    marker: &RedactionMarker,
+    entropy: Option<&EntropyClassifier>,
+) -> storage::Headers {
+    storage::merge_headers(headers.unwrap_or_default().into_iter()
+        .map(|header| (header.name, scrub(&header.value, password_hashes, marker, entropy))))
+}
+
+async fn archive_passwords(
+    _state: AppState,
+    Json(payload): Json<PasswordHashRequest>,
+) -> Json<ArchiveResponse> {
+    let count = payload.hashes.len();
+    
+    for hash in payload.hashes {
+        info!("Password hash recorded for field '{}' on {}", hash.field, hash.url);
+    }
+    
     Json(ArchiveResponse {
         success: true,
-        message: format!("Archived {} entries", count),
+        message: format!("Recorded {} password hashes", count),
         count,
+        ..Default::default()
+    })
+}
+
+/// An image stored for a recording batch, and whether its session already
+/// referenced the object.
+struct StoredImage {
+    hash: String,
+    had_session: bool,
+}
+
+async fn store_image(state: &AppState, data: &[u8], content_type: &str, session_id: &str) -> Result<StoredImage, StorageError> {
+    let had_session = state.storage.content_metadata(&state.storage.content_hash(data))?
+        .and_then(|metadata| metadata.sessions)
+        .is_some_and(|sessions| sessions.contains(session_id));
+    let hash = state.storage.store_content(data, Some(content_type), session_id).await?;
+    Ok(StoredImage { hash, had_session })
+}
+
+/// Password hashes earlier batches of a recording session carried, from
+/// memory or, for a session that isn't loaded, its stored batches.
+async fn session_password_hashes(state: &AppState, session_id: &str) -> HashSet<String> {
+    if let Some(session) = state.rrweb_sessions.lock().await.get(session_id) {
+        return session.password_hashes.clone();
+    }
+    match state.storage.load_recording_batches(session_id).await {
+        Ok(batches) => batches.into_iter().flat_map(|batch| batch.password_hashes).collect(),
+        Err(e) => {
+            tracing::warn!("Failed to load password hashes for recording session {}: {}", session_id, e);
+            HashSet::new()
+        }
+    }
+}
+
+/// Drops the references a recording batch that wasn't stored took for its
+/// images.
+async fn release_images(state: &AppState, session_id: &str, images: &[Option<StoredImage>]) {
+    for image in images.iter().flatten() {
+        if let Err(e) = state.storage.rollback_reference(&image.hash, session_id, image.had_session).await {
+            tracing::error!("Failed to release image {}: {}", image.hash, e);
+        }
+    }
+}
+
+async fn archive_recording(
+    state: AppState,
+    headers: axum::http::HeaderMap,
+    Query(query): Query<DurableQuery>,
+    Json(mut payload): Json<RrwebRecordingRequest>,
+) -> (StatusCode, Json<ArchiveResponse>) {
+    fill_user_agent(&mut payload.client_info, &headers);
+    info!("📹 Received recording request for session: {} from URL: {}", 
+        payload.session_id, payload.url);
+    
+    let event_count = payload.events.len();
+    debug!("Event batch size: {}", event_count);
+    
+    if !storage::is_safe_path_component(&payload.session_id) {
+        let error = StorageError::Invalid(format!("Invalid recording session ID: {:?}", payload.session_id));
+        return (storage_status(&error), Json(ArchiveResponse {
+            success: false,
+            message: error.to_string(),
+            failed: event_count,
+            ..Default::default()
+        }));
+    }
+    match url::normalize(&payload.url, &state.storage.config().strip_query_params) {
+        Ok(normalized) => payload.url = normalized.into(),
+        Err(e) => {
+            let error = StorageError::Invalid(format!("Invalid recording URL {:?}: {}", payload.url, e));
+            return (storage_status(&error), Json(ArchiveResponse {
+                success: false,
+                message: error.to_string(),
+                failed: event_count,
+                ..Default::default()
+            }));
+        }
+    }
+    
+    // Typed-in values reach the events as input and text nodes, so they're
+    // scrubbed with every hash the session has sent so far
+    let mut password_hashes: HashSet<String> = payload.password_hashes.iter().cloned().collect();
+    password_hashes.extend(session_password_hashes(&state, &payload.session_id).await);
+    if !password_hashes.is_empty() {
+        let marker = &state.storage.config().redaction_marker;
+        for event in payload.events.iter_mut() {
+            strip_password_hashes_in(event, &password_hashes, marker);
+        }
+    }
+    
+    let threshold = state.storage.config().rrweb_asset_threshold;
+    if threshold > 0 {
+        dedupe_recording_assets(&state, &payload.session_id, &mut payload.events, threshold).await;
+    }
+    
+    let mut image_hashes = Vec::new();
+    let images = [
+        (ImageKind::Screenshot, payload.screenshot.take()),
+        (ImageKind::Favicon, payload.favicon.take()),
+    ];
+    for (kind, image) in images {
+        let Some(image) = image else {
+            image_hashes.push(None);
+            continue;
+        };
+        let stored = match decode_image(&image) {
+            Ok((bytes, content_type)) => store_image(&state, &bytes, &content_type, &payload.session_id).await,
+            Err(e) => Err(StorageError::Invalid(e)),
+        };
+        match stored {
+            Ok(image) => image_hashes.push(Some(image)),
+            Err(e) => {
+                tracing::error!("Failed to store {} for {}: {}", kind, payload.session_id, e);
+                release_images(&state, &payload.session_id, &image_hashes).await;
+                return (storage_status(&e), Json(ArchiveResponse {
+                    success: false,
+                    message: format!("Failed to store {}: {}", kind, e),
+                    failed: event_count,
+                    errors: vec![e.to_string()],
+                    ..Default::default()
+                }));
+            }
+        }
+    }
+    
+    let batch = storage::RecordingBatch {
+        session_id: payload.session_id.clone(),
+        url: payload.url.clone(),
+        timestamp: payload.timestamp,
+        events: payload.events.clone(),
+        password_hashes: payload.password_hashes.clone(),
+        batch_seq: payload.batch_seq,
+        screenshot_hash: image_hashes[0].as_ref().map(|image| image.hash.clone()),
+        favicon_hash: image_hashes[1].as_ref().map(|image| image.hash.clone()),
+        client_info: payload.client_info.clone(),
+    };
+    if let Err(e) = state.storage.store_recording_batch(&batch).await {
+        tracing::error!("Failed to store recording batch for {}: {}", payload.session_id, e);
+        release_images(&state, &payload.session_id, &image_hashes).await;
+        return (write_failure_status(matches!(e, StorageError::DiskFull(_)), false), Json(ArchiveResponse {
+            success: false,
+            message: format!("Failed to store recording batch: {}", e),
+            failed: event_count,
+            errors: vec![e.to_string()],
+            ..Default::default()
+        }));
+    }
+    if query.durable {
+        if let Err(e) = state.storage.sync_db().await {
+            tracing::error!("Failed to flush database: {}", e);
+            return (storage_status(&e), Json(ArchiveResponse {
+                success: false,
+                message: format!("Stored recording batch but failed to flush it: {}", e),
+                errors: vec![e.to_string()],
+                ..Default::default()
+            }));
+        }
+    }
+    
+    let mut sessions = state.rrweb_sessions.lock().await;
+    let max_sessions = state.storage.config().max_rrweb_sessions;
+    if max_sessions > 0 && !sessions.contains_key(&payload.session_id) {
+        // Batches are stored before this point, so dropping a session from
+        // memory loses nothing
+        while sessions.len() >= max_sessions {
+            let Some(oldest) = sessions.values()
+                .min_by_key(|session| session.updated_at)
+                .map(|session| session.session_id.clone())
+            else {
+                break;
+            };
+            sessions.remove(&oldest);
+            info!("Evicted recording session {} from memory ({} session limit)", oldest, max_sessions);
+        }
+    }
+    
+    let new_hashes = payload.password_hashes.len();
+    let bytes_stored = serde_json::to_vec(&payload.events)
+        .map(|b| b.len())
+        .unwrap_or_default();
+    let session = match sessions.entry(payload.session_id.clone()) {
+        std::collections::hash_map::Entry::Occupied(entry) => {
+            let session = entry.into_mut();
+            session.add_batch(payload.batch_seq, payload.events, payload.password_hashes);
+            session
+        }
+        std::collections::hash_map::Entry::Vacant(entry) => {
+            // Includes the batch just stored, and any from before an
+            // eviction or restart
+            let batches = match state.storage.load_recording_batches(&payload.session_id).await {
+                Ok(batches) if !batches.is_empty() => batches,
+                Ok(_) => vec![batch],
+                Err(e) => {
+                    tracing::warn!("Failed to reload recording session {}: {}", payload.session_id, e);
+                    vec![batch]
+                }
+            };
+            if batches.len() == 1 {
+                info!("🆕 Creating new recording session: {}", payload.session_id);
+            }
+            entry.insert(RrwebSession::from_batches(batches)
+                .expect("batches is never empty"))
+        }
+    };
+    session.updated_at = chrono::Utc::now().timestamp_millis();
+    state.counters.events.fetch_add(event_count as u64, Ordering::Relaxed);
+    
+    let _ = state.live_events.send(LiveEvent::Recording {
+        session_id: payload.session_id.clone(),
+        event_count,
+        bytes_stored,
+    });
+    
+    info!("✅ Recording session {} updated: {} new events, {} new password hashes, {} total events", 
+        payload.session_id, event_count, new_hashes, session.events.len());
+    
+    // Log first few event types for debugging
+    if event_count > 0 {
+        debug!("Event types in batch: {:?}", 
+            session.events.iter()
+                .rev()
+                .take(3)
+                .map(|e| e.get("type").and_then(|t| t.as_u64()))
+                .collect::<Vec<_>>()
+        );
+    }
+    
+    // Network traffic isn't captured here; `/sessions/{id}/replay` revisits
+    // the recorded pages in a headless browser to collect it
+    
+    (StatusCode::OK, Json(ArchiveResponse {
+        success: true,
+        message: format!("Received {} events for recording session", event_count),
+        count: event_count,
+        missing_batches: session.missing_batches.clone(),
+        ..Default::default()
+    }))
+}
+
+/// Moves large inline assets out of `events` into content storage, so an
+/// asset repeated across snapshots and sessions is stored once. Assets that
+/// fail to store stay inline.
+async fn dedupe_recording_assets(state: &AppState, session_id: &str, events: &mut [serde_json::Value], threshold: usize) {
+    let mut assets = Vec::new();
+    for event in events.iter_mut() {
+        rrweb::extract_assets(event, threshold, state.storage.config().hash_algorithm, &mut assets);
+    }
+    
+    let mut stored = HashSet::new();
+    let mut failed = HashMap::new();
+    for asset in assets {
+        if stored.contains(&asset.hash) || failed.contains_key(&asset.hash) {
+            continue;
+        }
+        match state.storage.store_content(asset.data.as_bytes(), Some(&asset.content_type), session_id).await {
+            Ok(_) => {
+                stored.insert(asset.hash);
+            }
+            Err(e) => {
+                tracing::error!("Failed to store recording asset {}: {}", asset.hash, e);
+                failed.insert(asset.hash, asset.data);
+            }
+        }
+    }
+    
+    if !failed.is_empty() {
+        for event in events.iter_mut() {
+            rrweb::restore_assets(event, &failed);
+        }
+    }
+}
+
+/// A recording from memory, or reassembled from storage when it has been
+/// dropped from memory. Deduplicated assets stay as references.
+async fn load_recording(state: &AppState, session_id: &str) -> Result<RrwebSession, StatusCode> {
+    let in_memory = state.rrweb_sessions.lock().await.get(session_id).cloned();
+    match in_memory {
+        Some(recording) => Ok(recording),
+        None => {
+            let batches = state.storage.load_recording_batches(session_id).await.map_err(|e| {
+                tracing::error!("Failed to load recording {}: {}", session_id, e);
+                storage_status(&e)
+            })?;
+            RrwebSession::from_batches(batches).ok_or(StatusCode::NOT_FOUND)
+        }
+    }
+}
+
+async fn get_recording(
+    state: AppState,
+    Path(session_id): Path<String>,
+) -> Result<Json<RrwebSession>, StatusCode> {
+    let mut recording = load_recording(&state, &session_id).await?;
+    restore_recording_assets(&state, &mut recording).await;
+    Ok(Json(recording))
+}
+
+/// Puts the assets `dedupe_recording_assets` moved into content storage back
+/// into the recording's events.
+async fn restore_recording_assets(state: &AppState, recording: &mut RrwebSession) {
+    let mut refs = HashSet::new();
+    for event in &recording.events {
+        rrweb::collect_refs(event, &mut refs);
+    }
+    let mut assets = HashMap::new();
+    for hash in refs {
+        match state.storage.retrieve_content(&hash).await {
+            Ok(data) => {
+                assets.insert(hash, String::from_utf8_lossy(&data).into_owned());
+            }
+            Err(e) => tracing::warn!("Missing recording asset {}: {}", hash, e),
+        }
+    }
+    for event in &mut recording.events {
+        rrweb::restore_assets(event, &assets);
+    }
+}
+
+async fn read_player_file(path: &std::path::Path) -> Result<String, StatusCode> {
+    tokio::fs::read_to_string(path).await.map_err(|e| {
+        tracing::error!("Failed to read rrweb player file {}: {}", path.display(), e);
+        StatusCode::INTERNAL_SERVER_ERROR
     })
 }
 
-fn convert_headers(headers: Option<Vec<HttpHeader>>, password_hashes: &HashSet<String>) -> Vec<(String, String)> {
-    headers.map(|h| {
-        h.into_iter()
-            .map(|header| (header.name, strip_password_hashes(&header.value, password_hashes)))
-            .collect()
-    }).unwrap_or_default()
+/// A recording as one HTML file that replays it: events with their assets
+/// restored, and the resources its nodes load (images, stylesheets, fonts)
+/// inlined as `data:` URIs from the session's archived responses.
+async fn export_session_html(
+    state: AppState,
+    Path(session_id): Path<String>,
+) -> Result<Response, StatusCode> {
+    let mut recording = load_recording(&state, &session_id).await?;
+    restore_recording_assets(&state, &mut recording).await;
+    // Batches stored before a hash arrived still hold it
+    let marker = &state.storage.config().redaction_marker;
+    for event in recording.events.iter_mut() {
+        strip_password_hashes_in(event, &recording.password_hashes, marker);
+    }
+    
+    let mut referenced = HashSet::new();
+    for event in &recording.events {
+        rrweb::collect_attribute_values(event, &mut referenced);
+    }
+    // Exchanges are filed by host, so the recorded page's are looked for too
+    let mut session_ids = vec![session_id.clone()];
+    if let Ok(page_url) = ::url::Url::parse(&recording.url) {
+        session_ids.push(url::session_id(&page_url));
+    }
+    session_ids.dedup();
+    
+    let mut inlined = HashMap::new();
+    for archived_session in &session_ids {
+        let page_fetches = match state.storage.load_session(archived_session).await {
+            Ok(page_fetches) => page_fetches.unwrap_or_default(),
+            Err(e) => {
+                tracing::error!("Failed to load session {}: {}", archived_session, e);
+                return Err(storage_status(&e));
+            }
+        };
+        for request in page_fetches.iter().flat_map(|page_fetch| &page_fetch.requests) {
+            if !referenced.contains(&request.url) || inlined.contains_key(&request.url) {
+                continue;
+            }
+            let Some(response) = &request.response else { continue };
+            let Some(hash) = response.served_body_hash() else { continue };
+            let Ok(body) = state.storage.retrieve_content(hash).await else {
+                tracing::warn!("Missing content {} for {}", hash, request.url);
+                continue;
+            };
+            let content_type = response.body_type.clone().unwrap_or_else(|| fallback_content_type(&state));
+            let data = base64::engine::general_purpose::STANDARD.encode(&body);
+            inlined.insert(request.url.clone(), format!("data:{};base64,{}", content_type, data));
+        }
+    }
+    for event in recording.events.iter_mut() {
+        rrweb::replace_attribute_values(event, &inlined);
+    }
+    
+    let config = state.storage.config();
+    let player = match &config.rrweb_player_js {
+        Some(path) => export::ReplayPlayer::Inline {
+            script: read_player_file(path).await?,
+            stylesheet: match &config.rrweb_player_css {
+                Some(path) => Some(read_player_file(path).await?),
+                None => None,
+            },
+        },
+        None => export::ReplayPlayer::Cdn,
+    };
+    let note = session_note_text(&state, &session_id);
+    let html = export::build_replay_html(&recording.url, note.as_deref(), &recording.events, &player);
+    
+    Ok((
+        [
+            (header::CONTENT_TYPE, "text/html; charset=utf-8".to_string()),
+            (header::CONTENT_DISPOSITION, export::attachment(&session_id, "html")),
+        ],
+        html,
+    ).into_response())
+}
+
+#[cfg(feature = "replay")]
+type ReplayResult = Result<(StatusCode, Json<ArchiveResponse>), StatusCode>;
+
+/// A replay job for the worker, with the request waiting on it, if any.
+#[cfg(feature = "replay")]
+struct QueuedReplay {
+    state: AppState,
+    job: storage::ReplayJob,
+    done: Option<tokio::sync::oneshot::Sender<ReplayResult>>,
+}
+
+/// Queues a replay of the recording, waits for its turn, and answers with
+/// what archiving the traffic did.
+#[cfg(feature = "replay")]
+async fn replay_recording(
+    state: AppState,
+    Path(session_id): Path<String>,
+) -> ReplayResult {
+    if state.browser.is_none() || state.replay_queue.is_none() {
+        return Err(StatusCode::SERVICE_UNAVAILABLE);
+    }
+    let job = storage::ReplayJob {
+        id: Uuid::new_v4().to_string(),
+        session_id,
+        state: storage::ReplayJobState::Queued,
+        queued_at: chrono::Utc::now().timestamp_millis(),
+    };
+    let (done, result) = tokio::sync::oneshot::channel();
+    queue_replay(&state, job, Some(done)).await.map_err(|e| {
+        tracing::error!("Failed to queue replay job: {}", e);
+        storage_status(&e)
+    })?;
+    // Dropped unanswered only if the worker died with the job
+    result.await.unwrap_or(Err(StatusCode::INTERNAL_SERVER_ERROR))
+}
+
+/// Hands a job to the replay worker, recording it first with
+/// `persist_replay_jobs` on.
+#[cfg(feature = "replay")]
+async fn queue_replay(
+    state: &AppState,
+    job: storage::ReplayJob,
+    done: Option<tokio::sync::oneshot::Sender<ReplayResult>>,
+) -> Result<(), StorageError> {
+    let Some(queue) = &state.replay_queue else {
+        return Err(StorageError::Busy("Replay worker isn't running"));
+    };
+    if state.storage.config().persist_replay_jobs {
+        state.storage.save_replay_job(&job).await?;
+    }
+    // The worker outlives every sender, so this only fails after it panicked
+    let _ = queue.send(QueuedReplay { state: state.clone(), job, done });
+    Ok(())
+}
+
+/// Runs queued replays one at a time. A job stays recorded, marked
+/// running, until it finishes, so a restart in between runs it again.
+#[cfg(feature = "replay")]
+async fn run_replay_jobs(mut jobs: tokio::sync::mpsc::UnboundedReceiver<QueuedReplay>) {
+    while let Some(QueuedReplay { state, mut job, done }) = jobs.recv().await {
+        if state.storage.config().persist_replay_jobs {
+            job.state = storage::ReplayJobState::Running;
+            if let Err(e) = state.storage.save_replay_job(&job).await {
+                tracing::error!("Failed to record replay job {} as running: {}", job.id, e);
+            }
+        }
+        // Its own task, so a panicking replay doesn't take the worker down
+        let result = tokio::spawn(replay_session(state.clone(), job.session_id.clone())).await
+            .unwrap_or(Err(StatusCode::INTERNAL_SERVER_ERROR));
+        info!("Finished replay job {} for recording {}", job.id, job.session_id);
+        if let Err(e) = state.storage.remove_replay_job(&job.id) {
+            tracing::error!("Failed to remove finished replay job {}: {}", job.id, e);
+        }
+        if let Some(done) = done {
+            let _ = done.send(result);
+        }
+    }
+}
+
+/// Queues the replay jobs a previous run left recorded, in the order they
+/// were first queued; ones that were running start over. Returns how many
+/// were resumed.
+#[cfg(feature = "replay")]
+async fn resume_replay_jobs(state: &AppState) -> Result<usize, StorageError> {
+    let jobs = state.storage.replay_jobs()?;
+    let resumed = jobs.len();
+    for mut job in jobs {
+        match job.state {
+            storage::ReplayJobState::Running => info!("Re-queuing replay job {} for recording {}, interrupted while running", job.id, job.session_id),
+            storage::ReplayJobState::Queued => info!("Resuming queued replay job {} for recording {}", job.id, job.session_id),
+        }
+        job.state = storage::ReplayJobState::Queued;
+        queue_replay(state, job, None).await?;
+    }
+    Ok(resumed)
+}
+
+/// Revisits a recording's pages in a headless browser and archives the
+/// traffic through `/archive`, filed under a `replay-{id}-` navigation.
+#[cfg(feature = "replay")]
+async fn replay_session(state: AppState, session_id: String) -> ReplayResult {
+    let browser = state.browser.clone().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    let recording = load_recording(&state, &session_id).await?;
+    let plan = replay::ReplayPlan::from_events(&recording.events, &recording.url);
+    if plan.steps.is_empty() {
+        return Err(StatusCode::UNPROCESSABLE_ENTITY);
+    }
+    
+    info!("Replaying recording {} ({} pages)", session_id, plan.steps.len());
+    let captured = browser.capture(&plan).await.map_err(|e| {
+        tracing::error!("Failed to replay recording {}: {}", session_id, e);
+        StatusCode::BAD_GATEWAY
+    })?;
+    
+    let mut entries = Vec::new();
+    for (index, exchange) in captured.into_iter().enumerate() {
+        let id = format!("replay-{}", index);
+        let headers = |headers: Vec<(String, String)>| {
+            Some(headers.into_iter().map(|(name, value)| HttpHeader { name, value }).collect())
+        };
+        entries.push(ArchiveEntry::Request {
+            id: id.clone(),
+            timestamp: exchange.timestamp,
+            url: exchange.url.clone(),
+            method: exchange.method.clone(),
+            request_headers: headers(exchange.request_headers),
+            request_body: exchange.request_body.map(serde_json::Value::String),
+            request_body_sha256: None,
+            resource_type: exchange.resource_type,
+            priority: None,
+        });
+        if let Some(response) = exchange.response {
+            let (response_body, body_encoding) = match response.body.map(String::from_utf8) {
+                Some(Ok(text)) => (Some(text), BodyEncoding::Text),
+                Some(Err(e)) => (Some(base64::engine::general_purpose::STANDARD.encode(e.into_bytes())), BodyEncoding::Base64),
+                None => (None, BodyEncoding::Text),
+            };
+            entries.push(ArchiveEntry::Response {
+                id: format!("{}_response", id),
+                timestamp: response.timestamp,
+                url: exchange.url,
+                method: exchange.method,
+                status_code: Some(response.status_code),
+                status_text: response.status_text,
+                http_version: response.http_version,
+                response_headers: headers(response.headers),
+                response_body,
+                body_encoding,
+                response_body_sha256: None,
+                duration_ms: None,
+            });
+        }
+    }
+    
+    let request = ArchiveRequest {
+        entries: entries.into_iter().map(|entry| serde_json::json!(entry)).collect(),
+        password_hashes: recording.password_hashes.into_iter().collect(),
+        navigation_id: Some(format!("replay-{}-{}", session_id, Uuid::new_v4())),
+        atomic: false,
+        client_version: Some(format!("archiver-replay/{}", env!("CARGO_PKG_VERSION"))),
+        client_info: Some(ClientInfo {
+            name: "archiver-replay".to_string(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            user_agent: None,
+        }),
+        screenshot: None,
+        favicon: None,
+        page_url: None,
+    };
+    Ok(archive_batch(state, Query(DurableQuery::default()), Json(request)).await)
+}
+
+async fn rebalance_content(state: AppState) -> (StatusCode, Json<ArchiveResponse>) {
+    info!("Rebalancing content fanout");
+    
+    match state.storage.rebalance_content().await {
+        Ok(report) => {
+            info!("Rebalance complete: {} scanned, {} moved", report.scanned, report.moved);
+            (StatusCode::OK, Json(ArchiveResponse {
+                success: true,
+                message: format!("Moved {} of {} content files", report.moved, report.scanned),
+                count: report.moved,
+                ..Default::default()
+            }))
+        }
+        Err(e) => {
+            tracing::error!("Failed to rebalance content: {}", e);
+            (storage_status(&e), Json(ArchiveResponse {
+                success: false,
+                message: format!("Rebalance failed: {}", e),
+                count: 0,
+                ..Default::default()
+            }))
+        }
+    }
+}
+
+async fn compact_content(state: AppState) -> Result<Json<storage::CompactionReport>, StatusCode> {
+    info!("Compacting content");
+    
+    let report = state.storage.compact().await.map_err(|e| {
+        tracing::error!("Failed to compact content: {}", e);
+        storage_status(&e)
+    })?;
+    info!("Compaction complete: {} objects packed ({} bytes), {} empty directories removed",
+          report.objects_packed, report.bytes_packed, report.empty_dirs_removed);
+    Ok(Json(report))
+}
+
+async fn flush_db(state: AppState) -> Result<Json<storage::DbFlushReport>, StatusCode> {
+    let report = state.storage.flush_db().await.map_err(|e| {
+        tracing::error!("Failed to flush database: {}", e);
+        storage_status(&e)
+    })?;
+    info!("Database flushed ({} bytes): {} -> {} bytes on disk",
+          report.bytes_flushed, report.size_before, report.size_after);
+    Ok(Json(report))
+}
+
+async fn recompress_content(
+    state: AppState,
+    Query(query): Query<RecompressQuery>,
+) -> Result<Json<storage::RecompressReport>, StatusCode> {
+    if !(1..=22).contains(&query.level) {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    info!("Recompressing content at level {}", query.level);
+    
+    let report = state.storage.recompress(query.level).await.map_err(|e| {
+        tracing::error!("Failed to recompress content: {}", e);
+        storage_status(&e)
+    })?;
+    info!("Recompression complete: {} of {} objects recompressed, {} -> {} bytes",
+          report.recompressed, report.scanned, report.bytes_before, report.bytes_after);
+    Ok(Json(report))
+}
+
+/// Removes a session's files and releases its content. Deleting an unknown
+/// (or already deleted) session returns 404.
+async fn delete_session(
+    state: AppState,
+    Path(session_id): Path<String>,
+) -> Result<Json<storage::DeletionReport>, StatusCode> {
+    if !storage::is_safe_path_component(&session_id) {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    
+    let report = state.storage.delete_session(&session_id).await
+        .map_err(|e| {
+            tracing::error!("Failed to delete session {}: {}", session_id, e);
+            storage_status(&e)
+        })?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    
+    state.active_sessions.lock().await.remove(&session_id);
+    state.rrweb_sessions.lock().await.remove(&session_id);
+    
+    info!("🗑️ Deleted session {}: {} files, {} bytes reclaimed",
+        session_id, report.files_removed, report.bytes_reclaimed);
+    Ok(Json(report))
+}
+
+async fn get_session_note(
+    state: AppState,
+    Path(session_id): Path<String>,
+) -> Result<Json<storage::SessionNote>, StatusCode> {
+    match state.storage.session_note(&session_id) {
+        Ok(Some(note)) => Ok(Json(note)),
+        Ok(None) => Err(StatusCode::NOT_FOUND),
+        Err(e) => {
+            tracing::error!("Failed to read note for session {}: {}", session_id, e);
+            Err(storage_status(&e))
+        }
+    }
+}
+
+async fn set_session_note(
+    state: AppState,
+    Path(session_id): Path<String>,
+    Json(payload): Json<SessionNoteRequest>,
+) -> (StatusCode, Json<ArchiveResponse>) {
+    let text = payload.text.filter(|text| !text.is_empty());
+    if let Some(text) = text.as_ref().filter(|text| text.len() > MAX_NOTE_BYTES) {
+        return (StatusCode::PAYLOAD_TOO_LARGE, Json(ArchiveResponse {
+            success: false,
+            message: format!("Note is {} bytes, over the {} byte limit", text.len(), MAX_NOTE_BYTES),
+            ..Default::default()
+        }));
+    }
+    let cleared = text.is_none();
+    match state.storage.set_session_note(&session_id, text).await {
+        Ok(true) => (StatusCode::OK, Json(ArchiveResponse {
+            success: true,
+            message: if cleared {
+                format!("Cleared note for session {}", session_id)
+            } else {
+                format!("Updated note for session {}", session_id)
+            },
+            count: 1,
+            ..Default::default()
+        })),
+        Ok(false) => (StatusCode::NOT_FOUND, Json(ArchiveResponse {
+            success: false,
+            message: format!("Session {} not found", session_id),
+            ..Default::default()
+        })),
+        Err(e) => {
+            tracing::error!("Failed to set session note: {}", e);
+            (storage_status(&e), Json(ArchiveResponse {
+                success: false,
+                message: format!("Failed to set note: {}", e),
+                ..Default::default()
+            }))
+        }
+    }
+}
+
+async fn set_session_ttl(
+    state: AppState,
+    Path(session_id): Path<String>,
+    Json(payload): Json<SessionTtlRequest>,
+) -> (StatusCode, Json<ArchiveResponse>) {
+    match state.storage.set_session_ttl(&session_id, payload.ttl_secs).await {
+        Ok(true) => {
+            info!("Session {} TTL set to {:?}", session_id, payload.ttl_secs);
+            (StatusCode::OK, Json(ArchiveResponse {
+                success: true,
+                message: format!("Updated TTL for session {}", session_id),
+                count: 1,
+                ..Default::default()
+            }))
+        }
+        Ok(false) => (StatusCode::NOT_FOUND, Json(ArchiveResponse {
+            success: false,
+            message: format!("Session {} not found", session_id),
+            count: 0,
+            ..Default::default()
+        })),
+        Err(e) => {
+            tracing::error!("Failed to set session TTL: {}", e);
+            (storage_status(&e), Json(ArchiveResponse {
+                success: false,
+                message: format!("Failed to set TTL: {}", e),
+                count: 0,
+                ..Default::default()
+            }))
+        }
+    }
+}
+
+async fn export_page_mhtml(
+    state: AppState,
+    Path((session_id, navigation_id)): Path<(String, String)>,
+) -> Result<Response, StatusCode> {
+    let page_fetches = match state.storage.load_session(&session_id).await {
+        Ok(Some(page_fetches)) => page_fetches,
+        Ok(None) => return Err(StatusCode::NOT_FOUND),
+        Err(e) => {
+            tracing::error!("Failed to load session {}: {}", session_id, e);
+            return Err(storage_status(&e));
+        }
+    };
+    let page = page_fetches.into_iter()
+        .find(|page_fetch| page_fetch.navigation_id == navigation_id)
+        .ok_or(StatusCode::NOT_FOUND)?;
+    
+    let mut resources: Vec<export::Resource> = Vec::new();
+    let mut seen_urls = HashSet::new();
+    
+    for request in &page.requests {
+        let Some(response) = &request.response else { continue };
+        let Some(hash) = response.served_body_hash() else { continue };
+        // Re-captured URLs keep their first body
+        if !seen_urls.insert(request.url.clone()) {
+            continue;
+        }
+        let Ok(body) = state.storage.retrieve_content(hash).await else {
+            tracing::warn!("Missing content {} for {}", hash, request.url);
+            continue;
+        };
+        
+        resources.push(export::Resource {
+            url: request.url.clone(),
+            content_type: response.body_type.clone()
+                .unwrap_or_else(|| fallback_content_type(&state)),
+            body,
+        });
+    }
+    
+    // The page URL's HTML response is the document; fall back to the first HTML
+    let is_html = |resource: &export::Resource| resource.content_type.starts_with("text/html");
+    let document_index = resources.iter()
+        .position(|resource| is_html(resource) && resource.url == page.page_url)
+        .or_else(|| resources.iter().position(is_html));
+    let document = document_index.map(|index| resources.remove(index));
+    
+    let document = document.ok_or(StatusCode::NOT_FOUND)?;
+    let mhtml = export::build_mhtml(&page, document, &resources);
+    
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/x-mimearchive".to_string()),
+            (header::CONTENT_DISPOSITION, export::attachment(&navigation_id, "mhtml")),
+        ],
+        mhtml,
+    ).into_response())
+}
+
+async fn search_requests(
+    state: AppState,
+    Query(query): Query<SearchQuery>,
+) -> Result<Json<Vec<SearchMatch>>, StatusCode> {
+    let session_ids = match query.session_id {
+        Some(session_id) => vec![session_id],
+        None => state.storage.list_sessions().map_err(|e| {
+            tracing::error!("Failed to list sessions: {}", e);
+            storage_status(&e)
+        })?,
+    };
+    let resource_type = query.resource_type.map(|t| t.to_lowercase());
+    
+    let mut matches = Vec::new();
+    for session_id in session_ids {
+        let page_fetches = state.storage.load_session(&session_id).await
+            .map_err(|e| {
+                tracing::error!("Failed to load session {}: {}", session_id, e);
+                storage_status(&e)
+            })?
+            .unwrap_or_default();
+        
+        for page_fetch in page_fetches {
+            for request in page_fetch.requests {
+                if resource_type.is_some() && request.resource_type != resource_type {
+                    continue;
+                }
+                matches.push(SearchMatch {
+                    session_id: session_id.clone(),
+                    navigation_id: page_fetch.navigation_id.clone(),
+                    request,
+                });
+            }
+        }
+    }
+    
+    Ok(Json(matches))
+}
+
+async fn get_session_requests(
+    state: AppState,
+    Path(session_id): Path<String>,
+    Query(query): Query<TimeRangeQuery>,
+) -> Result<Json<Vec<SearchMatch>>, StatusCode> {
+    let requests = match state.storage.load_requests_in_range(&session_id, query.from, query.to).await {
+        Ok(Some(requests)) => requests,
+        Ok(None) => return Err(StatusCode::NOT_FOUND),
+        Err(e) => {
+            tracing::error!("Failed to load requests for session {}: {}", session_id, e);
+            return Err(storage_status(&e));
+        }
+    };
+    
+    Ok(Json(requests.into_iter()
+        .map(|(navigation_id, request)| SearchMatch {
+            session_id: session_id.clone(),
+            navigation_id,
+            request,
+        })
+        .collect()))
+}
+
+async fn get_session_screenshot(
+    state: AppState,
+    Path(session_id): Path<String>,
+) -> Result<Response, StatusCode> {
+    session_image(&state, &session_id, ImageKind::Screenshot).await
+}
+
+async fn get_session_favicon(
+    state: AppState,
+    Path(session_id): Path<String>,
+) -> Result<Response, StatusCode> {
+    session_image(&state, &session_id, ImageKind::Favicon).await
+}
+
+/// The session's most recently attached image of `kind`, with the content
+/// type it was sent as.
+async fn session_image(state: &AppState, session_id: &str, kind: ImageKind) -> Result<Response, StatusCode> {
+    let hash = match state.storage.latest_image(session_id, kind).await {
+        Ok(Some(hash)) => hash,
+        Ok(None) => return Err(StatusCode::NOT_FOUND),
+        Err(e) => {
+            tracing::error!("Failed to look up {} for session {}: {}", kind, session_id, e);
+            return Err(storage_status(&e));
+        }
+    };
+    let content_type = state.storage.content_metadata(&hash)
+        .ok()
+        .flatten()
+        .and_then(|metadata| metadata.content_type)
+        .unwrap_or_else(|| fallback_content_type(state));
+    let body = load_body(state, Some(&hash)).await?.unwrap_or_default();
+    
+    Ok(([(header::CONTENT_TYPE, content_type)], body).into_response())
+}
+
+#[derive(Debug, Serialize)]
+struct RawExchange {
+    request_id: String,
+    session_id: String,
+    request: String,
+    /// `None` when no response was captured.
+    response: Option<String>,
+}
+
+/// The captured exchange as raw HTTP/1.1 text, bodies included.
+async fn get_request(
+    state: AppState,
+    Path(request_id): Path<String>,
+) -> Result<Json<RawExchange>, StatusCode> {
+    let (session_id, request) = match state.storage.find_request(&request_id).await {
+        Ok(Some(found)) => found,
+        Ok(None) => return Err(StatusCode::NOT_FOUND),
+        Err(e) => {
+            tracing::error!("Failed to look up request {}: {}", request_id, e);
+            return Err(storage_status(&e));
+        }
+    };
+    
+    let request_body = load_body(&state, request.request_body_hash.as_ref()).await?;
+    let response = match &request.response {
+        Some(response) => {
+            let body = load_body(&state, response.body_hash.as_ref()).await?;
+            Some(export::raw_response(response, body.as_deref()))
+        }
+        None => None,
+    };
+    
+    Ok(Json(RawExchange {
+        request_id,
+        session_id,
+        request: export::raw_request(&request, request_body.as_deref()),
+        response,
+    }))
+}
+
+async fn load_body(state: &AppState, hash: Option<&String>) -> Result<Option<Vec<u8>>, StatusCode> {
+    let Some(hash) = hash else {
+        return Ok(None);
+    };
+    state.storage.retrieve_content(hash).await.map(Some).map_err(|e| {
+        tracing::error!("Missing body {}: {}", hash, e);
+        // The exchange references it, so even a missing body is our failure
+        StatusCode::INTERNAL_SERVER_ERROR
+    })
+}
+
+async fn get_request_drift(
+    state: AppState,
+    Path(request_id): Path<String>,
+) -> Result<Json<drift::DriftReport>, StatusCode> {
+    if !state.live_fetcher.is_enabled() {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    
+    let request = match state.storage.find_request(&request_id).await {
+        Ok(Some((_, request))) => request,
+        Ok(None) => return Err(StatusCode::NOT_FOUND),
+        Err(e) => {
+            tracing::error!("Failed to look up request {}: {}", request_id, e);
+            return Err(storage_status(&e));
+        }
+    };
+    if !state.live_fetcher.is_allowed(&request.url) {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    
+    let request_body = match &request.request_body_hash {
+        Some(hash) => Some(state.storage.retrieve_content(hash).await.map_err(|e| {
+            tracing::error!("Missing request body {} for drift check: {}", hash, e);
+            storage_status(&e)
+        })?),
+        None => None,
+    };
+    let archived_body = match request.response.as_ref().and_then(|r| r.body_hash.as_ref()) {
+        Some(hash) => state.storage.retrieve_content(hash).await.ok(),
+        None => None,
+    };
+    
+    let live = state.live_fetcher.fetch(&request, request_body).await.map_err(|e| {
+        tracing::warn!("Live fetch of {} failed: {}", request.url, e);
+        StatusCode::BAD_GATEWAY
+    })?;
+    
+    Ok(Json(drift::build_report(&request, archived_body.as_deref(), &live)))
+}
+
+async fn get_session_errors(
+    state: AppState,
+    Path(session_id): Path<String>,
+) -> Result<Json<Vec<ErrorResponseGroup>>, StatusCode> {
+    let page_fetches = match state.storage.load_session(&session_id).await {
+        Ok(Some(page_fetches)) => page_fetches,
+        Ok(None) => return Err(StatusCode::NOT_FOUND),
+        Err(e) => {
+            tracing::error!("Failed to load session {}: {}", session_id, e);
+            return Err(storage_status(&e));
+        }
+    };
+    
+    let mut groups: BTreeMap<(u16, String), Vec<ErrorResponseEntry>> = BTreeMap::new();
+    for request in page_fetches.into_iter().flat_map(|page_fetch| page_fetch.requests) {
+        let Some(response) = &request.response else { continue };
+        if response.status_code < 400 {
+            continue;
+        }
+        
+        let body = match &response.body_hash {
+            Some(hash) => match state.storage.retrieve_content(hash).await {
+                Ok(body) => Some(String::from_utf8_lossy(&body).into_owned()),
+                Err(e) => {
+                    tracing::warn!("Missing content {} for error response: {}", hash, e);
+                    None
+                }
+            },
+            None => None,
+        };
+        
+        groups.entry((response.status_code, request.url.clone()))
+            .or_default()
+            .push(ErrorResponseEntry {
+                request_id: request.request_id,
+                timestamp: request.timestamp,
+                method: request.method,
+                body,
+            });
+    }
+    
+    Ok(Json(groups.into_iter()
+        .map(|((status_code, url), requests)| ErrorResponseGroup {
+            status_code,
+            url,
+            count: requests.len(),
+            requests,
+        })
+        .collect()))
 }
 
-fn extract_session_id(url: &str) -> String {
-    // Simple session ID extraction - in production, this would be more sophisticated
-    // For now, use the domain as session ID
-    url.split('/')
-        .nth(2)
-        .unwrap_or("default")
-        .to_string()
+/// Provenance of one page fetch, checked against the current key.
+#[derive(Debug, Serialize)]
+struct PageProvenance {
+    navigation_id: String,
+    page_url: String,
+    provenance: Option<provenance::Provenance>,
+    /// False if the record is missing or the page changed since signing.
+    verified: bool,
 }
 
-async fn archive_passwords(
-    State(_state): State<AppState>,
-    Json(payload): Json<PasswordHashRequest>,
-) -> Json<ArchiveResponse> {
-    let count = payload.hashes.len();
+async fn get_session_provenance(
+    state: AppState,
+    Path(session_id): Path<String>,
+) -> Result<Json<Vec<PageProvenance>>, StatusCode> {
+    let Some(key) = state.storage.config().provenance_key.as_deref() else {
+        return Err(StatusCode::NOT_IMPLEMENTED);
+    };
+    let page_fetches = match state.storage.load_session(&session_id).await {
+        Ok(Some(page_fetches)) => page_fetches,
+        Ok(None) => return Err(StatusCode::NOT_FOUND),
+        Err(e) => {
+            tracing::error!("Failed to load session {}: {}", session_id, e);
+            return Err(storage_status(&e));
+        }
+    };
     
-    for hash in payload.hashes {
-        info!("Password hash recorded for field '{}' on {}", hash.field, hash.url);
+    Ok(Json(page_fetches.into_iter()
+        .map(|page_fetch| PageProvenance {
+            verified: provenance::verify(key, &page_fetch),
+            navigation_id: page_fetch.navigation_id,
+            page_url: page_fetch.page_url,
+            provenance: page_fetch.provenance,
+        })
+        .collect()))
+}
+
+#[derive(Debug, Serialize)]
+struct SessionRedactions {
+    session_id: String,
+    /// Distinct password hashes redacted from the session's pages.
+    count: usize,
+    /// SHA-256 of each redacted hash, sorted, so redactions can be matched
+    /// across sessions without revealing what was redacted.
+    fingerprints: Vec<String>,
+}
+
+async fn get_session_redactions(
+    state: AppState,
+    Path(session_id): Path<String>,
+) -> Result<Json<SessionRedactions>, StatusCode> {
+    let page_fetches = match state.storage.load_session(&session_id).await {
+        Ok(Some(page_fetches)) => page_fetches,
+        Ok(None) => return Err(StatusCode::NOT_FOUND),
+        Err(e) => {
+            tracing::error!("Failed to load session {}: {}", session_id, e);
+            return Err(storage_status(&e));
+        }
+    };
+    
+    let fingerprints: BTreeSet<String> = page_fetches.iter()
+        .flat_map(|page_fetch| &page_fetch.password_hashes)
+        .map(|hash| Storage::compute_hash(hash.as_bytes()))
+        .collect();
+    Ok(Json(SessionRedactions {
+        session_id,
+        count: fingerprints.len(),
+        fingerprints: fingerprints.into_iter().collect(),
+    }))
+}
+
+#[derive(Debug, Serialize)]
+struct HostEntry {
+    host: String,
+    #[serde(flatten)]
+    stats: storage::HostStats,
+}
+
+async fn list_hosts(state: AppState) -> Result<Json<Vec<HostEntry>>, StatusCode> {
+    let hosts = state.storage.list_hosts().map_err(|e| {
+        tracing::error!("Failed to list hosts: {}", e);
+        storage_status(&e)
+    })?;
+    Ok(Json(hosts.into_iter()
+        .map(|(host, stats)| HostEntry { host, stats })
+        .collect()))
+}
+
+#[derive(Debug, Serialize)]
+struct SessionEntry {
+    session_id: String,
+    updated_at: Option<chrono::DateTime<chrono::Utc>>,
+    pages: usize,
+    clients: BTreeSet<ClientInfo>,
+    note: Option<storage::SessionNote>,
+}
+
+/// Sessions with a page written by a matching client; all of them when
+/// neither filter is given.
+async fn list_sessions(
+    state: AppState,
+    Query(query): Query<SessionListQuery>,
+) -> Result<Json<Vec<SessionEntry>>, StatusCode> {
+    let sessions = state.storage.list_session_indexes().map_err(|e| {
+        tracing::error!("Failed to list sessions: {}", e);
+        storage_status(&e)
+    })?;
+    let matches = |client: &ClientInfo| {
+        query.client_name.as_ref().is_none_or(|name| *name == client.name)
+            && query.client_version.as_ref().is_none_or(|version| *version == client.version)
+    };
+    let filtered = query.client_name.is_some() || query.client_version.is_some();
+    Ok(Json(sessions.into_iter()
+        .filter(|(_, index)| !filtered || index.clients.iter().any(matches))
+        .map(|(session_id, index)| SessionEntry {
+            session_id,
+            updated_at: index.updated_at,
+            pages: index.paths.len(),
+            clients: index.clients,
+            note: index.note,
+        })
+        .collect()))
+}
+
+async fn get_session_manifest(
+    state: AppState,
+    Path(session_id): Path<String>,
+) -> Result<Json<SessionManifest>, StatusCode> {
+    let page_fetches = state.storage.load_session(&session_id).await.map_err(|e| {
+        tracing::error!("Failed to load session {}: {}", session_id, e);
+        storage_status(&e)
+    })?;
+    let recordings = state.storage.list_recording_batches(&session_id).await.map_err(|e| {
+        tracing::error!("Failed to list recordings for {}: {}", session_id, e);
+        storage_status(&e)
+    })?;
+    if page_fetches.is_none() && recordings.is_empty() {
+        return Err(StatusCode::NOT_FOUND);
     }
     
-    Json(ArchiveResponse {
-        success: true,
-        message: format!("Recorded {} password hashes", count),
-        count,
-    })
+    let mut entries = Vec::new();
+    for page_fetch in page_fetches.unwrap_or_default() {
+        for request in page_fetch.requests {
+            entries.push(ManifestEntry::Request {
+                timestamp: request.timestamp,
+                navigation_id: page_fetch.navigation_id.clone(),
+                request_id: request.request_id,
+                method: request.method,
+                url: request.url,
+                status_code: request.response.as_ref().map(|r| r.status_code),
+                request_body_hash: request.request_body_hash,
+                response_body_hash: request.response.and_then(|r| r.body_hash),
+            });
+        }
+    }
+    entries.extend(recordings.into_iter().map(|batch| ManifestEntry::Recording {
+        timestamp: batch.first_timestamp,
+        end_timestamp: batch.last_timestamp,
+        event_count: batch.event_count,
+        path: batch.path,
+    }));
+    entries.sort_by_key(ManifestEntry::timestamp);
+    
+    Ok(Json(SessionManifest { session_id, entries }))
 }
 
-async fn archive_recording(
-    State(state): State<AppState>,
-    Json(payload): Json<RrwebRecordingRequest>,
-) -> Json<ArchiveResponse> {
-    info!("📹 Received recording request for session: {} from URL: {}", 
-        payload.session_id, payload.url);
+async fn get_session_metrics(
+    state: AppState,
+    Path(session_id): Path<String>,
+) -> Result<Response, StatusCode> {
+    let page_fetches = match state.storage.load_session(&session_id).await {
+        Ok(Some(page_fetches)) => page_fetches,
+        Ok(None) => return Err(StatusCode::NOT_FOUND),
+        Err(e) => {
+            tracing::error!("Failed to load session {}: {}", session_id, e);
+            return Err(storage_status(&e));
+        }
+    };
     
-    let event_count = payload.events.len();
-    debug!("Event batch size: {}", event_count);
+    // Collapsed repeats count once per occurrence
+    let mut by_status: BTreeMap<String, u64> = BTreeMap::new();
+    let mut request_bytes = 0u64;
+    let mut response_bytes = 0u64;
+    for request in page_fetches.iter().flat_map(|page_fetch| &page_fetch.requests) {
+        let occurrences = u64::from(request.occurrences.max(1));
+        let status = request.response.as_ref()
+            .map_or_else(|| "none".to_string(), |r| r.status_code.to_string());
+        *by_status.entry(status).or_default() += occurrences;
+        request_bytes += request.request_body_size.unwrap_or(0) as u64 * occurrences;
+        response_bytes += request.response.as_ref().and_then(|r| r.body_size).unwrap_or(0) as u64 * occurrences;
+    }
     
-    let mut sessions = state.rrweb_sessions.lock().await;
-    let _is_new_session = !sessions.contains_key(&payload.session_id);
-    
-    let session = sessions.entry(payload.session_id.clone())
-        .or_insert_with(|| {
-            info!("🆕 Creating new recording session: {}", payload.session_id);
-            RrwebSession {
-                session_id: payload.session_id.clone(),
-                url: payload.url.clone(),
-                timestamp: payload.timestamp,
-                events: Vec::new(),
-                password_hashes: HashSet::new(),
+    let session = session_id.as_str();
+    let mut exposition = metrics::Exposition::default();
+    exposition.family("archiver_session_requests_total", "counter", "Archived requests by response status.");
+    for (status, count) in &by_status {
+        exposition.sample("archiver_session_requests_total", &[("session", session), ("status", status)], count);
+    }
+    exposition
+        .family("archiver_session_body_bytes_total", "counter", "Archived body bytes by direction.")
+        .sample("archiver_session_body_bytes_total", &[("session", session), ("direction", "request")], request_bytes)
+        .sample("archiver_session_body_bytes_total", &[("session", session), ("direction", "response")], response_bytes)
+        .family("archiver_session_page_fetches", "gauge", "Page fetches recorded for the session.")
+        .sample("archiver_session_page_fetches", &[("session", session)], page_fetches.len());
+    
+    Ok((
+        [(header::CONTENT_TYPE, metrics::CONTENT_TYPE)],
+        exposition.finish(),
+    ).into_response())
+}
+
+/// Server-wide counters and gauges in Prometheus text format. Counters
+/// reset on restart.
+async fn get_metrics(state: AppState) -> Response {
+    let storage_stats = state.storage.get_stats();
+    let ingest = &state.counters;
+    let content = state.storage.counters();
+    let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+    
+    let mut exposition = metrics::Exposition::default();
+    exposition
+        .family("archiver_archived_requests_total", "counter", "Requests archived.")
+        .sample("archiver_archived_requests_total", &[], load(&ingest.requests))
+        .family("archiver_archived_responses_total", "counter", "Responses archived.")
+        .sample("archiver_archived_responses_total", &[], load(&ingest.responses))
+        .family("archiver_recorded_events_total", "counter", "rrweb events recorded.")
+        .sample("archiver_recorded_events_total", &[], load(&ingest.events))
+        .family("archiver_content_stored_total", "counter", "Content objects newly written.")
+        .sample("archiver_content_stored_total", &[], load(&content.objects_stored))
+        .family("archiver_content_stored_bytes_total", "counter", "Uncompressed bytes of newly written content.")
+        .sample("archiver_content_stored_bytes_total", &[], load(&content.bytes_stored))
+        .family("archiver_dedup_hits_total", "counter", "Stores of content that was already present.")
+        .sample("archiver_dedup_hits_total", &[], load(&content.dedup_hits))
+        .family("archiver_bloom_locks_total", "counter", "Bloom filter shard locks taken.")
+        .sample("archiver_bloom_locks_total", &[], state.storage.bloom_locks_taken())
+        .family("archiver_cache_requests_total", "counter", "Content reads by cache result.")
+        .sample("archiver_cache_requests_total", &[("result", "hit")], load(&content.cache_hits))
+        .sample("archiver_cache_requests_total", &[("result", "miss")], load(&content.cache_misses))
+        .family("archiver_content_objects", "gauge", "Content objects stored.")
+        .sample("archiver_content_objects", &[], storage_stats.content_count)
+        .family("archiver_disk_bytes", "gauge", "Disk space used by content, sessions, and metadata.")
+        .sample("archiver_disk_bytes", &[], storage_stats.disk_bytes)
+        .family("archiver_content_writes_in_progress", "gauge", "Content and chunk writes running.")
+        .sample("archiver_content_writes_in_progress", &[], storage_stats.writes_in_progress)
+        .family("archiver_content_write_queue_depth", "gauge", "Content and chunk writes waiting for a turn.")
+        .sample("archiver_content_write_queue_depth", &[], storage_stats.write_queue_depth);
+    if let Some(webhook) = &state.webhook {
+        let deliveries = webhook.counters();
+        exposition
+            .family("archiver_webhook_summaries_total", "counter", "Page summaries by webhook delivery outcome, across tenants.")
+            .sample("archiver_webhook_summaries_total", &[("result", "delivered")], load(&deliveries.delivered))
+            .sample("archiver_webhook_summaries_total", &[("result", "failed")], load(&deliveries.failed))
+            .sample("archiver_webhook_summaries_total", &[("result", "dropped")], load(&deliveries.dropped));
+    }
+    
+    (
+        [(header::CONTENT_TYPE, metrics::CONTENT_TYPE)],
+        exposition.finish(),
+    ).into_response()
+}
+
+async fn export_session_har(
+    state: AppState,
+    Path(session_id): Path<String>,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    let page_fetches = match state.storage.load_session(&session_id).await {
+        Ok(Some(page_fetches)) => page_fetches,
+        Ok(None) => return Err(StatusCode::NOT_FOUND),
+        Err(e) => {
+            tracing::error!("Failed to load session {}: {}", session_id, e);
+            return Err(storage_status(&e));
+        }
+    };
+    
+    let hashes: HashSet<&String> = page_fetches.iter()
+        .flat_map(|page_fetch| page_fetch.requests.iter())
+        .flat_map(|request| {
+            let response_hash = request.response.as_ref().and_then(|r| r.served_body_hash());
+            request.request_body_hash.iter().chain(response_hash)
+        })
+        .chain(page_fetches.iter().flat_map(PageFetchIndex::frame_hashes))
+        .collect();
+    
+    let mut bodies = HashMap::new();
+    for hash in hashes {
+        match state.storage.retrieve_content(hash).await {
+            Ok(body) => {
+                bodies.insert(hash.clone(), body);
             }
-        });
+            Err(e) => tracing::warn!("Missing content {} for HAR export: {}", hash, e),
+        }
+    }
+    
+    let note = session_note_text(&state, &session_id);
+    Ok(Json(export::build_har(&page_fetches, &bodies, note.as_deref())))
+}
+
+/// A session's note for an export, which goes without one if it can't be read.
+fn session_note_text(state: &AppState, session_id: &str) -> Option<String> {
+    state.storage.session_note(session_id)
+        .inspect_err(|e| tracing::warn!("Failed to read note for session {}: {}", session_id, e))
+        .ok()
+        .flatten()
+        .map(|note| note.text)
+}
+
+/// Parses a single `bytes=` range against a body of `len` bytes into an
+/// inclusive `(start, end)`. `Err` means the range can't be satisfied;
+/// `Ok(None)` means it's absent or unsupported, so the full body is served.
+fn parse_range(header: &str, len: usize) -> Result<Option<(usize, usize)>, ()> {
+    let Some(spec) = header.trim().strip_prefix("bytes=") else {
+        return Ok(None);
+    };
+    if spec.contains(',') {
+        // Multipart ranges aren't supported; serving everything is allowed
+        return Ok(None);
+    }
+    let (start, end) = spec.split_once('-').ok_or(())?;
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", suffix) => {
+            let suffix: usize = suffix.parse().map_err(|_| ())?;
+            if suffix == 0 {
+                return Err(());
+            }
+            (len.saturating_sub(suffix), len.checked_sub(1).ok_or(())?)
+        }
+        (start, "") => (start.parse().map_err(|_| ())?, len.checked_sub(1).ok_or(())?),
+        (start, end) => {
+            let end: usize = end.parse().map_err(|_| ())?;
+            (start.parse().map_err(|_| ())?, end.min(len.checked_sub(1).ok_or(())?))
+        }
+    };
+    if start > end || start >= len {
+        return Err(());
+    }
+    Ok(Some((start, end)))
+}
+
+/// True if `Accept-Encoding` lists `zstd` without `q=0`.
+fn accepts_zstd(headers: &axum::http::HeaderMap) -> bool {
+    headers.get_all(header::ACCEPT_ENCODING).iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|coding| {
+            let mut params = coding.split(';').map(str::trim);
+            let name = params.next().unwrap_or_default();
+            let rejected = params
+                .filter_map(|param| param.strip_prefix("q="))
+                .any(|q| q.parse::<f32>().is_ok_and(|q| q == 0.0));
+            name.eq_ignore_ascii_case("zstd") && !rejected
+        })
+}
+
+/// Content never changes under its hash, so clients can keep it indefinitely.
+const IMMUTABLE_CACHE_CONTROL: &str = "public, max-age=31536000, immutable";
+
+/// Entity tag for a body: its hash, marked for the zstd-encoded
+/// representation so caches don't confuse the two.
+fn content_etag(hash: &str, zstd: bool) -> String {
+    if zstd {
+        format!("\"{}+zstd\"", hash)
+    } else {
+        format!("\"{}\"", hash)
+    }
+}
+
+/// True if `If-None-Match` is `*` or lists `etag`, compared weakly.
+fn if_none_match(headers: &axum::http::HeaderMap, etag: &str) -> bool {
+    headers.get_all(header::IF_NONE_MATCH).iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|tag| tag.trim().trim_start_matches("W/"))
+        .any(|tag| tag == "*" || tag == etag)
+}
+
+async fn head_content(
+    state: AppState,
+    Path(hash): Path<String>,
+) -> Result<Response, StatusCode> {
+    let hash = state.storage.qualify_hash(hash);
+    let metadata = state.storage.content_metadata(&hash)
+        .map_err(|e| {
+            tracing::error!("Failed to read metadata for {}: {}", hash, e);
+            storage_status(&e)
+        })?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    
+    Ok((
+        [
+            (header::CONTENT_TYPE, metadata.content_type.unwrap_or_else(|| fallback_content_type(&state))),
+            (header::CONTENT_LENGTH, metadata.size.to_string()),
+            (header::ACCEPT_RANGES, "bytes".to_string()),
+            (header::ETAG, content_etag(&hash, false)),
+            (header::CACHE_CONTROL, IMMUTABLE_CACHE_CONTROL.to_string()),
+        ],
+    ).into_response())
+}
+
+async fn get_content(
+    state: AppState,
+    Path(hash): Path<String>,
+    headers: axum::http::HeaderMap,
+) -> Result<Response, StatusCode> {
+    let hash = state.storage.qualify_hash(hash);
+    let metadata = state.storage.content_metadata(&hash)
+        .ok()
+        .flatten();
+    let content_type = metadata.as_ref()
+        .and_then(|metadata| metadata.content_type.clone())
+        .unwrap_or_else(|| fallback_content_type(&state));
+    let range = headers.get(header::RANGE).and_then(|value| value.to_str().ok());
+    // Ranges address the decoded body, so only whole-body requests skip decompression
+    let zstd = range.is_none() && accepts_zstd(&headers);
+    let etag = content_etag(&hash, zstd);
     
-    // Add events to session
-    session.events.extend(payload.events);
+    if metadata.is_some() && if_none_match(&headers, &etag) {
+        return Ok((
+            StatusCode::NOT_MODIFIED,
+            [
+                (header::ETAG, etag),
+                (header::CACHE_CONTROL, IMMUTABLE_CACHE_CONTROL.to_string()),
+                (header::VARY, "accept-encoding".to_string()),
+            ],
+        ).into_response());
+    }
     
-    // Add password hashes
-    let new_hashes = payload.password_hashes.len();
-    for hash in payload.password_hashes {
-        session.password_hashes.insert(hash);
+    if zstd {
+        let compressed = state.storage.retrieve_compressed(&hash).await
+            .map_err(|e| content_error_status(&hash, &e))?;
+        return Ok((
+            [
+                (header::CONTENT_TYPE, content_type),
+                (header::CONTENT_ENCODING, "zstd".to_string()),
+                (header::VARY, "accept-encoding".to_string()),
+                (header::ETAG, etag),
+                (header::CACHE_CONTROL, IMMUTABLE_CACHE_CONTROL.to_string()),
+            ],
+            compressed,
+        ).into_response());
     }
     
-    info!("✅ Recording session {} updated: {} new events, {} new password hashes, {} total events", 
-        payload.session_id, event_count, new_hashes, session.events.len());
+    let body = state.storage.retrieve_content(&hash).await
+        .map_err(|e| content_error_status(&hash, &e))?;
     
-    // Log first few event types for debugging
-    if event_count > 0 {
-        debug!("Event types in batch: {:?}", 
-            session.events.iter()
-                .rev()
-                .take(3)
-                .map(|e| e.get("type").and_then(|t| t.as_u64()))
-                .collect::<Vec<_>>()
-        );
+    match range.map(|range| parse_range(range, body.len())).unwrap_or(Ok(None)) {
+        Ok(Some((start, end))) => Ok((
+            StatusCode::PARTIAL_CONTENT,
+            [
+                (header::CONTENT_TYPE, content_type),
+                (header::CONTENT_RANGE, format!("bytes {}-{}/{}", start, end, body.len())),
+                (header::ACCEPT_RANGES, "bytes".to_string()),
+                (header::VARY, "accept-encoding".to_string()),
+                (header::ETAG, etag),
+                (header::CACHE_CONTROL, IMMUTABLE_CACHE_CONTROL.to_string()),
+            ],
+            body[start..=end].to_vec(),
+        ).into_response()),
+        Ok(None) => Ok((
+            [
+                (header::CONTENT_TYPE, content_type),
+                (header::ACCEPT_RANGES, "bytes".to_string()),
+                (header::VARY, "accept-encoding".to_string()),
+                (header::ETAG, etag),
+                (header::CACHE_CONTROL, IMMUTABLE_CACHE_CONTROL.to_string()),
+            ],
+            body,
+        ).into_response()),
+        Err(()) => Ok((
+            StatusCode::RANGE_NOT_SATISFIABLE,
+            [(header::CONTENT_RANGE, format!("bytes */{}", body.len()))],
+        ).into_response()),
+    }
+}
+
+/// Logs retrieval failures other than the content simply not being there.
+fn content_error_status(hash: &str, error: &StorageError) -> StatusCode {
+    let status = storage_status(error);
+    if status.is_server_error() {
+        tracing::error!("Failed to read content {}: {}", hash, error);
     }
+    status
+}
+
+async fn content_exists(
+    state: AppState,
+    Json(payload): Json<ContentExistsRequest>,
+) -> Result<Json<ContentExistsResponse>, StatusCode> {
+    let hashes: Vec<String> = payload.hashes.into_iter()
+        .map(|hash| state.storage.qualify_hash(hash))
+        .collect();
     
-    // TODO: In the future, this is where we would:
-    // 1. Check if we have enough events to replay
-    // 2. Spawn a headless browser to replay the session
-    // 3. Capture all network requests during replay
-    // 4. Store the captured data
+    let existing = state.storage.existing_content(&hashes).await.map_err(|e| {
+        tracing::error!("Failed to check content existence: {}", e);
+        storage_status(&e)
+    })?;
     
-    Json(ArchiveResponse {
-        success: true,
-        message: format!("Received {} events for recording session", event_count),
-        count: event_count,
-    })
+    let existing_set: HashSet<&String> = existing.iter().collect();
+    let missing = hashes.iter()
+        .filter(|hash| !existing_set.contains(hash))
+        .cloned()
+        .collect();
+    
+    Ok(Json(ContentExistsResponse { existing, missing }))
+}
+
+async fn get_session_schema(
+    state: AppState,
+    Path(session_id): Path<String>,
+    Query(query): Query<SchemaQuery>,
+) -> Result<Json<SchemaResponse>, StatusCode> {
+    let page_fetches = match state.storage.load_session(&session_id).await {
+        Ok(Some(page_fetches)) => page_fetches,
+        Ok(None) => return Err(StatusCode::NOT_FOUND),
+        Err(e) => {
+            tracing::error!("Failed to load session {}: {}", session_id, e);
+            return Err(storage_status(&e));
+        }
+    };
+    
+    let mut inferred = schema::InferredSchema::default();
+    let mut skipped = 0;
+    
+    let body_hashes = page_fetches.iter()
+        .flat_map(|page_fetch| page_fetch.requests.iter())
+        .filter(|request| request.url == query.url)
+        .filter_map(|request| request.response.as_ref()?.body_hash.clone());
+    
+    for hash in body_hashes {
+        let parsed = state.storage.retrieve_content(&hash).await
+            .ok()
+            .and_then(|body| serde_json::from_slice::<serde_json::Value>(&body).ok());
+        match parsed {
+            Some(value) => inferred.add_sample(&value),
+            None => skipped += 1,
+        }
+    }
+    
+    Ok(Json(SchemaResponse {
+        session_id,
+        url: query.url,
+        skipped,
+        schema: inferred,
+    }))
+}
+
+async fn save_bloom(state: AppState) -> (StatusCode, Json<ArchiveResponse>) {
+    match state.storage.save_bloom().await {
+        Ok(()) => {
+            info!("Bloom filter saved");
+            (StatusCode::OK, Json(ArchiveResponse {
+                success: true,
+                message: "Bloom filter saved".to_string(),
+                count: 1,
+                ..Default::default()
+            }))
+        }
+        Err(e) => {
+            tracing::error!("Failed to save bloom filter: {}", e);
+            (storage_status(&e), Json(ArchiveResponse {
+                success: false,
+                message: format!("Failed to save bloom filter: {}", e),
+                count: 0,
+                ..Default::default()
+            }))
+        }
+    }
+}
+
+async fn run_bloom_saves(tenants: Tenants) {
+    let mut interval = tokio::time::interval(tenants.default.storage.bloom_save_interval());
+    loop {
+        interval.tick().await;
+        for state in tenants.all().await {
+            if let Err(e) = state.storage.save_bloom_if_dirty().await {
+                tracing::error!("Failed to save bloom filter: {}", e);
+            }
+        }
+    }
+}
+
+/// Like `run_db_flushes`, but frequent and quiet, for durability rather than
+/// space.
+async fn run_db_syncs(state: AppState, period: std::time::Duration) {
+    let mut interval = tokio::time::interval(period);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        if let Err(e) = state.storage.sync_db().await {
+            tracing::error!("Failed to flush database: {}", e);
+        }
+    }
+}
+
+/// Measures each tenant's disk usage for `/stats`, starting straight away.
+async fn run_disk_scans(tenants: Tenants, period: std::time::Duration) {
+    let mut interval = tokio::time::interval(period);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        for state in tenants.all().await {
+            if let Err(e) = state.storage.scan_disk_usage().await {
+                tracing::error!("Failed to measure disk usage: {}", e);
+            }
+        }
+    }
+}
+
+/// Tenants share one database, so only the default tenant's storage flushes it.
+async fn run_db_flushes(state: AppState, period: std::time::Duration) {
+    let mut interval = tokio::time::interval(period);
+    // The first tick fires immediately; there's nothing to flush at startup
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        match state.storage.flush_db().await {
+            Ok(report) if report.bytes_flushed > 0 => info!("Database flushed ({} bytes): {} -> {} bytes on disk",
+                                report.bytes_flushed, report.size_before, report.size_after),
+            Ok(_) => debug!("Database flush had nothing to write"),
+            Err(e) => tracing::error!("Failed to flush database: {}", e),
+        }
+    }
+}
+
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        let _ = tokio::signal::ctrl_c().await;
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        if let Ok(mut signal) = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            signal.recv().await;
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+    info!("Shutting down");
+}
+
+async fn run_retention_sweeps(tenants: Tenants) {
+    let mut interval = tokio::time::interval(RETENTION_SWEEP_INTERVAL);
+    loop {
+        interval.tick().await;
+        for state in tenants.all().await {
+            match state.storage.sweep_idempotency_keys() {
+                Ok(0) => {}
+                Ok(removed) => debug!("Removed {} expired idempotency keys", removed),
+                Err(e) => tracing::error!("Idempotency key sweep failed: {}", e),
+            }
+            match state.storage.sweep_expired_sessions().await {
+                Ok(expired) => {
+                    if !expired.is_empty() {
+                        info!("🧹 Retention sweep removed {} sessions", expired.len());
+                    }
+                    let mut sessions = state.active_sessions.lock().await;
+                    for session_id in expired {
+                        sessions.remove(&session_id);
+                    }
+                }
+                Err(e) => {
+                    tracing::error!("Retention sweep failed: {}", e);
+                }
+            }
+        }
+    }
+}
+
+async fn live_events(ws: WebSocketUpgrade, state: AppState) -> Response {
+    let receiver = state.live_events.subscribe();
+    ws.on_upgrade(move |socket| stream_live_events(socket, receiver))
+}
+
+async fn stream_live_events(mut socket: WebSocket, mut receiver: broadcast::Receiver<LiveEvent>) {
+    loop {
+        let received = tokio::select! {
+            incoming = socket.recv() => match incoming {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => continue,
+            },
+            received = receiver.recv() => received,
+        };
+        
+        let event = match received {
+            Ok(event) => event,
+            // A slow client misses events rather than holding up ingest
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                debug!("Live event subscriber lagged, skipped {} events", skipped);
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => break,
+        };
+        
+        let Ok(text) = serde_json::to_string(&event) else {
+            continue;
+        };
+        if socket.send(Message::Text(text)).await.is_err() {
+            break;
+        }
+    }
+}
+
+async fn get_top_content(
+    state: AppState,
+    Query(query): Query<TopContentQuery>,
+) -> Result<Json<Vec<storage::LargeObject>>, StatusCode> {
+    let n = query.n.unwrap_or(DEFAULT_TOP_CONTENT).min(MAX_TOP_CONTENT);
+    let objects = state.storage.largest_content(n, query.offset, query.min_size, query.max_size).map_err(|e| {
+        tracing::error!("Failed to list largest content: {}", e);
+        storage_status(&e)
+    })?;
+    Ok(Json(objects))
+}
+
+async fn get_stats_by_type(state: AppState) -> Result<Json<BTreeMap<String, storage::TypeStats>>, StatusCode> {
+    debug!("📊 Per-type stats request received");
+    
+    let by_type = state.storage.get_stats_by_type().await.map_err(|e| {
+        tracing::error!("Failed to get per-type stats: {}", e);
+        storage_status(&e)
+    })?;
+    
+    Ok(Json(by_type))
 }
 
-async fn get_stats(State(state): State<AppState>) -> Json<StatsResponse> {
+async fn get_stats(state: AppState) -> Json<StatsResponse> {
     debug!("📊 Stats request received");
     
-    let storage_stats = state.storage.get_stats().await
-        .unwrap_or(storage::StorageStats {
-            content_count: 0,
-            cache_size: 0,
-            total_size: 0,
-            compressed_size: 0,
-            compression_ratio: 1.0,
-        });
+    let storage_stats = state.storage.get_stats();
+    
+    // Copied out under the lock and summed after, so ingest isn't held up
+    let tallies: Vec<PageTally> = state.active_sessions.lock().await.values()
+        .flat_map(|active| active.tallies.values().cloned())
+        .collect();
+    let (rrweb_session_count, total_events) = {
+        let rrweb_sessions = state.rrweb_sessions.lock().await;
+        (rrweb_sessions.len(), rrweb_sessions.values().map(|s| s.events.len()).sum::<usize>())
+    };
     
-    let sessions = state.active_sessions.lock().await;
     let mut total_requests = 0;
     let mut total_responses = 0;
-    
-    for (_, page_fetch) in sessions.iter() {
-        total_requests += page_fetch.requests.len();
-        total_responses += page_fetch.requests.iter()
-            .filter(|r| r.response.is_some())
-            .count();
+    let mut third_party_requests = 0;
+    let (mut duration_ms, mut timed) = (0.0, 0);
+    let mut password_hashes = HashSet::new();
+    for tally in &tallies {
+        total_requests += tally.requests;
+        total_responses += tally.responses;
+        third_party_requests += tally.third_party;
+        duration_ms += tally.duration_ms;
+        timed += tally.timed;
+        password_hashes.extend(&tally.password_hashes);
     }
     
-    // Get rrweb session stats
-    let rrweb_sessions = state.rrweb_sessions.lock().await;
-    let rrweb_session_count = rrweb_sessions.len();
-    let total_events: usize = rrweb_sessions.values()
-        .map(|s| s.events.len())
-        .sum();
-    
     let stats = StatsResponse {
         total_archives: total_requests,
-        total_password_hashes: 0, // We don't store these separately anymore
+        total_password_hashes: password_hashes.len(),
         requests: total_requests,
         responses: total_responses,
         sessions: rrweb_session_count,
         events: total_events,
+        avg_duration_ms: (timed > 0).then(|| duration_ms / timed as f64),
+        first_party_requests: total_requests - third_party_requests,
+        third_party_requests,
         storage: storage_stats,
     };
     
@@ -429,12 +3958,122 @@ async fn get_stats(State(state): State<AppState>) -> Json<StatsResponse> {
     Json(stats)
 }
 
+/// Origins matching one of `patterns`, or any origin when one is `*`.
+fn cors_origins(patterns: &[String]) -> AllowOrigin {
+    if patterns.iter().any(|pattern| pattern == "*") {
+        return Any.into();
+    }
+    let patterns = patterns.to_vec();
+    AllowOrigin::predicate(move |origin, _| {
+        origin.to_str().is_ok_and(|origin| {
+            let origin = origin.to_lowercase();
+            patterns.iter().any(|pattern| filter::glob_match(pattern, &origin))
+        })
+    })
+}
+
+/// The named headers, or any header when one is `*`.
+fn cors_headers(names: &[String]) -> AllowHeaders {
+    if names.iter().any(|name| name == "*") {
+        return Any.into();
+    }
+    AllowHeaders::list(names.iter().filter_map(|name| match header::HeaderName::try_from(name.as_str()) {
+        Ok(name) => Some(name),
+        Err(_) => {
+            tracing::warn!("Ignoring invalid CORS header name {:?}", name);
+            None
+        }
+    }))
+}
+
+/// Every route, with the middleware shared by all of them.
+fn app(tenants: Tenants) -> Router {
+    let cors = CorsLayer::new()
+        .allow_origin(cors_origins(&tenants.default.storage.config().cors_origins))
+        .allow_methods([Method::GET, Method::POST, Method::PUT, Method::DELETE])
+        .allow_headers(cors_headers(&tenants.default.storage.config().cors_headers));
+    
+    // Ingest routes replace axum's fixed 2 MiB extractor limit with the configured one
+    let ingest_limit = ServiceBuilder::new()
+        .layer(DefaultBodyLimit::disable())
+        .layer(RequestBodyLimitLayer::new(tenants.default.storage.config().max_request_bytes));
+    
+    let app = Router::new()
+        .route("/health", get(health))
+        .route("/ready", get(ready))
+        .route("/openapi.json", get(get_openapi))
+        .route("/archive", post(archive_entries).layer(ingest_limit.clone()))
+        .route("/passwords", post(archive_passwords).layer(ingest_limit.clone()))
+        .route("/recording", post(archive_recording).layer(ingest_limit))
+        .route("/recordings/:session_id", get(get_recording))
+        .route("/stats", get(get_stats))
+        .route("/stats/by-type", get(get_stats_by_type))
+        .route("/metrics", get(get_metrics))
+        .route("/ws", get(live_events))
+        .route("/content/exists", post(content_exists))
+        .route("/content/top", get(get_top_content))
+        .route("/content/:hash", get(get_content).head(head_content))
+        .route("/maintenance/rebalance", post(rebalance_content))
+        .route("/maintenance/save-bloom", post(save_bloom))
+        .route("/compact", post(compact_content))
+        .route("/recompress", post(recompress_content))
+        .route("/db/flush", post(flush_db))
+        .route("/sessions", get(list_sessions))
+        .route("/sessions/:session_id", delete(delete_session))
+        .route("/sessions/:session_id/ttl", post(set_session_ttl))
+        .route("/sessions/:session_id/note", get(get_session_note).put(set_session_note))
+        .route("/sessions/:session_id/schema", get(get_session_schema))
+        .route("/sessions/:session_id/export.har", get(export_session_har))
+        .route("/sessions/:session_id/export.html", get(export_session_html))
+        .route("/sessions/:session_id/errors", get(get_session_errors))
+        .route("/sessions/:session_id/metrics", get(get_session_metrics))
+        .route("/sessions/:session_id/manifest", get(get_session_manifest))
+        .route("/sessions/:session_id/provenance", get(get_session_provenance))
+        .route("/sessions/:session_id/redactions", get(get_session_redactions))
+        .route("/sessions/:session_id/requests", get(get_session_requests))
+        .route("/sessions/:session_id/screenshot", get(get_session_screenshot))
+        .route("/sessions/:session_id/favicon", get(get_session_favicon))
+        .route("/requests/:request_id", get(get_request))
+        .route("/requests/:request_id/drift", get(get_request_drift))
+        .route("/search", get(search_requests))
+        .route("/hosts", get(list_hosts))
+        .route("/sessions/:session_id/pages/:navigation_id/export.mhtml", get(export_page_mhtml));
+    #[cfg(feature = "replay")]
+    let app = app.route("/sessions/:session_id/replay", post(replay_recording));
+    app
+        .with_state(tenants)
+        .layer(CatchPanicLayer::custom(errors::panic_response))
+        .layer(cors)
+        .layer(TraceLayer::new_for_http().make_span_with(|request: &axum::extract::Request| {
+            let request_id = request.extensions().get::<errors::RequestId>()
+                .map_or("", |id| id.0.as_str());
+            tracing::info_span!("request", method = %request.method(), uri = %request.uri(), request_id = %request_id)
+        }))
+        .layer(axum::middleware::from_fn(errors::envelope))
+}
+
+/// The classifiers `config` turns on for response bodies, combined, or
+/// `None` if it turns on none.
+fn response_classifier(config: &StorageConfig) -> Option<Arc<dyn classify::Classifier>> {
+    let mut classifiers: Vec<Box<dyn classify::Classifier>> = Vec::new();
+    if config.classify_response_bodies {
+        classifiers.push(Box::new(classify::RegexClassifier::default()));
+    }
+    if let Some(entropy) = &config.entropy_redaction {
+        classifiers.push(Box::new(entropy.clone()));
+    }
+    (!classifiers.is_empty())
+        .then(|| Arc::new(classify::Combined(classifiers)) as Arc<dyn classify::Classifier>)
+}
+
 #[tokio::main]
 async fn main() {
-    // Initialize tracing with environment filter
+    let cli = Cli::parse();
+    
+    // Initialize tracing with the resolved filter
     tracing_subscriber::fmt()
         .with_env_filter(
-            tracing_subscriber::EnvFilter::try_from_default_env()
+            tracing_subscriber::EnvFilter::try_new(&cli.log_level)
                 .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new("info"))
         )
         .with_target(false)
@@ -444,38 +4083,93 @@ async fn main() {
         .init();
     
     // Initialize storage
-    let storage = Storage::new("./archiver-data").await
+    let mut config = StorageConfig::from_env();
+    config.compression_level = cli.compression_level;
+    let storage = Storage::new(&cli.data_dir, config).await
         .expect("Failed to initialize storage");
     
-    let state = AppState {
-        storage: Arc::new(storage),
-        active_sessions: Arc::new(Mutex::new(HashMap::new())),
-        rrweb_sessions: Arc::new(Mutex::new(HashMap::new())),
+    let live_fetcher = drift::LiveFetcher::new(cli.live_fetch_allowlist)
+        .expect("Failed to build live fetch client");
+    let classifier = response_classifier(storage.config());
+    let webhook = cli.webhook_url.map(|url| {
+        info!("Forwarding page summaries to {}", url);
+        webhook::Webhook::spawn(url).expect("Failed to build webhook client")
+    });
+    #[allow(unused_mut)]
+    let mut default = AppState::new(storage, live_fetcher, classifier, webhook);
+    #[cfg(feature = "replay")]
+    {
+        default.browser = Some(Arc::new(replay::ChromeDriver::new(cli.chrome_path)));
+        let (queue, jobs) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(run_replay_jobs(jobs));
+        default.replay_queue = Some(queue);
+    }
+    let tenants = Tenants {
+        default,
+        others: Arc::new(Mutex::new(HashMap::new())),
     };
+    // Open existing tenants up front so background tasks cover them
+    for tenant in tenants.default.storage.tenant_names() {
+        tenants.get(&tenant).await.expect("Failed to open tenant");
+    }
+    for state in tenants.all().await {
+        match replay_write_ahead_log(&state).await {
+            Ok(0) => {}
+            Ok(replayed) => info!("Replayed {} batches from the write-ahead log{}", replayed,
+                state.storage.tenant().map(|t| format!(" for tenant {}", t)).unwrap_or_default()),
+            Err(e) => tracing::error!("Failed to replay the write-ahead log: {}", e),
+        }
+    }
+    #[cfg(feature = "replay")]
+    for state in tenants.all().await {
+        match resume_replay_jobs(&state).await {
+            Ok(0) => {}
+            Ok(resumed) => info!("Resumed {} replay jobs{}", resumed,
+                state.storage.tenant().map(|t| format!(" for tenant {}", t)).unwrap_or_default()),
+            Err(e) => tracing::error!("Failed to resume replay jobs: {}", e),
+        }
+    }
+    if tenants.default.storage.config().warm_cache {
+        for state in tenants.all().await {
+            match state.storage.warm_cache().await {
+                Ok(warmed) => info!("Warmed content cache{} with {} objects",
+                    state.storage.tenant().map(|t| format!(" for tenant {}", t)).unwrap_or_default(), warmed),
+                Err(e) => tracing::error!("Failed to warm content cache: {}", e),
+            }
+        }
+    }
     
-    let cors = CorsLayer::new()
-        .allow_origin(Any)
-        .allow_methods([Method::GET, Method::POST])
-        .allow_headers(Any);
+    tokio::spawn(run_retention_sweeps(tenants.clone()));
+    tokio::spawn(run_bloom_saves(tenants.clone()));
+    if let Some(secs) = tenants.default.storage.config().db_flush_interval_secs {
+        tokio::spawn(run_db_flushes(tenants.default.clone(), std::time::Duration::from_secs(secs)));
+    }
+    if let Some(ms) = tenants.default.storage.config().db_sync_interval_ms {
+        tokio::spawn(run_db_syncs(tenants.default.clone(), std::time::Duration::from_millis(ms)));
+    }
+    if let Some(secs) = tenants.default.storage.config().disk_scan_interval_secs {
+        tokio::spawn(run_disk_scans(tenants.clone(), std::time::Duration::from_secs(secs)));
+    }
     
-    let app = Router::new()
-        .route("/health", get(health))
-        .route("/archive", post(archive_entries))
-        .route("/passwords", post(archive_passwords))
-        .route("/recording", post(archive_recording))
-        .route("/stats", get(get_stats))
-        .with_state(state)
-        .layer(cors)
-        .layer(TraceLayer::new_for_http());
+    let app = app(tenants.clone());
     
-    let listener = tokio::net::TcpListener::bind("127.0.0.1:41788")
+    let listener = tokio::net::TcpListener::bind(cli.bind)
         .await
-        .unwrap();
+        .expect("Failed to bind listener");
     
-    info!("🚀 Archiver server listening on http://127.0.0.1:41788");
-    info!("💾 Storage initialized at ./archiver-data");
-    info!("📝 Logging level: {}", std::env::var("RUST_LOG").unwrap_or_else(|_| "info".to_string()));
+    info!("🚀 Archiver server listening on http://{}", cli.bind);
+    info!("💾 Storage initialized at {}", cli.data_dir.display());
+    info!("📝 Logging level: {}", cli.log_level);
     info!("Ready to receive rrweb recordings!");
     
-    axum::serve(listener, app).await.unwrap();
+    axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown_signal())
+        .await
+        .unwrap();
+    
+    for state in tenants.all().await {
+        if let Err(e) = state.storage.save_bloom().await {
+            tracing::error!("Failed to save bloom filter on shutdown: {}", e);
+        }
+    }
 }
\ No newline at end of file
@@ -0,0 +1,209 @@
+//! MessagePack for JSON values, enough to round-trip what `serde_json`
+//! produces: nil, booleans, integers, doubles, strings, arrays, and maps
+//! with string keys. See https://github.com/msgpack/msgpack/blob/master/spec.md.
+
+use serde_json::{Map, Number, Value};
+
+/// Deepest nesting `decode` accepts, as with `serde_json`'s own limit.
+const MAX_DEPTH: usize = 128;
+
+pub fn encode(value: &Value) -> Vec<u8> {
+    let mut out = Vec::new();
+    write_value(&mut out, value);
+    out
+}
+
+pub fn decode(data: &[u8]) -> Result<Value, String> {
+    let mut reader = Reader { data, pos: 0 };
+    let value = reader.value(0)?;
+    if reader.pos != data.len() {
+        return Err(format!("{} trailing bytes after MessagePack value", data.len() - reader.pos));
+    }
+    Ok(value)
+}
+
+fn write_value(out: &mut Vec<u8>, value: &Value) {
+    match value {
+        Value::Null => out.push(0xc0),
+        Value::Bool(false) => out.push(0xc2),
+        Value::Bool(true) => out.push(0xc3),
+        Value::Number(number) => write_number(out, number),
+        Value::String(text) => write_str(out, text),
+        Value::Array(items) => {
+            write_len(out, items.len(), 0x90, 16, 0xdc);
+            for item in items {
+                write_value(out, item);
+            }
+        }
+        Value::Object(map) => {
+            write_len(out, map.len(), 0x80, 16, 0xde);
+            for (key, item) in map {
+                write_str(out, key);
+                write_value(out, item);
+            }
+        }
+    }
+}
+
+fn write_number(out: &mut Vec<u8>, number: &Number) {
+    if let Some(n) = number.as_u64() {
+        match n {
+            0..=0x7f => out.push(n as u8),
+            0x80..=0xff => out.extend([0xcc, n as u8]),
+            0x100..=0xffff => {
+                out.push(0xcd);
+                out.extend((n as u16).to_be_bytes());
+            }
+            0x1_0000..=0xffff_ffff => {
+                out.push(0xce);
+                out.extend((n as u32).to_be_bytes());
+            }
+            _ => {
+                out.push(0xcf);
+                out.extend(n.to_be_bytes());
+            }
+        }
+    } else if let Some(n) = number.as_i64() {
+        // Only negative numbers get here
+        match n {
+            -32..=-1 => out.push(n as i8 as u8),
+            -0x80..=-33 => out.extend([0xd0, n as i8 as u8]),
+            -0x8000..=-0x81 => {
+                out.push(0xd1);
+                out.extend((n as i16).to_be_bytes());
+            }
+            -0x8000_0000..=-0x8001 => {
+                out.push(0xd2);
+                out.extend((n as i32).to_be_bytes());
+            }
+            _ => {
+                out.push(0xd3);
+                out.extend(n.to_be_bytes());
+            }
+        }
+    } else {
+        out.push(0xcb);
+        out.extend(number.as_f64().unwrap_or_default().to_be_bytes());
+    }
+}
+
+fn write_str(out: &mut Vec<u8>, text: &str) {
+    if text.len() < 32 {
+        out.push(0xa0 | text.len() as u8);
+    } else if text.len() <= 0xff {
+        out.extend([0xd9, text.len() as u8]);
+    } else {
+        write_len(out, text.len(), 0xa0, 0, 0xda);
+    }
+    out.extend_from_slice(text.as_bytes());
+}
+
+/// Writes a length as a fix type (`fix_tag | len`, below `fix_limit`), or
+/// with the 16-bit tag `tag16`, or the 32-bit tag after it.
+fn write_len(out: &mut Vec<u8>, len: usize, fix_tag: u8, fix_limit: usize, tag16: u8) {
+    if len < fix_limit {
+        out.push(fix_tag | len as u8);
+    } else if len <= 0xffff {
+        out.push(tag16);
+        out.extend((len as u16).to_be_bytes());
+    } else {
+        out.push(tag16 + 1);
+        out.extend((len as u32).to_be_bytes());
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], String> {
+        let end = self.pos.checked_add(n).filter(|&end| end <= self.data.len())
+            .ok_or("MessagePack value ends early")?;
+        let bytes = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+    
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], String> {
+        Ok(self.take(N)?.try_into().expect("took N bytes"))
+    }
+    
+    fn len(&mut self, width: usize) -> Result<usize, String> {
+        Ok(match width {
+            1 => self.array::<1>()?[0] as usize,
+            2 => u16::from_be_bytes(self.array()?) as usize,
+            _ => u32::from_be_bytes(self.array()?) as usize,
+        })
+    }
+    
+    fn str(&mut self, len: usize) -> Result<String, String> {
+        let bytes = self.take(len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|e| format!("MessagePack string isn't UTF-8: {}", e))
+    }
+    
+    fn value(&mut self, depth: usize) -> Result<Value, String> {
+        if depth > MAX_DEPTH {
+            return Err("MessagePack value nested too deeply".to_string());
+        }
+        let tag = self.array::<1>()?[0];
+        Ok(match tag {
+            0x00..=0x7f => Value::from(tag),
+            0x80..=0x8f => self.map((tag & 0x0f) as usize, depth)?,
+            0x90..=0x9f => self.items((tag & 0x0f) as usize, depth)?,
+            0xa0..=0xbf => Value::String(self.str((tag & 0x1f) as usize)?),
+            0xc0 => Value::Null,
+            0xc2 => Value::Bool(false),
+            0xc3 => Value::Bool(true),
+            0xca => float(f32::from_be_bytes(self.array()?) as f64)?,
+            0xcb => float(f64::from_be_bytes(self.array()?))?,
+            0xcc => Value::from(self.array::<1>()?[0]),
+            0xcd => Value::from(u16::from_be_bytes(self.array()?)),
+            0xce => Value::from(u32::from_be_bytes(self.array()?)),
+            0xcf => Value::from(u64::from_be_bytes(self.array()?)),
+            0xd0 => Value::from(i8::from_be_bytes(self.array()?)),
+            0xd1 => Value::from(i16::from_be_bytes(self.array()?)),
+            0xd2 => Value::from(i32::from_be_bytes(self.array()?)),
+            0xd3 => Value::from(i64::from_be_bytes(self.array()?)),
+            0xd9..=0xdb => {
+                let len = self.len(1 << (tag - 0xd9))?;
+                Value::String(self.str(len)?)
+            }
+            0xdc | 0xdd => {
+                let len = self.len(if tag == 0xdc { 2 } else { 4 })?;
+                self.items(len, depth)?
+            }
+            0xde | 0xdf => {
+                let len = self.len(if tag == 0xde { 2 } else { 4 })?;
+                self.map(len, depth)?
+            }
+            0xe0..=0xff => Value::from(tag as i8),
+            other => return Err(format!("Unsupported MessagePack type 0x{:02x}", other)),
+        })
+    }
+    
+    fn items(&mut self, len: usize, depth: usize) -> Result<Value, String> {
+        // Every item takes at least a byte, so a bogus length can't over-allocate
+        let mut items = Vec::with_capacity(len.min(self.data.len() - self.pos));
+        for _ in 0..len {
+            items.push(self.value(depth + 1)?);
+        }
+        Ok(Value::Array(items))
+    }
+    
+    fn map(&mut self, len: usize, depth: usize) -> Result<Value, String> {
+        let mut map = Map::new();
+        for _ in 0..len {
+            let Value::String(key) = self.value(depth + 1)? else {
+                return Err("MessagePack map key isn't a string".to_string());
+            };
+            map.insert(key, self.value(depth + 1)?);
+        }
+        Ok(Value::Object(map))
+    }
+}
+
+fn float(f: f64) -> Result<Value, String> {
+    Number::from_f64(f).map(Value::Number).ok_or_else(|| format!("MessagePack float {} isn't valid JSON", f))
+}
@@ -0,0 +1,135 @@
+//! The OpenAPI 3.1 document served at `/openapi.json`. Request and response
+//! schemas are derived from the serde types with `schemars`, so they follow
+//! the types (and their doc comments) as they change; only the routes are
+//! listed here, and they must be kept in step with `main`.
+
+use crate::errors::ErrorBody;
+use crate::storage::SessionNote;
+use crate::{
+    ArchiveRequest, ArchiveResponse, ContentExistsRequest, ContentExistsResponse, PasswordHashRequest,
+    ReadyResponse, RrwebRecordingRequest, SessionNoteRequest, StatsResponse,
+};
+use schemars::gen::{SchemaGenerator, SchemaSettings};
+use schemars::schema::Schema;
+use schemars::JsonSchema;
+use serde_json::{json, Map, Value};
+
+/// The schema of `T` as a JSON body, a `$ref` under `components/schemas`
+/// that `generator` collects.
+fn body<T: JsonSchema>(generator: &mut SchemaGenerator) -> Option<Schema> {
+    Some(generator.subschema_for::<T>())
+}
+
+/// An operation whose JSON request and 200 response bodies have the given
+/// schemas, if any, answering errors with `error`.
+fn operation(error: &Schema, summary: &str, request: Option<Schema>, response: Option<Schema>) -> Value {
+    let mut operation = json!({
+        "summary": summary,
+        "responses": {
+            "200": { "description": "OK" },
+            "default": {
+                "description": "Error",
+                "content": { "application/json": { "schema": error } },
+            },
+        },
+    });
+    if let Some(schema) = request {
+        operation["requestBody"] = json!({
+            "required": true,
+            "content": { "application/json": { "schema": schema } },
+        });
+    }
+    if let Some(schema) = response {
+        operation["responses"]["200"]["content"] = json!({ "application/json": { "schema": schema } });
+    }
+    operation
+}
+
+/// `(path, method, operation)` for each route in `main`, with path
+/// parameters in OpenAPI's `{name}` form.
+fn operations(generator: &mut SchemaGenerator) -> Vec<(&'static str, &'static str, Value)> {
+    let error = generator.subschema_for::<ErrorBody>();
+    #[allow(unused_mut)]
+    let mut operations = vec![
+        ("/health", "get", operation(&error, "Liveness check", None, None)),
+        ("/ready", "get", operation(&error, "Whether storage takes writes", None, body::<ReadyResponse>(generator))),
+        ("/openapi.json", "get", operation(&error, "This document", None, None)),
+        ("/archive", "post", operation(&error, "Archive a batch of exchanges", body::<ArchiveRequest>(generator), body::<ArchiveResponse>(generator))),
+        ("/passwords", "post", operation(&error, "Record password hashes to redact", body::<PasswordHashRequest>(generator), body::<ArchiveResponse>(generator))),
+        ("/recording", "post", operation(&error, "Archive a batch of rrweb events", body::<RrwebRecordingRequest>(generator), body::<ArchiveResponse>(generator))),
+        ("/recordings/{session_id}", "get", operation(&error, "A recording's events", None, None)),
+        ("/stats", "get", operation(&error, "Ingest and storage statistics", None, body::<StatsResponse>(generator))),
+        ("/stats/by-type", "get", operation(&error, "Storage statistics by content type", None, None)),
+        ("/metrics", "get", operation(&error, "Prometheus metrics", None, None)),
+        ("/ws", "get", operation(&error, "Live ingest events over a WebSocket", None, None)),
+        ("/content/exists", "post", operation(&error, "Which content hashes are stored", body::<ContentExistsRequest>(generator), body::<ContentExistsResponse>(generator))),
+        ("/content/top", "get", operation(&error, "Largest stored objects", None, None)),
+        ("/content/{hash}", "get", operation(&error, "A stored object's bytes", None, None)),
+        ("/content/{hash}", "head", operation(&error, "A stored object's headers", None, None)),
+        ("/maintenance/rebalance", "post", operation(&error, "Move content to the configured fanout", None, body::<ArchiveResponse>(generator))),
+        ("/maintenance/save-bloom", "post", operation(&error, "Persist the bloom filter", None, body::<ArchiveResponse>(generator))),
+        ("/compact", "post", operation(&error, "Pack small objects together", None, None)),
+        ("/recompress", "post", operation(&error, "Recompress content at a new level", None, None)),
+        ("/db/flush", "post", operation(&error, "Flush the metadata database", None, None)),
+        ("/sessions", "get", operation(&error, "Sessions, optionally by client name and version", None, None)),
+        ("/sessions/{session_id}", "delete", operation(&error, "Delete a session", None, None)),
+        ("/sessions/{session_id}/note", "get", operation(&error, "A session's note", None, body::<SessionNote>(generator))),
+        ("/sessions/{session_id}/note", "put", operation(&error, "Set or clear a session's note", body::<SessionNoteRequest>(generator), body::<ArchiveResponse>(generator))),
+        ("/sessions/{session_id}/ttl", "post", operation(&error, "Set a session's retention", None, None)),
+        ("/sessions/{session_id}/schema", "get", operation(&error, "Inferred JSON schema of a URL's responses", None, None)),
+        ("/sessions/{session_id}/export.har", "get", operation(&error, "The session as HAR", None, None)),
+        ("/sessions/{session_id}/export.html", "get", operation(&error, "The session's recording as a standalone replay page", None, None)),
+        ("/sessions/{session_id}/errors", "get", operation(&error, "Error responses, grouped", None, None)),
+        ("/sessions/{session_id}/metrics", "get", operation(&error, "Per-session metrics", None, None)),
+        ("/sessions/{session_id}/manifest", "get", operation(&error, "Timeline of exchanges and recordings", None, None)),
+        ("/sessions/{session_id}/provenance", "get", operation(&error, "Provenance records", None, None)),
+        ("/sessions/{session_id}/redactions", "get", operation(&error, "Redaction counts", None, None)),
+        ("/sessions/{session_id}/requests", "get", operation(&error, "Archived exchanges", None, None)),
+        ("/sessions/{session_id}/screenshot", "get", operation(&error, "Latest screenshot", None, None)),
+        ("/sessions/{session_id}/favicon", "get", operation(&error, "Latest favicon", None, None)),
+        ("/sessions/{session_id}/pages/{navigation_id}/export.mhtml", "get", operation(&error, "A page as MHTML", None, None)),
+        ("/requests/{request_id}", "get", operation(&error, "An archived exchange", None, None)),
+        ("/requests/{request_id}/drift", "get", operation(&error, "Compare an exchange with the live URL", None, None)),
+        ("/search", "get", operation(&error, "Find exchanges", None, None)),
+        ("/hosts", "get", operation(&error, "Hosts with archived exchanges", None, None)),
+    ];
+    #[cfg(feature = "replay")]
+    operations.push(("/sessions/{session_id}/replay", "post", operation(&error, "Replay a recording in a headless browser", None, body::<ArchiveResponse>(generator))));
+    operations
+}
+
+pub fn document() -> Value {
+    let mut settings = SchemaSettings::draft2019_09();
+    settings.definitions_path = "#/components/schemas/".to_string();
+    let mut generator = settings.into_generator();
+    
+    let mut paths = Map::new();
+    for (path, method, mut operation) in operations(&mut generator) {
+        let parameters: Vec<Value> = path.split('/')
+            .filter_map(|segment| segment.strip_prefix('{')?.strip_suffix('}'))
+            .map(|name| json!({ "name": name, "in": "path", "required": true, "schema": { "type": "string" } }))
+            .collect();
+        if !parameters.is_empty() {
+            operation["parameters"] = Value::Array(parameters);
+        }
+        if path == "/archive" {
+            operation["parameters"] = json!([{
+                "name": "Idempotency-Key",
+                "in": "header",
+                "required": false,
+                "schema": { "type": "string", "maxLength": 255 },
+            }]);
+        }
+        let item = paths.entry(path).or_insert_with(|| json!({}));
+        item[method] = operation;
+    }
+    json!({
+        "openapi": "3.1.0",
+        "info": {
+            "title": "Archiver",
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "paths": paths,
+        "components": { "schemas": generator.take_definitions() },
+    })
+}
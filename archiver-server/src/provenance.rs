@@ -0,0 +1,85 @@
+use crate::storage::PageFetchIndex;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::BTreeSet;
+
+const ALGORITHM: &str = "hmac-sha256";
+/// Prefix of the signed message, so a signature can't be replayed against
+/// a different message layout.
+const MESSAGE_VERSION: &str = "archiver-provenance-v1";
+
+/// Chain-of-custody record for a page fetch, signed with the server's
+/// provenance key.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Provenance {
+    /// Version of the extension or client that captured the page.
+    pub client_version: Option<String>,
+    /// When the server last wrote the page fetch.
+    pub captured_at: chrono::DateTime<chrono::Utc>,
+    pub server_version: String,
+    pub algorithm: String,
+    /// Hex signature over the fields above, the page's identifiers, and
+    /// every body hash it references.
+    pub signature: String,
+}
+
+/// Builds a signed record for `page_fetch` as it stands now. Re-sign after
+/// adding requests, since the signature covers the page's content hashes.
+pub fn sign(key: &str, page_fetch: &PageFetchIndex, client_version: Option<String>) -> Provenance {
+    let mut provenance = Provenance {
+        client_version,
+        captured_at: chrono::Utc::now(),
+        server_version: env!("CARGO_PKG_VERSION").to_string(),
+        algorithm: ALGORITHM.to_string(),
+        signature: String::new(),
+    };
+    let mut mac = new_mac(key);
+    mac.update(signed_message(page_fetch, &provenance).as_bytes());
+    provenance.signature = hex::encode(mac.finalize().into_bytes());
+    provenance
+}
+
+/// Whether `page_fetch` carries a provenance record whose signature matches
+/// its current contents under `key`.
+pub fn verify(key: &str, page_fetch: &PageFetchIndex) -> bool {
+    let Some(provenance) = &page_fetch.provenance else {
+        return false;
+    };
+    let Ok(signature) = hex::decode(&provenance.signature) else {
+        return false;
+    };
+    if provenance.algorithm != ALGORITHM {
+        return false;
+    }
+    let mut mac = new_mac(key);
+    mac.update(signed_message(page_fetch, provenance).as_bytes());
+    mac.verify_slice(&signature).is_ok()
+}
+
+fn new_mac(key: &str) -> Hmac<Sha256> {
+    Hmac::<Sha256>::new_from_slice(key.as_bytes()).expect("HMAC accepts keys of any length")
+}
+
+/// One field per line, with body hashes sorted so request order and
+/// repeats don't affect the signature.
+fn signed_message(page_fetch: &PageFetchIndex, provenance: &Provenance) -> String {
+    let hashes: BTreeSet<&String> = page_fetch.requests.iter()
+        .flat_map(|request| {
+            let response_hash = request.response.as_ref().and_then(|r| r.body_hash.as_ref());
+            request.request_body_hash.iter().chain(response_hash)
+        })
+        .chain(page_fetch.frame_hashes())
+        .collect();
+    
+    let mut lines = vec![
+        MESSAGE_VERSION.to_string(),
+        page_fetch.session_id.clone(),
+        page_fetch.navigation_id.clone(),
+        provenance.client_version.clone().unwrap_or_default(),
+        provenance.captured_at.to_rfc3339_opts(chrono::SecondsFormat::AutoSi, true),
+        provenance.server_version.clone(),
+    ];
+    lines.extend(hashes.into_iter().cloned());
+    lines.join("\n")
+}
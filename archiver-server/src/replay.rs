@@ -0,0 +1,330 @@
+use base64::Engine;
+use chromiumoxide::cdp::browser_protocol::network::{self, EventLoadingFinished, EventRequestWillBeSent, EventResponseReceived, GetResponseBodyParams, Request, RequestId, Response};
+use chromiumoxide::cdp::browser_protocol::page::{EnableParams as EnablePageParams, EventLoadEventFired, NavigateParams};
+use chromiumoxide::{Browser, BrowserConfig, Page};
+use futures::{stream, FutureExt, Stream, StreamExt};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+
+pub type ReplayError = Box<dyn std::error::Error + Send + Sync>;
+
+/// rrweb's Meta event, recorded at each page load with the page's `href`.
+const RRWEB_META_EVENT: u64 = 4;
+/// Longest pause between pages, however far apart the recording has them.
+const MAX_STEP_GAP: Duration = Duration::from_secs(5);
+/// How long the network has to stay quiet after a page loads.
+const NETWORK_IDLE: Duration = Duration::from_secs(1);
+/// Most time spent on one page, loaded or not.
+const STEP_TIMEOUT: Duration = Duration::from_secs(30);
+const LAUNCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A page the recording loaded, `offset_ms` after its first one.
+#[derive(Debug, Clone)]
+pub struct ReplayStep {
+    pub url: String,
+    pub offset_ms: i64,
+}
+
+/// The pages to revisit, in recording order.
+#[derive(Debug, Clone, Default)]
+pub struct ReplayPlan {
+    pub steps: Vec<ReplayStep>,
+}
+
+impl ReplayPlan {
+    /// One step per Meta event; a recording without any falls back to
+    /// `recorded_url` at offset 0. Only http(s) pages are kept.
+    pub fn from_events(events: &[Value], recorded_url: &str) -> Self {
+        let mut steps: Vec<ReplayStep> = events.iter()
+            .filter(|event| event.get("type").and_then(Value::as_u64) == Some(RRWEB_META_EVENT))
+            .filter_map(|event| Some(ReplayStep {
+                url: event.pointer("/data/href")?.as_str()?.to_string(),
+                offset_ms: event.get("timestamp").and_then(Value::as_i64).unwrap_or(0),
+            }))
+            .collect();
+        if steps.is_empty() {
+            steps.push(ReplayStep { url: recorded_url.to_string(), offset_ms: 0 });
+        }
+        steps.retain(|step| step.url.starts_with("http://") || step.url.starts_with("https://"));
+        
+        let start = steps.first().map_or(0, |step| step.offset_ms);
+        for step in &mut steps {
+            step.offset_ms = (step.offset_ms - start).max(0);
+        }
+        ReplayPlan { steps }
+    }
+}
+
+/// A request the browser made during replay. Timestamps are milliseconds
+/// since the epoch.
+#[derive(Debug, Clone)]
+pub struct CapturedExchange {
+    pub timestamp: i64,
+    pub url: String,
+    pub method: String,
+    pub request_headers: Vec<(String, String)>,
+    pub request_body: Option<String>,
+    /// Lowercased DevTools resource type, e.g. `document`, `xhr`.
+    pub resource_type: Option<String>,
+    /// `None` when the request failed before a response arrived.
+    pub response: Option<CapturedResponse>,
+}
+
+#[derive(Debug, Clone)]
+pub struct CapturedResponse {
+    pub timestamp: i64,
+    pub status_code: u16,
+    pub status_text: Option<String>,
+    pub http_version: Option<String>,
+    pub headers: Vec<(String, String)>,
+    /// `None` for redirects and bodies the browser didn't keep.
+    pub body: Option<Vec<u8>>,
+}
+
+/// Drives a browser through a replay plan, recording its network traffic.
+#[axum::async_trait]
+pub trait BrowserDriver: Send + Sync {
+    async fn capture(&self, plan: &ReplayPlan) -> Result<Vec<CapturedExchange>, ReplayError>;
+}
+
+/// Launches a fresh headless Chromium per replay, with a throwaway profile,
+/// and controls it over the DevTools protocol.
+pub struct ChromeDriver {
+    binary: PathBuf,
+}
+
+impl ChromeDriver {
+    pub fn new(binary: PathBuf) -> Self {
+        ChromeDriver { binary }
+    }
+}
+
+#[axum::async_trait]
+impl BrowserDriver for ChromeDriver {
+    async fn capture(&self, plan: &ReplayPlan) -> Result<Vec<CapturedExchange>, ReplayError> {
+        let profile = std::env::temp_dir().join(format!("archiver-replay-{}", uuid::Uuid::new_v4()));
+        let config = BrowserConfig::builder()
+            .chrome_executable(&self.binary)
+            .user_data_dir(&profile)
+            .new_headless_mode()
+            .launch_timeout(LAUNCH_TIMEOUT)
+            .build()?;
+        let (mut browser, mut handler) = Browser::launch(config).await
+            .map_err(|e| format!("Failed to launch {}: {}", self.binary.display(), e))?;
+        // The handler pumps the DevTools connection; nothing moves without it
+        let handler = tokio::spawn(async move {
+            while let Some(event) = handler.next().await {
+                if let Err(e) = event {
+                    tracing::debug!("chromium: {}", e);
+                }
+            }
+        });
+        
+        let result = replay(&browser, plan).await;
+        
+        let _ = browser.close().await;
+        let _ = browser.wait().await;
+        handler.abort();
+        let _ = tokio::fs::remove_dir_all(&profile).await;
+        result
+    }
+}
+
+async fn replay(browser: &Browser, plan: &ReplayPlan) -> Result<Vec<CapturedExchange>, ReplayError> {
+    let page = browser.new_page("about:blank").await?;
+    page.execute(network::EnableParams::default()).await?;
+    page.execute(EnablePageParams::default()).await?;
+    
+    let mut events = stream::select_all([
+        page.event_listener::<EventRequestWillBeSent>().await?.map(NetworkEvent::RequestWillBeSent).boxed(),
+        page.event_listener::<EventResponseReceived>().await?.map(NetworkEvent::ResponseReceived).boxed(),
+        page.event_listener::<EventLoadingFinished>().await?.map(NetworkEvent::LoadingFinished).boxed(),
+        page.event_listener::<EventLoadEventFired>().await?.map(NetworkEvent::LoadEventFired).boxed(),
+    ]);
+    
+    let mut capture = Capture::default();
+    let mut previous_offset = 0;
+    for step in &plan.steps {
+        // Late requests from the previous page still count while we wait
+        let gap = Duration::from_millis((step.offset_ms - previous_offset).max(0) as u64).min(MAX_STEP_GAP);
+        previous_offset = step.offset_ms;
+        collect(&mut events, &mut capture, Instant::now() + gap, false).await;
+        
+        tracing::debug!("Replaying {}", step.url);
+        page.execute(NavigateParams::new(step.url.clone())).await?;
+        collect(&mut events, &mut capture, Instant::now() + STEP_TIMEOUT, true).await;
+        fetch_bodies(&page, &mut capture).await;
+    }
+    
+    // Events that arrived while fetching bodies
+    collect(&mut events, &mut capture, Instant::now(), false).await;
+    fetch_bodies(&page, &mut capture).await;
+    Ok(capture.exchanges)
+}
+
+/// The DevTools events a replay listens for. Each kind arrives on its own
+/// stream, so they're put back in protocol order by `timestamp`.
+enum NetworkEvent {
+    RequestWillBeSent(Arc<EventRequestWillBeSent>),
+    ResponseReceived(Arc<EventResponseReceived>),
+    LoadingFinished(Arc<EventLoadingFinished>),
+    LoadEventFired(Arc<EventLoadEventFired>),
+}
+
+impl NetworkEvent {
+    fn timestamp(&self) -> f64 {
+        match self {
+            NetworkEvent::RequestWillBeSent(event) => *event.timestamp.inner(),
+            NetworkEvent::ResponseReceived(event) => *event.timestamp.inner(),
+            NetworkEvent::LoadingFinished(event) => *event.timestamp.inner(),
+            NetworkEvent::LoadEventFired(event) => *event.timestamp.inner(),
+        }
+    }
+}
+
+/// Records events until `deadline`, or with `until_idle`, until the page
+/// has loaded and the network has been quiet for `NETWORK_IDLE`.
+async fn collect(events: &mut (impl Stream<Item = NetworkEvent> + Unpin), capture: &mut Capture, deadline: Instant, until_idle: bool) {
+    let mut idle_at = None;
+    loop {
+        let wait_until = idle_at.map_or(deadline, |idle_at: Instant| idle_at.min(deadline));
+        let Ok(Some(first)) = tokio::time::timeout_at(wait_until, events.next()).await else {
+            return;
+        };
+        let mut batch = vec![first];
+        while let Some(Some(event)) = events.next().now_or_never() {
+            batch.push(event);
+        }
+        batch.sort_by(|a, b| a.timestamp().total_cmp(&b.timestamp()));
+        
+        let mut loaded = false;
+        for event in batch {
+            loaded |= matches!(event, NetworkEvent::LoadEventFired(_));
+            capture.record(&event);
+        }
+        if until_idle && (loaded || idle_at.is_some()) {
+            idle_at = Some(Instant::now() + NETWORK_IDLE);
+        }
+    }
+}
+
+/// Fills in bodies for finished requests. The browser doesn't keep every
+/// body, so failures leave the body empty rather than failing the replay.
+async fn fetch_bodies(page: &Page, capture: &mut Capture) {
+    for request_id in std::mem::take(&mut capture.finished) {
+        let Some(&Started { index, .. }) = capture.started.get(request_id.inner()) else {
+            continue;
+        };
+        let body = match page.execute(GetResponseBodyParams::new(request_id)).await {
+            Ok(body) => body.result,
+            Err(e) => {
+                tracing::debug!("No body for {}: {}", capture.exchanges[index].url, e);
+                continue;
+            }
+        };
+        let bytes = if body.base64_encoded {
+            base64::engine::general_purpose::STANDARD.decode(&body.body).ok()
+        } else {
+            Some(body.body.into_bytes())
+        };
+        if let Some(response) = &mut capture.exchanges[index].response {
+            response.body = bytes;
+        }
+    }
+}
+
+/// Where and when a DevTools request started.
+struct Started {
+    index: usize,
+    wall_ms: i64,
+    /// DevTools' monotonic clock, in seconds; later events only carry this.
+    monotonic: f64,
+}
+
+#[derive(Default)]
+struct Capture {
+    exchanges: Vec<CapturedExchange>,
+    /// Keyed by DevTools request ID; a redirect reuses its request's ID.
+    started: HashMap<String, Started>,
+    /// Requests that finished loading whose bodies haven't been fetched.
+    finished: Vec<RequestId>,
+}
+
+impl Capture {
+    fn record(&mut self, event: &NetworkEvent) {
+        match event {
+            NetworkEvent::RequestWillBeSent(event) => {
+                let monotonic = *event.timestamp.inner();
+                if let Some(redirect) = &event.redirect_response {
+                    self.respond(&event.request_id, redirect, monotonic);
+                }
+                let request = &event.request;
+                if !request.url.starts_with("http://") && !request.url.starts_with("https://") {
+                    return;
+                }
+                let wall_ms = (*event.wall_time.inner() * 1000.0) as i64;
+                self.started.insert(event.request_id.inner().clone(), Started { index: self.exchanges.len(), wall_ms, monotonic });
+                self.exchanges.push(CapturedExchange {
+                    timestamp: wall_ms,
+                    url: request.url.clone(),
+                    method: request.method.clone(),
+                    request_headers: devtools_headers(request.headers.inner()),
+                    request_body: post_data(request),
+                    resource_type: event.r#type.as_ref().map(|kind| kind.as_ref().to_lowercase()),
+                    response: None,
+                });
+            }
+            NetworkEvent::ResponseReceived(event) => {
+                self.respond(&event.request_id, &event.response, *event.timestamp.inner());
+            }
+            NetworkEvent::LoadingFinished(event) if self.started.contains_key(event.request_id.inner()) => {
+                self.finished.push(event.request_id.clone());
+            }
+            _ => {}
+        }
+    }
+    
+    fn respond(&mut self, request_id: &RequestId, response: &Response, monotonic: f64) {
+        let Some(started) = self.started.get(request_id.inner()) else {
+            return;
+        };
+        let elapsed_ms = ((monotonic - started.monotonic) * 1000.0).max(0.0) as i64;
+        self.exchanges[started.index].response = Some(CapturedResponse {
+            timestamp: started.wall_ms + elapsed_ms,
+            status_code: response.status as u16,
+            status_text: Some(response.status_text.clone()).filter(|text| !text.is_empty()),
+            http_version: response.protocol.clone(),
+            headers: devtools_headers(response.headers.inner()),
+            body: None,
+        });
+    }
+}
+
+/// DevTools hands request bodies over as base64 chunks.
+fn post_data(request: &Request) -> Option<String> {
+    let entries = request.post_data_entries.as_ref()?;
+    let mut body = Vec::new();
+    for bytes in entries.iter().filter_map(|entry| entry.bytes.as_ref()) {
+        let encoded: &str = bytes.as_ref();
+        body.extend(base64::engine::general_purpose::STANDARD.decode(encoded).ok()?);
+    }
+    Some(String::from_utf8_lossy(&body).into_owned())
+}
+
+/// DevTools reports headers as an object, joining repeated ones with newlines.
+fn devtools_headers(headers: &Value) -> Vec<(String, String)> {
+    let Some(headers) = headers.as_object() else {
+        return Vec::new();
+    };
+    headers.iter()
+        .flat_map(|(name, value)| {
+            value.as_str().unwrap_or_default()
+                .split('\n')
+                .map(move |value| (name.clone(), value.to_string()))
+        })
+        .collect()
+}
@@ -0,0 +1,127 @@
+use crate::storage::HashAlgorithm;
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+
+/// Key of the object that stands in for an extracted asset.
+const REF_KEY: &str = "__archiver_ref";
+
+/// An inline asset lifted out of an rrweb event.
+pub struct ExtractedAsset {
+    pub hash: String,
+    pub content_type: String,
+    pub data: String,
+}
+
+/// Replaces every string of at least `threshold` bytes in `event` (inline
+/// stylesheets, data URIs, and the like) with `{"__archiver_ref": hash}`,
+/// appending the originals to `assets` for storage.
+pub fn extract_assets(event: &mut Value, threshold: usize, algorithm: HashAlgorithm, assets: &mut Vec<ExtractedAsset>) {
+    match event {
+        Value::String(text) if text.len() >= threshold => {
+            let data = std::mem::take(text);
+            let hash = algorithm.hash(data.as_bytes());
+            *event = serde_json::json!({ REF_KEY: hash });
+            assets.push(ExtractedAsset {
+                hash,
+                content_type: asset_content_type(&data),
+                data,
+            });
+        }
+        Value::Array(items) => {
+            for item in items {
+                extract_assets(item, threshold, algorithm, assets);
+            }
+        }
+        Value::Object(fields) => {
+            for value in fields.values_mut() {
+                extract_assets(value, threshold, algorithm, assets);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Collects the hashes referenced by placeholders in `event`.
+pub fn collect_refs(event: &Value, refs: &mut HashSet<String>) {
+    if let Some(hash) = as_ref(event) {
+        refs.insert(hash.to_string());
+        return;
+    }
+    match event {
+        Value::Array(items) => items.iter().for_each(|item| collect_refs(item, refs)),
+        Value::Object(fields) => fields.values().for_each(|value| collect_refs(value, refs)),
+        _ => {}
+    }
+}
+
+/// Puts extracted assets back in place of their placeholders. Placeholders
+/// whose asset is missing from `assets` are left as they are.
+pub fn restore_assets(event: &mut Value, assets: &HashMap<String, String>) {
+    if let Some(data) = as_ref(event).and_then(|hash| assets.get(hash)) {
+        *event = Value::String(data.clone());
+        return;
+    }
+    match event {
+        Value::Array(items) => items.iter_mut().for_each(|item| restore_assets(item, assets)),
+        Value::Object(fields) => fields.values_mut().for_each(|value| restore_assets(value, assets)),
+        _ => {}
+    }
+}
+
+/// Collects the values of node attributes in `event`, from full snapshots
+/// and attribute mutations alike, which is where `src` and `href` URLs are.
+pub fn collect_attribute_values(event: &Value, values: &mut HashSet<String>) {
+    match event {
+        Value::Array(items) => items.iter().for_each(|item| collect_attribute_values(item, values)),
+        Value::Object(fields) => {
+            for (key, value) in fields {
+                match value {
+                    Value::Object(attributes) if key == "attributes" => {
+                        values.extend(attributes.values().filter_map(Value::as_str).map(str::to_string));
+                    }
+                    _ => collect_attribute_values(value, values),
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Replaces node attribute values found in `replacements`, such as
+/// resource URLs with `data:` URIs of their archived bodies.
+pub fn replace_attribute_values(event: &mut Value, replacements: &HashMap<String, String>) {
+    match event {
+        Value::Array(items) => items.iter_mut().for_each(|item| replace_attribute_values(item, replacements)),
+        Value::Object(fields) => {
+            for (key, value) in fields.iter_mut() {
+                match value {
+                    Value::Object(attributes) if key == "attributes" => {
+                        for attribute in attributes.values_mut() {
+                            if let Some(replacement) = attribute.as_str().and_then(|value| replacements.get(value)) {
+                                *attribute = Value::String(replacement.clone());
+                            }
+                        }
+                    }
+                    _ => replace_attribute_values(value, replacements),
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+fn as_ref(value: &Value) -> Option<&str> {
+    match value {
+        Value::Object(fields) if fields.len() == 1 => fields.get(REF_KEY)?.as_str(),
+        _ => None,
+    }
+}
+
+/// Uses the media type of a data URI; anything else is stored as text.
+fn asset_content_type(data: &str) -> String {
+    data.strip_prefix("data:")
+        .and_then(|rest| rest.split([';', ',']).next())
+        .filter(|mime| !mime.is_empty())
+        .unwrap_or("text/plain")
+        .to_string()
+}
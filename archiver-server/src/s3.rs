@@ -0,0 +1,268 @@
+use crate::content_store::ContentStore;
+use crate::storage::StorageError;
+use hmac::{Hmac, Mac};
+use reqwest::{Method, StatusCode};
+use sha2::{Digest, Sha256};
+
+const REQUEST_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(60);
+const DEFAULT_REGION: &str = "us-east-1";
+
+/// An S3-compatible bucket to keep content in, from `ARCHIVER_S3_*`.
+#[derive(Debug, Clone)]
+pub struct S3Config {
+    /// Base URL of the service, e.g. `https://s3.us-east-1.amazonaws.com` or
+    /// a MinIO address. Buckets are addressed by path.
+    pub endpoint: String,
+    pub bucket: String,
+    pub region: String,
+    /// Prepended to every object key, e.g. `archiver/`.
+    pub prefix: String,
+}
+
+impl S3Config {
+    /// `None` unless `ARCHIVER_S3_BUCKET` is set.
+    pub fn from_env() -> Option<Self> {
+        let bucket = std::env::var("ARCHIVER_S3_BUCKET").ok().filter(|b| !b.is_empty())?;
+        let region = std::env::var("ARCHIVER_S3_REGION").unwrap_or_else(|_| DEFAULT_REGION.to_string());
+        let endpoint = std::env::var("ARCHIVER_S3_ENDPOINT")
+            .unwrap_or_else(|_| format!("https://s3.{}.amazonaws.com", region));
+        Some(S3Config {
+            endpoint: endpoint.trim_end_matches('/').to_string(),
+            bucket,
+            region,
+            prefix: std::env::var("ARCHIVER_S3_PREFIX").unwrap_or_default(),
+        })
+    }
+}
+
+/// Credentials from the standard `AWS_*` variables.
+struct Credentials {
+    access_key_id: String,
+    secret_access_key: String,
+    session_token: Option<String>,
+}
+
+impl Credentials {
+    fn from_env() -> Result<Self, StorageError> {
+        Ok(Credentials {
+            access_key_id: std::env::var("AWS_ACCESS_KEY_ID").map_err(|_| "AWS_ACCESS_KEY_ID is not set")?,
+            secret_access_key: std::env::var("AWS_SECRET_ACCESS_KEY").map_err(|_| "AWS_SECRET_ACCESS_KEY is not set")?,
+            session_token: std::env::var("AWS_SESSION_TOKEN").ok().filter(|t| !t.is_empty()),
+        })
+    }
+}
+
+/// Objects as `{prefix}{namespace}/{key}.zst` in an S3-compatible bucket,
+/// with requests signed using AWS Signature Version 4.
+pub struct S3Store {
+    client: reqwest::Client,
+    config: S3Config,
+    credentials: Credentials,
+    /// Storage namespace, e.g. `content` or `chunks/{tenant}`.
+    namespace: String,
+}
+
+impl S3Store {
+    pub fn new(config: S3Config, namespace: String) -> Result<Self, StorageError> {
+        let client = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()?;
+        Ok(S3Store {
+            client,
+            config,
+            credentials: Credentials::from_env()?,
+            namespace,
+        })
+    }
+    
+    fn object_url(&self, key: &str) -> Result<reqwest::Url, StorageError> {
+        let url = format!("{}/{}/{}{}/{}.zst", self.config.endpoint, self.config.bucket, self.config.prefix, self.namespace, key);
+        Ok(reqwest::Url::parse(&url)?)
+    }
+    
+    async fn send(&self, method: Method, key: &str, body: Vec<u8>) -> Result<reqwest::Response, StorageError> {
+        let url = self.object_url(key)?;
+        let now = chrono::Utc::now();
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = now.format("%Y%m%d").to_string();
+        let payload_hash = hex::encode(Sha256::digest(&body));
+        let host = match (url.host_str(), url.port()) {
+            (Some(host), Some(port)) => format!("{}:{}", host, port),
+            (Some(host), None) => host.to_string(),
+            (None, _) => return Err("S3 endpoint has no host".into()),
+        };
+        
+        // Canonical headers must be sorted by name
+        let mut headers = vec![
+            ("host", host),
+            ("x-amz-content-sha256", payload_hash.clone()),
+            ("x-amz-date", amz_date.clone()),
+        ];
+        if let Some(token) = &self.credentials.session_token {
+            headers.push(("x-amz-security-token", token.clone()));
+        }
+        let signed_headers = headers.iter().map(|(name, _)| *name).collect::<Vec<_>>().join(";");
+        let canonical_headers: String = headers.iter()
+            .map(|(name, value)| format!("{}:{}\n", name, value.trim()))
+            .collect();
+        // The URL parser has already percent-encoded the path, as S3 expects
+        let canonical_request = format!("{}\n{}\n\n{}\n{}\n{}",
+            method, url.path(), canonical_headers, signed_headers, payload_hash);
+        
+        let scope = format!("{}/{}/s3/aws4_request", date, self.config.region);
+        let string_to_sign = format!("AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date, scope, hex::encode(Sha256::digest(canonical_request.as_bytes())));
+        let mut signing_key = hmac(format!("AWS4{}", self.credentials.secret_access_key).as_bytes(), date.as_bytes());
+        for part in [self.config.region.as_str(), "s3", "aws4_request"] {
+            signing_key = hmac(&signing_key, part.as_bytes());
+        }
+        let signature = hex::encode(hmac(&signing_key, string_to_sign.as_bytes()));
+        let authorization = format!("AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            self.credentials.access_key_id, scope, signed_headers, signature);
+        
+        // reqwest sets `Host` itself, to the same value
+        let mut request = self.client.request(method, url)
+            .header(reqwest::header::AUTHORIZATION, authorization);
+        for (name, value) in headers.into_iter().filter(|(name, _)| *name != "host") {
+            request = request.header(name, value);
+        }
+        Ok(request.body(body).send().await?)
+    }
+}
+
+#[axum::async_trait]
+impl ContentStore for S3Store {
+    async fn put(&self, key: &str, data: &[u8]) -> Result<(), StorageError> {
+        let response = self.send(Method::PUT, key, data.to_vec()).await?;
+        check(response, "PUT", key).await?;
+        Ok(())
+    }
+    
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, StorageError> {
+        let response = self.send(Method::GET, key, Vec::new()).await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        Ok(Some(check(response, "GET", key).await?.bytes().await?.to_vec()))
+    }
+    
+    async fn delete(&self, key: &str) -> Result<u64, StorageError> {
+        // DELETE doesn't say how much it removed, or whether anything was there
+        let Some(size) = self.object_size(key).await? else {
+            return Ok(0);
+        };
+        let response = self.send(Method::DELETE, key, Vec::new()).await?;
+        check(response, "DELETE", key).await?;
+        Ok(size)
+    }
+}
+
+impl S3Store {
+    async fn object_size(&self, key: &str) -> Result<Option<u64>, StorageError> {
+        let response = self.send(Method::HEAD, key, Vec::new()).await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let response = check(response, "HEAD", key).await?;
+        // Not `content_length()`, which is the (empty) body's length
+        let size = response.headers().get(reqwest::header::CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(0);
+        Ok(Some(size))
+    }
+}
+
+/// Passes successful responses through; anything else becomes an error
+/// carrying the status and S3's error body.
+async fn check(response: reqwest::Response, operation: &str, key: &str) -> Result<reqwest::Response, StorageError> {
+    if response.status().is_success() {
+        return Ok(response);
+    }
+    let status = response.status();
+    let body = response.text().await.unwrap_or_default();
+    Err(format!("S3 {} {} failed with {}: {}", operation, key, status, body.trim()).into())
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Bytes;
+    use axum::extract::State;
+    use axum::http::{HeaderMap, Uri};
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+    
+    type Bucket = Arc<Mutex<HashMap<String, Vec<u8>>>>;
+    
+    /// Just enough of S3 for `S3Store`: objects by path, and requests
+    /// rejected unless they carry a signature and the body's hash.
+    async fn mock_s3(State(bucket): State<Bucket>, method: Method, uri: Uri, headers: HeaderMap, body: Bytes) -> (StatusCode, HeaderMap, Vec<u8>) {
+        let signed = headers.get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.starts_with("AWS4-HMAC-SHA256 Credential=test-key/"));
+        let hashed = headers.get("x-amz-content-sha256")
+            .is_some_and(|value| value.as_bytes() == hex::encode(Sha256::digest(&body)).as_bytes());
+        if !signed || !hashed {
+            return (StatusCode::FORBIDDEN, HeaderMap::new(), b"SignatureDoesNotMatch".to_vec());
+        }
+        
+        let mut bucket = bucket.lock().unwrap();
+        let key = uri.path().to_string();
+        let mut response_headers = HeaderMap::new();
+        match method {
+            Method::PUT => {
+                bucket.insert(key, body.to_vec());
+                (StatusCode::OK, response_headers, Vec::new())
+            }
+            Method::GET | Method::HEAD => match bucket.get(&key) {
+                Some(data) if method == Method::HEAD => {
+                    response_headers.insert("content-length", data.len().into());
+                    (StatusCode::OK, response_headers, Vec::new())
+                }
+                Some(data) => (StatusCode::OK, response_headers, data.clone()),
+                None => (StatusCode::NOT_FOUND, response_headers, Vec::new()),
+            },
+            Method::DELETE => {
+                bucket.remove(&key);
+                (StatusCode::NO_CONTENT, response_headers, Vec::new())
+            }
+            _ => (StatusCode::METHOD_NOT_ALLOWED, response_headers, Vec::new()),
+        }
+    }
+    
+    #[tokio::test]
+    async fn s3_store_round_trip() {
+        let bucket = Bucket::default();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let app = axum::Router::new().fallback(mock_s3).with_state(bucket.clone());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        
+        let store = S3Store {
+            client: reqwest::Client::new(),
+            config: S3Config {
+                endpoint,
+                bucket: "archive".to_string(),
+                region: DEFAULT_REGION.to_string(),
+                prefix: "archiver/".to_string(),
+            },
+            credentials: Credentials {
+                access_key_id: "test-key".to_string(),
+                secret_access_key: "test-secret".to_string(),
+                session_token: None,
+            },
+            namespace: "content".to_string(),
+        };
+        crate::content_store::tests::round_trip(&store).await;
+        
+        let keys: Vec<String> = bucket.lock().unwrap().keys().cloned().collect();
+        assert_eq!(keys, ["/archive/archiver/content/ff00.zst"]);
+    }
+}
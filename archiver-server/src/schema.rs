@@ -0,0 +1,89 @@
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Observed shape of one field path across all sampled documents.
+#[derive(Debug, Default, Serialize)]
+pub struct FieldSchema {
+    pub types: BTreeSet<&'static str>,
+    /// Number of samples in which the field appeared at least once.
+    pub count: usize,
+    pub frequency: f64,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct InferredSchema {
+    pub samples: usize,
+    pub fields: BTreeMap<String, FieldSchema>,
+}
+
+impl InferredSchema {
+    pub fn add_sample(&mut self, value: &serde_json::Value) {
+        let mut seen: BTreeMap<String, BTreeSet<&'static str>> = BTreeMap::new();
+        collect_fields("$", value, &mut seen);
+        
+        self.samples += 1;
+        for (path, types) in seen {
+            let field = self.fields.entry(path).or_default();
+            field.count += 1;
+            field.types.extend(types);
+        }
+        
+        for field in self.fields.values_mut() {
+            field.frequency = field.count as f64 / self.samples as f64;
+        }
+    }
+}
+
+fn type_name(value: &serde_json::Value) -> &'static str {
+    match value {
+        serde_json::Value::Null => "null",
+        serde_json::Value::Bool(_) => "boolean",
+        serde_json::Value::Number(_) => "number",
+        serde_json::Value::String(_) => "string",
+        serde_json::Value::Array(_) => "array",
+        serde_json::Value::Object(_) => "object",
+    }
+}
+
+fn collect_fields(path: &str, value: &serde_json::Value, seen: &mut BTreeMap<String, BTreeSet<&'static str>>) {
+    seen.entry(path.to_string()).or_default().insert(type_name(value));
+    
+    match value {
+        serde_json::Value::Object(map) => {
+            for (key, child) in map {
+                collect_fields(&format!("{}.{}", path, key), child, seen);
+            }
+        }
+        serde_json::Value::Array(items) => {
+            // Array elements share one path so their fields merge
+            let item_path = format!("{}[]", path);
+            for item in items {
+                collect_fields(&item_path, item, seen);
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    
+    #[test]
+    fn two_samples_report_the_union_of_fields() {
+        let mut schema = InferredSchema::default();
+        schema.add_sample(&json!({ "id": 1, "name": "a", "tags": [{ "label": "x" }] }));
+        schema.add_sample(&json!({ "id": "2", "email": null }));
+        
+        assert_eq!(schema.samples, 2);
+        let paths: Vec<&str> = schema.fields.keys().map(String::as_str).collect();
+        assert_eq!(paths, ["$", "$.email", "$.id", "$.name", "$.tags", "$.tags[]", "$.tags[].label"]);
+        assert_eq!(schema.fields["$.id"].count, 2);
+        assert_eq!(schema.fields["$.id"].frequency, 1.0);
+        assert_eq!(schema.fields["$.id"].types, BTreeSet::from(["number", "string"]));
+        assert_eq!(schema.fields["$.name"].frequency, 0.5);
+        assert_eq!(schema.fields["$.email"].types, BTreeSet::from(["null"]));
+        assert_eq!(schema.fields["$.tags[].label"].frequency, 0.5);
+    }
+}
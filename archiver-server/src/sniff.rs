@@ -0,0 +1,128 @@
+//! Picks a response body's content type when it's archived: from the
+//! `Content-Type` header, the body's leading bytes, or the URL's extension,
+//! in the order `StorageConfig::content_type_sniffing` lists them.
+
+/// One way of finding a body's content type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SniffStep {
+    /// The response's `Content-Type` header, as sent.
+    Header,
+    /// Signatures at the start of the body: image and font formats, PDF,
+    /// archives, and markup or JSON text.
+    Magic,
+    /// The extension of the URL's last path segment.
+    Extension,
+}
+
+impl SniffStep {
+    pub const DEFAULT_ORDER: [SniffStep; 3] = [SniffStep::Header, SniffStep::Magic, SniffStep::Extension];
+    
+    pub fn parse(name: &str) -> Option<SniffStep> {
+        match name {
+            "header" => Some(SniffStep::Header),
+            "magic" => Some(SniffStep::Magic),
+            "extension" => Some(SniffStep::Extension),
+            _ => None,
+        }
+    }
+}
+
+/// The first type `steps` find for a body, or `None` if none does.
+pub fn content_type(steps: &[SniffStep], header: Option<&str>, body: &[u8], url: &str) -> Option<String> {
+    steps.iter().find_map(|step| match step {
+        SniffStep::Header => header.map(str::trim).filter(|value| !value.is_empty()).map(str::to_string),
+        SniffStep::Magic => magic(body).map(str::to_string),
+        SniffStep::Extension => extension(url).map(str::to_string),
+    })
+}
+
+const SIGNATURES: &[(&[u8], &str)] = &[
+    (b"\x89PNG\r\n\x1a\n", "image/png"),
+    (b"\xff\xd8\xff", "image/jpeg"),
+    (b"GIF87a", "image/gif"),
+    (b"GIF89a", "image/gif"),
+    (b"\x00\x00\x01\x00", "image/x-icon"),
+    (b"%PDF-", "application/pdf"),
+    (b"wOFF", "font/woff"),
+    (b"wOF2", "font/woff2"),
+    (b"\x1f\x8b", "application/gzip"),
+    (b"PK\x03\x04", "application/zip"),
+    (b"\x00asm", "application/wasm"),
+    (b"ID3", "audio/mpeg"),
+    (b"\x1a\x45\xdf\xa3", "video/webm"),
+];
+
+/// Lowercased prefixes of markup, after any BOM and leading whitespace.
+const MARKUP: &[(&str, &str)] = &[
+    ("<!doctype html", "text/html"),
+    ("<html", "text/html"),
+    ("<head", "text/html"),
+    ("<body", "text/html"),
+    ("<script", "text/html"),
+    ("<iframe", "text/html"),
+    ("<svg", "image/svg+xml"),
+    ("<?xml", "application/xml"),
+];
+
+fn magic(body: &[u8]) -> Option<&'static str> {
+    if let Some((_, content_type)) = SIGNATURES.iter().find(|(signature, _)| body.starts_with(signature)) {
+        return Some(content_type);
+    }
+    // RIFF and ISO media containers name their format after a size field
+    match (body.get(..4), body.get(4..8), body.get(8..12)) {
+        (Some(b"RIFF"), _, Some(b"WEBP")) => return Some("image/webp"),
+        (_, Some(b"ftyp"), Some(b"avif")) => return Some("image/avif"),
+        (_, Some(b"ftyp"), Some(_)) => return Some("video/mp4"),
+        _ => {}
+    }
+    
+    let text = body.strip_prefix(b"\xef\xbb\xbf").unwrap_or(body);
+    let start = text.iter().position(|b| !b.is_ascii_whitespace())?;
+    let text = &text[start..];
+    let head = text.get(..16).unwrap_or(text).to_ascii_lowercase();
+    if let Some((_, content_type)) = MARKUP.iter().find(|(prefix, _)| head.starts_with(prefix.as_bytes())) {
+        return Some(content_type);
+    }
+    if matches!(text[0], b'{' | b'[') && serde_json::from_slice::<serde::de::IgnoredAny>(text).is_ok() {
+        return Some("application/json");
+    }
+    None
+}
+
+const EXTENSIONS: &[(&str, &str)] = &[
+    ("html", "text/html"),
+    ("htm", "text/html"),
+    ("css", "text/css"),
+    ("js", "text/javascript"),
+    ("mjs", "text/javascript"),
+    ("json", "application/json"),
+    ("xml", "application/xml"),
+    ("txt", "text/plain"),
+    ("svg", "image/svg+xml"),
+    ("png", "image/png"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("gif", "image/gif"),
+    ("webp", "image/webp"),
+    ("avif", "image/avif"),
+    ("ico", "image/x-icon"),
+    ("woff", "font/woff"),
+    ("woff2", "font/woff2"),
+    ("ttf", "font/ttf"),
+    ("otf", "font/otf"),
+    ("pdf", "application/pdf"),
+    ("wasm", "application/wasm"),
+    ("mp4", "video/mp4"),
+    ("webm", "video/webm"),
+    ("mp3", "audio/mpeg"),
+];
+
+fn extension(url: &str) -> Option<&'static str> {
+    let url = ::url::Url::parse(url).ok()?;
+    let segment = url.path_segments()?.next_back()?;
+    let (_, extension) = segment.rsplit_once('.')?;
+    let extension = extension.to_ascii_lowercase();
+    EXTENSIONS.iter()
+        .find(|(known, _)| *known == extension)
+        .map(|(_, content_type)| *content_type)
+}
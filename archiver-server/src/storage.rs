@@ -1,292 +1,4933 @@
-use bloomfilter::Bloom;
+use crate::bloom::ShardedBloom;
+use crate::classify::EntropyClassifier;
+use crate::content_store::{ContentStore, LocalStore};
+use crate::filter::IngestFilter;
+use crate::sniff::SniffStep;
 use dashmap::DashMap;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use sled::transaction::{ConflictableTransactionError, TransactionError, Transactional};
 use sha2::{Digest, Sha256};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, BinaryHeap, HashMap, HashSet};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use tokio::fs;
-use tokio::io::AsyncWriteExt;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
 use zstd::stream::{decode_all, encode_all};
 
 const BLOOM_ITEMS: usize = 1_000_000;
 const BLOOM_FP_RATE: f64 = 0.01;
+/// Fraction of its capacity at which the bloom filter is rebuilt larger.
+const BLOOM_GROW_AT: f64 = 0.9;
 const CACHE_SIZE: usize = 1000;
+const MAX_CACHEABLE_BYTES: usize = 1_000_000;
 const COMPRESSION_LEVEL: i32 = 3;
+const METADATA_ZSTD_MARKER: u8 = 0x01;
+/// First byte of a MessagePack page fetch file. It's the one byte
+/// MessagePack never uses, and JSON files start with `{`.
+const PAGE_FETCH_MSGPACK_MARKER: u8 = 0xc1;
+const BLOOM_SHARDS: usize = 16;
+/// Version of the on-disk layout, kept in `metadata/schema_version`. Bump it
+/// together with a new step in `Storage::migrate`.
+pub const SCHEMA_VERSION: u32 = 2;
+const DEFAULT_FANOUT_DEPTH: usize = 2;
+const MAX_FANOUT_DEPTH: usize = 3;
+/// `meta` key listing the fanout depths content has been written at.
+const FANOUT_DEPTHS_KEY: &[u8] = b"fanout_depths";
+/// `meta` key written and removed by `Storage::probe`.
+const PROBE_KEY: &[u8] = b"probe";
+/// Alternative names tried for a page fetch whose filename is taken.
+const MAX_PAGE_FETCH_PROBES: usize = 16;
+/// Locks serializing object and chunk writes against their deletion, picked by hash.
+const LOCK_STRIPES: usize = 64;
+const REBALANCE_BATCH: usize = 500;
+const REBALANCE_PAUSE: std::time::Duration = std::time::Duration::from_millis(50);
+const DEFAULT_REDACTION_MARKER: &str = "[REDACTED]";
+const DEFAULT_MAX_REQUEST_BYTES: usize = 2 * 1024 * 1024;
+const DEFAULT_MAX_CLOCK_SKEW_SECS: u64 = 7 * 24 * 60 * 60;
+const DEFAULT_IDEMPOTENCY_TTL_SECS: u64 = 24 * 60 * 60;
+const DEFAULT_MAX_CONCURRENT_WRITES: usize = 32;
+
+/// What password hashes found in archived text are replaced with.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RedactionMarker {
+    /// The same token for every secret.
+    Fixed(String),
+    /// A run of `*` as long as the secret, so the surrounding text keeps its
+    /// layout.
+    MatchLength,
+}
+
+impl RedactionMarker {
+    pub fn replacement(&self, secret: &str) -> String {
+        match self {
+            RedactionMarker::Fixed(marker) => marker.clone(),
+            RedactionMarker::MatchLength => "*".repeat(secret.chars().count()),
+        }
+    }
+}
+
+/// Which clock dates a new page fetch, and so the `sessions/{date}`
+/// directory its file goes in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimestampSource {
+    /// The first request's timestamp as the client sent it, kept within
+    /// `max_clock_skew_secs` of the server's clock.
+    Client,
+    /// The server's clock when the page is first archived.
+    Server,
+}
+
+/// Digest content is addressed by, named by the hash's prefix. Objects are
+/// found by whichever produced their hash, so changing it only affects
+/// what's stored next; the same body stored under both is kept twice.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum HashAlgorithm {
+    #[default]
+    Sha256,
+    /// Several times faster than SHA-256 on large bodies.
+    Blake3,
+}
+
+impl HashAlgorithm {
+    pub const ALL: [HashAlgorithm; 2] = [HashAlgorithm::Sha256, HashAlgorithm::Blake3];
+    
+    pub fn prefix(self) -> &'static str {
+        match self {
+            HashAlgorithm::Sha256 => "sha256:",
+            HashAlgorithm::Blake3 => "blake3:",
+        }
+    }
+    
+    /// `data`'s hash, prefixed.
+    pub fn hash(self, data: &[u8]) -> String {
+        match self {
+            HashAlgorithm::Sha256 => format!("sha256:{}", hex::encode(Sha256::digest(data))),
+            HashAlgorithm::Blake3 => format!("blake3:{}", blake3::hash(data).to_hex()),
+        }
+    }
+    
+    /// The algorithm named by `hash`'s prefix.
+    pub fn of(hash: &str) -> Option<HashAlgorithm> {
+        HashAlgorithm::ALL.into_iter().find(|algorithm| hash.starts_with(algorithm.prefix()))
+    }
+}
+
+/// How `store_page_fetch` writes page fetch files. Either is read back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageFetchFormat {
+    /// Pretty-printed JSON, for reading by eye.
+    Json,
+    /// MessagePack behind `PAGE_FETCH_MSGPACK_MARKER`: smaller, and quicker
+    /// to parse for big pages.
+    MessagePack,
+}
+
+#[derive(Debug, Clone)]
+pub struct StorageConfig {
+    /// Zstd level for content files.
+    pub compression_level: i32,
+    /// Number of two-hex-character directory levels under `content/`.
+    pub fanout_depth: usize,
+    /// Default age after which sessions are deleted; `None` keeps them forever.
+    /// Sessions with their own TTL ignore this.
+    pub retention_secs: Option<u64>,
+    /// Zstd-compress sled values; values written either way remain readable.
+    pub compress_metadata: bool,
+    /// Record which sessions reference each content object, so deleting a
+    /// session frees a blob only once no session references it.
+    pub track_content_sessions: bool,
+    /// Collapse identical request/response pairs within a session into
+    /// one entry, on the page that first stored it, carrying an occurrence
+    /// count.
+    pub collapse_repeated_requests: bool,
+    /// Store bodies whose client-supplied SHA-256 doesn't match, marking them,
+    /// instead of rejecting them.
+    pub flag_body_hash_mismatches: bool,
+    /// Also file each session's requests into index files spanning this many
+    /// seconds, so time-range queries read only the overlapping files.
+    pub session_bucket_secs: Option<u64>,
+    /// Strings in rrweb events at least this long are stored as content and
+    /// replaced with a reference; 0 keeps events as sent.
+    pub rrweb_asset_threshold: usize,
+    /// rrweb build (`rrweb.min.js` and its stylesheet) inlined into
+    /// `export.html`; without the script it's loaded from a CDN.
+    pub rrweb_player_js: Option<PathBuf>,
+    pub rrweb_player_css: Option<PathBuf>,
+    /// Save the bloom filter after this many inserts since the last save.
+    pub bloom_save_every_inserts: u64,
+    /// Background interval for saving a bloom filter with unsaved inserts.
+    pub bloom_save_interval_secs: u64,
+    /// Background interval for flushing the metadata database, which is
+    /// when sled reclaims space from rewritten segments; `None` leaves it to
+    /// sled's own flushes and `POST /db/flush`.
+    pub db_flush_interval_secs: Option<u64>,
+    /// Background interval for measuring disk usage for `/stats` and
+    /// `/metrics`, which walks every stored file; `None` never measures it.
+    pub disk_scan_interval_secs: Option<u64>,
+    /// Background interval, in milliseconds, for flushing just so metadata
+    /// writes reach disk promptly; `None` leaves that to sled's own
+    /// flusher. Use `?durable=true` on ingest routes for a guarantee.
+    pub db_sync_interval_ms: Option<u64>,
+    /// Items the bloom filter is first sized for; it's rebuilt at double the
+    /// size whenever content approaches its capacity.
+    pub bloom_capacity: usize,
+    /// False-positive rate the bloom filter is sized for.
+    pub bloom_fp_rate: f64,
+    /// Maximum number of bodies held in the in-memory content cache.
+    pub cache_entries: usize,
+    /// Bodies of this many bytes or more are never cached.
+    pub max_cacheable_bytes: usize,
+    /// Fill the content cache with the most referenced objects on startup,
+    /// rather than waiting for reads to.
+    pub warm_cache: bool,
+    /// Secret for signing a provenance record into each page fetch; `None`
+    /// records no provenance.
+    pub provenance_key: Option<String>,
+    /// Query parameters dropped when normalizing URLs; a trailing `*`
+    /// matches by prefix.
+    pub strip_query_params: Vec<String>,
+    pub redaction_marker: RedactionMarker,
+    /// Most rrweb sessions held in memory; the least recently updated is
+    /// dropped beyond this (its batches are already on disk). 0 is unlimited.
+    pub max_rrweb_sessions: usize,
+    /// Content files at least this many compressed bytes are decompressed
+    /// from a memory map instead of being read into a buffer; `None` always
+    /// reads.
+    pub mmap_min_bytes: Option<u64>,
+    /// `POST /compact` moves content files smaller than this many compressed
+    /// bytes into the pack file; `None` only removes empty directories.
+    pub pack_below_bytes: Option<u64>,
+    /// Run response bodies through the body classifier and redact what it
+    /// finds before storing them.
+    pub classify_response_bodies: bool,
+    /// Redact long high-entropy tokens from response bodies before storing
+    /// them, to catch secrets the client didn't hash; `None` doesn't look.
+    pub entropy_redaction: Option<EntropyClassifier>,
+    /// Average chunk size for content-defined chunking. Bodies larger than
+    /// this are split with FastCDC and each distinct chunk is stored once, so
+    /// near-identical bodies share most of their bytes; `None` stores every
+    /// body as a single object.
+    pub chunk_avg_bytes: Option<u32>,
+    /// Largest request body the ingest routes (`/archive`, `/passwords`,
+    /// `/recording`) accept; bigger requests get 413. Default 2 MiB.
+    pub max_request_bytes: usize,
+    /// Largest single body `store_content` accepts, after decoding; `None`,
+    /// the default, leaves bodies bounded only by `max_request_bytes`.
+    pub max_content_bytes: Option<usize>,
+    /// Most content and chunk objects compressed and written at once; more
+    /// wait their turn. `None` is unlimited. Default 32.
+    pub max_concurrent_writes: Option<usize>,
+    /// Most logical bytes (see `SessionUsage`) one session may store; `None`
+    /// is unlimited. Batches that would go over are rejected with 413.
+    pub max_session_bytes: Option<u64>,
+    /// Most exchanges one session may store; `None` is unlimited.
+    pub max_session_requests: Option<u64>,
+    /// Content-type and host globs deciding which exchanges `/archive`
+    /// stores; the default stores everything.
+    pub ingest_filter: IngestFilter,
+    /// How a response body's type is found, first match wins. Steps left
+    /// out are skipped.
+    pub content_type_sniffing: Vec<SniffStep>,
+    /// Type given to bodies no sniffing step identifies, and served for
+    /// content stored without one; `None` serves `application/octet-stream`.
+    pub default_content_type: Option<String>,
+    /// Fraction of URLs, from 0.0 to 1.0, whose exchanges `/archive` keeps;
+    /// `None` keeps them all. See `filter::sampled_in`.
+    pub sample_rate: Option<f64>,
+    /// Origins browsers may call the API from, as lowercase globs; `*` on
+    /// its own allows any. Defaults to extension pages.
+    pub cors_origins: Vec<String>,
+    /// Request headers browsers may send cross-origin, lowercase; `*` on its
+    /// own allows any.
+    pub cors_headers: Vec<String>,
+    pub timestamp_source: TimestampSource,
+    /// Furthest a client timestamp may be from the server's clock, either
+    /// way, before it's clamped; `None` trusts it as sent.
+    pub max_clock_skew_secs: Option<u64>,
+    pub page_fetch_format: PageFetchFormat,
+    /// Digest new content and chunks are addressed by.
+    pub hash_algorithm: HashAlgorithm,
+    /// How long an `Idempotency-Key` answers retries with the first
+    /// response; `None` ignores the header.
+    pub idempotency_ttl_secs: Option<u64>,
+    /// Log each `/archive` batch to disk before processing it and replay
+    /// the ones left unfinished on startup.
+    pub write_ahead_log: bool,
+    /// Keep replay jobs in the database while they're queued or running,
+    /// so a restart picks them up again.
+    #[cfg(feature = "replay")]
+    pub persist_replay_jobs: bool,
+    /// Keep content and chunk objects in an S3-compatible bucket instead of
+    /// under the data directory. Metadata stays in the local database.
+    #[cfg(feature = "s3")]
+    pub s3: Option<crate::s3::S3Config>,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        StorageConfig {
+            compression_level: COMPRESSION_LEVEL,
+            fanout_depth: DEFAULT_FANOUT_DEPTH,
+            retention_secs: None,
+            compress_metadata: false,
+            track_content_sessions: true,
+            collapse_repeated_requests: false,
+            flag_body_hash_mismatches: false,
+            session_bucket_secs: None,
+            rrweb_asset_threshold: 4096,
+            rrweb_player_js: None,
+            rrweb_player_css: None,
+            bloom_save_every_inserts: 10_000,
+            bloom_save_interval_secs: 60,
+            db_flush_interval_secs: Some(3600),
+            disk_scan_interval_secs: Some(300),
+            db_sync_interval_ms: None,
+            bloom_capacity: BLOOM_ITEMS,
+            bloom_fp_rate: BLOOM_FP_RATE,
+            cache_entries: CACHE_SIZE,
+            max_cacheable_bytes: MAX_CACHEABLE_BYTES,
+            warm_cache: false,
+            provenance_key: None,
+            strip_query_params: Vec::new(),
+            redaction_marker: RedactionMarker::Fixed(DEFAULT_REDACTION_MARKER.to_string()),
+            max_rrweb_sessions: 1000,
+            mmap_min_bytes: None,
+            pack_below_bytes: None,
+            classify_response_bodies: false,
+            entropy_redaction: None,
+            chunk_avg_bytes: None,
+            max_request_bytes: DEFAULT_MAX_REQUEST_BYTES,
+            max_content_bytes: None,
+            max_concurrent_writes: Some(DEFAULT_MAX_CONCURRENT_WRITES),
+            max_session_bytes: None,
+            max_session_requests: None,
+            ingest_filter: IngestFilter::default(),
+            content_type_sniffing: SniffStep::DEFAULT_ORDER.to_vec(),
+            default_content_type: None,
+            sample_rate: None,
+            cors_origins: vec!["chrome-extension://*".to_string()],
+            cors_headers: ["content-type", "x-archiver-tenant", "if-none-match", "range", "idempotency-key"]
+                .map(str::to_string)
+                .to_vec(),
+            timestamp_source: TimestampSource::Client,
+            max_clock_skew_secs: Some(DEFAULT_MAX_CLOCK_SKEW_SECS),
+            page_fetch_format: PageFetchFormat::Json,
+            hash_algorithm: HashAlgorithm::Sha256,
+            idempotency_ttl_secs: Some(DEFAULT_IDEMPOTENCY_TTL_SECS),
+            write_ahead_log: false,
+            #[cfg(feature = "replay")]
+            persist_replay_jobs: false,
+            #[cfg(feature = "s3")]
+            s3: None,
+        }
+    }
+}
+
+impl StorageConfig {
+    pub fn from_env() -> Self {
+        let mut config = StorageConfig::default();
+        if let Some(depth) = env_parse::<usize>("ARCHIVER_FANOUT_DEPTH") {
+            config.fanout_depth = depth.clamp(1, MAX_FANOUT_DEPTH);
+        }
+        if let Some(days) = env_parse::<u64>("ARCHIVER_RETENTION_DAYS") {
+            config.retention_secs = Some(days * 24 * 60 * 60);
+        }
+        if let Some(compress) = env_parse::<bool>("ARCHIVER_COMPRESS_METADATA") {
+            config.compress_metadata = compress;
+        }
+        if let Some(track) = env_parse::<bool>("ARCHIVER_TRACK_CONTENT_SESSIONS") {
+            config.track_content_sessions = track;
+        }
+        if let Some(collapse) = env_parse::<bool>("ARCHIVER_COLLAPSE_REPEATED_REQUESTS") {
+            config.collapse_repeated_requests = collapse;
+        }
+        if let Some(flag) = env_parse::<bool>("ARCHIVER_FLAG_BODY_HASH_MISMATCHES") {
+            config.flag_body_hash_mismatches = flag;
+        }
+        if let Some(secs) = env_parse::<u64>("ARCHIVER_SESSION_BUCKET_SECS") {
+            config.session_bucket_secs = (secs > 0).then_some(secs);
+        }
+        if let Some(bytes) = env_parse::<usize>("ARCHIVER_RRWEB_ASSET_THRESHOLD") {
+            config.rrweb_asset_threshold = bytes;
+        }
+        config.rrweb_player_js = std::env::var_os("ARCHIVER_RRWEB_PLAYER_JS").map(PathBuf::from);
+        config.rrweb_player_css = std::env::var_os("ARCHIVER_RRWEB_PLAYER_CSS").map(PathBuf::from);
+        if let Some(inserts) = env_parse::<u64>("ARCHIVER_BLOOM_SAVE_EVERY_INSERTS") {
+            config.bloom_save_every_inserts = inserts.max(1);
+        }
+        if let Some(secs) = env_parse::<u64>("ARCHIVER_BLOOM_SAVE_INTERVAL_SECS") {
+            config.bloom_save_interval_secs = secs.max(1);
+        }
+        if let Some(items) = env_parse::<usize>("ARCHIVER_BLOOM_CAPACITY") {
+            config.bloom_capacity = items.max(1);
+        }
+        if let Some(rate) = env_parse::<f64>("ARCHIVER_BLOOM_FP_RATE") {
+            if rate > 0.0 && rate < 1.0 {
+                config.bloom_fp_rate = rate;
+            }
+        }
+        if let Some(entries) = env_parse::<usize>("ARCHIVER_CACHE_ENTRIES") {
+            config.cache_entries = entries;
+        }
+        if let Some(bytes) = env_parse::<usize>("ARCHIVER_MAX_CACHEABLE_BYTES") {
+            config.max_cacheable_bytes = bytes;
+        }
+        if let Some(warm) = env_parse::<bool>("ARCHIVER_WARM_CACHE") {
+            config.warm_cache = warm;
+        }
+        if let Ok(key) = std::env::var("ARCHIVER_PROVENANCE_KEY") {
+            config.provenance_key = (!key.is_empty()).then_some(key);
+        }
+        if let Ok(params) = std::env::var("ARCHIVER_STRIP_QUERY_PARAMS") {
+            config.strip_query_params = params.split(',')
+                .map(|param| param.trim().to_string())
+                .filter(|param| !param.is_empty())
+                .collect();
+        }
+        if let Ok(marker) = std::env::var("ARCHIVER_REDACTION_MARKER") {
+            config.redaction_marker = RedactionMarker::Fixed(marker);
+        }
+        if env_parse::<bool>("ARCHIVER_REDACTION_PRESERVE_LENGTH") == Some(true) {
+            config.redaction_marker = RedactionMarker::MatchLength;
+        }
+        if let Some(sessions) = env_parse::<usize>("ARCHIVER_MAX_RRWEB_SESSIONS") {
+            config.max_rrweb_sessions = sessions;
+        }
+        if let Some(bytes) = env_parse::<u64>("ARCHIVER_MMAP_MIN_BYTES") {
+            config.mmap_min_bytes = Some(bytes);
+        }
+        if let Some(bytes) = env_parse::<u64>("ARCHIVER_PACK_BELOW_BYTES") {
+            config.pack_below_bytes = (bytes > 0).then_some(bytes);
+        }
+        if let Some(classify) = env_parse::<bool>("ARCHIVER_CLASSIFY_RESPONSE_BODIES") {
+            config.classify_response_bodies = classify;
+        }
+        if env_parse::<bool>("ARCHIVER_ENTROPY_REDACTION") == Some(true) {
+            let defaults = EntropyClassifier::default();
+            config.entropy_redaction = Some(EntropyClassifier {
+                min_length: env_parse("ARCHIVER_ENTROPY_MIN_LENGTH").unwrap_or(defaults.min_length),
+                min_bits_per_char: env_parse("ARCHIVER_ENTROPY_MIN_BITS").unwrap_or(defaults.min_bits_per_char),
+            });
+        }
+        if let Some(secs) = env_parse::<u64>("ARCHIVER_DB_FLUSH_INTERVAL_SECS") {
+            config.db_flush_interval_secs = (secs > 0).then_some(secs);
+        }
+        if let Some(secs) = env_parse::<u64>("ARCHIVER_DISK_SCAN_INTERVAL_SECS") {
+            config.disk_scan_interval_secs = (secs > 0).then_some(secs);
+        }
+        if let Some(ms) = env_parse::<u64>("ARCHIVER_DB_SYNC_INTERVAL_MS") {
+            config.db_sync_interval_ms = (ms > 0).then_some(ms);
+        }
+        if let Some(bytes) = env_parse::<usize>("ARCHIVER_MAX_REQUEST_BYTES") {
+            config.max_request_bytes = bytes;
+        }
+        if let Some(bytes) = env_parse::<usize>("ARCHIVER_MAX_CONTENT_BYTES") {
+            config.max_content_bytes = (bytes > 0).then_some(bytes);
+        }
+        if let Some(writes) = env_parse::<usize>("ARCHIVER_MAX_CONCURRENT_WRITES") {
+            config.max_concurrent_writes = (writes > 0).then_some(writes.min(tokio::sync::Semaphore::MAX_PERMITS));
+        }
+        if let Some(bytes) = env_parse::<u64>("ARCHIVER_MAX_SESSION_BYTES") {
+            config.max_session_bytes = (bytes > 0).then_some(bytes);
+        }
+        if let Some(count) = env_parse::<u64>("ARCHIVER_MAX_SESSION_REQUESTS") {
+            config.max_session_requests = (count > 0).then_some(count);
+        }
+        if let Some(bytes) = env_parse::<u32>("ARCHIVER_CHUNK_AVG_BYTES") {
+            config.chunk_avg_bytes = (bytes > 0)
+                .then(|| bytes.clamp(fastcdc::v2020::AVERAGE_MIN, fastcdc::v2020::AVERAGE_MAX));
+        }
+        config.ingest_filter = IngestFilter::from_env();
+        if let Some(steps) = env_list("ARCHIVER_CONTENT_TYPE_SNIFFING") {
+            config.content_type_sniffing = steps.iter()
+                .filter_map(|step| SniffStep::parse(step).or_else(|| {
+                    tracing::warn!("Unknown ARCHIVER_CONTENT_TYPE_SNIFFING step {:?}; ignoring it", step);
+                    None
+                }))
+                .collect();
+        }
+        if let Ok(content_type) = std::env::var("ARCHIVER_DEFAULT_CONTENT_TYPE") {
+            let content_type = content_type.trim();
+            config.default_content_type = (!content_type.is_empty()).then(|| content_type.to_string());
+        }
+        if let Some(rate) = env_parse::<f64>("ARCHIVER_SAMPLE_RATE") {
+            // NaN and rates of 1 or more keep everything
+            config.sample_rate = (rate < 1.0).then_some(rate.max(0.0));
+        }
+        if let Some(origins) = env_list("ARCHIVER_CORS_ORIGINS") {
+            config.cors_origins = origins;
+        }
+        if let Some(headers) = env_list("ARCHIVER_CORS_HEADERS") {
+            config.cors_headers = headers;
+        }
+        match std::env::var("ARCHIVER_TIMESTAMP_SOURCE").map(|source| source.trim().to_lowercase()).as_deref() {
+            Ok("client") => config.timestamp_source = TimestampSource::Client,
+            Ok("server") => config.timestamp_source = TimestampSource::Server,
+            Ok(other) => tracing::warn!("Unknown ARCHIVER_TIMESTAMP_SOURCE {:?}; using client", other),
+            Err(_) => {}
+        }
+        if let Some(secs) = env_parse::<u64>("ARCHIVER_MAX_CLOCK_SKEW_SECS") {
+            config.max_clock_skew_secs = (secs > 0).then_some(secs);
+        }
+        match std::env::var("ARCHIVER_PAGE_FETCH_FORMAT").map(|format| format.trim().to_lowercase()).as_deref() {
+            Ok("json") => config.page_fetch_format = PageFetchFormat::Json,
+            Ok("msgpack" | "messagepack") => config.page_fetch_format = PageFetchFormat::MessagePack,
+            Ok(other) => tracing::warn!("Unknown ARCHIVER_PAGE_FETCH_FORMAT {:?}; using json", other),
+            Err(_) => {}
+        }
+        match std::env::var("ARCHIVER_HASH_ALGORITHM").map(|algorithm| algorithm.trim().to_lowercase()).as_deref() {
+            Ok("sha256") => config.hash_algorithm = HashAlgorithm::Sha256,
+            Ok("blake3") => config.hash_algorithm = HashAlgorithm::Blake3,
+            Ok(other) => tracing::warn!("Unknown ARCHIVER_HASH_ALGORITHM {:?}; using sha256", other),
+            Err(_) => {}
+        }
+        if let Some(secs) = env_parse::<u64>("ARCHIVER_IDEMPOTENCY_TTL_SECS") {
+            config.idempotency_ttl_secs = (secs > 0).then_some(secs);
+        }
+        if let Some(enabled) = env_parse::<bool>("ARCHIVER_WRITE_AHEAD_LOG") {
+            config.write_ahead_log = enabled;
+        }
+        #[cfg(feature = "replay")]
+        if let Some(enabled) = env_parse::<bool>("ARCHIVER_PERSIST_REPLAY_JOBS") {
+            config.persist_replay_jobs = enabled;
+        }
+        #[cfg(feature = "s3")]
+        {
+            config.s3 = crate::s3::S3Config::from_env();
+        }
+        config
+    }
+    
+    /// Timestamp, in milliseconds, for a page fetch whose first request the
+    /// client stamped `client_ms`.
+    pub fn page_timestamp(&self, client_ms: Option<i64>) -> i64 {
+        let now = chrono::Utc::now().timestamp_millis();
+        let Some(client_ms) = client_ms.filter(|_| self.timestamp_source == TimestampSource::Client) else {
+            return now;
+        };
+        let Some(skew_secs) = self.max_clock_skew_secs else {
+            return client_ms;
+        };
+        let skew_ms = i64::try_from(skew_secs.saturating_mul(1000)).unwrap_or(i64::MAX);
+        let clamped = client_ms.clamp(now.saturating_sub(skew_ms), now.saturating_add(skew_ms));
+        if clamped != client_ms {
+            tracing::warn!(
+                "Client timestamp {} is more than {}s from the server's clock; using {}",
+                client_ms, skew_secs, clamped,
+            );
+        }
+        clamped
+    }
+}
+
+/// A comma-separated, lowercased list; `None` when the variable is unset.
+fn env_list(key: &str) -> Option<Vec<String>> {
+    let value = std::env::var(key).ok()?;
+    Some(value.split(',')
+        .map(|item| item.trim().to_lowercase())
+        .filter(|item| !item.is_empty())
+        .collect())
+}
+
+fn env_parse<T: std::str::FromStr>(key: &str) -> Option<T> {
+    std::env::var(key).ok().and_then(|v| v.parse().ok())
+}
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ContentMetadata {
     pub size: usize,
+    /// For a chunked object, only the bytes of the chunks it added; chunks it
+    /// shares with earlier objects are counted there.
     pub compressed_size: usize,
     pub content_type: Option<String>,
     pub first_seen: chrono::DateTime<chrono::Utc>,
     pub reference_count: u32,
+    /// Sessions referencing this object. `None` when the object was first
+    /// stored without tracking, in which case `reference_count` decides GC.
+    #[serde(default)]
+    pub sessions: Option<BTreeSet<String>>,
+    /// Manifest of a chunked object: the hashes of its chunks, in order.
+    /// Chunked objects have no content file of their own.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chunks: Option<Vec<String>>,
+}
+
+/// A chunk shared by chunked objects, keyed by the hash of its bytes.
+#[derive(Debug, Serialize, Deserialize)]
+struct ChunkMetadata {
+    size: usize,
+    compressed_size: usize,
+    /// Manifest entries pointing at this chunk, counting repeats.
+    reference_count: u32,
+}
+
+/// An object write in progress, recorded before its file is written and
+/// removed once its metadata is. One left over at startup was cut short and
+/// is undone by `reconcile_pending_writes`.
+#[derive(Debug, Serialize, Deserialize)]
+enum PendingWrite {
+    /// A content object; `chunks` lists each chunk a chunked object has
+    /// taken a reference on, recorded in the same transaction as the
+    /// reference.
+    Content {
+        hash: String,
+        #[serde(default)]
+        chunks: Vec<String>,
+    },
+    Chunk {
+        hash: String,
+    },
+}
+
+impl PendingWrite {
+    /// Content and chunks of the same bytes share a hash, so the kind is
+    /// part of the key.
+    fn key(&self) -> String {
+        match self {
+            PendingWrite::Content { hash, .. } => format!("content/{}", hash),
+            PendingWrite::Chunk { hash } => format!("chunk/{}", hash),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PageFetchIndex {
+    pub session_id: String,
+    pub page_url: String,
+    pub timestamp: i64,
+    pub navigation_id: String,
+    pub requests: Vec<ArchivedRequest>,
+    pub password_hashes: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub provenance: Option<crate::provenance::Provenance>,
+    /// Content hash of the page's latest screenshot, if the client sent one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub screenshot_hash: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub favicon_hash: Option<String>,
+    /// WebSocket messages sent and received while the page was open, in the
+    /// order they arrived.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub websocket_frames: Vec<WebSocketFrame>,
+    /// The client that sent the page's latest batch, if it said.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_info: Option<ClientInfo>,
+}
+
+/// The extension or other client that submitted a batch.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, JsonSchema)]
+pub struct ClientInfo {
+    pub name: String,
+    pub version: String,
+    /// From the request's `User-Agent` header when the client doesn't send one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_agent: Option<String>,
+}
+
+impl PageFetchIndex {
+    /// The screenshot or favicon hash.
+    pub fn image_hash(&self, kind: ImageKind) -> Option<&String> {
+        match kind {
+            ImageKind::Screenshot => self.screenshot_hash.as_ref(),
+            ImageKind::Favicon => self.favicon_hash.as_ref(),
+        }
+    }
+    
+    /// Content hashes of the page's WebSocket frame payloads, repeats included.
+    pub fn frame_hashes(&self) -> impl Iterator<Item = &String> {
+        self.websocket_frames.iter().filter_map(|frame| frame.payload_hash.as_ref())
+    }
+}
+
+/// Which way a WebSocket frame went, from the page's side.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum FrameDirection {
+    /// Received from the server.
+    Inbound,
+    /// Sent by the page.
+    Outbound,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebSocketFrame {
+    pub frame_id: String,
+    pub timestamp: i64,
+    /// The socket's URL, minus password hashes.
+    pub url: String,
+    pub direction: FrameDirection,
+    /// WebSocket opcode: 1 for text, 2 for binary.
+    pub opcode: u8,
+    /// `None` for an empty payload.
+    pub payload_hash: Option<String>,
+    pub payload_size: Option<usize>,
+}
+
+/// Images a client can attach to a page fetch or recording batch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageKind {
+    Screenshot,
+    Favicon,
+}
+
+impl std::fmt::Display for ImageKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            ImageKind::Screenshot => "screenshot",
+            ImageKind::Favicon => "favicon",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ArchivedRequest {
+    pub request_id: String,
+    pub timestamp: i64,
+    pub method: String,
+    /// The URL as the client sent it, minus password hashes.
+    pub url: String,
+    /// Canonical form used for grouping; see `crate::url::normalize`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub normalized_url: Option<String>,
+    pub request_headers: Headers,
+    pub request_body_hash: Option<String>,
+    pub request_body_size: Option<usize>,
+    /// The stored body didn't match the SHA-256 the client supplied.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub request_body_hash_mismatch: bool,
+    #[serde(default)]
+    pub resource_type: Option<String>,
+    #[serde(default)]
+    pub priority: Option<String>,
+    pub response: Option<ArchivedResponse>,
+    /// Identical exchanges this entry stands for when repeats are collapsed.
+    #[serde(default = "default_occurrences")]
+    pub occurrences: u32,
+    /// Start time of every collapsed occurrence, including the first.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub occurrence_timestamps: Vec<i64>,
+    /// When the response arrived, in milliseconds since the epoch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub response_timestamp: Option<i64>,
+    /// Milliseconds from `timestamp` to the response, as the client timed it
+    /// or else the gap between the two timestamps.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration_ms: Option<f64>,
+    /// The request went to a different registrable domain (eTLD+1) than the
+    /// page it was made from.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub third_party: bool,
+}
+
+fn default_occurrences() -> u32 {
+    1
+}
+
+impl ArchivedRequest {
+    /// Request and response body sizes together.
+    pub fn body_bytes(&self) -> usize {
+        self.request_body_size.unwrap_or(0)
+            + self.response.as_ref().and_then(|r| r.body_size).unwrap_or(0)
+    }
+    
+    /// True when both describe the same exchange: same method and URL, and
+    /// the same request body, response status, and response body.
+    pub fn is_repeat_of(&self, other: &ArchivedRequest) -> bool {
+        let response_key = |r: &ArchivedRequest| {
+            r.response.as_ref().map(|resp| (resp.status_code, resp.body_hash.clone()))
+        };
+        self.method == other.method
+            && self.url == other.url
+            && self.request_body_hash == other.request_body_hash
+            && response_key(self) == response_key(other)
+    }
+    
+    /// Counts one more occurrence of the exchange, started at `timestamp`.
+    pub fn add_occurrence(&mut self, timestamp: i64) {
+        if self.occurrence_timestamps.is_empty() {
+            self.occurrence_timestamps.push(self.timestamp);
+        }
+        self.occurrences += 1;
+        self.occurrence_timestamps.push(timestamp);
+    }
+    
+    /// True if `not_modified`, a 304 exchange, revalidated the body this
+    /// exchange stored: same URL, and an ETag or Last-Modified validator
+    /// from the 304's request or response matches this response's.
+    pub fn is_revalidated_by(&self, not_modified: &ArchivedRequest) -> bool {
+        let (Some(response), Some(revalidation)) = (&self.response, &not_modified.response) else {
+            return false;
+        };
+        if response.body_hash.is_none()
+            || !(200..300).contains(&response.status_code)
+            || self.canonical_url() != not_modified.canonical_url()
+        {
+            return false;
+        }
+        
+        if let Some(etag) = response.header("etag") {
+            let mut validators: Vec<&str> = not_modified.request_headers.get("if-none-match")
+                .map(|tags| tags.split(',').map(str::trim).collect())
+                .unwrap_or_default();
+            validators.extend(revalidation.header("etag"));
+            // Weak comparison, as for If-None-Match
+            let weak = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+            if validators.iter().any(|tag| weak(tag) == weak(etag)) {
+                return true;
+            }
+        }
+        if let Some(last_modified) = response.header("last-modified") {
+            let validators = [
+                not_modified.request_headers.get("if-modified-since"),
+                revalidation.header("last-modified"),
+            ];
+            if validators.iter().flatten().any(|date| date.trim() == last_modified.trim()) {
+                return true;
+            }
+        }
+        false
+    }
+    
+    fn canonical_url(&self) -> &str {
+        self.normalized_url.as_deref().unwrap_or(&self.url)
+    }
+    
+    /// Lowercased host the request went to, if its URL has one.
+    pub fn host(&self) -> Option<String> {
+        crate::url::normalize(self.canonical_url(), &[]).ok()?
+            .host_str()
+            .map(str::to_string)
+    }
+}
+
+/// Headers in the order and casing they were sent, looked up by name
+/// case-insensitively. Stored as a list of `[name, value]` pairs.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Headers(Vec<(String, String)>);
+
+impl Headers {
+    /// Value of the first header called `name`, in any case. Repeats are
+    /// merged on ingest, so this is the whole value, except for
+    /// `Set-Cookie`, where it's the first cookie.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0.iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+    
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+    
+    /// Name and value pairs, as sent.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0.iter().map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+}
+
+/// Headers whose values can contain commas, so repeats can't be joined.
+const UNMERGEABLE_HEADERS: &[&str] = &["set-cookie"];
+
+/// Trims header names and folds repeated headers into their first
+/// occurrence, comma-separated as RFC 9110 allows, keeping first-seen order
+/// and casing. `Set-Cookie` keeps one entry per cookie.
+pub fn merge_headers(headers: impl IntoIterator<Item = (String, String)>) -> Headers {
+    let mut merged: Vec<(String, String)> = Vec::new();
+    for (name, value) in headers {
+        let name = name.trim().to_string();
+        if name.is_empty() {
+            continue;
+        }
+        let mergeable = !UNMERGEABLE_HEADERS.iter().any(|unmergeable| name.eq_ignore_ascii_case(unmergeable));
+        match merged.iter_mut().find(|(existing, _)| mergeable && existing.eq_ignore_ascii_case(&name)) {
+            Some((_, existing)) => {
+                existing.push_str(", ");
+                existing.push_str(value.trim());
+            }
+            None => merged.push((name, value.trim().to_string())),
+        }
+    }
+    Headers(merged)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ArchivedResponse {
+    pub status_code: u16,
+    /// Reason phrase as the client received it; `None` when it wasn't sent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status_text: Option<String>,
+    /// Protocol version, e.g. `HTTP/1.1` or `HTTP/2`; `None` when it wasn't sent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub http_version: Option<String>,
+    pub headers: Headers,
+    pub body_hash: Option<String>,
+    pub body_size: Option<usize>,
+    pub body_type: Option<String>,
+    /// The stored body didn't match the SHA-256 the client supplied.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub body_hash_mismatch: bool,
+    /// For a 304, the request ID of the earlier exchange whose body it
+    /// revalidated.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub revalidates: Option<String>,
+    /// For a 304, the body of the exchange named by `revalidates`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cached_body_hash: Option<String>,
+    /// Number of spans the body classifier redacted, by category.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub redacted_categories: BTreeMap<String, usize>,
+}
+
+impl ArchivedResponse {
+    /// Value of the header `name`; see [`Headers::get`].
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name)
+    }
+    
+    /// The body a client would have rendered: the stored body, or for a
+    /// 304 the cached body it revalidated.
+    pub fn served_body_hash(&self) -> Option<&String> {
+        self.body_hash.as_ref().or(self.cached_body_hash.as_ref())
+    }
+    
+    /// The captured reason phrase, or the standard one for the status code.
+    pub fn reason(&self) -> &str {
+        self.status_text.as_deref().unwrap_or_else(|| {
+            axum::http::StatusCode::from_u16(self.status_code).ok()
+                .and_then(|status| status.canonical_reason())
+                .unwrap_or("")
+        })
+    }
+    
+    /// The captured protocol version, assuming HTTP/1.1 when unknown.
+    pub fn version(&self) -> &str {
+        self.http_version.as_deref().unwrap_or("HTTP/1.1")
+    }
+}
+
+/// Value stored under the session ID in the `sessions` sled tree.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct SessionIndex {
+    pub paths: Vec<String>,
+    #[serde(default)]
+    pub updated_at: Option<chrono::DateTime<chrono::Utc>>,
+    /// Overrides `StorageConfig::retention_secs` for this session.
+    #[serde(default)]
+    pub ttl_secs: Option<u64>,
+    /// Manifest of time-bucket files, keyed by bucket start in milliseconds.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub buckets: BTreeMap<i64, BucketEntry>,
+    /// What each page fetch file holds, keyed by path, for session quotas.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub usage: BTreeMap<String, SessionUsage>,
+    /// Every client name and version that has written one of the session's
+    /// pages, without user agents, so sessions can be listed by client.
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub clients: BTreeSet<ClientInfo>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub note: Option<SessionNote>,
+}
+
+/// Free text an investigator left on a session.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct SessionNote {
+    pub text: String,
+    pub updated_at: chrono::DateTime<chrono::Utc>,
+}
+
+impl SessionIndex {
+    /// Usage summed over the session's page fetches.
+    fn total_usage(&self) -> SessionUsage {
+        self.usage.values().fold(SessionUsage::default(), |total, page| total.plus(*page))
+    }
+}
+
+/// Exchanges stored and their bodies' logical size. A body counts at full
+/// size for every exchange carrying it, however it was deduplicated, so
+/// quotas don't depend on what other sessions stored.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct SessionUsage {
+    pub requests: u64,
+    pub bytes: u64,
+}
+
+impl SessionUsage {
+    /// Counts every occurrence of a collapsed exchange.
+    pub fn of(requests: &[ArchivedRequest]) -> Self {
+        requests.iter().fold(SessionUsage::default(), |total, request| total.plus(SessionUsage {
+            requests: u64::from(request.occurrences),
+            bytes: request.body_bytes() as u64 * u64::from(request.occurrences),
+        }))
+    }
+    
+    /// The page's exchanges, plus its WebSocket payload bytes.
+    pub fn of_page(page_fetch: &PageFetchIndex) -> Self {
+        let frame_bytes: usize = page_fetch.websocket_frames.iter()
+            .filter_map(|frame| frame.payload_size)
+            .sum();
+        SessionUsage::of(&page_fetch.requests).plus(SessionUsage { requests: 0, bytes: frame_bytes as u64 })
+    }
+    
+    pub fn plus(self, other: SessionUsage) -> Self {
+        SessionUsage {
+            requests: self.requests + other.requests,
+            bytes: self.bytes + other.bytes,
+        }
+    }
+}
+
+/// One time-bucket file listed in a session's manifest.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BucketEntry {
+    pub path: String,
+    /// Exclusive end of the bucket in milliseconds.
+    pub end: i64,
+    pub request_count: usize,
+    /// Navigations with requests in this bucket.
+    pub navigations: BTreeSet<String>,
+}
+
+/// Requests from one session whose timestamps fall in `[start, end)`,
+/// grouped by navigation.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct TimeBucket {
+    start: i64,
+    end: i64,
+    requests: BTreeMap<String, Vec<ArchivedRequest>>,
+}
+
+impl SessionIndex {
+    fn from_slice(data: &[u8]) -> Result<Self, StorageError> {
+        let json = metadata_json(data)?;
+        // Older stores kept a bare list of page fetch paths
+        if let Ok(paths) = serde_json::from_slice::<Vec<String>>(&json) {
+            return Ok(SessionIndex { paths, ..Default::default() });
+        }
+        Ok(serde_json::from_slice(&json)?)
+    }
+}
+
+/// A replay of a recording, waiting for the browser or using it. Kept in the
+/// `replay_jobs` sled tree with `persist_replay_jobs` on.
+#[cfg(feature = "replay")]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReplayJob {
+    pub id: String,
+    /// The recording to replay.
+    pub session_id: String,
+    pub state: ReplayJobState,
+    /// When the job was first queued, in milliseconds.
+    pub queued_at: i64,
+}
+
+#[cfg(feature = "replay")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReplayJobState {
+    Queued,
+    Running,
+}
+
+/// One rrweb batch as received, with large assets already replaced by
+/// content references.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecordingBatch {
+    pub session_id: String,
+    pub url: String,
+    pub timestamp: i64,
+    pub events: Vec<serde_json::Value>,
+    pub password_hashes: Vec<String>,
+    /// The client's sequence number for the batch, counting from 0.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub batch_seq: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub screenshot_hash: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub favicon_hash: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_info: Option<ClientInfo>,
+}
+
+impl RecordingBatch {
+    pub fn image_hash(&self, kind: ImageKind) -> Option<&String> {
+        match kind {
+            ImageKind::Screenshot => self.screenshot_hash.as_ref(),
+            ImageKind::Favicon => self.favicon_hash.as_ref(),
+        }
+    }
+    
+    /// Earliest and latest event timestamps, falling back to the batch's.
+    pub fn time_span(&self) -> (i64, i64) {
+        let timestamps = self.events.iter()
+            .filter_map(|event| event.get("timestamp").and_then(|t| t.as_i64()));
+        let (first, last) = timestamps.fold((i64::MAX, i64::MIN), |(first, last), t| (first.min(t), last.max(t)));
+        if first > last { (self.timestamp, self.timestamp) } else { (first, last) }
+    }
+}
+
+/// Requests and body bytes captured for one host.
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+pub struct HostStats {
+    pub request_count: u64,
+    /// Request plus response body bytes, before compression or dedup.
+    pub bytes: u64,
+}
+
+/// Per-host totals for a set of exchanges, applied to the hosts index in
+/// one pass.
+#[derive(Debug, Default)]
+pub struct HostTally(BTreeMap<String, HostStats>);
+
+impl HostTally {
+    /// Counts a collapsed exchange once, as it's stored: repeats folded
+    /// into it later store nothing.
+    pub fn add(&mut self, request: &ArchivedRequest) {
+        let Some(host) = request.host() else {
+            return;
+        };
+        let stats = self.0.entry(host).or_default();
+        stats.request_count += 1;
+        stats.bytes += request.body_bytes() as u64;
+    }
+    
+    /// Takes back bytes counted for one of `request`'s bodies that wasn't
+    /// stored after all.
+    pub fn remove_bytes(&mut self, request: &ArchivedRequest, bytes: usize) {
+        if let Some(stats) = request.host().and_then(|host| self.0.get_mut(&host)) {
+            stats.bytes = stats.bytes.saturating_sub(bytes as u64);
+        }
+    }
+}
+
+/// What deleting a session removed.
+#[derive(Debug, Default, Serialize)]
+pub struct DeletionReport {
+    pub files_removed: usize,
+    /// Size of removed index and recording files plus freed content objects.
+    pub bytes_reclaimed: u64,
+}
+
+impl DeletionReport {
+    fn add_removed(&mut self, bytes: u64) {
+        self.files_removed += 1;
+        self.bytes_reclaimed += bytes;
+    }
+}
+
+/// Summary of a stored recording batch file.
+#[derive(Debug, Serialize)]
+pub struct RecordingBatchInfo {
+    pub path: String,
+    pub first_timestamp: i64,
+    pub last_timestamp: i64,
+    pub event_count: usize,
+}
+
+/// Why a storage operation failed, so handlers can answer with a status
+/// that fits instead of a blanket 500.
+#[derive(Debug)]
+pub enum StorageError {
+    /// Nothing is stored under the hash, session, or ID asked for.
+    NotFound(String),
+    /// A name that can't refer to anything stored, like a malformed hash.
+    Invalid(String),
+    /// Stored data that doesn't decode, or metadata pointing at data that's gone.
+    Corrupt(String),
+    /// A write that failed for lack of disk space or quota.
+    DiskFull(std::io::Error),
+    Io(std::io::Error),
+    Serde(serde_json::Error),
+    Sled(sled::Error),
+    /// A body larger than `StorageConfig::max_content_bytes`.
+    TooLarge { size: usize, limit: usize },
+    /// A maintenance operation that can't start while another is running.
+    Busy(&'static str),
+    /// Anything else, such as a remote store's failure.
+    Other(Box<dyn std::error::Error + Send + Sync>),
+}
+
+impl std::fmt::Display for StorageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StorageError::NotFound(what) => write!(f, "{} not found", what),
+            StorageError::Invalid(message) | StorageError::Corrupt(message) => f.write_str(message),
+            StorageError::DiskFull(e) => write!(f, "Out of disk space: {}", e),
+            StorageError::Io(e) => e.fmt(f),
+            StorageError::Serde(e) => e.fmt(f),
+            StorageError::Sled(e) => e.fmt(f),
+            StorageError::TooLarge { size, limit } => write!(f, "Body of {} bytes exceeds the {} byte limit", size, limit),
+            StorageError::Busy(message) => f.write_str(message),
+            StorageError::Other(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for StorageError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            StorageError::DiskFull(e) | StorageError::Io(e) => Some(e),
+            StorageError::Serde(e) => Some(e),
+            StorageError::Sled(e) => Some(e),
+            StorageError::Other(e) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for StorageError {
+    fn from(e: std::io::Error) -> Self {
+        if is_disk_full(&e) {
+            StorageError::DiskFull(e)
+        } else {
+            StorageError::Io(e)
+        }
+    }
+}
+
+impl From<sled::Error> for StorageError {
+    fn from(e: sled::Error) -> Self {
+        match e {
+            sled::Error::Io(e) if is_disk_full(&e) => StorageError::DiskFull(e),
+            e => StorageError::Sled(e),
+        }
+    }
+}
+
+impl From<serde_json::Error> for StorageError {
+    fn from(e: serde_json::Error) -> Self {
+        StorageError::Serde(e)
+    }
+}
+
+impl From<reqwest::Error> for StorageError {
+    fn from(e: reqwest::Error) -> Self {
+        StorageError::Other(Box::new(e))
+    }
+}
+
+impl From<url::ParseError> for StorageError {
+    fn from(e: url::ParseError) -> Self {
+        StorageError::Other(Box::new(e))
+    }
+}
+
+impl From<&str> for StorageError {
+    fn from(message: &str) -> Self {
+        StorageError::Other(message.into())
+    }
+}
+
+impl From<String> for StorageError {
+    fn from(message: String) -> Self {
+        StorageError::Other(message.into())
+    }
+}
+
+/// A body for `store_content_batch`, hashed when it's created.
+pub struct ContentItem<'a> {
+    pub data: &'a [u8],
+    pub content_type: Option<&'a str>,
+    pub hash: String,
+}
+
+impl<'a> ContentItem<'a> {
+    pub fn new(data: &'a [u8], content_type: Option<&'a str>, algorithm: HashAlgorithm) -> Self {
+        ContentItem { data, content_type, hash: algorithm.hash(data) }
+    }
+}
+
+/// What storing one body did.
+#[derive(Debug)]
+pub enum StoreOutcome {
+    /// Written as a new object.
+    Stored(String),
+    /// Already stored, so only a reference was taken.
+    Deduplicated(String),
+    Failed(StorageError),
+}
+
+impl StoreOutcome {
+    pub fn into_result(self) -> Result<String, StorageError> {
+        match self {
+            StoreOutcome::Stored(hash) | StoreOutcome::Deduplicated(hash) => Ok(hash),
+            StoreOutcome::Failed(e) => Err(e),
+        }
+    }
+}
+
+/// Cumulative content counts since startup, for `/metrics`.
+#[derive(Debug, Default)]
+pub struct StorageCounters {
+    /// Objects newly written to disk.
+    pub objects_stored: AtomicU64,
+    /// Uncompressed bytes of newly written objects.
+    pub bytes_stored: AtomicU64,
+    /// Stores of content that was already present.
+    pub dedup_hits: AtomicU64,
+    pub cache_hits: AtomicU64,
+    pub cache_misses: AtomicU64,
+    /// Objects in `content_db` and their uncompressed and compressed sizes,
+    /// kept in step with it so stats needn't scan it.
+    pub content_objects: AtomicU64,
+    pub content_bytes: AtomicU64,
+    pub content_compressed_bytes: AtomicU64,
+    /// Object writes waiting for a `write_permits` permit.
+    pub writes_queued: AtomicU64,
+    /// Walks over every `content_db` entry.
+    pub content_scans: AtomicU64,
+}
+
+/// What the last walk of the data directory found.
+#[derive(Debug, Clone, Copy)]
+struct DiskUsage {
+    disk_bytes: u64,
+    orphan_files: usize,
+    scanned_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct DbFlushReport {
+    pub bytes_flushed: usize,
+    pub size_before: u64,
+    pub size_after: u64,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct CompactionReport {
+    pub empty_dirs_removed: usize,
+    pub objects_packed: usize,
+    pub bytes_packed: u64,
+}
+
+/// Value stored under a request ID in the `requests` sled tree: the page
+/// fetch file holding the request.
+#[derive(Debug, Serialize, Deserialize)]
+struct RequestLocation {
+    session_id: String,
+    path: String,
+}
+
+/// Value stored under an `Idempotency-Key` in the `idempotency` sled tree.
+#[derive(Debug, Serialize, Deserialize)]
+struct IdempotencyRecord {
+    /// Hash of the request the key was first used with.
+    fingerprint: String,
+    /// When the key was claimed, in milliseconds.
+    created_at: i64,
+    /// `None` while the first request is still being processed.
+    response: Option<StoredResponse>,
+}
+
+/// A response kept for answering retries.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredResponse {
+    pub status: u16,
+    pub body: serde_json::Value,
+}
+
+/// What `Storage::claim_idempotency_key` found.
+#[derive(Debug)]
+pub enum IdempotencyClaim {
+    /// The key is new (or expired); the caller processes the request and
+    /// then completes or releases the key.
+    Claimed,
+    /// The first request with the key hasn't finished.
+    InProgress,
+    /// The key was used with a different request.
+    Mismatch,
+    /// The first request's response, to answer the retry with.
+    Completed(StoredResponse),
+}
+
+/// Where a packed object's zstd frame sits in the pack file.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct PackEntry {
+    offset: u64,
+    len: u64,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct RecompressReport {
+    pub level: i32,
+    pub scanned: usize,
+    pub recompressed: usize,
+    /// Objects already no larger than at `level`, packed or chunked objects,
+    /// and files that failed to decode.
+    pub skipped: usize,
+    /// Compressed bytes of the recompressed objects, before and after.
+    pub bytes_before: u64,
+    pub bytes_after: u64,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct RebalanceReport {
+    pub scanned: usize,
+    pub moved: usize,
+}
+
+pub struct Storage {
+    base_path: PathBuf,
+    /// Namespace for everything this instance stores; `None` is the default
+    /// tenant, which keeps the unprefixed layout.
+    tenant: Option<String>,
+    /// Shared by every tenant; each opens its own trees in it.
+    db: sled::Db,
+    config: StorageConfig,
+    rebalance_lock: tokio::sync::Mutex<()>,
+    bloom_grow_lock: tokio::sync::Mutex<()>,
+    /// Content metadata keyed by hash.
+    content_db: sled::Tree,
+    /// `SessionIndex` values keyed by session ID.
+    sessions_db: sled::Tree,
+    /// `HostStats` values keyed by host.
+    hosts_db: sled::Tree,
+    /// `PackEntry` values keyed by hash, for objects moved into the pack file.
+    packed_db: sled::Tree,
+    /// `RequestLocation` values keyed by request ID.
+    requests_db: sled::Tree,
+    /// `ChunkMetadata` values keyed by chunk hash.
+    chunks_db: sled::Tree,
+    /// Namespace-wide settings that outlive configuration changes.
+    meta_db: sled::Tree,
+    /// `IdempotencyRecord` values keyed by `Idempotency-Key`.
+    idempotency_db: sled::Tree,
+    /// Empty values keyed by `size_index_key`, so content can be listed by
+    /// `compressed_size` without reading `content_db`.
+    size_index_db: sled::Tree,
+    /// `PendingWrite` values keyed by `PendingWrite::key`.
+    pending_db: sled::Tree,
+    /// One permit per object write in progress, see
+    /// `StorageConfig::max_concurrent_writes`.
+    write_permits: tokio::sync::Semaphore,
+    /// `ReplayJob` values keyed by job ID.
+    #[cfg(feature = "replay")]
+    replay_jobs_db: sled::Tree,
+    /// Held to write or free a content object. Take before a chunk lock, never after.
+    content_locks: Vec<tokio::sync::Mutex<()>>,
+    chunk_locks: Vec<tokio::sync::Mutex<()>>,
+    /// Held to read-modify-write a `SessionIndex`. Take before a content lock.
+    session_locks: Vec<tokio::sync::Mutex<()>>,
+    /// Compressed objects keyed by hex hash.
+    content_store: Box<dyn ContentStore>,
+    /// Compressed chunks keyed by hex hash.
+    chunk_store: Box<dyn ContentStore>,
+    bloom_filter: ShardedBloom,
+    /// Inserts not yet reflected in the saved bloom file.
+    bloom_unsaved_inserts: AtomicU64,
+    content_cache: Arc<DashMap<String, Vec<u8>>>,
+    counters: StorageCounters,
+    /// Set by `scan_disk_usage`; `None` until the first scan.
+    disk_usage: std::sync::Mutex<Option<DiskUsage>>,
+}
+
+impl Storage {
+    /// Opens the default tenant's storage under `base_path`, first migrating
+    /// a data directory written by an older build. Refuses one written by a
+    /// newer build.
+    pub async fn new(base_path: impl AsRef<Path>, config: StorageConfig) -> Result<Self, StorageError> {
+        let base_path = base_path.as_ref().to_path_buf();
+        fs::create_dir_all(base_path.join("metadata")).await?;
+        
+        let db_path = base_path.join("metadata").join("content_index.db");
+        let version_path = base_path.join("metadata").join("schema_version");
+        let version = read_schema_version(&version_path, fs::try_exists(&db_path).await?).await?;
+        if version > SCHEMA_VERSION {
+            return Err(StorageError::Invalid(format!(
+                "Data directory has schema version {}, newer than the {} this build supports", version, SCHEMA_VERSION)));
+        }
+        
+        // Open sled database
+        let db = sled::open(&db_path)?;
+        let storage = Self::open(db, base_path, None, config).await?;
+        if version < SCHEMA_VERSION {
+            storage.migrate(version).await?;
+        }
+        write_atomic(&version_path, SCHEMA_VERSION.to_string().as_bytes()).await?;
+        Ok(storage)
+    }
+    
+    /// Opens another tenant's namespace in the same data directory and
+    /// database. Content is deduplicated only within a tenant.
+    pub async fn open_tenant(&self, tenant: &str) -> Result<Self, StorageError> {
+        if !is_valid_tenant(tenant) {
+            return Err(StorageError::Invalid(format!("Invalid tenant: {:?}", tenant)));
+        }
+        Self::open(self.db.clone(), self.base_path.clone(), Some(tenant.to_string()), self.config.clone()).await
+    }
+    
+    /// Tenants that have stored anything, from the database's tree names.
+    pub fn tenant_names(&self) -> Vec<String> {
+        self.db.tree_names().iter()
+            .filter_map(|name| std::str::from_utf8(name).ok()?.strip_suffix("/sessions").map(str::to_string))
+            .filter(|tenant| is_valid_tenant(tenant))
+            .collect()
+    }
+    
+    async fn open(db: sled::Db, base_path: PathBuf, tenant: Option<String>, config: StorageConfig) -> Result<Self, StorageError> {
+        let tree = |name: &str| match &tenant {
+            Some(tenant) => db.open_tree(format!("{}/{}", tenant, name)),
+            None => db.open_tree(name),
+        };
+        let content_db = tree("content")?;
+        let sessions_db = tree("sessions")?;
+        let hosts_db = tree("hosts")?;
+        let packed_db = tree("packed")?;
+        let requests_db = tree("requests")?;
+        let chunks_db = tree("chunks")?;
+        let meta_db = tree("meta")?;
+        let idempotency_db = tree("idempotency")?;
+        let size_index_db = tree("content_by_size")?;
+        let pending_db = tree("pending_writes")?;
+        #[cfg(feature = "replay")]
+        let replay_jobs_db = tree("replay_jobs")?;
+        if tenant.is_none() {
+            Self::migrate_default_tree(&db, &content_db, &sessions_db)?;
+        }
+        let fanout_depths = Self::record_fanout_depth(&meta_db, &content_db, config.fanout_depth)?;
+        // Chunks use a fixed single-level fanout, independent of content's
+        let content_store = open_store(&config, &base_path, namespace("content", tenant.as_deref()), config.fanout_depth, &fanout_depths)?;
+        let chunk_store = open_store(&config, &base_path, namespace("chunks", tenant.as_deref()), 1, &[])?;
+        
+        let write_permits = tokio::sync::Semaphore::new(write_permit_count(&config));
+        
+        let mut storage = Storage {
+            base_path,
+            tenant,
+            db,
+            config,
+            rebalance_lock: tokio::sync::Mutex::new(()),
+            bloom_grow_lock: tokio::sync::Mutex::new(()),
+            content_db,
+            sessions_db,
+            hosts_db,
+            packed_db,
+            requests_db,
+            chunks_db,
+            meta_db,
+            idempotency_db,
+            size_index_db,
+            pending_db,
+            write_permits,
+            #[cfg(feature = "replay")]
+            replay_jobs_db,
+            content_locks: (0..LOCK_STRIPES).map(|_| tokio::sync::Mutex::new(())).collect(),
+            chunk_locks: (0..LOCK_STRIPES).map(|_| tokio::sync::Mutex::new(())).collect(),
+            session_locks: (0..LOCK_STRIPES).map(|_| tokio::sync::Mutex::new(())).collect(),
+            content_store,
+            chunk_store,
+            bloom_filter: ShardedBloom::new(BLOOM_SHARDS, 1, BLOOM_FP_RATE),
+            bloom_unsaved_inserts: AtomicU64::new(0),
+            content_cache: Arc::new(DashMap::new()),
+            counters: StorageCounters::default(),
+            disk_usage: std::sync::Mutex::new(None),
+        };
+        
+        // Create directory structure
+        for kind in ["sessions", "content", "chunks", "cache", "recordings", "wal"] {
+            fs::create_dir_all(storage.dir(kind)).await?;
+        }
+        
+        // Load or create bloom filter
+        let (bloom, rebuilt) = Self::load_or_rebuild_bloom(&storage.bloom_path(), &storage.content_db, &storage.config).await?;
+        storage.bloom_filter = bloom;
+        storage.bloom_unsaved_inserts = AtomicU64::new(rebuilt);
+        
+        storage.backfill_hosts().await?;
+        storage.backfill_request_index().await?;
+        storage.backfill_size_index()?;
+        storage.release_unfinished_idempotency_keys()?;
+        storage.reconcile_pending_writes().await?;
+        storage.count_content()?;
+        Ok(storage)
+    }
+    
+    /// Adds `depth` to the fanout depths recorded for this namespace and
+    /// returns them all, so files written before a depth change stay
+    /// readable. Stores from before depths were recorded may have used any.
+    fn record_fanout_depth(meta_db: &sled::Tree, content_db: &sled::Tree, depth: usize) -> Result<Vec<usize>, StorageError> {
+        let mut depths: Vec<usize> = match meta_db.get(FANOUT_DEPTHS_KEY)? {
+            Some(bytes) => serde_json::from_slice(&bytes)
+                .map_err(|e| StorageError::Corrupt(format!("Recorded fanout depths: {}", e)))?,
+            None if content_db.is_empty() => Vec::new(),
+            None => (1..=MAX_FANOUT_DEPTH).collect(),
+        };
+        if !depths.contains(&depth) {
+            depths.push(depth);
+        }
+        meta_db.insert(FANOUT_DEPTHS_KEY, serde_json::to_vec(&depths)?)?;
+        Ok(depths)
+    }
+    
+    /// Root of one kind of file (`content`, `sessions`, ...) for this
+    /// namespace: `{kind}/{tenant}`, or just `{kind}` for the default tenant.
+    fn dir(&self, kind: &str) -> PathBuf {
+        self.base_path.join(namespace(kind, self.tenant.as_deref()))
+    }
+    
+    fn pack_path(&self) -> PathBuf {
+        self.dir("content").join("pack.bin")
+    }
+    
+    fn bloom_path(&self) -> PathBuf {
+        self.dir("cache").join("bloom_filter.bin")
+    }
+    
+    /// True for another tenant's directory inside one of the default
+    /// tenant's roots, which the default tenant's walks must skip.
+    fn is_other_namespace(&self, root: &Path, dir: &Path) -> bool {
+        self.tenant.is_none()
+            && dir.parent() == Some(root)
+            && dir.file_name().and_then(|n| n.to_str()).is_some_and(is_valid_tenant)
+    }
+    
+    /// Stores from before the hosts index get it built from their sessions.
+    async fn backfill_hosts(&self) -> Result<(), StorageError> {
+        if !self.hosts_db.is_empty() || self.sessions_db.is_empty() {
+            return Ok(());
+        }
+        let mut tally = HostTally::default();
+        for key in self.sessions_db.iter().keys() {
+            let session_id = String::from_utf8_lossy(&key?).into_owned();
+            for page_fetch in self.load_session(&session_id).await?.unwrap_or_default() {
+                page_fetch.requests.iter().for_each(|request| tally.add(request));
+            }
+        }
+        tracing::info!("Built hosts index for {} hosts", tally.0.len());
+        self.record_hosts(&tally)
+    }
+    
+    /// Stores from before the request index get it built from their sessions.
+    async fn backfill_request_index(&self) -> Result<(), StorageError> {
+        if !self.requests_db.is_empty() || self.sessions_db.is_empty() {
+            return Ok(());
+        }
+        let mut indexed = 0;
+        for item in self.sessions_db.iter() {
+            let (key, value) = item?;
+            let session_id = String::from_utf8_lossy(&key).into_owned();
+            let index = SessionIndex::from_slice(&value)?;
+            for path in index.paths.iter().collect::<HashSet<_>>() {
+                let Ok(data) = fs::read(path).await else {
+                    continue;
+                };
+                let page_fetch: PageFetchIndex = decode_page_fetch(&data)?;
+                self.index_requests(&session_id, &page_fetch.requests, path)?;
+                indexed += page_fetch.requests.len();
+            }
+        }
+        tracing::info!("Built request index for {} requests", indexed);
+        Ok(())
+    }
+    
+    /// Stores from before the size index get it built from `content_db`.
+    fn backfill_size_index(&self) -> Result<(), StorageError> {
+        if !self.size_index_db.is_empty() || self.content_db.is_empty() {
+            return Ok(());
+        }
+        let mut batch = sled::Batch::default();
+        let mut indexed = 0;
+        for item in self.content_entries() {
+            let (key, value) = item?;
+            let Ok(metadata) = decode_metadata::<ContentMetadata>(&value) else {
+                continue;
+            };
+            batch.insert(size_index_key(metadata.compressed_size, &key), &[]);
+            indexed += 1;
+        }
+        self.size_index_db.apply_batch(batch)?;
+        tracing::info!("Built size index for {} objects", indexed);
+        Ok(())
+    }
+    
+    /// Moves an object's size index entry from its `compressed_size` before
+    /// a change to after it, `None` meaning it isn't stored.
+    fn reindex_size(&self, hash: &str, before: Option<usize>, after: Option<usize>) -> Result<(), StorageError> {
+        if before == after {
+            return Ok(());
+        }
+        let mut batch = sled::Batch::default();
+        if let Some(size) = before {
+            batch.remove(size_index_key(size, hash.as_bytes()));
+        }
+        if let Some(size) = after {
+            batch.insert(size_index_key(size, hash.as_bytes()), &[]);
+        }
+        self.size_index_db.apply_batch(batch)?;
+        Ok(())
+    }
+    
+    fn index_requests(&self, session_id: &str, requests: &[ArchivedRequest], path: &str) -> Result<(), StorageError> {
+        let location = self.encode_metadata(&RequestLocation {
+            session_id: session_id.to_string(),
+            path: path.to_string(),
+        })?;
+        let mut batch = sled::Batch::default();
+        for request in requests {
+            batch.insert(request.request_id.as_bytes(), location.clone());
+        }
+        self.requests_db.apply_batch(batch)?;
+        Ok(())
+    }
+    
+    fn unindex_requests(&self, requests: &[ArchivedRequest]) -> Result<(), StorageError> {
+        let mut batch = sled::Batch::default();
+        for request in requests {
+            batch.remove(request.request_id.as_bytes());
+        }
+        self.requests_db.apply_batch(batch)?;
+        Ok(())
+    }
+    
+    /// Older stores kept everything in the default tree, with sessions under
+    /// a `session:` prefix. Moves those keys into their own trees.
+    fn migrate_default_tree(db: &sled::Db, content_db: &sled::Tree, sessions_db: &sled::Tree) -> Result<(), StorageError> {
+        let mut migrated = 0;
+        for item in db.iter() {
+            let (key, value) = item?;
+            if let Some(session_id) = key.strip_prefix(b"session:") {
+                sessions_db.insert(session_id, value)?;
+            } else if key.starts_with(b"sha256:") {
+                content_db.insert(&key, value)?;
+            } else {
+                continue;
+            }
+            db.remove(&key)?;
+            migrated += 1;
+        }
+        if migrated > 0 {
+            tracing::info!("Migrated {} sled keys into content/sessions trees", migrated);
+        }
+        Ok(())
+    }
+    
+    /// Upgrades a data directory from schema version `from`, one version at
+    /// a time, covering every tenant. Runs before anything is served.
+    async fn migrate(&self, from: u32) -> Result<(), StorageError> {
+        let mut tenants = Vec::new();
+        for tenant in self.tenant_names() {
+            tenants.push(self.open_tenant(&tenant).await?);
+        }
+        for version in from..SCHEMA_VERSION {
+            tracing::info!("Migrating storage from schema version {} to {}", version, version + 1);
+            for storage in std::iter::once(self).chain(&tenants) {
+                storage.migrate_namespace(version).await?;
+            }
+        }
+        Ok(())
+    }
+    
+    /// This namespace's step up from schema version `version`.
+    async fn migrate_namespace(&self, version: u32) -> Result<(), StorageError> {
+        match version {
+            0 => {
+                let filled = self.backfill_content_types().await?;
+                tracing::info!("Filled in content types for {} objects", filled);
+            }
+            1 => {
+                let sessions = self.backfill_session_usage().await?;
+                tracing::info!("Recorded usage for {} sessions", sessions);
+            }
+            _ => unreachable!("no migration from schema version {}", version),
+        }
+        Ok(())
+    }
+    
+    /// Records what each stored page fetch holds, for session quotas.
+    /// Returns how many sessions were updated.
+    async fn backfill_session_usage(&self) -> Result<usize, StorageError> {
+        let mut updated = 0;
+        for item in self.sessions_db.iter() {
+            let (key, value) = item?;
+            let mut index = SessionIndex::from_slice(&value)?;
+            for path in &index.paths {
+                let Ok(data) = fs::read(path).await else {
+                    continue;
+                };
+                let page_fetch: PageFetchIndex = decode_page_fetch(&data)?;
+                index.usage.insert(path.clone(), SessionUsage::of_page(&page_fetch));
+            }
+            self.save_session_index(&String::from_utf8_lossy(&key), &index)?;
+            updated += 1;
+        }
+        Ok(updated)
+    }
+    
+    /// Gives objects stored without a content type the `Content-Type` of a
+    /// response that has them as its body. Returns how many were filled in.
+    async fn backfill_content_types(&self) -> Result<usize, StorageError> {
+        let mut untyped = HashSet::new();
+        for item in self.content_entries() {
+            let (key, value) = item?;
+            let metadata: ContentMetadata = decode_metadata(&value)?;
+            if metadata.content_type.is_none() {
+                untyped.insert(String::from_utf8_lossy(&key).into_owned());
+            }
+        }
+        if untyped.is_empty() {
+            return Ok(0);
+        }
+        
+        let mut types = HashMap::new();
+        for item in self.sessions_db.iter() {
+            let (_, value) = item?;
+            let index = SessionIndex::from_slice(&value)?;
+            for path in index.paths.iter().collect::<HashSet<_>>() {
+                let Ok(data) = fs::read(path).await else {
+                    continue;
+                };
+                let page_fetch: PageFetchIndex = decode_page_fetch(&data)?;
+                for response in page_fetch.requests.iter().filter_map(|request| request.response.as_ref()) {
+                    let (Some(hash), Some(content_type)) = (&response.body_hash, response.header("content-type")) else {
+                        continue;
+                    };
+                    if untyped.contains(hash) {
+                        types.entry(hash.clone()).or_insert_with(|| content_type.to_string());
+                    }
+                }
+            }
+        }
+        
+        for (hash, content_type) in &types {
+            let Some(mut metadata) = self.content_metadata(hash)? else {
+                continue;
+            };
+            metadata.content_type = Some(normalize_content_type(content_type));
+            self.content_db.insert(hash.as_bytes(), self.encode_metadata(&metadata)?)?;
+        }
+        Ok(types.len())
+    }
+    
+    /// Loads the saved bloom filter, or rebuilds it from the content index
+    /// when the file is missing, unreadable, predates sharding, or is too
+    /// small for the configured capacity or the stored content. Also returns
+    /// how many hashes were rebuilt, so a rebuilt filter gets saved.
+    async fn load_or_rebuild_bloom(bloom_path: &Path, content_db: &sled::Tree, config: &StorageConfig) -> Result<(ShardedBloom, u64), StorageError> {
+        let items = content_db.len();
+        let capacity = bloom_capacity_for(items, config.bloom_capacity);
+        match fs::read(bloom_path).await {
+            Ok(data) => {
+                if let Some(bloom) = ShardedBloom::decode(&data, config.bloom_fp_rate) {
+                    // The capacity is recovered from bitmap sizes, which are rounded
+                    if bloom.capacity() + bloom.shard_count() >= capacity {
+                        bloom.set_items(items);
+                        return Ok((bloom, 0));
+                    }
+                    tracing::info!("Rebuilding bloom filter for {} items at capacity {} (was {})",
+                        items, capacity, bloom.capacity());
+                } else if ShardedBloom::is_single_filter_file(&data) {
+                    tracing::info!("Rebuilding single-filter bloom file at {:?} as sharded", bloom_path);
+                } else {
+                    tracing::warn!("Ignoring unreadable bloom filter at {:?}", bloom_path);
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        
+        Self::build_bloom(content_db, BLOOM_SHARDS, capacity, config.bloom_fp_rate).await
+    }
+    
+    async fn build_bloom(content_db: &sled::Tree, shard_count: usize, capacity: usize, fp_rate: f64) -> Result<(ShardedBloom, u64), StorageError> {
+        let bloom = ShardedBloom::new(shard_count, capacity, fp_rate);
+        let mut rebuilt = 0;
+        for key in content_db.iter().keys() {
+            bloom.set(&String::from_utf8_lossy(&key?)).await;
+            rebuilt += 1;
+        }
+        Ok((bloom, rebuilt))
+    }
+    
+    /// Rebuilds the bloom filter from the content index at a larger capacity
+    /// once it's nearly full, so its false-positive rate holds as content
+    /// grows. Hashes stored during the rebuild may be missing from the new
+    /// filter, which only costs a redundant write, as with a stale filter.
+    async fn grow_bloom_if_full(&self) -> Result<(), StorageError> {
+        let is_full = |bloom: &ShardedBloom| bloom.items() as f64 >= bloom.capacity() as f64 * BLOOM_GROW_AT;
+        if !is_full(&self.bloom_filter) {
+            return Ok(());
+        }
+        // One rebuild at a time; other stores keep using the current filter
+        let Ok(_guard) = self.bloom_grow_lock.try_lock() else {
+            return Ok(());
+        };
+        if !is_full(&self.bloom_filter) {
+            return Ok(());
+        }
+        
+        let old_capacity = self.bloom_filter.capacity();
+        let capacity = bloom_capacity_for(self.content_entries().count(), old_capacity.saturating_mul(2));
+        tracing::info!("Bloom filter at {} of {} items; rebuilding with capacity {}",
+            self.bloom_filter.items(), old_capacity, capacity);
+        let (bloom, rebuilt) = Self::build_bloom(
+            &self.content_db,
+            self.bloom_filter.shard_count(),
+            capacity,
+            self.config.bloom_fp_rate,
+        ).await?;
+        self.bloom_filter.replace(bloom).await;
+        self.bloom_unsaved_inserts.fetch_add(rebuilt.max(1), Ordering::Relaxed);
+        Ok(())
+    }
+    
+    /// Writes the bloom filter to `cache/bloom_filter.bin` (under the tenant's
+    /// cache directory for other tenants).
+    pub async fn save_bloom(&self) -> Result<(), StorageError> {
+        // Reset under the shard locks so concurrent inserts aren't lost from the count
+        let encoded = self.bloom_filter
+            .encode(|| self.bloom_unsaved_inserts.store(0, Ordering::Relaxed))
+            .await;
+        write_atomic(&self.bloom_path(), &encoded).await
+    }
+    
+    /// Saves the bloom filter only if it has inserts since the last save.
+    pub async fn save_bloom_if_dirty(&self) -> Result<bool, StorageError> {
+        if self.bloom_unsaved_inserts.load(Ordering::Relaxed) == 0 {
+            return Ok(false);
+        }
+        self.save_bloom().await?;
+        Ok(true)
+    }
+    
+    /// Durably records a batch in the write-ahead log before it's processed,
+    /// returning its entry's ID. IDs increase across restarts.
+    pub async fn append_wal(&self, data: &[u8]) -> Result<u64, StorageError> {
+        let id = self.db.generate_id()?;
+        write_atomic(&self.wal_path(id), data).await?;
+        Ok(id)
+    }
+    
+    /// Drops a write-ahead log entry once its batch has been processed. The
+    /// database is flushed first, so the entry only goes once what the batch
+    /// stored would survive a crash.
+    pub async fn complete_wal(&self, id: u64) -> Result<(), StorageError> {
+        self.db.flush_async().await?;
+        remove_file_if_exists(&self.wal_path(id)).await?;
+        Ok(())
+    }
+    
+    /// Entries whose batches weren't finished when the process stopped, in
+    /// the order they were logged.
+    pub async fn pending_wal(&self) -> Result<Vec<(u64, Vec<u8>)>, StorageError> {
+        let mut entries = Vec::new();
+        let mut dir = fs::read_dir(self.dir("wal")).await?;
+        while let Some(entry) = dir.next_entry().await? {
+            let name = entry.file_name();
+            // Skips leftover temp files, which start with `.`
+            let Some(id) = name.to_str().and_then(|n| n.strip_suffix(".json")).and_then(|n| n.parse().ok()) else {
+                continue;
+            };
+            entries.push((id, fs::read(entry.path()).await?));
+        }
+        entries.sort_by_key(|(id, _)| *id);
+        Ok(entries)
+    }
+    
+    fn wal_path(&self, id: u64) -> PathBuf {
+        self.dir("wal").join(format!("{:020}.json", id))
+    }
+    
+    pub fn bloom_save_interval(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.config.bloom_save_interval_secs)
+    }
+    
+    /// Flushes the metadata database to disk. sled 0.34 has no explicit
+    /// compaction: its segment cleaner relocates live data out of mostly
+    /// dead segments as writes are flushed, so this is also what gives back
+    /// space from session index and refcount churn. Every tenant shares the
+    /// database, so a flush from any of them covers all.
+    pub async fn flush_db(&self) -> Result<DbFlushReport, StorageError> {
+        let size_before = self.db.size_on_disk()?;
+        let bytes_flushed = self.db.flush_async().await?;
+        let size_after = self.db.size_on_disk()?;
+        Ok(DbFlushReport { bytes_flushed, size_before, size_after })
+    }
+    
+    /// Writes and deletes a file in the data directory and a key in the
+    /// database, failing if either doesn't take the write (a read-only
+    /// mount, a full disk).
+    pub async fn probe(&self) -> Result<(), StorageError> {
+        let path = self.base_path.join("metadata").join(format!(".probe-{}", uuid::Uuid::new_v4()));
+        write_atomic(&path, b"ok").await?;
+        fs::remove_file(&path).await?;
+        self.meta_db.insert(PROBE_KEY, b"ok".as_slice())?;
+        self.meta_db.remove(PROBE_KEY)?;
+        Ok(())
+    }
+    
+    /// Flushes the metadata database without measuring it, so every write
+    /// made so far survives a crash. Files are already synced as written.
+    pub async fn sync_db(&self) -> Result<(), StorageError> {
+        self.db.flush_async().await?;
+        Ok(())
+    }
+    
+    /// `None` for the default tenant.
+    pub fn tenant(&self) -> Option<&str> {
+        self.tenant.as_deref()
+    }
+    
+    pub fn config(&self) -> &StorageConfig {
+        &self.config
+    }
+    
+    pub fn counters(&self) -> &StorageCounters {
+        &self.counters
+    }
+    
+    /// Every `content_db` entry, counted in `content_scans`.
+    fn content_entries(&self) -> sled::Iter {
+        self.counters.content_scans.fetch_add(1, Ordering::Relaxed);
+        self.content_db.iter()
+    }
+    
+    /// Bloom filter shard locks taken by content stores and lookups.
+    pub fn bloom_locks_taken(&self) -> u64 {
+        self.bloom_filter.locks_taken()
+    }
+    
+    /// SHA-256 of `data`, prefixed, for naming things other than content.
+    pub fn compute_hash(data: &[u8]) -> String {
+        HashAlgorithm::Sha256.hash(data)
+    }
+    
+    /// The hash `data` would be stored under, by the configured algorithm.
+    pub fn content_hash(&self, data: &[u8]) -> String {
+        self.config.hash_algorithm.hash(data)
+    }
+    
+    /// The stored hash whose hex digest is `hash_only`, under any algorithm.
+    fn stored_hash(&self, hash_only: &str) -> Result<Option<String>, StorageError> {
+        for algorithm in HashAlgorithm::ALL {
+            let hash = format!("{}{}", algorithm.prefix(), hash_only);
+            if self.content_db.contains_key(&hash)? {
+                return Ok(Some(hash));
+            }
+        }
+        Ok(None)
+    }
+    
+    /// Gives a bare hex digest the prefix of the stored object it names,
+    /// or the configured algorithm's if none is stored. Prefixed hashes
+    /// are returned as they are.
+    pub fn qualify_hash(&self, hash: String) -> String {
+        if hash.contains(':') {
+            return hash;
+        }
+        match self.stored_hash(&hash) {
+            Ok(Some(stored)) => stored,
+            _ => format!("{}{}", self.config.hash_algorithm.prefix(), hash),
+        }
+    }
+    
+    /// Takes another reference to an object already stored under `hash`, as
+    /// `store_content` would for its bytes. Returns false if it isn't stored.
+    pub fn reference_content(&self, hash: &str, content_type: Option<&str>, session_id: &str) -> Result<bool, StorageError> {
+        let referenced = self.increment_ref_count(hash, content_type, session_id)?;
+        if referenced {
+            self.counters.dedup_hits.fetch_add(1, Ordering::Relaxed);
+        }
+        Ok(referenced)
+    }
+    
+    pub async fn store_content(&self, data: &[u8], content_type: Option<&str>, session_id: &str) -> Result<String, StorageError> {
+        if let Some(limit) = self.config.max_content_bytes.filter(|&limit| data.len() > limit) {
+            return Err(StorageError::TooLarge { size: data.len(), limit });
+        }
+        let item = ContentItem::new(data, content_type, self.config.hash_algorithm);
+        let maybe_stored = self.bloom_filter.check(&item.hash).await;
+        let outcome = self.store_item(&item, maybe_stored, session_id).await?;
+        if let StoreOutcome::Stored(hash) = &outcome {
+            self.note_inserted(&[hash.as_str()]).await;
+        }
+        outcome.into_result()
+    }
+    
+    /// `store_content` for many bodies of one session. The bloom filter is
+    /// checked for all of them up front and updated once at the end, taking
+    /// each shard's lock once per batch rather than twice per body. Objects
+    /// stored early in the batch aren't in the filter until it finishes,
+    /// which only sends concurrent stores of them down the slower path.
+    pub async fn store_content_batch(&self, items: &[ContentItem<'_>], session_id: &str) -> Vec<StoreOutcome> {
+        let hashes: Vec<&str> = items.iter().map(|item| item.hash.as_str()).collect();
+        let maybe_stored = self.bloom_filter.check_many(&hashes).await;
+        
+        let mut outcomes = Vec::with_capacity(items.len());
+        for (item, maybe_stored) in items.iter().zip(maybe_stored) {
+            let outcome = match self.config.max_content_bytes.filter(|&limit| item.data.len() > limit) {
+                Some(limit) => StoreOutcome::Failed(StorageError::TooLarge { size: item.data.len(), limit }),
+                None => self.store_item(item, maybe_stored, session_id).await
+                    .unwrap_or_else(StoreOutcome::Failed),
+            };
+            outcomes.push(outcome);
+        }
+        
+        let inserted: Vec<&str> = outcomes.iter()
+            .filter_map(|outcome| match outcome {
+                StoreOutcome::Stored(hash) => Some(hash.as_str()),
+                _ => None,
+            })
+            .collect();
+        self.note_inserted(&inserted).await;
+        outcomes
+    }
+    
+    /// Takes a reference on `item` if it's stored, else writes it. The
+    /// bloom filter is left to the caller.
+    async fn store_item(&self, item: &ContentItem<'_>, maybe_stored: bool, session_id: &str) -> Result<StoreOutcome, StorageError> {
+        let ContentItem { data, content_type, ref hash } = *item;
+        let hash_only = hash_key(hash);
+        
+        // Might exist, so try taking a reference
+        if maybe_stored && self.increment_ref_count(hash, content_type, session_id)? {
+            self.counters.dedup_hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(StoreOutcome::Deduplicated(hash.clone()));
+        }
+        
+        let _guard = self.content_lock(hash).lock().await;
+        // Another store may have written it while we waited
+        if self.increment_ref_count(hash, content_type, session_id)? {
+            self.counters.dedup_hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(StoreOutcome::Deduplicated(hash.clone()));
+        }
+        
+        // A crash before the metadata insert leaves the file (or chunk
+        // references) with nothing pointing at them, undone at startup
+        let mut pending = PendingWrite::Content { hash: hash.clone(), chunks: Vec::new() };
+        self.begin_write(&pending).await?;
+        let (compressed_size, chunks) = match self.config.chunk_avg_bytes {
+            Some(avg_bytes) if data.len() > avg_bytes as usize => {
+                let (chunks, written) = match self.store_chunks(data, avg_bytes, &mut pending).await {
+                    Ok(stored) => stored,
+                    Err(e) => {
+                        self.finish_write(&pending)?;
+                        return Err(e);
+                    }
+                };
+                (written, Some(chunks))
+            }
+            _ => {
+                let _permit = self.write_permit().await;
+                // Compress the content
+                let compressed = encode_all(data, self.config.compression_level)?;
+                
+                if let Err(e) = self.content_store.put(hash_only, &compressed).await {
+                    if matches!(e, StorageError::DiskFull(_)) {
+                        tracing::error!("Out of disk space storing {} ({} bytes)", hash, compressed.len());
+                    }
+                    self.finish_write(&pending)?;
+                    return Err(e);
+                }
+                (compressed.len(), None)
+            }
+        };
+        
+        // Update metadata
+        let metadata = ContentMetadata {
+            size: data.len(),
+            compressed_size,
+            content_type: content_type.map(normalize_content_type),
+            first_seen: chrono::Utc::now(),
+            reference_count: 1,
+            sessions: self.config.track_content_sessions
+                .then(|| BTreeSet::from([session_id.to_string()])),
+            chunks,
+        };
+        
+        // Only insert if absent so existing reference counts aren't clobbered
+        let inserted = self.content_db.compare_and_swap(
+            hash.as_bytes(),
+            None as Option<&[u8]>,
+            Some(self.encode_metadata(&metadata)?),
+        )?;
+        self.finish_write(&pending)?;
+        self.cache_content(hash, data);
+        if inserted.is_err() {
+            // The stored object keeps its own manifest; give back ours
+            if let Some(chunks) = &metadata.chunks {
+                self.release_chunks(chunks).await?;
+            }
+            self.increment_ref_count(hash, content_type, session_id)?;
+            self.counters.dedup_hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(StoreOutcome::Deduplicated(hash.clone()));
+        }
+        self.counters.objects_stored.fetch_add(1, Ordering::Relaxed);
+        self.counters.bytes_stored.fetch_add(data.len() as u64, Ordering::Relaxed);
+        self.adjust_content_totals(None, Some((metadata.size, metadata.compressed_size)));
+        self.reindex_size(hash, None, Some(metadata.compressed_size))?;
+        Ok(StoreOutcome::Stored(hash.clone()))
+    }
+    
+    /// Adds newly stored objects to the bloom filter, growing and saving it
+    /// as needed.
+    async fn note_inserted(&self, hashes: &[&str]) {
+        if hashes.is_empty() {
+            return;
+        }
+        self.bloom_filter.set_many(hashes).await;
+        if let Err(e) = self.grow_bloom_if_full().await {
+            tracing::error!("Failed to grow bloom filter: {}", e);
+        }
+        let unsaved = self.bloom_unsaved_inserts.fetch_add(hashes.len() as u64, Ordering::Relaxed) + hashes.len() as u64;
+        if unsaved >= self.config.bloom_save_every_inserts {
+            if let Err(e) = self.save_bloom().await {
+                tracing::error!("Failed to save bloom filter: {}", e);
+            }
+        }
+    }
+    
+    /// Splits `data` at content-defined boundaries and takes a reference on
+    /// each chunk, writing the ones not stored yet and recording each in
+    /// `pending`. Returns the chunk hashes in order and the compressed bytes
+    /// written.
+    async fn store_chunks(&self, data: &[u8], avg_bytes: u32, pending: &mut PendingWrite) -> Result<(Vec<String>, usize), StorageError> {
+        let chunker = fastcdc::v2020::FastCDC::new(data, avg_bytes / 4, avg_bytes, avg_bytes * 4);
+        let mut hashes = Vec::new();
+        let mut written = 0;
+        
+        for chunk in chunker {
+            let bytes = &data[chunk.offset..chunk.offset + chunk.length];
+            match self.add_chunk_reference(bytes, pending).await {
+                Ok((hash, chunk_written)) => {
+                    hashes.push(hash);
+                    written += chunk_written;
+                }
+                Err(e) => {
+                    // Don't leave references behind for an object that was never stored
+                    if let Err(release_err) = self.release_chunks(&hashes).await {
+                        tracing::error!("Failed to release chunks after a failed store: {}", release_err);
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        Ok((hashes, written))
+    }
+    
+    /// Waits for a turn to write an object, counted in `writes_queued`
+    /// meanwhile.
+    async fn write_permit(&self) -> tokio::sync::SemaphorePermit<'_> {
+        /// Uncounts the wait even if the store is cancelled during it.
+        struct Queued<'a>(&'a AtomicU64);
+        impl Drop for Queued<'_> {
+            fn drop(&mut self) {
+                self.0.fetch_sub(1, Ordering::Relaxed);
+            }
+        }
+        
+        self.counters.writes_queued.fetch_add(1, Ordering::Relaxed);
+        let _queued = Queued(&self.counters.writes_queued);
+        self.write_permits.acquire().await.expect("write permits are never closed")
+    }
+    
+    /// Returns the chunk's hash and the compressed bytes written for it, which
+    /// is 0 when it was already stored. The reference is recorded in
+    /// `content_write`, the pending write of the object taking it.
+    async fn add_chunk_reference(&self, bytes: &[u8], content_write: &mut PendingWrite) -> Result<(String, usize), StorageError> {
+        let hash = self.content_hash(bytes);
+        let _guard = self.chunk_lock(&hash).lock().await;
+        
+        if let Some(data) = self.chunks_db.get(&hash)? {
+            let mut metadata: ChunkMetadata = decode_metadata(&data)?;
+            metadata.reference_count += 1;
+            self.reference_chunk(&hash, &metadata, content_write)?;
+            return Ok((hash, 0));
+        }
+        
+        let _permit = self.write_permit().await;
+        let compressed = encode_all(bytes, self.config.compression_level)?;
+        let pending = PendingWrite::Chunk { hash: hash.clone() };
+        self.begin_write(&pending).await?;
+        if let Err(e) = self.chunk_store.put(hash_key(&hash), &compressed).await {
+            if matches!(e, StorageError::DiskFull(_)) {
+                tracing::error!("Out of disk space storing chunk {} ({} bytes)", hash, compressed.len());
+            }
+            self.finish_write(&pending)?;
+            return Err(e);
+        }
+        let metadata = ChunkMetadata {
+            size: bytes.len(),
+            compressed_size: compressed.len(),
+            reference_count: 1,
+        };
+        self.reference_chunk(&hash, &metadata, content_write)?;
+        self.finish_write(&pending)?;
+        Ok((hash, compressed.len()))
+    }
+    
+    /// Saves a chunk's bumped metadata and adds it to `content_write`'s
+    /// chunks in one transaction, so a crash can't leave a reference that
+    /// reconciliation wouldn't release, or release one never taken.
+    fn reference_chunk(&self, hash: &str, metadata: &ChunkMetadata, content_write: &mut PendingWrite) -> Result<(), StorageError> {
+        if let PendingWrite::Content { chunks, .. } = content_write {
+            chunks.push(hash.to_string());
+        }
+        let metadata = self.encode_metadata(metadata)?;
+        let recorded = serde_json::to_vec(content_write)?;
+        let key = content_write.key();
+        let result = (&self.chunks_db, &self.pending_db).transaction(|(chunks_db, pending_db)| {
+            chunks_db.insert(hash.as_bytes(), metadata.as_slice())?;
+            pending_db.insert(key.as_bytes(), recorded.as_slice())?;
+            Ok::<_, ConflictableTransactionError>(())
+        });
+        if let Err(e) = result {
+            if let PendingWrite::Content { chunks, .. } = content_write {
+                chunks.pop();
+            }
+            let (TransactionError::Abort(e) | TransactionError::Storage(e)) = e;
+            return Err(e.into());
+        }
+        Ok(())
+    }
+    
+    /// Drops one reference per entry in `hashes`, deleting chunks nothing
+    /// references anymore. Returns the compressed bytes freed.
+    async fn release_chunks(&self, hashes: &[String]) -> Result<u64, StorageError> {
+        let mut freed = 0;
+        for hash in hashes {
+            let _guard = self.chunk_lock(hash).lock().await;
+            let Some(data) = self.chunks_db.get(hash)? else {
+                continue;
+            };
+            let mut metadata: ChunkMetadata = decode_metadata(&data)?;
+            metadata.reference_count = metadata.reference_count.saturating_sub(1);
+            if metadata.reference_count > 0 {
+                self.chunks_db.insert(hash.as_bytes(), self.encode_metadata(&metadata)?)?;
+                continue;
+            }
+            self.chunks_db.remove(hash)?;
+            freed += self.chunk_store.delete(hash_key(hash)).await?;
+        }
+        Ok(freed)
+    }
+    
+    /// Writing a new chunk and deleting an unreferenced one both happen under
+    /// this lock, so a chunk can't be deleted between being found and reused.
+    fn chunk_lock(&self, hash: &str) -> &tokio::sync::Mutex<()> {
+        &self.chunk_locks[lock_stripe(hash)]
+    }
+    
+    /// Writing a new object and freeing an unreferenced one both happen under
+    /// this lock, so a re-stored object's file can't be deleted by the free
+    /// that preceded it. Reference count changes don't need it.
+    fn content_lock(&self, hash: &str) -> &tokio::sync::Mutex<()> {
+        &self.content_locks[lock_stripe(hash)]
+    }
+    
+    /// Every change to a session's index loads, edits, and saves it under
+    /// this lock, so concurrent ones (a page fetch and a note, say) can't
+    /// drop each other's edits.
+    fn session_lock(&self, session_id: &str) -> &tokio::sync::Mutex<()> {
+        &self.session_locks[lock_stripe(&Self::compute_hash(session_id.as_bytes()))]
+    }
+    
+    /// Caches `data` if it's under the configured size cutoff, evicting an
+    /// arbitrary entry once the cache is over capacity.
+    fn cache_content(&self, hash: &str, data: &[u8]) {
+        if data.len() >= self.config.max_cacheable_bytes || self.config.cache_entries == 0 {
+            return;
+        }
+        self.content_cache.insert(hash.to_string(), data.to_vec());
+        
+        // Evict old entries if cache is too large
+        if self.content_cache.len() > self.config.cache_entries {
+            // Simple random eviction
+            let evict = self.content_cache.iter()
+                .map(|entry| entry.key().clone())
+                .find(|key| key != hash);
+            if let Some(key) = evict {
+                self.content_cache.remove(&key);
+            }
+        }
+    }
+    
+    pub fn content_metadata(&self, hash: &str) -> Result<Option<ContentMetadata>, StorageError> {
+        match self.content_db.get(hash)? {
+            Some(data) => Ok(Some(decode_metadata(&data)?)),
+            None => Ok(None),
+        }
+    }
+    
+    /// Returns the subset of `hashes` already stored. The bloom filter rules
+    /// out absent hashes cheaply; its positives are confirmed against sled.
+    pub async fn existing_content(&self, hashes: &[String]) -> Result<Vec<String>, StorageError> {
+        let mut existing = Vec::new();
+        for hash in hashes {
+            if self.bloom_filter.check(hash).await && self.content_db.contains_key(hash)? {
+                existing.push(hash.clone());
+            }
+        }
+        Ok(existing)
+    }
+    
+    pub async fn retrieve_content(&self, hash: &str) -> Result<Vec<u8>, StorageError> {
+        // Check cache first
+        if let Some(cached) = self.content_cache.get(hash) {
+            self.counters.cache_hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(cached.clone());
+        }
+        self.counters.cache_misses.fetch_add(1, Ordering::Relaxed);
+        
+        let decompressed = self.read_content(hash).await?;
+        
+        self.cache_content(hash, &decompressed);
+        
+        Ok(decompressed)
+    }
+    
+    async fn read_content(&self, hash: &str) -> Result<Vec<u8>, StorageError> {
+        match self.config.mmap_min_bytes {
+            Some(min_bytes) => self.decompress_mapped(hash, min_bytes).await,
+            None => decompress(hash, &self.retrieve_compressed(hash).await?),
+        }
+    }
+    
+    /// Loads the most referenced objects small enough to cache, up to the
+    /// cache's capacity, returning how many were cached. Objects that fail
+    /// to load are skipped.
+    pub async fn warm_cache(&self) -> Result<usize, StorageError> {
+        let capacity = self.config.cache_entries;
+        if capacity == 0 {
+            return Ok(0);
+        }
+        // Min-heap of the hottest `capacity` so far; ties go to smaller objects
+        let mut heap = BinaryHeap::with_capacity(capacity + 1);
+        for item in self.content_entries() {
+            let (key, value) = item?;
+            let Ok(metadata) = decode_metadata::<ContentMetadata>(&value) else {
+                continue;
+            };
+            if metadata.size >= self.config.max_cacheable_bytes {
+                continue;
+            }
+            heap.push(Reverse((metadata.reference_count, Reverse(metadata.size), key)));
+            if heap.len() > capacity {
+                heap.pop();
+            }
+        }
+        
+        let mut warmed = 0;
+        for Reverse((_, _, key)) in heap {
+            let hash = String::from_utf8_lossy(&key).into_owned();
+            match self.read_content(&hash).await {
+                Ok(data) => {
+                    self.cache_content(&hash, &data);
+                    warmed += 1;
+                }
+                Err(e) => tracing::warn!("Failed to warm cache with {}: {}", hash, e),
+            }
+        }
+        Ok(warmed)
+    }
+    
+    /// Decompresses straight from a memory map of the content file when it
+    /// holds at least `min_bytes`, saving the copy into a read buffer. Smaller
+    /// files, files that can't be mapped, and objects in a remote store are
+    /// read normally.
+    async fn decompress_mapped(&self, hash: &str, min_bytes: u64) -> Result<Vec<u8>, StorageError> {
+        let Some(content_path) = self.content_store.local_path(checked_hash_key(hash)?) else {
+            return decompress(hash, &self.retrieve_compressed(hash).await?);
+        };
+        
+        let mapped_hash = hash.to_string();
+        let mapped = tokio::task::spawn_blocking(move || -> Result<Option<Vec<u8>>, StorageError> {
+            let file = match std::fs::File::open(&content_path) {
+                Ok(file) => file,
+                // Possibly packed; the plain read looks there
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+                Err(e) => return Err(e.into()),
+            };
+            if file.metadata()?.len() < min_bytes {
+                return Ok(None);
+            }
+            // SAFETY: content files are written to a temporary path and renamed
+            // into place, never modified afterwards; one removed while mapped
+            // stays readable until the map is dropped.
+            let Ok(map) = (unsafe { memmap2::Mmap::map(&file) }) else {
+                return Ok(None);
+            };
+            decompress(&mapped_hash, &map[..]).map(Some)
+        }).await.map_err(|e| format!("Content read task failed: {}", e))??;
+        
+        match mapped {
+            Some(decompressed) => Ok(decompressed),
+            None => decompress(hash, &self.retrieve_compressed(hash).await?),
+        }
+    }
+    
+    /// Reads the stored zstd data for `hash` without decompressing it, from
+    /// its own file, the pack file, or its chunks. A chunked object comes back
+    /// as one frame per chunk, which still decodes as a single stream.
+    pub async fn retrieve_compressed(&self, hash: &str) -> Result<Vec<u8>, StorageError> {
+        if let Some(compressed) = self.content_store.get(checked_hash_key(hash)?).await? {
+            return Ok(compressed);
+        }
+        if let Some(compressed) = self.read_packed(hash).await? {
+            return Ok(compressed);
+        }
+        self.read_chunked(hash).await?.ok_or_else(|| StorageError::NotFound(format!("Content {}", hash)))
+    }
+    
+    async fn read_packed(&self, hash: &str) -> Result<Option<Vec<u8>>, StorageError> {
+        let Some(data) = self.packed_db.get(hash)? else {
+            return Ok(None);
+        };
+        let entry: PackEntry = decode_metadata(&data)?;
+        
+        let mut pack = fs::File::open(self.pack_path()).await?;
+        pack.seek(std::io::SeekFrom::Start(entry.offset)).await?;
+        let mut compressed = vec![0; entry.len as usize];
+        pack.read_exact(&mut compressed).await?;
+        Ok(Some(compressed))
+    }
+    
+    async fn read_chunked(&self, hash: &str) -> Result<Option<Vec<u8>>, StorageError> {
+        let Some(chunks) = self.content_metadata(hash)?.and_then(|metadata| metadata.chunks) else {
+            return Ok(None);
+        };
+        let mut compressed = Vec::new();
+        for chunk in &chunks {
+            let data = self.chunk_store.get(hash_key(chunk)).await?
+                .ok_or_else(|| StorageError::Corrupt(format!("Chunk {} of {} is missing", chunk, hash)))?;
+            compressed.extend_from_slice(&data);
+        }
+        Ok(Some(compressed))
+    }
+    
+    pub async fn store_page_fetch(&self, session_id: &str, page_fetch: &PageFetchIndex) -> Result<PathBuf, StorageError> {
+        // Dated like the file name, so rewrites after midnight find the same file
+        let date = chrono::DateTime::from_timestamp_millis(page_fetch.timestamp)
+            .unwrap_or_else(chrono::Utc::now)
+            .format("%Y-%m-%d")
+            .to_string();
+        let page_hash = Self::compute_hash(page_fetch.page_url.as_bytes());
+        let page_hash_only = page_hash.strip_prefix("sha256:").unwrap();
+        // Navigation IDs come from clients, so they're hashed rather than used in paths
+        let navigation_hash = Self::compute_hash(page_fetch.navigation_id.as_bytes());
+        let navigation_hash_only = navigation_hash.strip_prefix("sha256:").unwrap();
+        
+        let dir = self.dir("sessions").join(&date).join(session_id);
+        let stem = format!("{}_{}_{}", page_fetch.timestamp, &page_hash_only[..8], &navigation_hash_only[..16]);
+        let _guard = self.session_lock(session_id).lock().await;
+        let path = Self::claim_page_fetch_path(&dir, &stem, &page_fetch.navigation_id).await?;
+        
+        write_atomic(&path, &encode_page_fetch(page_fetch, self.config.page_fetch_format)?).await?;
+        
+        // Update session index
+        // The filename is stable for a page, so rewrites replace the same entry
+        let mut index = self.load_session_index(session_id)?.unwrap_or_default();
+        let path_str = path.to_string_lossy().to_string();
+        
+        // Pages first written under the old `{timestamp}_{page_hash}` name move to the new one
+        let legacy = dir.join(format!("{}_{}.json", page_fetch.timestamp, &page_hash_only[..8]));
+        if stored_navigation_id(&legacy).await?.as_deref() == Some(page_fetch.navigation_id.as_str()) {
+            remove_file_if_exists(&legacy).await?;
+            let legacy_str = legacy.to_string_lossy();
+            index.paths.retain(|p| *p != legacy_str);
+            index.usage.remove(legacy_str.as_ref());
+        }
+        
+        let mut seen = HashSet::new();
+        index.paths.retain(|p| seen.insert(p.clone()));
+        if !seen.contains(&path_str) {
+            index.paths.push(path_str.clone());
+        }
+        index.updated_at = Some(chrono::Utc::now());
+        index.usage.insert(path_str.clone(), SessionUsage::of_page(page_fetch));
+        if let Some(client) = &page_fetch.client_info {
+            index.clients.insert(ClientInfo { user_agent: None, ..client.clone() });
+        }
+        
+        if let Some(bucket_secs) = self.config.session_bucket_secs {
+            let bucket_ms = bucket_secs.saturating_mul(1000) as i64;
+            if index.buckets.is_empty() {
+                // Sessions recorded before bucketing get their earlier pages filed too
+                let earlier_paths: Vec<String> = index.paths.iter()
+                    .filter(|p| **p != path_str)
+                    .cloned()
+                    .collect();
+                for other in earlier_paths {
+                    if let Ok(data) = fs::read(&other).await {
+                        let earlier: PageFetchIndex = decode_page_fetch(&data)?;
+                        self.update_buckets(session_id, &earlier.navigation_id, &earlier.requests, bucket_ms, &mut index).await?;
+                    }
+                }
+            }
+            self.update_buckets(session_id, &page_fetch.navigation_id, &page_fetch.requests, bucket_ms, &mut index).await?;
+        }
+        self.save_session_index(session_id, &index)?;
+        self.index_requests(session_id, &page_fetch.requests, &path_str)?;
+        
+        Ok(path)
+    }
+    
+    /// Picks the file for a navigation: `{stem}.json`, or `{stem}_{n}.json`
+    /// if a different navigation already holds that name. Probing in order
+    /// means later rewrites land on the same file.
+    async fn claim_page_fetch_path(dir: &Path, stem: &str, navigation_id: &str) -> Result<PathBuf, StorageError> {
+        for attempt in 0..MAX_PAGE_FETCH_PROBES {
+            let path = match attempt {
+                0 => dir.join(format!("{}.json", stem)),
+                n => dir.join(format!("{}_{}.json", stem, n)),
+            };
+            match stored_navigation_id(&path).await? {
+                Some(existing) if existing != navigation_id => {
+                    tracing::warn!("Page fetch filename collision at {}; trying the next name", path.display());
+                }
+                _ => return Ok(path),
+            }
+        }
+        Err(format!("No free page fetch filename for {} in {}", stem, dir.display()).into())
+    }
+    
+    /// Replaces a navigation's requests across the session's time buckets,
+    /// creating, rewriting, or deleting bucket files and manifest entries.
+    async fn update_buckets(
+        &self,
+        session_id: &str,
+        navigation_id: &str,
+        requests: &[ArchivedRequest],
+        bucket_ms: i64,
+        index: &mut SessionIndex,
+    ) -> Result<(), StorageError> {
+        let mut grouped: BTreeMap<i64, Vec<ArchivedRequest>> = BTreeMap::new();
+        for request in requests {
+            // Requests stay in the bucket they were first filed under
+            let existing = index.buckets.range(..=request.timestamp).next_back()
+                .filter(|(_, entry)| request.timestamp < entry.end)
+                .map(|(start, _)| *start);
+            let start = existing.unwrap_or(request.timestamp - request.timestamp.rem_euclid(bucket_ms.max(1)));
+            grouped.entry(start).or_default().push(request.clone());
+        }
+        
+        let stale: Vec<i64> = index.buckets.iter()
+            .filter(|(start, entry)| entry.navigations.contains(navigation_id) && !grouped.contains_key(start))
+            .map(|(start, _)| *start)
+            .collect();
+        let touched = grouped.into_iter()
+            .map(|(start, requests)| (start, Some(requests)))
+            .chain(stale.into_iter().map(|start| (start, None)));
+        
+        for (start, requests) in touched {
+            let path = match index.buckets.get(&start) {
+                Some(entry) => PathBuf::from(&entry.path),
+                None => self.dir("sessions").join("buckets").join(session_id).join(format!("{}.json", start)),
+            };
+            let mut bucket = match fs::read(&path).await {
+                Ok(data) => serde_json::from_slice(&data)?,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => TimeBucket {
+                    start,
+                    end: start + bucket_ms,
+                    ..Default::default()
+                },
+                Err(e) => return Err(e.into()),
+            };
+            match requests {
+                Some(requests) => bucket.requests.insert(navigation_id.to_string(), requests),
+                None => bucket.requests.remove(navigation_id),
+            };
+            
+            if bucket.requests.is_empty() {
+                match fs::remove_file(&path).await {
+                    Ok(()) => {}
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                    Err(e) => return Err(e.into()),
+                }
+                index.buckets.remove(&start);
+                continue;
+            }
+            
+            write_atomic(&path, serde_json::to_string_pretty(&bucket)?.as_bytes()).await?;
+            index.buckets.insert(start, BucketEntry {
+                path: path.to_string_lossy().to_string(),
+                end: bucket.end,
+                request_count: bucket.requests.values().map(Vec::len).sum(),
+                navigations: bucket.requests.keys().cloned().collect(),
+            });
+        }
+        Ok(())
+    }
+    
+    /// Returns a session's requests with timestamps in `[from, to]`, paired
+    /// with their navigation IDs and ordered by time. Bucketed sessions read
+    /// only the overlapping bucket files. Returns `None` if the session is unknown.
+    pub async fn load_requests_in_range(
+        &self,
+        session_id: &str,
+        from: Option<i64>,
+        to: Option<i64>,
+    ) -> Result<Option<Vec<(String, ArchivedRequest)>>, StorageError> {
+        let Some(index) = self.load_session_index(session_id)? else {
+            return Ok(None);
+        };
+        let from = from.unwrap_or(i64::MIN);
+        let to = to.unwrap_or(i64::MAX);
+        let in_range = |request: &ArchivedRequest| (from..=to).contains(&request.timestamp);
+        
+        let mut requests = Vec::new();
+        if index.buckets.is_empty() {
+            for page_fetch in self.load_session(session_id).await?.unwrap_or_default() {
+                let navigation_id = page_fetch.navigation_id;
+                requests.extend(page_fetch.requests.into_iter()
+                    .filter(in_range)
+                    .map(|request| (navigation_id.clone(), request)));
+            }
+        } else {
+            let overlapping = index.buckets.iter()
+                .filter(|(start, entry)| **start <= to && entry.end > from);
+            for (_, entry) in overlapping {
+                let bucket: TimeBucket = match fs::read(&entry.path).await {
+                    Ok(data) => serde_json::from_slice(&data)?,
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                    Err(e) => return Err(e.into()),
+                };
+                for (navigation_id, bucket_requests) in bucket.requests {
+                    requests.extend(bucket_requests.into_iter()
+                        .filter(in_range)
+                        .map(|request| (navigation_id.clone(), request)));
+                }
+            }
+        }
+        
+        requests.sort_by_key(|(_, request)| request.timestamp);
+        Ok(Some(requests))
+    }
+    
+    /// Removes a page fetch written by `store_page_fetch`, dropping the
+    /// session's index entry once it has no page fetches left.
+    pub async fn remove_page_fetch(&self, session_id: &str, navigation_id: &str, path: &Path) -> Result<(), StorageError> {
+        if let Ok(data) = fs::read(path).await {
+            if let Ok(page_fetch) = decode_page_fetch::<PageFetchIndex>(&data) {
+                self.unindex_requests(&page_fetch.requests)?;
+            }
+        }
+        match fs::remove_file(path).await {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        
+        let _guard = self.session_lock(session_id).lock().await;
+        if let Some(mut index) = self.load_session_index(session_id)? {
+            let path_str = path.to_string_lossy();
+            index.paths.retain(|p| *p != path_str);
+            index.usage.remove(path_str.as_ref());
+            if let Some(bucket_secs) = self.config.session_bucket_secs {
+                let bucket_ms = bucket_secs.saturating_mul(1000) as i64;
+                self.update_buckets(session_id, navigation_id, &[], bucket_ms, &mut index).await?;
+            }
+            if index.paths.is_empty() {
+                self.sessions_db.remove(session_id)?;
+            } else {
+                self.save_session_index(session_id, &index)?;
+            }
+        }
+        Ok(())
+    }
+    
+    /// Writes an rrweb batch under `recordings/{session_id}/`.
+    pub async fn store_recording_batch(&self, batch: &RecordingBatch) -> Result<PathBuf, StorageError> {
+        if !is_safe_path_component(&batch.session_id) {
+            return Err(StorageError::Invalid(format!("Invalid recording session ID: {:?}", batch.session_id)));
+        }
+        let (first, _) = batch.time_span();
+        let filename = format!("{}_{}.json", first, &uuid::Uuid::new_v4().simple().to_string()[..8]);
+        let path = self.dir("recordings").join(&batch.session_id).join(filename);
+        write_atomic(&path, &serde_json::to_vec(batch)?).await?;
+        Ok(path)
+    }
+    
+    /// Lists a session's stored rrweb batches, oldest first.
+    pub async fn list_recording_batches(&self, session_id: &str) -> Result<Vec<RecordingBatchInfo>, StorageError> {
+        Ok(self.read_recording_batches(session_id).await?
+            .into_iter()
+            .map(|(path, batch)| {
+                let (first_timestamp, last_timestamp) = batch.time_span();
+                RecordingBatchInfo {
+                    path: path.to_string_lossy().to_string(),
+                    first_timestamp,
+                    last_timestamp,
+                    event_count: batch.events.len(),
+                }
+            })
+            .collect())
+    }
+    
+    /// Hash of the most recent screenshot or favicon attached to any of the
+    /// session's page fetches or recording batches. `None` if there's none,
+    /// or the session is unknown.
+    pub async fn latest_image(&self, session_id: &str, kind: ImageKind) -> Result<Option<String>, StorageError> {
+        let mut latest: Option<(i64, String)> = None;
+        let mut consider = |timestamp: i64, hash: Option<&String>| {
+            if let Some(hash) = hash {
+                if latest.as_ref().is_none_or(|(newest, _)| timestamp >= *newest) {
+                    latest = Some((timestamp, hash.clone()));
+                }
+            }
+        };
+        for page_fetch in self.load_session(session_id).await?.unwrap_or_default() {
+            consider(page_fetch.timestamp, page_fetch.image_hash(kind));
+        }
+        for batch in self.load_recording_batches(session_id).await? {
+            consider(batch.timestamp, batch.image_hash(kind));
+        }
+        Ok(latest.map(|(_, hash)| hash))
+    }
+    
+    /// Reads a session's stored rrweb batches, oldest first.
+    pub async fn load_recording_batches(&self, session_id: &str) -> Result<Vec<RecordingBatch>, StorageError> {
+        Ok(self.read_recording_batches(session_id).await?
+            .into_iter()
+            .map(|(_, batch)| batch)
+            .collect())
+    }
+    
+    async fn read_recording_batches(&self, session_id: &str) -> Result<Vec<(PathBuf, RecordingBatch)>, StorageError> {
+        if !is_safe_path_component(session_id) {
+            return Ok(Vec::new());
+        }
+        let mut entries = match fs::read_dir(self.dir("recordings").join(session_id)).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        
+        let mut batches = Vec::new();
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("json") {
+                continue;
+            }
+            let batch: RecordingBatch = serde_json::from_slice(&fs::read(&path).await?)?;
+            batches.push((path, batch));
+        }
+        batches.sort_by_cached_key(|(path, batch)| (batch.time_span().0, path.clone()));
+        Ok(batches)
+    }
+    
+    /// Reads every page fetch recorded for a session. Returns `None` if the
+    /// session is unknown.
+    pub async fn load_session(&self, session_id: &str) -> Result<Option<Vec<PageFetchIndex>>, StorageError> {
+        let Some(index) = self.load_session_index(session_id)? else {
+            return Ok(None);
+        };
+        
+        let mut page_fetches = Vec::new();
+        let mut seen = HashSet::new();
+        for path in &index.paths {
+            if !seen.insert(path) {
+                continue;
+            }
+            match fs::read(path).await {
+                Ok(data) => page_fetches.push(decode_page_fetch(&data)?),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        
+        Ok(Some(page_fetches))
+    }
+    
+    pub fn list_sessions(&self) -> Result<Vec<String>, StorageError> {
+        let mut session_ids = Vec::new();
+        for key in self.sessions_db.iter().keys() {
+            session_ids.push(String::from_utf8_lossy(&key?).to_string());
+        }
+        Ok(session_ids)
+    }
+    
+    /// Every session with its index entry, which is read without opening
+    /// any page fetch files.
+    pub fn list_session_indexes(&self) -> Result<Vec<(String, SessionIndex)>, StorageError> {
+        let mut sessions = Vec::new();
+        for item in self.sessions_db.iter() {
+            let (key, value) = item?;
+            sessions.push((String::from_utf8_lossy(&key).into_owned(), SessionIndex::from_slice(&value)?));
+        }
+        Ok(sessions)
+    }
+    
+    /// Looks a request up through the `requests` index, reading only the page
+    /// fetch file that holds it. Returns its session ID too.
+    pub async fn find_request(&self, request_id: &str) -> Result<Option<(String, ArchivedRequest)>, StorageError> {
+        let Some(data) = self.requests_db.get(request_id)? else {
+            return Ok(None);
+        };
+        let location: RequestLocation = decode_metadata(&data)?;
+        
+        let request = match fs::read(&location.path).await {
+            Ok(data) => decode_page_fetch::<PageFetchIndex>(&data)?.requests.into_iter()
+                .find(|request| request.request_id == request_id),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+        if request.is_none() {
+            // A rolled-back batch can leave an entry behind for its page
+            self.requests_db.remove(request_id)?;
+        }
+        Ok(request.map(|request| (location.session_id, request)))
+    }
+    
+    pub async fn find_page_fetch(&self, session_id: &str, navigation_id: &str) -> Result<Option<PageFetchIndex>, StorageError> {
+        let page_fetches = self.load_session(session_id).await?.unwrap_or_default();
+        Ok(page_fetches.into_iter().find(|p| p.navigation_id == navigation_id))
+    }
+    
+    /// What the session's stored page fetches hold, for quotas.
+    pub fn session_usage(&self, session_id: &str) -> Result<SessionUsage, StorageError> {
+        Ok(self.load_session_index(session_id)?.map(|index| index.total_usage()).unwrap_or_default())
+    }
+    
+    fn load_session_index(&self, session_id: &str) -> Result<Option<SessionIndex>, StorageError> {
+        match self.sessions_db.get(session_id)? {
+            Some(data) => Ok(Some(SessionIndex::from_slice(&data)?)),
+            None => Ok(None),
+        }
+    }
+    
+    fn save_session_index(&self, session_id: &str, index: &SessionIndex) -> Result<(), StorageError> {
+        self.sessions_db.insert(
+            session_id.as_bytes(),
+            self.encode_metadata(index)?
+        )?;
+        Ok(())
+    }
+    
+    /// Adds newly archived exchanges to the hosts index.
+    pub fn record_hosts(&self, tally: &HostTally) -> Result<(), StorageError> {
+        self.apply_host_tally(tally, |stats, delta| HostStats {
+            request_count: stats.request_count + delta.request_count,
+            bytes: stats.bytes + delta.bytes,
+        })
+    }
+    
+    /// Takes deleted exchanges out of the hosts index, dropping hosts with
+    /// no requests left.
+    fn forget_hosts(&self, tally: &HostTally) -> Result<(), StorageError> {
+        self.apply_host_tally(tally, |stats, delta| HostStats {
+            request_count: stats.request_count.saturating_sub(delta.request_count),
+            bytes: stats.bytes.saturating_sub(delta.bytes),
+        })
+    }
+    
+    fn apply_host_tally(&self, tally: &HostTally, apply: impl Fn(HostStats, HostStats) -> HostStats) -> Result<(), StorageError> {
+        for (host, delta) in &tally.0 {
+            self.hosts_db.fetch_and_update(host.as_bytes(), |current| {
+                let stats = current
+                    .and_then(|data| decode_metadata::<HostStats>(data).ok())
+                    .unwrap_or_default();
+                let updated = apply(stats, *delta);
+                (updated.request_count > 0).then(|| serde_json::to_vec(&updated).unwrap_or_default())
+            })?;
+        }
+        Ok(())
+    }
+    
+    /// Every captured host with its totals, sorted by host.
+    pub fn list_hosts(&self) -> Result<Vec<(String, HostStats)>, StorageError> {
+        self.hosts_db.iter()
+            .map(|item| {
+                let (key, value) = item?;
+                Ok((String::from_utf8_lossy(&key).into_owned(), decode_metadata(&value)?))
+            })
+            .collect()
+    }
+    
+    /// Records a replay job's state, replacing what was recorded for it.
+    /// Flushed before returning, so a job that was accepted survives a
+    /// crash right after.
+    #[cfg(feature = "replay")]
+    pub async fn save_replay_job(&self, job: &ReplayJob) -> Result<(), StorageError> {
+        self.replay_jobs_db.insert(job.id.as_bytes(), self.encode_metadata(job)?)?;
+        self.replay_jobs_db.flush_async().await?;
+        Ok(())
+    }
+    
+    #[cfg(feature = "replay")]
+    pub fn remove_replay_job(&self, id: &str) -> Result<(), StorageError> {
+        self.replay_jobs_db.remove(id)?;
+        Ok(())
+    }
+    
+    /// Recorded replay jobs, oldest first.
+    #[cfg(feature = "replay")]
+    pub fn replay_jobs(&self) -> Result<Vec<ReplayJob>, StorageError> {
+        let mut jobs = self.replay_jobs_db.iter()
+            .map(|item| decode_metadata::<ReplayJob>(&item?.1))
+            .collect::<Result<Vec<_>, _>>()?;
+        jobs.sort_by_key(|job| job.queued_at);
+        Ok(jobs)
+    }
+    
+    /// Sets or clears the per-session TTL. Returns false if the session is unknown.
+    pub async fn set_session_ttl(&self, session_id: &str, ttl_secs: Option<u64>) -> Result<bool, StorageError> {
+        let _guard = self.session_lock(session_id).lock().await;
+        let Some(mut index) = self.load_session_index(session_id)? else {
+            return Ok(false);
+        };
+        index.ttl_secs = ttl_secs;
+        self.save_session_index(session_id, &index)?;
+        Ok(true)
+    }
+    
+    /// Sets or, with `None`, clears a session's note. Returns false if the
+    /// session is unknown.
+    pub async fn set_session_note(&self, session_id: &str, text: Option<String>) -> Result<bool, StorageError> {
+        let _guard = self.session_lock(session_id).lock().await;
+        let Some(mut index) = self.load_session_index(session_id)? else {
+            return Ok(false);
+        };
+        index.note = text.map(|text| SessionNote { text, updated_at: chrono::Utc::now() });
+        self.save_session_index(session_id, &index)?;
+        Ok(true)
+    }
+    
+    pub fn session_note(&self, session_id: &str) -> Result<Option<SessionNote>, StorageError> {
+        Ok(self.load_session_index(session_id)?.and_then(|index| index.note))
+    }
+    
+    /// Deletes every session older than its TTL (or the global retention),
+    /// returning the IDs of the sessions removed.
+    pub async fn sweep_expired_sessions(&self) -> Result<Vec<String>, StorageError> {
+        let now = chrono::Utc::now();
+        let mut expired = Vec::new();
+        
+        for (key, value) in self.sessions_db.iter().flatten() {
+            let session_id = String::from_utf8_lossy(&key).to_string();
+            let index = SessionIndex::from_slice(&value)?;
+            
+            let Some(ttl_secs) = index.ttl_secs.or(self.config.retention_secs) else {
+                continue;
+            };
+            let updated_at = match index.updated_at {
+                Some(updated_at) => updated_at,
+                None => Self::newest_mtime(&index.paths).await.unwrap_or(now),
+            };
+            
+            if (now - updated_at).num_seconds() > ttl_secs as i64 {
+                expired.push(session_id);
+            }
+        }
+        
+        for session_id in &expired {
+            self.delete_session(session_id).await?;
+        }
+        
+        Ok(expired)
+    }
+    
+    /// Claims an `Idempotency-Key` for a request hashing to `fingerprint`,
+    /// unless an unexpired claim already holds it.
+    pub fn claim_idempotency_key(&self, key: &str, fingerprint: &str) -> Result<IdempotencyClaim, StorageError> {
+        let now = chrono::Utc::now().timestamp_millis();
+        let claim = self.encode_metadata(&IdempotencyRecord {
+            fingerprint: fingerprint.to_string(),
+            created_at: now,
+            response: None,
+        })?;
+        loop {
+            let current = self.idempotency_db.get(key)?;
+            if let Some(data) = &current {
+                let record: IdempotencyRecord = decode_metadata(data)?;
+                if !self.idempotency_key_expired(&record, now) {
+                    return Ok(match record.response {
+                        _ if record.fingerprint != fingerprint => IdempotencyClaim::Mismatch,
+                        None => IdempotencyClaim::InProgress,
+                        Some(response) => IdempotencyClaim::Completed(response),
+                    });
+                }
+            }
+            if self.idempotency_db.compare_and_swap(key, current, Some(claim.as_slice()))?.is_ok() {
+                return Ok(IdempotencyClaim::Claimed);
+            }
+        }
+    }
+    
+    /// Records the response to a request holding `key`, for its retries.
+    pub fn complete_idempotency_key(&self, key: &str, fingerprint: &str, response: StoredResponse) -> Result<(), StorageError> {
+        let record = IdempotencyRecord {
+            fingerprint: fingerprint.to_string(),
+            created_at: chrono::Utc::now().timestamp_millis(),
+            response: Some(response),
+        };
+        self.idempotency_db.insert(key, self.encode_metadata(&record)?)?;
+        Ok(())
+    }
+    
+    /// Frees a claimed key without a response, so a retry runs again.
+    pub fn release_idempotency_key(&self, key: &str) -> Result<(), StorageError> {
+        self.idempotency_db.remove(key)?;
+        Ok(())
+    }
+    
+    /// Removes keys older than `StorageConfig::idempotency_ttl_secs`.
+    /// Returns how many were removed.
+    pub fn sweep_idempotency_keys(&self) -> Result<usize, StorageError> {
+        let now = chrono::Utc::now().timestamp_millis();
+        let mut removed = 0;
+        for item in self.idempotency_db.iter() {
+            let (key, value) = item?;
+            let expired = decode_metadata::<IdempotencyRecord>(&value)
+                .map_or(true, |record| self.idempotency_key_expired(&record, now));
+            // Compared so a key reclaimed meanwhile survives
+            if expired && self.idempotency_db.compare_and_swap(&key, Some(value), None::<&[u8]>)?.is_ok() {
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+    
+    fn idempotency_key_expired(&self, record: &IdempotencyRecord, now: i64) -> bool {
+        let ttl_ms = self.config.idempotency_ttl_secs.map_or(i64::MAX, |secs| secs.saturating_mul(1000) as i64);
+        now.saturating_sub(record.created_at) > ttl_ms
+    }
+    
+    /// Records that a write is under way, replacing what was recorded for it.
+    /// Flushed before returning, so the record is on disk before the file it
+    /// covers is.
+    async fn begin_write(&self, pending: &PendingWrite) -> Result<(), StorageError> {
+        self.pending_db.insert(pending.key(), serde_json::to_vec(pending)?)?;
+        // Not `flush_async`, whose futures can miss their wakeup when several
+        // are awaited at once, hanging concurrent stores
+        let pending_db = self.pending_db.clone();
+        tokio::task::spawn_blocking(move || pending_db.flush())
+            .await.map_err(|e| format!("Pending write flush failed: {}", e))??;
+        Ok(())
+    }
+    
+    fn finish_write(&self, pending: &PendingWrite) -> Result<(), StorageError> {
+        self.pending_db.remove(pending.key())?;
+        Ok(())
+    }
+    
+    /// Undoes writes the process stopped in the middle of: an object or
+    /// chunk whose metadata never made it into sled has its file deleted and
+    /// the chunk references it took released. Ones whose metadata did are
+    /// complete and kept.
+    async fn reconcile_pending_writes(&self) -> Result<(), StorageError> {
+        let mut undone = 0;
+        for item in self.pending_db.iter() {
+            let (key, value) = item?;
+            let pending: PendingWrite = serde_json::from_slice(&value)
+                .map_err(|e| StorageError::Corrupt(format!("Pending write {:?}: {}", String::from_utf8_lossy(&key), e)))?;
+            match &pending {
+                PendingWrite::Content { hash, chunks } if !self.content_db.contains_key(hash)? => {
+                    self.content_store.delete(hash_key(hash)).await?;
+                    self.release_chunks(chunks).await?;
+                    undone += 1;
+                }
+                PendingWrite::Chunk { hash } if !self.chunks_db.contains_key(hash)? => {
+                    self.chunk_store.delete(hash_key(hash)).await?;
+                    undone += 1;
+                }
+                _ => {}
+            }
+            self.pending_db.remove(key)?;
+        }
+        if undone > 0 {
+            tracing::warn!("Removed {} object writes interrupted by the last shutdown", undone);
+        }
+        Ok(())
+    }
+    
+    /// Claims without a response were left by requests a restart cut short.
+    fn release_unfinished_idempotency_keys(&self) -> Result<(), StorageError> {
+        let mut batch = sled::Batch::default();
+        for item in self.idempotency_db.iter() {
+            let (key, value) = item?;
+            if decode_metadata::<IdempotencyRecord>(&value).map_or(true, |record| record.response.is_none()) {
+                batch.remove(key);
+            }
+        }
+        self.idempotency_db.apply_batch(batch)?;
+        Ok(())
+    }
+    
+    async fn newest_mtime(paths: &[String]) -> Option<chrono::DateTime<chrono::Utc>> {
+        let mut newest = None;
+        for path in paths {
+            if let Ok(modified) = fs::metadata(path).await.and_then(|m| m.modified()) {
+                let modified = chrono::DateTime::<chrono::Utc>::from(modified);
+                newest = newest.max(Some(modified));
+            }
+        }
+        newest
+    }
+    
+    /// Removes a session's page fetch, bucket, and recording files and its
+    /// index entry, releasing the content they referenced. Returns `None` if
+    /// the session is unknown.
+    pub async fn delete_session(&self, session_id: &str) -> Result<Option<DeletionReport>, StorageError> {
+        let _guard = self.session_lock(session_id).lock().await;
+        let index = self.load_session_index(session_id)?;
+        let recordings = self.list_recording_batches(session_id).await?;
+        if index.is_none() && recordings.is_empty() {
+            return Ok(None);
+        }
+        let index = index.unwrap_or_default();
+        let mut report = DeletionReport::default();
+        let mut hosts = HostTally::default();
+        
+        let unique_paths: HashSet<&String> = index.paths.iter().collect();
+        for path in unique_paths {
+            let data = match fs::read(path).await {
+                Ok(data) => data,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+            
+            if let Ok(page_fetch) = decode_page_fetch::<PageFetchIndex>(&data) {
+                self.unindex_requests(&page_fetch.requests)?;
+                for request in &page_fetch.requests {
+                    hosts.add(request);
+                    if let Some(hash) = &request.request_body_hash {
+                        report.bytes_reclaimed += self.release_content(hash, session_id).await?;
+                    }
+                    if let Some(hash) = request.response.as_ref().and_then(|r| r.body_hash.as_ref()) {
+                        report.bytes_reclaimed += self.release_content(hash, session_id).await?;
+                    }
+                }
+                for hash in page_fetch.screenshot_hash.iter().chain(&page_fetch.favicon_hash) {
+                    report.bytes_reclaimed += self.release_content(hash, session_id).await?;
+                }
+                for hash in page_fetch.frame_hashes() {
+                    report.bytes_reclaimed += self.release_content(hash, session_id).await?;
+                }
+            }
+            
+            report.add_removed(remove_file_if_exists(Path::new(path)).await?);
+            if let Some(parent) = Path::new(path).parent() {
+                // Only succeeds once the session directory is empty
+                let _ = fs::remove_dir(parent).await;
+            }
+        }
+        
+        for entry in index.buckets.values() {
+            report.add_removed(remove_file_if_exists(Path::new(&entry.path)).await?);
+        }
+        // Only succeeds once no bucket files remain
+        let _ = fs::remove_dir(self.dir("sessions").join("buckets").join(session_id)).await;
+        
+        for recording in &recordings {
+            let batch: RecordingBatch = serde_json::from_slice(&fs::read(&recording.path).await?)?;
+            // Each batch took one reference per distinct asset
+            let mut refs = HashSet::new();
+            for event in &batch.events {
+                crate::rrweb::collect_refs(event, &mut refs);
+            }
+            for hash in refs {
+                report.bytes_reclaimed += self.release_content(&hash, session_id).await?;
+            }
+            for hash in batch.screenshot_hash.iter().chain(&batch.favicon_hash) {
+                report.bytes_reclaimed += self.release_content(hash, session_id).await?;
+            }
+            report.add_removed(remove_file_if_exists(Path::new(&recording.path)).await?);
+        }
+        let _ = fs::remove_dir(self.dir("recordings").join(session_id)).await;
+        
+        self.sessions_db.remove(session_id)?;
+        self.forget_hosts(&hosts)?;
+        
+        Ok(Some(report))
+    }
+    
+    /// Drops one reference held by `session_id`, deleting the object once no
+    /// session (or, for untracked objects, no reference) remains.
+    /// Returns the compressed bytes freed, if any.
+    pub async fn release_content(&self, hash: &str, session_id: &str) -> Result<u64, StorageError> {
+        self.drop_reference(hash, Some(session_id)).await
+    }
+    
+    /// Undoes a reference taken by `store_content` for a batch that's being
+    /// rolled back. `keep_session` leaves the session in the object's set
+    /// because it already referenced the object before the batch.
+    pub async fn rollback_reference(&self, hash: &str, session_id: &str, keep_session: bool) -> Result<(), StorageError> {
+        self.drop_reference(hash, (!keep_session).then_some(session_id)).await?;
+        Ok(())
+    }
+    
+    /// Drops a page's reference to an image it no longer shows. Other pages
+    /// of the session may still show it, so the session stays in the
+    /// object's set, but the object is freed once its count runs out.
+    pub async fn release_replaced(&self, hash: &str) -> Result<u64, StorageError> {
+        self.drop_reference_where(hash, None, |metadata| metadata.reference_count > 0).await
+    }
+    
+    /// Decrements the reference count, removes `session_id` from the object's
+    /// sessions if given, and frees the object once nothing references it.
+    /// Returns the compressed size freed.
+    async fn drop_reference(&self, hash: &str, session_id: Option<&str>) -> Result<u64, StorageError> {
+        self.drop_reference_where(hash, session_id, |metadata| match &metadata.sessions {
+            Some(sessions) => !sessions.is_empty(),
+            None => metadata.reference_count > 0,
+        }).await
+    }
+    
+    /// `drop_reference`, keeping the object while `referenced` holds.
+    async fn drop_reference_where(
+        &self,
+        hash: &str,
+        session_id: Option<&str>,
+        referenced: impl Fn(&ContentMetadata) -> bool,
+    ) -> Result<u64, StorageError> {
+        let _guard = self.content_lock(hash).lock().await;
+        let updated = self.update_content_metadata(hash, |metadata| {
+            metadata.reference_count = metadata.reference_count.saturating_sub(1);
+            if let (Some(sessions), Some(session_id)) = (metadata.sessions.as_mut(), session_id) {
+                sessions.remove(session_id);
+            }
+            referenced(metadata)
+        })?;
+        let Some((metadata, false)) = updated else {
+            return Ok(0);
+        };
+        
+        self.content_cache.remove(hash);
+        // The pack is append-only, so a packed object's bytes aren't reclaimed
+        self.packed_db.remove(hash)?;
+        
+        let mut freed = match &metadata.chunks {
+            Some(chunks) => self.release_chunks(chunks).await?,
+            None => 0,
+        };
+        freed += self.content_store.delete(hash_key(hash)).await?;
+        Ok(freed)
+    }
+    
+    /// Takes a reference on a stored object. Returns false, changing nothing,
+    /// if the object isn't stored.
+    fn increment_ref_count(&self, hash: &str, content_type: Option<&str>, session_id: &str) -> Result<bool, StorageError> {
+        let updated = self.update_content_metadata(hash, |metadata| {
+            metadata.reference_count += 1;
+            // Untracked objects stay untracked: earlier sessions are unknown
+            if let Some(sessions) = metadata.sessions.as_mut() {
+                sessions.insert(session_id.to_string());
+            }
+            if metadata.content_type.is_none() {
+                metadata.content_type = content_type.map(normalize_content_type);
+            }
+            true
+        })?;
+        Ok(updated.is_some())
+    }
+    
+    /// Applies `change` to an object's metadata with compare-and-swap,
+    /// retrying from the fresh value whenever another writer got there first,
+    /// so concurrent reference changes are never lost. `change` returns false
+    /// to remove the entry. Returns the metadata as changed and whether it was
+    /// kept, or `None` if the object isn't stored.
+    fn update_content_metadata(
+        &self,
+        hash: &str,
+        mut change: impl FnMut(&mut ContentMetadata) -> bool,
+    ) -> Result<Option<(ContentMetadata, bool)>, StorageError> {
+        loop {
+            let Some(current) = self.content_db.get(hash)? else {
+                return Ok(None);
+            };
+            let mut metadata: ContentMetadata = decode_metadata(&current)?;
+            let before = Some((metadata.size, metadata.compressed_size));
+            let keep = change(&mut metadata);
+            let updated = if keep {
+                Some(self.encode_metadata(&metadata)?)
+            } else {
+                None
+            };
+            if self.content_db.compare_and_swap(hash, Some(current), updated)?.is_ok() {
+                let after = keep.then_some((metadata.size, metadata.compressed_size));
+                self.adjust_content_totals(before, after);
+                self.reindex_size(hash, before.map(|(_, compressed)| compressed), after.map(|(_, compressed)| compressed))?;
+                return Ok(Some((metadata, keep)));
+            }
+        }
+    }
+    
+    fn encode_metadata<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, StorageError> {
+        let json = serde_json::to_vec(value)?;
+        if !self.config.compress_metadata {
+            return Ok(json);
+        }
+        
+        let compressed = encode_all(&json[..], COMPRESSION_LEVEL)?;
+        if compressed.len() + 1 >= json.len() {
+            // Tiny values don't shrink; plain JSON is still readable
+            return Ok(json);
+        }
+        
+        let mut encoded = Vec::with_capacity(compressed.len() + 1);
+        encoded.push(METADATA_ZSTD_MARKER);
+        encoded.extend_from_slice(&compressed);
+        Ok(encoded)
+    }
+    
+    /// Moves content files written under a different fanout depth into the
+    /// layout for the configured depth. Paths are derived from the hash, so no
+    /// metadata changes; an interrupted run simply picks up where it left off.
+    /// A no-op with a remote content store, which has no directories.
+    pub async fn rebalance_content(&self) -> Result<RebalanceReport, StorageError> {
+        let _guard = self.rebalance_lock.try_lock()
+            .map_err(|_| StorageError::Busy("Rebalance already in progress"))?;
+        
+        let mut report = RebalanceReport::default();
+        let root = self.dir("content");
+        let mut dirs = vec![root.clone()];
+        
+        while let Some(dir) = dirs.pop() {
+            let mut entries = fs::read_dir(&dir).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                let path = entry.path();
+                if entry.file_type().await?.is_dir() {
+                    if !self.is_other_namespace(&root, &path) {
+                        dirs.push(path);
+                    }
+                    continue;
+                }
+                
+                let hash = match path.file_name().and_then(|n| n.to_str()).and_then(|n| n.strip_suffix(".zst")) {
+                    Some(hash) if hash.len() >= MAX_FANOUT_DEPTH * 2 => hash.to_string(),
+                    _ => continue,
+                };
+                report.scanned += 1;
+                
+                let Some(target) = self.content_store.local_path(&hash) else {
+                    continue;
+                };
+                if target == path {
+                    continue;
+                }
+                
+                // Not `exists`, which would find this very file at its old depth
+                if fs::try_exists(&target).await? {
+                    // A previous run already placed it; drop the stale copy
+                    fs::remove_file(&path).await?;
+                } else {
+                    fs::create_dir_all(target.parent().unwrap()).await?;
+                    fs::rename(&path, &target).await?;
+                }
+                report.moved += 1;
+                
+                if report.moved % REBALANCE_BATCH == 0 {
+                    tokio::time::sleep(REBALANCE_PAUSE).await;
+                }
+            }
+        }
+        
+        self.remove_empty_dirs(&root).await?;
+        // Everything is at the configured depth now; other depths stop being
+        // searched after a restart
+        self.meta_db.insert(FANOUT_DEPTHS_KEY, serde_json::to_vec(&[self.config.fanout_depth])?)?;
+        
+        Ok(report)
+    }
+    
+    /// Removes empty content directories left behind by deletions and, with
+    /// `pack_below_bytes` set, appends smaller content files to the pack file
+    /// and deletes them. Packed objects are served by `retrieve_content` as
+    /// before.
+    pub async fn compact(&self) -> Result<CompactionReport, StorageError> {
+        // Shares the rebalance lock: both move content files around
+        let _guard = self.rebalance_lock.try_lock()
+            .map_err(|_| StorageError::Busy("Rebalance, compaction, or recompression already in progress"))?;
+        
+        let mut report = CompactionReport::default();
+        let root = self.dir("content");
+        if let Some(threshold) = self.config.pack_below_bytes {
+            let (packed, bytes) = self.pack_small_files(&root, threshold).await?;
+            report.objects_packed = packed;
+            report.bytes_packed = bytes;
+        }
+        report.empty_dirs_removed = self.remove_empty_dirs(&root).await?;
+        
+        Ok(report)
+    }
+    
+    /// Re-encodes every loose content file at `level`, keeping the new file
+    /// only when it's smaller. Hashes cover the plaintext, so nothing that
+    /// references the objects changes.
+    pub async fn recompress(&self, level: i32) -> Result<RecompressReport, StorageError> {
+        // Shares the rebalance lock: a file moved mid-recompress would be rewritten at its old path
+        let _guard = self.rebalance_lock.try_lock()
+            .map_err(|_| StorageError::Busy("Rebalance, compaction, or recompression already in progress"))?;
+        
+        let mut report = RecompressReport { level, ..Default::default() };
+        for key in self.content_entries().keys() {
+            let key = key?;
+            let Some(hash) = std::str::from_utf8(&key).ok().filter(|k| HashAlgorithm::of(k).is_some()) else {
+                continue;
+            };
+            let (hash, hash_only) = (hash.to_string(), hash_key(hash).to_string());
+            report.scanned += 1;
+            
+            // Packed and chunked objects have no object of their own
+            let Some(compressed) = self.content_store.get(&hash_only).await? else {
+                report.skipped += 1;
+                continue;
+            };
+            let before = compressed.len();
+            
+            let recompressed = tokio::task::spawn_blocking(move || -> std::io::Result<Vec<u8>> {
+                encode_all(&decode_all(&compressed[..])?[..], level)
+            }).await.map_err(|e| format!("Recompression task failed: {}", e))?;
+            let recompressed = match recompressed {
+                Ok(recompressed) if recompressed.len() < before => recompressed,
+                Ok(_) => {
+                    report.skipped += 1;
+                    continue;
+                }
+                Err(e) => {
+                    tracing::error!("Failed to recompress {}: {}", hash_only, e);
+                    report.skipped += 1;
+                    continue;
+                }
+            };
+            
+            let _object_guard = self.content_lock(&hash).lock().await;
+            let permit = self.write_permit().await;
+            self.content_store.put(&hash_only, &recompressed).await?;
+            drop(permit);
+            let updated = self.update_content_metadata(&hash, |metadata| {
+                metadata.compressed_size = recompressed.len();
+                true
+            })?;
+            if updated.is_none() {
+                // Released while we were re-encoding; don't leave the new object behind
+                self.content_store.delete(&hash_only).await?;
+                continue;
+            }
+            
+            report.recompressed += 1;
+            report.bytes_before += before as u64;
+            report.bytes_after += recompressed.len() as u64;
+        }
+        
+        Ok(report)
+    }
+    
+    async fn pack_small_files(&self, root: &Path, threshold: u64) -> Result<(usize, u64), StorageError> {
+        let mut candidates = Vec::new();
+        let mut dirs = vec![root.to_path_buf()];
+        while let Some(dir) = dirs.pop() {
+            let mut entries = fs::read_dir(&dir).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                let path = entry.path();
+                if entry.file_type().await?.is_dir() {
+                    if !self.is_other_namespace(root, &path) {
+                        dirs.push(path);
+                    }
+                    continue;
+                }
+                let Some(hash) = path.file_name().and_then(|n| n.to_str()).and_then(|n| n.strip_suffix(".zst")) else {
+                    continue;
+                };
+                // Orphans stay loose for a reconcile to deal with
+                if entry.metadata().await?.len() < threshold {
+                    if let Some(hash) = self.stored_hash(hash)? {
+                        candidates.push((hash, path));
+                    }
+                }
+            }
+        }
+        if candidates.is_empty() {
+            return Ok((0, 0));
+        }
+        
+        let mut pack = fs::OpenOptions::new().create(true).append(true).open(self.pack_path()).await?;
+        let mut offset = pack.metadata().await?.len();
+        let mut entries = Vec::with_capacity(candidates.len());
+        for (hash, path) in &candidates {
+            let compressed = fs::read(path).await?;
+            pack.write_all(&compressed).await?;
+            entries.push((hash, PackEntry { offset, len: compressed.len() as u64 }));
+            offset += compressed.len() as u64;
+        }
+        // Index entries only point at synced bytes; a crash before this
+        // leaves unreferenced bytes at the end of the pack, nothing worse
+        pack.flush().await?;
+        pack.sync_all().await?;
+        
+        let mut bytes = 0;
+        let mut packed = Vec::new();
+        for ((hash, entry), (_, path)) in entries.into_iter().zip(&candidates) {
+            // Skip objects released while the pack was being written
+            if self.content_db.contains_key(hash)? {
+                self.packed_db.insert(hash.as_bytes(), serde_json::to_vec(&entry)?)?;
+                packed.push(path);
+                bytes += entry.len;
+            }
+        }
+        // Loose files go only once the index pointing into the pack is durable
+        self.packed_db.flush_async().await?;
+        for path in &packed {
+            remove_file_if_exists(path).await?;
+        }
+        Ok((packed.len(), bytes))
+    }
+    
+    /// Removes every empty directory under `root`, returning how many.
+    async fn remove_empty_dirs(&self, root: &Path) -> Result<usize, StorageError> {
+        // Collect directories depth-first, then remove from the deepest up
+        let mut all_dirs = Vec::new();
+        let mut stack = vec![root.to_path_buf()];
+        while let Some(dir) = stack.pop() {
+            let mut entries = fs::read_dir(&dir).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                if entry.file_type().await?.is_dir() && !self.is_other_namespace(root, &entry.path()) {
+                    stack.push(entry.path());
+                    all_dirs.push(entry.path());
+                }
+            }
+        }
+        
+        let mut removed = 0;
+        for dir in all_dirs.iter().rev() {
+            // Fails harmlessly when the directory is not empty
+            if fs::remove_dir(dir).await.is_ok() {
+                removed += 1;
+            }
+        }
+        
+        Ok(removed)
+    }
+    
+    /// Reads counters only, so it costs the same however much is stored.
+    /// Disk usage is as of the last `scan_disk_usage`.
+    pub fn get_stats(&self) -> StorageStats {
+        // Not `content_db.len()`, which walks the tree
+        let content_count = self.counters.content_objects.load(Ordering::Relaxed) as usize;
+        let cache_size = self.content_cache.len();
+        let cache_hits = self.counters.cache_hits.load(Ordering::Relaxed);
+        let cache_misses = self.counters.cache_misses.load(Ordering::Relaxed);
+        let total_size = self.counters.content_bytes.load(Ordering::Relaxed);
+        let compressed_size = self.counters.content_compressed_bytes.load(Ordering::Relaxed);
+        let disk_usage = *self.disk_usage.lock().unwrap_or_else(|e| e.into_inner());
+        
+        StorageStats {
+            schema_version: SCHEMA_VERSION,
+            content_count,
+            cache_size,
+            cache_hits,
+            cache_misses,
+            cache_hit_ratio: if cache_hits + cache_misses > 0 {
+                cache_hits as f64 / (cache_hits + cache_misses) as f64
+            } else {
+                0.0
+            },
+            total_size,
+            compressed_size,
+            compression_ratio: if total_size > 0 {
+                compressed_size as f64 / total_size as f64
+            } else {
+                1.0
+            },
+            disk_bytes: disk_usage.map_or(0, |usage| usage.disk_bytes),
+            orphan_files: disk_usage.map_or(0, |usage| usage.orphan_files),
+            disk_scanned_at: disk_usage.map(|usage| usage.scanned_at),
+            writes_in_progress: write_permit_count(&self.config) - self.write_permits.available_permits(),
+            write_queue_depth: self.counters.writes_queued.load(Ordering::Relaxed),
+        }
+    }
+    
+    /// Sets the content totals from a full scan of `content_db`.
+    fn count_content(&self) -> Result<(), StorageError> {
+        let (mut objects, mut total_size, mut compressed_size) = (0u64, 0u64, 0u64);
+        for item in self.content_entries() {
+            let (_, value) = item?;
+            objects += 1;
+            if let Ok(metadata) = decode_metadata::<ContentMetadata>(&value) {
+                total_size += metadata.size as u64;
+                compressed_size += metadata.compressed_size as u64;
+            }
+        }
+        self.counters.content_objects.store(objects, Ordering::Relaxed);
+        self.counters.content_bytes.store(total_size, Ordering::Relaxed);
+        self.counters.content_compressed_bytes.store(compressed_size, Ordering::Relaxed);
+        Ok(())
+    }
+    
+    /// Moves the content totals from an object's `(size, compressed_size)`
+    /// before a change to after it, `None` meaning it isn't stored.
+    fn adjust_content_totals(&self, before: Option<(usize, usize)>, after: Option<(usize, usize)>) {
+        let adjust = |counter: &AtomicU64, before: usize, after: usize| {
+            if after >= before {
+                counter.fetch_add((after - before) as u64, Ordering::Relaxed);
+            } else {
+                counter.fetch_sub((before - after) as u64, Ordering::Relaxed);
+            }
+        };
+        adjust(&self.counters.content_objects, before.is_some() as usize, after.is_some() as usize);
+        let (before, after) = (before.unwrap_or_default(), after.unwrap_or_default());
+        adjust(&self.counters.content_bytes, before.0, after.0);
+        adjust(&self.counters.content_compressed_bytes, before.1, after.1);
+    }
+    
+    /// Walks the data directory for the allocated bytes and orphaned content
+    /// files `get_stats` reports. Takes time in proportion to what's stored.
+    pub async fn scan_disk_usage(&self) -> Result<(), StorageError> {
+        // Actual footprint, including block rounding, orphans and sled itself
+        let mut orphan_files = 0;
+        // Tenants share the database, so `metadata` counts toward each
+        let content_root = self.dir("content");
+        let skip = |dir: &Path| self.is_other_namespace(&content_root, dir);
+        let mut disk_bytes = dir_disk_usage(&content_root, skip, |path| {
+            let hash = path.file_name().and_then(|n| n.to_str()).and_then(|n| n.strip_suffix(".zst"));
+            if let Some(hash) = hash {
+                if !matches!(self.stored_hash(hash), Ok(Some(_))) {
+                    orphan_files += 1;
+                }
+            }
+        }).await?;
+        let sessions_root = self.dir("sessions");
+        let skip = |dir: &Path| self.is_other_namespace(&sessions_root, dir);
+        disk_bytes += dir_disk_usage(&sessions_root, skip, |_| {}).await?;
+        let chunks_root = self.dir("chunks");
+        let skip = |dir: &Path| self.is_other_namespace(&chunks_root, dir);
+        disk_bytes += dir_disk_usage(&chunks_root, skip, |_| {}).await?;
+        disk_bytes += dir_disk_usage(&self.base_path.join("metadata"), |_| false, |_| {}).await?;
+        
+        *self.disk_usage.lock().unwrap_or_else(|e| e.into_inner()) = Some(DiskUsage {
+            disk_bytes,
+            orphan_files,
+            scanned_at: chrono::Utc::now(),
+        });
+        Ok(())
+    }
+    
+    pub async fn get_stats_by_type(&self) -> Result<BTreeMap<String, TypeStats>, StorageError> {
+        let mut by_type: BTreeMap<String, TypeStats> = BTreeMap::new();
+        
+        for (_, value) in self.content_entries().flatten() {
+            if let Ok(metadata) = decode_metadata::<ContentMetadata>(&value) {
+                let bucket = by_type
+                    .entry(metadata.content_type.unwrap_or_else(|| "unknown".to_string()))
+                    .or_default();
+                bucket.count += 1;
+                bucket.total_size += metadata.size as u64;
+                bucket.compressed_size += metadata.compressed_size as u64;
+            }
+        }
+        
+        for bucket in by_type.values_mut() {
+            bucket.compression_ratio = if bucket.total_size > 0 {
+                bucket.compressed_size as f64 / bucket.total_size as f64
+            } else {
+                1.0
+            };
+        }
+        
+        Ok(by_type)
+    }
+    
+    /// Up to `n` objects by descending `compressed_size`, after skipping
+    /// `offset`, limited to sizes within `min..=max`. Walks the size index
+    /// from the top, so it reads only what it returns and skips.
+    pub fn largest_content(&self, n: usize, offset: usize, min: Option<u64>, max: Option<u64>) -> Result<Vec<LargeObject>, StorageError> {
+        let start = size_index_key(min.unwrap_or(0) as usize, &[]);
+        let entries = match max.and_then(|max| max.checked_add(1)) {
+            Some(end) => self.size_index_db.range(start..size_index_key(end as usize, &[])),
+            None => self.size_index_db.range(start..),
+        };
+        
+        let mut objects = Vec::with_capacity(n.min(1024));
+        let mut skipped = 0;
+        for key in entries.keys().rev() {
+            if objects.len() >= n {
+                break;
+            }
+            let key = key?;
+            let (size, hash) = key.split_at(8);
+            let Some(value) = self.content_db.get(hash)? else {
+                continue;
+            };
+            let metadata: ContentMetadata = decode_metadata(&value)?;
+            // A concurrent update can briefly leave an entry for the old size
+            if metadata.compressed_size as u64 != u64::from_be_bytes(size.try_into().expect("8-byte prefix")) {
+                continue;
+            }
+            if skipped < offset {
+                skipped += 1;
+                continue;
+            }
+            objects.push(LargeObject {
+                hash: String::from_utf8_lossy(hash).into_owned(),
+                size: metadata.size,
+                compressed_size: metadata.compressed_size,
+                content_type: metadata.content_type,
+                reference_count: metadata.reference_count,
+            });
+        }
+        Ok(objects)
+    }
+}
+
+/// One stored object as reported by [`Storage::largest_content`].
+#[derive(Debug, Serialize)]
+pub struct LargeObject {
+    pub hash: String,
+    pub size: usize,
+    pub compressed_size: usize,
+    pub content_type: Option<String>,
+    pub reference_count: u32,
+}
+
+/// Compression figures for all content sharing a `content_type`.
+#[derive(Debug, Default, Serialize)]
+pub struct TypeStats {
+    pub count: usize,
+    pub total_size: u64,
+    pub compressed_size: u64,
+    pub compression_ratio: f64,
+}
+
+/// Sums the allocated size of every file under `root`, calling `visit` on
+/// each. A missing `root` counts as empty.
+async fn dir_disk_usage(root: &Path, skip_dir: impl Fn(&Path) -> bool, mut visit: impl FnMut(&Path)) -> Result<u64, StorageError> {
+    let mut total = 0;
+    let mut dirs = vec![root.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let mut entries = match fs::read_dir(&dir).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
+        while let Some(entry) = entries.next_entry().await? {
+            let metadata = entry.metadata().await?;
+            if metadata.is_dir() {
+                if !skip_dir(&entry.path()) {
+                    dirs.push(entry.path());
+                }
+                continue;
+            }
+            total += allocated_bytes(&metadata);
+            visit(&entry.path());
+        }
+    }
+    Ok(total)
+}
+
+#[cfg(unix)]
+fn allocated_bytes(metadata: &std::fs::Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    metadata.blocks() * 512
+}
+
+#[cfg(not(unix))]
+fn allocated_bytes(metadata: &std::fs::Metadata) -> u64 {
+    metadata.len()
+}
+
+/// Deletes `path`, returning its size. A missing file counts as 0 bytes.
+pub(crate) async fn remove_file_if_exists(path: &Path) -> Result<u64, StorageError> {
+    let size = match fs::metadata(path).await {
+        Ok(metadata) => metadata.len(),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e.into()),
+    };
+    match fs::remove_file(path).await {
+        Ok(()) => Ok(size),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(0),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Decodes a stored object. A failure means the stored bytes are bad, not
+/// that reading them failed.
+fn decompress(hash: &str, compressed: &[u8]) -> Result<Vec<u8>, StorageError> {
+    decode_all(compressed).map_err(|e| StorageError::Corrupt(format!("Content {} doesn't decode: {}", hash, e)))
+}
+
+/// True for a write that failed for lack of disk space or quota.
+fn is_disk_full(error: &std::io::Error) -> bool {
+    matches!(error.kind(), std::io::ErrorKind::StorageFull | std::io::ErrorKind::QuotaExceeded)
+}
+
+/// Tenant names start with a letter and run 3-64 characters of letters,
+/// digits, `-` and `_`, so they never collide with the default tenant's
+/// date, fanout, or `buckets` directories.
+pub fn is_valid_tenant(name: &str) -> bool {
+    (3..=64).contains(&name.len())
+        && name.starts_with(|c: char| c.is_ascii_alphabetic())
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        && name != "buckets"
+}
+
+/// Smallest capacity, doubling from `minimum`, that holds `items` below the
+/// rebuild threshold.
+fn bloom_capacity_for(items: usize, minimum: usize) -> usize {
+    let mut capacity = minimum.max(1);
+    while items as f64 >= capacity as f64 * BLOOM_GROW_AT {
+        capacity = capacity.saturating_mul(2);
+    }
+    capacity
+}
+
+/// True if `name` can be used as a single directory name without escaping
+/// its parent.
+pub fn is_safe_path_component(name: &str) -> bool {
+    !name.is_empty()
+        && name != "."
+        && name != ".."
+        && !name.contains(['/', '\\', '\0'])
+}
+
+/// Returns the JSON bytes of a sled value, decompressing if it carries the
+/// zstd marker. Plain JSON always starts with `{` or `[`, so it can't collide.
+fn metadata_json(data: &[u8]) -> Result<std::borrow::Cow<'_, [u8]>, StorageError> {
+    match data.split_first() {
+        Some((&METADATA_ZSTD_MARKER, compressed)) => decode_all(compressed)
+            .map(Into::into)
+            .map_err(|e| StorageError::Corrupt(format!("Undecodable compressed metadata: {}", e))),
+        _ => Ok(data.into()),
+    }
+}
+
+fn decode_metadata<T: serde::de::DeserializeOwned>(data: &[u8]) -> Result<T, StorageError> {
+    serde_json::from_slice(&metadata_json(data)?)
+        .map_err(|e| StorageError::Corrupt(format!("Undecodable metadata: {}", e)))
+}
+
+fn encode_page_fetch(page_fetch: &PageFetchIndex, format: PageFetchFormat) -> Result<Vec<u8>, StorageError> {
+    match format {
+        PageFetchFormat::Json => Ok(serde_json::to_vec_pretty(page_fetch)?),
+        PageFetchFormat::MessagePack => {
+            let mut encoded = vec![PAGE_FETCH_MSGPACK_MARKER];
+            encoded.extend(crate::msgpack::encode(&serde_json::to_value(page_fetch)?));
+            Ok(encoded)
+        }
+    }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct PageFetchIndex {
-    pub session_id: String,
-    pub page_url: String,
-    pub timestamp: i64,
-    pub navigation_id: String,
-    pub requests: Vec<ArchivedRequest>,
-    pub password_hashes: Vec<String>,
+/// Reads a page fetch file in either `PageFetchFormat`, telling them apart
+/// by the first byte.
+fn decode_page_fetch<T: serde::de::DeserializeOwned>(data: &[u8]) -> Result<T, StorageError> {
+    match data.split_first() {
+        Some((&PAGE_FETCH_MSGPACK_MARKER, encoded)) => {
+            let value = crate::msgpack::decode(encoded)
+                .map_err(|e| StorageError::Corrupt(format!("Undecodable page fetch: {}", e)))?;
+            Ok(serde_json::from_value(value)?)
+        }
+        _ => Ok(serde_json::from_slice(data)?),
+    }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct ArchivedRequest {
-    pub request_id: String,
-    pub timestamp: i64,
-    pub method: String,
-    pub url: String,
-    pub request_headers: Vec<(String, String)>,
-    pub request_body_hash: Option<String>,
-    pub request_body_size: Option<usize>,
-    pub response: Option<ArchivedResponse>,
+/// Writes to a temp file beside `path` and renames it into place, so readers
+/// never observe a partially written file.
+pub(crate) async fn write_atomic(path: &Path, data: &[u8]) -> Result<(), StorageError> {
+    let parent = path.parent().ok_or("Path has no parent directory")?;
+    fs::create_dir_all(parent).await?;
+    
+    let file_name = path.file_name().and_then(|n| n.to_str()).unwrap_or_default();
+    let tmp_path = parent.join(format!(".{}.{}.tmp", file_name, uuid::Uuid::new_v4()));
+    
+    let result = async {
+        let mut file = fs::File::create(&tmp_path).await?;
+        file.write_all(data).await?;
+        // Tokio writes in the background; a failed write (such as ENOSPC)
+        // only surfaces on the next flush, not on `sync_all`
+        file.flush().await?;
+        file.sync_all().await?;
+        fs::rename(&tmp_path, path).await
+    }.await;
+    
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path).await;
+    }
+    Ok(result?)
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct ArchivedResponse {
-    pub status_code: u16,
-    pub headers: Vec<(String, String)>,
-    pub body_hash: Option<String>,
-    pub body_size: Option<usize>,
-    pub body_type: Option<String>,
+/// Path of one kind of storage (`content`, `sessions`, ...) relative to the
+/// data directory: `{kind}/{tenant}`, or just `{kind}` for the default tenant.
+/// Remote stores use it as their key prefix.
+fn namespace(kind: &str, tenant: Option<&str>) -> String {
+    match tenant {
+        Some(tenant) => format!("{}/{}", kind, tenant),
+        None => kind.to_string(),
+    }
 }
 
-pub type StorageError = Box<dyn std::error::Error + Send + Sync>;
+/// The configured bucket when there is one, else files under the data directory.
+/// Local files are looked for at `fanout_depth` and then `fallback_depths`.
+fn open_store(config: &StorageConfig, base_path: &Path, namespace: String, fanout_depth: usize, fallback_depths: &[usize]) -> Result<Box<dyn ContentStore>, StorageError> {
+    #[cfg(feature = "s3")]
+    if let Some(s3) = &config.s3 {
+        return Ok(Box::new(crate::s3::S3Store::new(s3.clone(), namespace)?));
+    }
+    #[cfg(not(feature = "s3"))]
+    let _ = config;
+    Ok(Box::new(LocalStore::new(base_path.join(namespace), fanout_depth).with_fallback_depths(fallback_depths)))
+}
 
-pub struct Storage {
-    base_path: PathBuf,
-    content_db: Arc<sled::Db>,
-    bloom_filter: Arc<tokio::sync::RwLock<Bloom<String>>>,
-    content_cache: Arc<DashMap<String, Vec<u8>>>,
+/// The schema version recorded at `path`. Without a record, an existing
+/// store predates versioning (0) and a new one starts at the current version.
+async fn read_schema_version(path: &Path, existing_store: bool) -> Result<u32, StorageError> {
+    match fs::read_to_string(path).await {
+        Ok(text) => text.trim().parse()
+            .map_err(|_| StorageError::Corrupt(format!("Unreadable schema version {:?}", text.trim()))),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(if existing_store { 0 } else { SCHEMA_VERSION }),
+        Err(e) => Err(e.into()),
+    }
 }
 
-impl Storage {
-    pub async fn new(base_path: impl AsRef<Path>) -> Result<Self, StorageError> {
-        let base_path = base_path.as_ref().to_path_buf();
+fn write_permit_count(config: &StorageConfig) -> usize {
+    config.max_concurrent_writes.unwrap_or(tokio::sync::Semaphore::MAX_PERMITS)
+}
+
+/// `size_index_db` key: the big-endian `compressed_size`, so keys sort by
+/// it, then the hash.
+fn size_index_key(compressed_size: usize, hash: &[u8]) -> Vec<u8> {
+    let mut key = (compressed_size as u64).to_be_bytes().to_vec();
+    key.extend_from_slice(hash);
+    key
+}
+
+/// A hash without its algorithm prefix, as content stores key objects.
+fn hash_key(hash: &str) -> &str {
+    match HashAlgorithm::of(hash) {
+        Some(algorithm) => &hash[algorithm.prefix().len()..],
+        None => hash,
+    }
+}
+
+/// `hash_key` for hashes from requests, which are checked before they reach
+/// a path or URL.
+fn checked_hash_key(hash: &str) -> Result<&str, StorageError> {
+    let hash_only = hash_key(hash);
+    if hash_only.len() < MAX_FANOUT_DEPTH * 2 || !hash_only.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(StorageError::Invalid(format!("Invalid content hash: {:?}", hash)));
+    }
+    Ok(hash_only)
+}
+
+/// Stripe of a hash-keyed lock array, from the hash's first byte.
+fn lock_stripe(hash: &str) -> usize {
+    let hash_only = hash_key(hash);
+    let first_byte = hash_only.get(..2).and_then(|hex| u8::from_str_radix(hex, 16).ok()).unwrap_or(0);
+    first_byte as usize % LOCK_STRIPES
+}
+
+/// Navigation ID of the page fetch stored at `path`, or `None` if there's
+/// no file there.
+async fn stored_navigation_id(path: &Path) -> Result<Option<String>, StorageError> {
+    #[derive(Deserialize)]
+    struct NavigationOnly {
+        navigation_id: String,
+    }
+    
+    match fs::read(path).await {
+        Ok(data) => Ok(Some(decode_page_fetch::<NavigationOnly>(&data)?.navigation_id)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Puts a client-reported protocol version in status-line form. Browsers
+/// report ALPN IDs (`h2`, `h3`) or lowercase names (`http/1.1`).
+pub fn normalize_http_version(raw: &str) -> Option<String> {
+    let raw = raw.trim();
+    if raw.is_empty() || raw.chars().any(|c| c.is_ascii_control() || c == ' ') {
+        return None;
+    }
+    Some(match raw.to_ascii_lowercase().as_str() {
+        "h2" | "h2c" | "http/2.0" => "HTTP/2".to_string(),
+        "h3" | "http/3.0" => "HTTP/3".to_string(),
+        lower => lower.to_ascii_uppercase(),
+    })
+}
+
+/// Strips parameters such as `; charset=utf-8` so variants bucket together.
+fn normalize_content_type(content_type: &str) -> String {
+    content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_lowercase()
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct StorageStats {
+    /// Version of the on-disk layout; see `SCHEMA_VERSION`.
+    pub schema_version: u32,
+    pub content_count: usize,
+    pub cache_size: usize,
+    /// `retrieve_content` calls served from the cache since startup.
+    pub cache_hits: u64,
+    pub cache_misses: u64,
+    /// Hits over all lookups; 0 before the first lookup.
+    pub cache_hit_ratio: f64,
+    pub total_size: u64,
+    pub compressed_size: u64,
+    pub compression_ratio: f64,
+    /// Bytes allocated on disk under `content/`, `sessions/` and `metadata/`.
+    pub disk_bytes: u64,
+    /// Content files with no metadata entry.
+    pub orphan_files: usize,
+    /// When `disk_bytes` and `orphan_files` were measured; `None` (and both
+    /// 0) before the first scan.
+    pub disk_scanned_at: Option<chrono::DateTime<chrono::Utc>>,
+    /// Object writes holding a permit right now, at most
+    /// `max_concurrent_writes`.
+    pub writes_in_progress: usize,
+    /// Object writes waiting for a permit.
+    pub write_queue_depth: u64,
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    
+    async fn open(dir: &tempfile::TempDir) -> Storage {
+        open_with(dir, StorageConfig::default()).await
+    }
+    
+    async fn open_with(dir: &tempfile::TempDir, config: StorageConfig) -> Storage {
+        open_at(dir.path(), config).await
+    }
+    
+    /// Opens storage under `path`, waiting out the database lock that the
+    /// background flusher of a just-dropped instance can hold for a moment.
+    pub(crate) async fn open_at(path: &Path, config: StorageConfig) -> Storage {
+        for _ in 0..100 {
+            match Storage::new(path, config.clone()).await {
+                Err(StorageError::Sled(sled::Error::Io(e))) if e.to_string().contains("could not acquire lock") => {
+                    tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+                }
+                storage => return storage.unwrap(),
+            }
+        }
+        panic!("Database under {} stayed locked", path.display());
+    }
+    
+    /// Writes objects to `store` from now on, to simulate a failing backend.
+    pub(crate) fn set_content_store(storage: &mut Storage, store: Box<dyn ContentStore>) {
+        storage.content_store = store;
+    }
+    
+    #[tokio::test]
+    async fn concurrent_stores_count_every_reference() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = Arc::new(open(&dir).await);
+        let data = b"shared body stored by every task".to_vec();
         
-        // Create directory structure
-        fs::create_dir_all(&base_path).await?;
-        fs::create_dir_all(base_path.join("sessions")).await?;
-        fs::create_dir_all(base_path.join("content")).await?;
-        fs::create_dir_all(base_path.join("metadata")).await?;
-        fs::create_dir_all(base_path.join("cache")).await?;
+        let tasks: Vec<_> = (0..64).map(|i| {
+            let storage = storage.clone();
+            let data = data.clone();
+            tokio::spawn(async move {
+                storage.store_content(&data, Some("text/plain"), &format!("session-{}", i)).await.unwrap()
+            })
+        }).collect();
+        let mut hashes = BTreeSet::new();
+        for task in tasks {
+            hashes.insert(task.await.unwrap());
+        }
         
-        // Open sled database
-        let db_path = base_path.join("metadata").join("content_index.db");
-        let content_db = sled::open(&db_path)?;
+        assert_eq!(hashes.len(), 1);
+        let metadata = storage.content_metadata(hashes.first().unwrap()).unwrap().unwrap();
+        assert_eq!(metadata.reference_count, 64);
+        assert_eq!(metadata.sessions.unwrap().len(), 64);
+    }
+    
+    /// A page fetch of GETs to `urls`, each answered 200 with `body_hash`.
+    fn page_fetch(session_id: &str, navigation_id: &str, urls: &[&str], body_hash: Option<&str>) -> PageFetchIndex {
+        let requests: Vec<serde_json::Value> = urls.iter().enumerate().map(|(i, url)| serde_json::json!({
+            "request_id": format!("{}-{}", navigation_id, i),
+            "timestamp": 1_700_000_000_000i64 + i as i64,
+            "method": "GET",
+            "url": url,
+            "request_headers": [],
+            "request_body_hash": null,
+            "request_body_size": null,
+            "response": {
+                "status_code": 200,
+                "headers": [["Content-Type", "text/plain"]],
+                "body_hash": body_hash,
+                "body_size": body_hash.map(|_| 4),
+                "body_type": "text/plain",
+            },
+        })).collect();
+        serde_json::from_value(serde_json::json!({
+            "session_id": session_id,
+            "page_url": urls.first().copied().unwrap_or("https://example.com/"),
+            "timestamp": 1_700_000_000_000i64,
+            "navigation_id": navigation_id,
+            "requests": requests,
+            "password_hashes": [],
+        })).unwrap()
+    }
+    
+    /// Bytes that don't repeat, so chunking finds real boundaries.
+    pub(crate) fn noise(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..len).map(|_| {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (state >> 56) as u8
+        }).collect()
+    }
+    
+    #[tokio::test]
+    async fn interrupted_write_is_undone_on_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = b"written but never recorded".to_vec();
+        let hash = {
+            let storage = open(&dir).await;
+            let hash = storage.content_hash(&data);
+            // The process dies between writing the file and its metadata
+            storage.begin_write(&PendingWrite::Content { hash: hash.clone(), chunks: Vec::new() }).await.unwrap();
+            storage.content_store.put(hash_key(&hash), &encode_all(&data[..], 3).unwrap()).await.unwrap();
+            hash
+        };
         
-        // Load or create bloom filter
-        let bloom = Self::load_or_create_bloom(&base_path).await?;
+        let storage = open(&dir).await;
+        assert!(storage.content_store.get(hash_key(&hash)).await.unwrap().is_none());
+        assert!(storage.pending_db.is_empty());
+        assert!(matches!(storage.retrieve_content(&hash).await, Err(StorageError::NotFound(_))));
         
-        Ok(Storage {
-            base_path,
-            content_db: Arc::new(content_db),
-            bloom_filter: Arc::new(tokio::sync::RwLock::new(bloom)),
-            content_cache: Arc::new(DashMap::new()),
-        })
+        storage.store_content(&data, None, "session").await.unwrap();
+        assert_eq!(storage.content_metadata(&hash).unwrap().unwrap().reference_count, 1);
+        assert_eq!(storage.retrieve_content(&hash).await.unwrap(), data);
+    }
+    
+    #[tokio::test]
+    async fn interrupted_chunked_write_releases_its_chunk_references() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = StorageConfig { chunk_avg_bytes: Some(1024), ..StorageConfig::default() };
+        let data = noise(32 * 1024, 7);
+        let chunk_counts = |storage: &Storage| -> Vec<u32> {
+            storage.chunks_db.iter()
+                .map(|item| decode_metadata::<ChunkMetadata>(&item.unwrap().1).unwrap().reference_count)
+                .collect()
+        };
+        {
+            let storage = open_with(&dir, config.clone()).await;
+            storage.store_content(&data, None, "first").await.unwrap();
+            assert!(chunk_counts(&storage).iter().all(|&count| count == 1));
+            
+            // A second object with the same chunks dies after referencing them
+            let mut pending = PendingWrite::Content { hash: "interrupted".to_string(), chunks: Vec::new() };
+            storage.begin_write(&pending).await.unwrap();
+            storage.store_chunks(&data, 1024, &mut pending).await.unwrap();
+            assert!(chunk_counts(&storage).iter().all(|&count| count == 2));
+        }
+        
+        let storage = open_with(&dir, config).await;
+        let counts = chunk_counts(&storage);
+        assert!(counts.len() > 1);
+        assert!(counts.iter().all(|&count| count == 1), "{:?}", counts);
+        let hash = storage.content_hash(&data);
+        assert_eq!(storage.retrieve_content(&hash).await.unwrap(), data);
     }
     
-    async fn load_or_create_bloom(base_path: &Path) -> Result<Bloom<String>, StorageError> {
-        let bloom_path = base_path.join("cache").join("bloom_filter.bin");
+    #[tokio::test]
+    async fn rebalance_moves_content_to_the_new_depth() {
+        let dir = tempfile::tempdir().unwrap();
+        let bodies: Vec<Vec<u8>> = (0..3).map(|i| format!("body number {}", i).into_bytes()).collect();
+        let mut hashes = Vec::new();
+        {
+            let storage = open_with(&dir, StorageConfig { fanout_depth: 1, ..StorageConfig::default() }).await;
+            for body in &bodies {
+                hashes.push(storage.store_content(body, None, "session").await.unwrap());
+            }
+        }
         
-        if bloom_path.exists() {
-            // TODO: Implement bloom filter persistence
-            Ok(Bloom::new_for_fp_rate(BLOOM_ITEMS, BLOOM_FP_RATE))
-        } else {
-            Ok(Bloom::new_for_fp_rate(BLOOM_ITEMS, BLOOM_FP_RATE))
+        let config = StorageConfig { fanout_depth: 3, ..StorageConfig::default() };
+        let storage = open_with(&dir, config.clone()).await;
+        let report = storage.rebalance_content().await.unwrap();
+        assert_eq!((report.scanned, report.moved), (3, 3));
+        for hash in &hashes {
+            let key = hash_key(hash);
+            let path = storage.content_store.local_path(key).unwrap();
+            assert!(path.exists());
+            assert_eq!(path.strip_prefix(storage.dir("content")).unwrap().components().count(), 4);
+            assert!(!storage.dir("content").join(&key[..2]).join(format!("{}.zst", key)).exists());
+        }
+        drop(storage);
+        
+        // Only the new depth is searched from now on
+        let storage = open_with(&dir, config).await;
+        for (hash, body) in hashes.iter().zip(&bodies) {
+            assert_eq!(&storage.retrieve_content(hash).await.unwrap(), body);
         }
     }
     
-    pub fn compute_hash(data: &[u8]) -> String {
-        let mut hasher = Sha256::new();
-        hasher.update(data);
-        format!("sha256:{}", hex::encode(hasher.finalize()))
+    #[tokio::test]
+    async fn stats_by_type_report_each_types_ratio() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = open(&dir).await;
+        let json: String = (0..200).map(|i| format!("{{\"id\":{},\"status\":\"ok\"}},", i)).collect();
+        storage.store_content(&noise(8192, 3), Some("image/png"), "session").await.unwrap();
+        storage.store_content(json.as_bytes(), Some("application/json; charset=utf-8"), "session").await.unwrap();
+        
+        let by_type = storage.get_stats_by_type().await.unwrap();
+        assert_eq!(by_type.keys().collect::<Vec<_>>(), ["application/json", "image/png"]);
+        let png = &by_type["image/png"];
+        let json = &by_type["application/json"];
+        assert_eq!((png.count, png.total_size), (1, 8192));
+        assert!(png.compression_ratio > 0.95, "{}", png.compression_ratio);
+        assert!(json.compression_ratio < 0.2, "{}", json.compression_ratio);
     }
     
-    pub async fn store_content(&self, data: &[u8]) -> Result<String, StorageError> {
-        let hash = Self::compute_hash(data);
-        let hash_only = hash.strip_prefix("sha256:").unwrap();
+    #[tokio::test]
+    async fn session_ttl_overrides_global_retention() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = open_with(&dir, StorageConfig { retention_secs: Some(3600), ..StorageConfig::default() }).await;
+        let aged = |session_id: &str, age: chrono::Duration| {
+            let mut index = storage.load_session_index(session_id).unwrap().unwrap();
+            index.updated_at = Some(chrono::Utc::now() - age);
+            storage.save_session_index(session_id, &index).unwrap();
+        };
+        for session_id in ["kept", "retained", "short"] {
+            storage.store_page_fetch(session_id, &page_fetch(session_id, "nav", &["https://example.com/"], None)).await.unwrap();
+        }
+        assert!(storage.set_session_ttl("kept", Some(24 * 3600)).await.unwrap());
+        assert!(storage.set_session_ttl("short", Some(60)).await.unwrap());
+        assert!(!storage.set_session_ttl("unknown", Some(60)).await.unwrap());
+        aged("kept", chrono::Duration::hours(2));
+        aged("retained", chrono::Duration::hours(2));
+        aged("short", chrono::Duration::minutes(10));
         
-        // Check bloom filter first
-        {
-            let bloom = self.bloom_filter.read().await;
-            if bloom.check(&hash) {
-                // Might exist, check database
-                if self.content_db.contains_key(&hash)? {
-                    // Already exists, increment reference count
-                    self.increment_ref_count(&hash).await?;
-                    return Ok(hash);
-                }
+        let mut expired = storage.sweep_expired_sessions().await.unwrap();
+        expired.sort();
+        assert_eq!(expired, ["retained", "short"]);
+        assert_eq!(storage.list_sessions().unwrap(), ["kept"]);
+    }
+    
+    #[tokio::test]
+    async fn failed_atomic_write_leaves_nothing_behind() {
+        let dir = tempfile::tempdir().unwrap();
+        // Renaming over a directory fails after the data is written
+        let path = dir.path().join("page.json");
+        std::fs::create_dir(&path).unwrap();
+        assert!(write_atomic(&path, b"{\"partial\": true}").await.is_err());
+        
+        let names: Vec<String> = std::fs::read_dir(dir.path()).unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        assert_eq!(names, ["page.json"]);
+        assert!(path.is_dir());
+    }
+    
+    #[tokio::test]
+    async fn partial_files_from_an_interrupted_write_stay_invisible() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = open(&dir).await;
+        let data = b"complete";
+        let hash = storage.content_hash(data);
+        let path = storage.content_store.local_path(hash_key(&hash)).unwrap();
+        // What a crash mid-write leaves: a temp file beside the object, never
+        // renamed into place
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        let temp = path.with_file_name(format!(".{}.zst.crashed.tmp", hash_key(&hash)));
+        std::fs::write(&temp, b"\x28\xb5\x2f").unwrap();
+        
+        assert!(!path.exists());
+        assert!(matches!(storage.retrieve_content(&hash).await, Err(StorageError::NotFound(_))));
+        assert_eq!(storage.rebalance_content().await.unwrap().scanned, 0);
+        
+        storage.store_content(data, None, "session").await.unwrap();
+        assert_eq!(storage.retrieve_content(&hash).await.unwrap(), data);
+    }
+    
+    #[tokio::test]
+    async fn storing_a_page_twice_indexes_it_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = open(&dir).await;
+        let page = page_fetch("example.com", "nav", &["https://example.com/"], None);
+        let first = storage.store_page_fetch("example.com", &page).await.unwrap();
+        let second = storage.store_page_fetch("example.com", &page).await.unwrap();
+        assert_eq!(first, second);
+        
+        let index = storage.load_session_index("example.com").unwrap().unwrap();
+        assert_eq!(index.paths.len(), 1);
+        assert_eq!(index.usage.len(), 1);
+        assert_eq!(storage.load_session("example.com").await.unwrap().unwrap().len(), 1);
+    }
+    
+    #[tokio::test]
+    async fn plain_and_compressed_metadata_both_read() {
+        let dir = tempfile::tempdir().unwrap();
+        for (session_id, compress_metadata) in [("plain.example", false), ("compressed.example", true)] {
+            let storage = open_with(&dir, StorageConfig { compress_metadata, ..StorageConfig::default() }).await;
+            for i in 0..20 {
+                let url = format!("https://{}/{}", session_id, i);
+                let page = page_fetch(session_id, &format!("nav-{}", i), &[&url], None);
+                storage.store_page_fetch(session_id, &page).await.unwrap();
             }
         }
         
-        // Compress the content
-        let compressed = encode_all(data, COMPRESSION_LEVEL)?;
-        
-        // Store to disk
-        let content_path = self.get_content_path(hash_only);
-        fs::create_dir_all(content_path.parent().unwrap()).await?;
+        let storage = open(&dir).await;
+        let raw = |session_id: &str| storage.sessions_db.get(session_id).unwrap().unwrap();
+        assert_eq!(raw("plain.example")[0], b'{');
+        assert_eq!(raw("compressed.example")[0], METADATA_ZSTD_MARKER);
+        for session_id in ["plain.example", "compressed.example"] {
+            assert_eq!(storage.load_session(session_id).await.unwrap().unwrap().len(), 20);
+        }
+    }
+    
+    #[tokio::test]
+    async fn deleting_every_referencing_session_frees_the_content() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = open(&dir).await;
+        let data = b"body shared by two sessions";
+        let mut hash = String::new();
+        for session_id in ["first.example", "second.example"] {
+            hash = storage.store_content(data, Some("text/plain"), session_id).await.unwrap();
+            let page = page_fetch(session_id, "nav", &[&format!("https://{}/", session_id)], Some(&hash));
+            storage.store_page_fetch(session_id, &page).await.unwrap();
+        }
         
-        let mut file = fs::File::create(&content_path).await?;
-        file.write_all(&compressed).await?;
-        file.sync_all().await?;
+        let report = storage.delete_session("first.example").await.unwrap().unwrap();
+        assert_eq!(report.files_removed, 1);
+        let metadata = storage.content_metadata(&hash).unwrap().unwrap();
+        assert_eq!(metadata.sessions.unwrap(), BTreeSet::from(["second.example".to_string()]));
+        assert_eq!(storage.retrieve_content(&hash).await.unwrap(), data);
         
-        // Update metadata
-        let metadata = ContentMetadata {
-            size: data.len(),
-            compressed_size: compressed.len(),
-            content_type: None,
-            first_seen: chrono::Utc::now(),
-            reference_count: 1,
+        storage.delete_session("second.example").await.unwrap().unwrap();
+        assert!(storage.content_metadata(&hash).unwrap().is_none());
+        assert!(matches!(storage.retrieve_content(&hash).await, Err(StorageError::NotFound(_))));
+    }
+    
+    #[tokio::test]
+    async fn content_scan_skips_session_keys() {
+        let dir = tempfile::tempdir().unwrap();
+        let hash = {
+            let storage = open(&dir).await;
+            let hash = storage.store_content(b"scanned body", Some("text/plain"), "scan.example").await.unwrap();
+            let page = page_fetch("scan.example", "nav", &["https://scan.example/"], Some(&hash));
+            storage.store_page_fetch("scan.example", &page).await.unwrap();
+            // Put both back under the single-tree layout older stores used
+            let content = storage.content_db.remove(&hash).unwrap().unwrap();
+            let session = storage.sessions_db.remove("scan.example").unwrap().unwrap();
+            storage.db.insert(&hash, content).unwrap();
+            storage.db.insert("session:scan.example", session).unwrap();
+            hash
         };
         
-        self.content_db.insert(
-            hash.as_bytes(),
-            serde_json::to_vec(&metadata)?
-        )?;
+        let storage = open(&dir).await;
+        let content_keys: Vec<_> = storage.content_db.iter().keys().map(Result::unwrap).collect();
+        assert_eq!(content_keys, [sled::IVec::from(hash.as_bytes())]);
+        assert!(storage.db.get("session:scan.example").unwrap().is_none());
+        assert_eq!(storage.load_session("scan.example").await.unwrap().unwrap().len(), 1);
+        assert_eq!(storage.content_metadata(&hash).unwrap().unwrap().reference_count, 1);
+    }
+    
+    #[tokio::test]
+    async fn bodies_over_the_cacheable_limit_stay_on_disk() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = open_with(&dir, StorageConfig { max_cacheable_bytes: 16, ..StorageConfig::default() }).await;
+        let small = storage.store_content(b"tiny", None, "cache.example").await.unwrap();
+        let large = storage.store_content(b"well over sixteen bytes long", None, "cache.example").await.unwrap();
         
-        // Update bloom filter
-        {
-            let mut bloom = self.bloom_filter.write().await;
-            bloom.set(&hash);
+        for _ in 0..2 {
+            assert_eq!(storage.retrieve_content(&large).await.unwrap(), b"well over sixteen bytes long");
+            assert_eq!(storage.retrieve_content(&small).await.unwrap(), b"tiny");
+        }
+        assert!(!storage.content_cache.contains_key(&large));
+        assert!(storage.content_cache.contains_key(&small));
+    }
+    
+    #[tokio::test]
+    async fn disk_scan_counts_orphaned_content_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = open(&dir).await;
+        storage.store_content(b"indexed body", None, "disk.example").await.unwrap();
+        storage.scan_disk_usage().await.unwrap();
+        let before = storage.get_stats();
+        assert_eq!(before.orphan_files, 0);
+        assert!(before.disk_bytes > 0);
+        
+        // A file whose metadata never made it into sled
+        let orphan = storage.content_hash(b"never indexed");
+        storage.content_store.put(hash_key(&orphan), &encode_all(&b"never indexed"[..], 3).unwrap()).await.unwrap();
+        storage.scan_disk_usage().await.unwrap();
+        let after = storage.get_stats();
+        assert_eq!(after.orphan_files, 1);
+        assert!(after.disk_bytes > before.disk_bytes);
+        assert_eq!(after.content_count, 1);
+    }
+    
+    #[tokio::test]
+    async fn requests_file_into_hourly_buckets_and_ranges_read_only_those() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = open_with(&dir, StorageConfig { session_bucket_secs: Some(3600), ..StorageConfig::default() }).await;
+        const HOUR: i64 = 3_600_000;
+        let start = 1_699_999_200_000;
+        let urls = ["https://buckets.example/a", "https://buckets.example/b", "https://buckets.example/c"];
+        let mut page = page_fetch("buckets.example", "nav", &urls, None);
+        for (request, offset) in page.requests.iter_mut().zip([10, HOUR + 10, 2 * HOUR + 10]) {
+            request.timestamp = start + offset;
         }
+        storage.store_page_fetch("buckets.example", &page).await.unwrap();
+        
+        let index = storage.load_session_index("buckets.example").unwrap().unwrap();
+        let starts: Vec<i64> = index.buckets.keys().copied().collect();
+        assert_eq!(starts, [start, start + HOUR, start + 2 * HOUR]);
+        assert!(index.buckets.values().all(|entry| entry.request_count == 1));
+        
+        // Unreadable, so the range query fails if it opens this bucket
+        std::fs::write(&index.buckets[&(start + 2 * HOUR)].path, b"not json").unwrap();
+        let requests = storage.load_requests_in_range("buckets.example", Some(start), Some(start + HOUR + 20)).await.unwrap().unwrap();
+        let urls: Vec<&str> = requests.iter().map(|(_, request)| request.url.as_str()).collect();
+        assert_eq!(urls, ["https://buckets.example/a", "https://buckets.example/b"]);
+        assert!(storage.load_requests_in_range("buckets.example", None, None).await.is_err());
+    }
+    
+    #[tokio::test]
+    async fn mapped_reads_return_identical_bytes() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = StorageConfig { mmap_min_bytes: Some(1024), cache_entries: 0, ..StorageConfig::default() };
+        let storage = open_with(&dir, config).await;
+        let large = noise(256 * 1024, 7);
+        let small = b"below the mapping threshold".to_vec();
         
-        // Cache if small enough
-        if data.len() < 1_000_000 {
-            self.content_cache.insert(hash.clone(), data.to_vec());
+        for data in [large, small] {
+            let hash = storage.store_content(&data, None, "mmap.example").await.unwrap();
+            assert!(storage.content_store.local_path(hash_key(&hash)).unwrap().exists());
+            let plain = decompress(&hash, &storage.retrieve_compressed(&hash).await.unwrap()).unwrap();
+            assert_eq!(storage.decompress_mapped(&hash, 1024).await.unwrap(), data);
+            assert_eq!(storage.retrieve_content(&hash).await.unwrap(), data);
+            assert_eq!(plain, data);
+        }
+        assert!(storage.content_cache.is_empty());
+    }
+    
+    #[tokio::test]
+    async fn compaction_packs_tiny_objects_that_still_read_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = StorageConfig { pack_below_bytes: Some(256), ..StorageConfig::default() };
+        let tiny: Vec<Vec<u8>> = (0..5).map(|i| format!("tiny object {}", i).into_bytes()).collect();
+        let large = noise(4096, 3);
+        let (tiny_hashes, large_hash) = {
+            let storage = open_with(&dir, config.clone()).await;
+            let mut tiny_hashes = Vec::new();
+            for data in &tiny {
+                tiny_hashes.push(storage.store_content(data, None, "pack.example").await.unwrap());
+            }
+            let large_hash = storage.store_content(&large, None, "pack.example").await.unwrap();
             
-            // Evict old entries if cache is too large
-            if self.content_cache.len() > CACHE_SIZE {
-                // Simple random eviction
-                if let Some(entry) = self.content_cache.iter().next() {
-                    self.content_cache.remove(entry.key());
-                }
+            let report = storage.compact().await.unwrap();
+            assert_eq!(report.objects_packed, 5);
+            // Both fanout levels above each packed object
+            assert_eq!(report.empty_dirs_removed, 10);
+            assert!(storage.pack_path().exists());
+            for hash in &tiny_hashes {
+                assert!(!storage.content_store.local_path(hash_key(hash)).unwrap().exists());
             }
-        }
+            assert!(storage.content_store.local_path(hash_key(&large_hash)).unwrap().exists());
+            (tiny_hashes, large_hash)
+        };
         
-        Ok(hash)
+        let storage = open_with(&dir, config).await;
+        for (hash, data) in tiny_hashes.iter().zip(&tiny) {
+            assert_eq!(&storage.retrieve_content(hash).await.unwrap(), data);
+        }
+        assert_eq!(storage.retrieve_content(&large_hash).await.unwrap(), large);
     }
     
-    pub async fn retrieve_content(&self, hash: &str) -> Result<Vec<u8>, StorageError> {
-        // Check cache first
-        if let Some(cached) = self.content_cache.get(hash) {
-            return Ok(cached.clone());
+    #[tokio::test]
+    async fn requests_are_found_by_id_without_loading_sessions() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = open(&dir).await;
+        for session_id in ["first.example", "second.example"] {
+            let urls: Vec<String> = (0..3).map(|i| format!("https://{}/{}", session_id, i)).collect();
+            let urls: Vec<&str> = urls.iter().map(String::as_str).collect();
+            let page = page_fetch(session_id, &format!("{}-nav", session_id), &urls, None);
+            storage.store_page_fetch(session_id, &page).await.unwrap();
         }
+        assert_eq!(storage.requests_db.len(), 6);
         
-        let hash_only = hash.strip_prefix("sha256:").unwrap_or(hash);
-        let content_path = self.get_content_path(hash_only);
+        let (session_id, request) = storage.find_request("second.example-nav-1").await.unwrap().unwrap();
+        assert_eq!(session_id, "second.example");
+        assert_eq!(request.url, "https://second.example/1");
+        assert!(storage.find_request("missing").await.unwrap().is_none());
         
-        if !content_path.exists() {
-            return Err("Content not found".into());
+        storage.delete_session("second.example").await.unwrap().unwrap();
+        assert!(storage.find_request("second.example-nav-1").await.unwrap().is_none());
+        assert_eq!(storage.requests_db.len(), 3);
+    }
+    
+    #[tokio::test]
+    async fn bloom_filter_grows_past_a_tiny_capacity() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = open_with(&dir, StorageConfig { bloom_capacity: 16, ..StorageConfig::default() }).await;
+        assert_eq!(storage.bloom_filter.capacity(), 16);
+        let mut hashes = Vec::new();
+        for i in 0..100 {
+            let data = format!("body number {}", i);
+            hashes.push(storage.store_content(data.as_bytes(), Some("text/plain"), "grow.example").await.unwrap());
         }
         
-        let compressed = fs::read(&content_path).await?;
-        let decompressed = decode_all(&compressed[..])?;
-        
-        // Cache if small enough
-        if decompressed.len() < 1_000_000 {
-            self.content_cache.insert(hash.to_string(), decompressed.clone());
+        let capacity = storage.bloom_filter.capacity();
+        assert!(capacity as f64 * BLOOM_GROW_AT > 100.0, "capacity {} too small for 100 items", capacity);
+        for hash in &hashes {
+            assert!(storage.bloom_filter.check(hash).await);
         }
+        let mut queried = hashes.clone();
+        queried.push(storage.content_hash(b"never stored"));
+        assert_eq!(storage.existing_content(&queried).await.unwrap(), hashes);
+    }
+    
+    #[tokio::test]
+    async fn near_identical_chunked_bodies_share_most_chunks() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = open_with(&dir, StorageConfig { chunk_avg_bytes: Some(1024), ..StorageConfig::default() }).await;
+        let first = noise(64 * 1024, 11);
+        // The same body with its last tenth replaced
+        let mut second = first.clone();
+        let tail = second.len() * 9 / 10;
+        second.truncate(tail);
+        second.extend(noise(first.len() - tail, 12));
         
-        Ok(decompressed)
+        let first_hash = storage.store_content(&first, None, "similar").await.unwrap();
+        let second_hash = storage.store_content(&second, None, "similar").await.unwrap();
+        let first_meta = storage.content_metadata(&first_hash).unwrap().unwrap();
+        let second_meta = storage.content_metadata(&second_hash).unwrap().unwrap();
+        assert!(second_meta.compressed_size * 4 < first_meta.compressed_size,
+            "second added {} bytes, first {}", second_meta.compressed_size, first_meta.compressed_size);
+        let first_chunks: BTreeSet<_> = first_meta.chunks.unwrap().into_iter().collect();
+        let shared = second_meta.chunks.unwrap().iter().filter(|chunk| first_chunks.contains(*chunk)).count();
+        assert!(shared * 2 > first_chunks.len());
+        
+        assert_eq!(storage.retrieve_content(&first_hash).await.unwrap(), first);
+        assert_eq!(storage.retrieve_content(&second_hash).await.unwrap(), second);
     }
     
-    pub async fn store_page_fetch(&self, session_id: &str, page_fetch: &PageFetchIndex) -> Result<PathBuf, StorageError> {
-        let date = chrono::Utc::now().format("%Y-%m-%d").to_string();
-        let page_hash = Self::compute_hash(page_fetch.page_url.as_bytes());
-        let page_hash_only = page_hash.strip_prefix("sha256:").unwrap();
+    #[tokio::test]
+    async fn colliding_page_fetch_filenames_both_persist() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = open(&dir).await;
+        let url = "https://collide.example/";
+        let first = storage.store_page_fetch("collide.example", &page_fetch("collide.example", "nav-a", &[url], None)).await.unwrap();
+        let second = storage.store_page_fetch("collide.example", &page_fetch("collide.example", "nav-b", &[url], None)).await.unwrap();
+        assert_ne!(first, second);
+        
+        // Another navigation already holds the name nav-c's hashes produce
+        let hash_only = |text: &str| Storage::compute_hash(text.as_bytes()).strip_prefix("sha256:").unwrap().to_string();
+        let stem = format!("{}_{}_{}", 1_700_000_000_000i64, &hash_only(url)[..8], &hash_only("nav-c")[..16]);
+        let taken = first.with_file_name(format!("{}.json", stem));
+        let squatter = std::fs::read(&first).unwrap();
+        std::fs::write(&taken, &squatter).unwrap();
         
-        let filename = format!("{}_{}.json", page_fetch.timestamp, &page_hash_only[..8]);
-        let path = self.base_path
-            .join("sessions")
-            .join(&date)
-            .join(session_id)
-            .join(&filename);
+        let third = storage.store_page_fetch("collide.example", &page_fetch("collide.example", "nav-c", &[url], None)).await.unwrap();
+        assert_eq!(third, first.with_file_name(format!("{}_1.json", stem)));
+        assert_eq!(std::fs::read(&taken).unwrap(), squatter);
+        let again = storage.store_page_fetch("collide.example", &page_fetch("collide.example", "nav-c", &[url], None)).await.unwrap();
+        assert_eq!(again, third);
         
-        fs::create_dir_all(path.parent().unwrap()).await?;
+        let mut navigations: Vec<String> = storage.load_session("collide.example").await.unwrap().unwrap()
+            .into_iter()
+            .map(|page| page.navigation_id)
+            .collect();
+        navigations.sort();
+        assert_eq!(navigations, ["nav-a", "nav-b", "nav-c"]);
+    }
+    
+    #[tokio::test]
+    async fn stats_count_one_cache_miss_then_one_hit() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = b"read once from disk, then from the cache";
+        let hash = open(&dir).await.store_content(data, Some("text/plain"), "cache.example").await.unwrap();
         
-        let json = serde_json::to_string_pretty(page_fetch)?;
-        fs::write(&path, json).await?;
+        // A fresh instance starts with an empty cache
+        let storage = open(&dir).await;
+        assert_eq!(storage.retrieve_content(&hash).await.unwrap(), data);
+        assert_eq!(storage.retrieve_content(&hash).await.unwrap(), data);
+        let stats = storage.get_stats();
+        assert_eq!((stats.cache_hits, stats.cache_misses), (1, 1));
+        assert_eq!(stats.cache_hit_ratio, 0.5);
+    }
+    
+    #[test]
+    fn repeated_set_cookie_headers_stay_separate() {
+        let headers = merge_headers([
+            ("Set-Cookie", "session=abc; Expires=Wed, 21 Oct 2026 07:28:00 GMT"),
+            ("Vary", "Accept"),
+            ("set-cookie", "theme=dark"),
+            ("vary", "Accept-Encoding"),
+        ].map(|(name, value)| (name.to_string(), value.to_string())));
         
-        // Update session index
-        let session_key = format!("session:{}", session_id);
-        let mut paths = Vec::new();
+        let cookies: Vec<&str> = headers.iter()
+            .filter(|(name, _)| name.eq_ignore_ascii_case("set-cookie"))
+            .map(|(_, value)| value)
+            .collect();
+        assert_eq!(cookies, ["session=abc; Expires=Wed, 21 Oct 2026 07:28:00 GMT", "theme=dark"]);
+        assert_eq!(headers.get("VARY"), Some("Accept, Accept-Encoding"));
+        assert_eq!(headers.iter().count(), 3);
+    }
+    
+    #[tokio::test]
+    async fn missing_content_is_the_not_found_variant() {
+        for config in [StorageConfig::default(), StorageConfig { chunk_avg_bytes: Some(1024), ..StorageConfig::default() }] {
+            let dir = tempfile::tempdir().unwrap();
+            let storage = open_with(&dir, config).await;
+            let missing = storage.content_hash(b"never stored");
+            assert!(matches!(storage.retrieve_content(&missing).await, Err(StorageError::NotFound(_))));
+            assert!(matches!(storage.retrieve_compressed(&missing).await, Err(StorageError::NotFound(_))));
+            
+            // Stored content is still found alongside it
+            let hash = storage.store_content(&noise(8192, 3), None, "found.example").await.unwrap();
+            assert_eq!(storage.retrieve_content(&hash).await.unwrap(), noise(8192, 3));
+        }
+    }
+    
+    #[tokio::test]
+    async fn content_round_trips_at_each_depth_and_reads_across_them() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut stored = Vec::new();
+        for depth in [1, 3] {
+            let storage = open_with(&dir, StorageConfig { fanout_depth: depth, ..StorageConfig::default() }).await;
+            let body = format!("written at depth {}", depth).into_bytes();
+            let hash = storage.store_content(&body, None, "depth.example").await.unwrap();
+            let path = storage.content_store.local_path(hash_key(&hash)).unwrap();
+            assert_eq!(path.strip_prefix(storage.dir("content")).unwrap().components().count(), depth + 1);
+            assert_eq!(storage.retrieve_content(&hash).await.unwrap(), body);
+            stored.push((hash, body));
+        }
         
-        if let Ok(Some(existing)) = self.content_db.get(&session_key) {
-            paths = serde_json::from_slice(&existing)?;
+        // Neither depth is the configured one, and nothing has been rebalanced
+        let storage = open(&dir).await;
+        assert_eq!(storage.config.fanout_depth, DEFAULT_FANOUT_DEPTH);
+        for (hash, body) in &stored {
+            assert_eq!(&storage.retrieve_content(hash).await.unwrap(), body);
         }
+    }
+    
+    #[tokio::test]
+    async fn version_zero_store_migrates_to_the_current_schema() {
+        let dir = tempfile::tempdir().unwrap();
+        let version_path = dir.path().join("metadata").join("schema_version");
+        let hash = {
+            let storage = open(&dir).await;
+            let hash = storage.store_content(b"body { color: red }", None, "old.example").await.unwrap();
+            assert!(storage.content_metadata(&hash).unwrap().unwrap().content_type.is_none());
+            let page = page_fetch("old.example", "nav", &["https://old.example/style.css"], Some(&hash));
+            storage.store_page_fetch("old.example", &page).await.unwrap();
+            // As written before content types and session usage were recorded
+            let mut index = storage.load_session_index("old.example").unwrap().unwrap();
+            index.usage.clear();
+            storage.save_session_index("old.example", &index).unwrap();
+            hash
+        };
+        std::fs::remove_file(&version_path).unwrap();
         
-        paths.push(path.to_string_lossy().to_string());
-        self.content_db.insert(
-            session_key.as_bytes(),
-            serde_json::to_vec(&paths)?
-        )?;
+        let storage = open(&dir).await;
+        assert_eq!(std::fs::read_to_string(&version_path).unwrap(), SCHEMA_VERSION.to_string());
+        let metadata = storage.content_metadata(&hash).unwrap().unwrap();
+        assert_eq!(metadata.content_type.as_deref(), Some("text/plain"));
+        assert_eq!(storage.load_session_index("old.example").unwrap().unwrap().usage.len(), 1);
+        drop(storage);
         
-        Ok(path)
+        std::fs::write(&version_path, (SCHEMA_VERSION + 1).to_string()).unwrap();
+        let newer = Storage::new(dir.path(), StorageConfig::default()).await;
+        assert!(matches!(newer, Err(StorageError::Invalid(_))));
     }
     
-    async fn increment_ref_count(&self, hash: &str) -> Result<(), StorageError> {
-        if let Ok(Some(data)) = self.content_db.get(hash) {
-            let mut metadata: ContentMetadata = serde_json::from_slice(&data)?;
-            metadata.reference_count += 1;
-            self.content_db.insert(
-                hash.as_bytes(),
-                serde_json::to_vec(&metadata)?
-            )?;
+    #[tokio::test]
+    async fn warming_caches_the_most_referenced_small_objects() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = StorageConfig { cache_entries: 2, max_cacheable_bytes: 1024, warm_cache: true, ..StorageConfig::default() };
+        let mut hashes = Vec::new();
+        {
+            let storage = open_with(&dir, config.clone()).await;
+            // Referenced 1 to 4 times; the most referenced is too big to cache
+            for (references, body) in [(1, vec![b'a'; 16]), (2, vec![b'b'; 16]), (3, vec![b'c'; 16]), (4, vec![b'd'; 4096])] {
+                let mut hash = String::new();
+                for session in 0..references {
+                    hash = storage.store_content(&body, None, &format!("session-{}", session)).await.unwrap();
+                }
+                hashes.push(hash);
+            }
+        }
+        
+        let storage = open_with(&dir, config).await;
+        assert!(storage.content_cache.is_empty());
+        assert_eq!(storage.warm_cache().await.unwrap(), 2);
+        let cached: Vec<bool> = hashes.iter().map(|hash| storage.content_cache.contains_key(hash)).collect();
+        assert_eq!(cached, [false, true, true, false]);
+        assert_eq!(storage.retrieve_content(&hashes[2]).await.unwrap(), vec![b'c'; 16]);
+        assert_eq!(storage.get_stats().cache_hits, 1);
+    }
+    
+    #[tokio::test]
+    async fn batch_store_matches_individual_stores_with_fewer_locks() {
+        // 80 distinct bodies, then 20 repeats of earlier ones
+        let bodies: Vec<Vec<u8>> = (0..100).map(|i| format!("body {}", i % 80).into_bytes()).collect();
+        let kind = |outcome: &StoreOutcome| match outcome {
+            StoreOutcome::Stored(hash) => ("stored", hash.clone()),
+            StoreOutcome::Deduplicated(hash) => ("deduplicated", hash.clone()),
+            StoreOutcome::Failed(e) => panic!("store failed: {}", e),
+        };
+        
+        let dir = tempfile::tempdir().unwrap();
+        let storage = open(&dir).await;
+        let locks_before = storage.bloom_locks_taken();
+        let mut individual = Vec::new();
+        for body in &bodies {
+            let known = storage.content_metadata(&storage.content_hash(body)).unwrap().is_some();
+            let hash = storage.store_content(body, Some("text/plain"), "session").await.unwrap();
+            individual.push((if known { "deduplicated" } else { "stored" }, hash));
+        }
+        let individual_locks = storage.bloom_locks_taken() - locks_before;
+        
+        let dir = tempfile::tempdir().unwrap();
+        let storage = open(&dir).await;
+        let locks_before = storage.bloom_locks_taken();
+        let items: Vec<_> = bodies.iter()
+            .map(|body| ContentItem::new(body, Some("text/plain"), HashAlgorithm::default()))
+            .collect();
+        let batched: Vec<_> = storage.store_content_batch(&items, "session").await.iter().map(kind).collect();
+        let batch_locks = storage.bloom_locks_taken() - locks_before;
+        
+        assert_eq!(batched, individual);
+        assert!(batch_locks < individual_locks, "batch took {} locks, individual stores {}", batch_locks, individual_locks);
+        let metadata = storage.content_metadata(&batched[0].1).unwrap().unwrap();
+        assert_eq!(metadata.reference_count, 2);
+        for (_, hash) in &batched {
+            assert!(storage.bloom_filter.check(hash).await);
         }
-        Ok(())
     }
     
-    fn get_content_path(&self, hash: &str) -> PathBuf {
-        // Split hash for directory structure
-        let dir1 = &hash[..2];
-        let dir2 = &hash[2..4];
+    #[tokio::test]
+    async fn message_pack_and_json_page_fetches_both_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let json_page = page_fetch("format.example", "json-nav", &["https://format.example/json"], Some("sha256:00"));
+        let packed_page = page_fetch("format.example", "packed-nav", &["https://format.example/packed"], Some("sha256:11"));
+        let json_path = open(&dir).await.store_page_fetch("format.example", &json_page).await.unwrap();
+        let config = StorageConfig { page_fetch_format: PageFetchFormat::MessagePack, ..StorageConfig::default() };
+        let storage = open_with(&dir, config).await;
+        let packed_path = storage.store_page_fetch("format.example", &packed_page).await.unwrap();
         
-        self.base_path
-            .join("content")
-            .join(dir1)
-            .join(dir2)
-            .join(format!("{}.zst", hash))
+        assert_eq!(std::fs::read(&json_path).unwrap()[0], b'{');
+        let packed = std::fs::read(&packed_path).unwrap();
+        assert_eq!(packed[0], PAGE_FETCH_MSGPACK_MARKER);
+        assert!(packed.len() < serde_json::to_vec_pretty(&packed_page).unwrap().len());
+        let mut loaded = storage.load_session("format.example").await.unwrap().unwrap();
+        loaded.sort_by(|a, b| a.navigation_id.cmp(&b.navigation_id));
+        let as_json = |pages: &[PageFetchIndex]| serde_json::to_value(pages).unwrap();
+        assert_eq!(as_json(&loaded), as_json(&[json_page, packed_page]));
     }
     
-    pub async fn get_stats(&self) -> Result<StorageStats, StorageError> {
-        let content_count = self.content_db.len();
-        let cache_size = self.content_cache.len();
+    #[tokio::test]
+    async fn blake3_content_is_found_alongside_sha256_content() {
+        assert_eq!(
+            HashAlgorithm::Blake3.hash(b"abc"),
+            "blake3:6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85",
+        );
         
-        // Calculate total size by iterating metadata
-        let mut total_size = 0u64;
-        let mut compressed_size = 0u64;
+        let dir = tempfile::tempdir().unwrap();
+        let sha256_stored = open(&dir).await.store_content(b"stored first", Some("text/plain"), "session").await.unwrap();
+        let config = StorageConfig { hash_algorithm: HashAlgorithm::Blake3, ..StorageConfig::default() };
+        let storage = open_with(&dir, config).await;
+        let blake3_stored = storage.store_content(b"stored second", Some("text/plain"), "session").await.unwrap();
+        let again = storage.store_content(b"stored first", Some("text/plain"), "session").await.unwrap();
+        let large = noise(8 * 1024 * 1024, 7);
+        let large_stored = storage.store_content(&large, None, "session").await.unwrap();
         
-        for item in self.content_db.iter() {
-            if let Ok((_, value)) = item {
-                if let Ok(metadata) = serde_json::from_slice::<ContentMetadata>(&value) {
-                    total_size += metadata.size as u64;
-                    compressed_size += metadata.compressed_size as u64;
-                }
+        assert!(sha256_stored.starts_with("sha256:"));
+        assert_eq!(blake3_stored, HashAlgorithm::Blake3.hash(b"stored second"));
+        assert_eq!(again, HashAlgorithm::Blake3.hash(b"stored first"));
+        assert_eq!(storage.retrieve_content(&sha256_stored).await.unwrap(), b"stored first");
+        assert_eq!(storage.retrieve_content(&blake3_stored).await.unwrap(), b"stored second");
+        assert_eq!(storage.retrieve_content(&again).await.unwrap(), b"stored first");
+        assert_eq!(HashAlgorithm::of(&large_stored), Some(HashAlgorithm::Blake3));
+        assert_eq!(storage.retrieve_content(&large_stored).await.unwrap(), large);
+    }
+    
+    #[tokio::test]
+    async fn largest_content_matches_a_full_scan() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = open(&dir).await;
+        let mut hashes = Vec::new();
+        for i in 0..40u64 {
+            let data = noise(512 + (i as usize * 7919) % 4096, i);
+            hashes.push(storage.store_content(&data, None, "session").await.unwrap());
+        }
+        for hash in hashes.iter().step_by(3) {
+            storage.release_content(hash, "session").await.unwrap();
+        }
+        assert_eq!(storage.size_index_db.len(), storage.content_db.len());
+        
+        let mut scanned: Vec<(usize, String)> = storage.content_db.iter()
+            .map(|item| {
+                let (hash, value) = item.unwrap();
+                let metadata: ContentMetadata = decode_metadata(&value).unwrap();
+                (metadata.compressed_size, String::from_utf8(hash.to_vec()).unwrap())
+            })
+            .collect();
+        scanned.sort_by(|a, b| b.cmp(a));
+        let listed = |n, offset, min, max| -> Vec<(usize, String)> {
+            storage.largest_content(n, offset, min, max).unwrap().into_iter()
+                .map(|object| (object.compressed_size, object.hash))
+                .collect()
+        };
+        
+        assert_eq!(listed(10, 0, None, None), scanned[..10]);
+        assert_eq!(listed(10, 5, None, None), scanned[5..15]);
+        let (min, max) = (1024, 3072);
+        let in_range: Vec<_> = scanned.iter().filter(|(size, _)| (min..=max).contains(size)).cloned().collect();
+        assert!(!in_range.is_empty() && in_range.len() < scanned.len());
+        assert_eq!(listed(usize::MAX, 0, Some(min as u64), Some(max as u64)), in_range);
+    }
+    
+    #[tokio::test]
+    async fn concurrent_stores_write_at_most_the_configured_number_at_once() {
+        /// Holds each write open a moment, tracking how many overlap.
+        struct SlowStore {
+            inner: crate::content_store::LocalStore,
+            in_flight: Arc<AtomicU64>,
+            peak: Arc<AtomicU64>,
+        }
+        
+        #[axum::async_trait]
+        impl ContentStore for SlowStore {
+            async fn put(&self, key: &str, data: &[u8]) -> Result<(), StorageError> {
+                let running = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                self.peak.fetch_max(running, Ordering::SeqCst);
+                tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+                let result = self.inner.put(key, data).await;
+                self.in_flight.fetch_sub(1, Ordering::SeqCst);
+                result
+            }
+            
+            async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, StorageError> {
+                self.inner.get(key).await
+            }
+            
+            async fn delete(&self, key: &str) -> Result<u64, StorageError> {
+                self.inner.delete(key).await
             }
         }
         
-        Ok(StorageStats {
-            content_count,
-            cache_size,
-            total_size,
-            compressed_size,
-            compression_ratio: if total_size > 0 {
-                compressed_size as f64 / total_size as f64
-            } else {
-                1.0
-            },
-        })
+        let dir = tempfile::tempdir().unwrap();
+        let config = StorageConfig { max_concurrent_writes: Some(3), ..StorageConfig::default() };
+        let mut storage = open_with(&dir, config).await;
+        let peak = Arc::new(AtomicU64::new(0));
+        set_content_store(&mut storage, Box::new(SlowStore {
+            inner: crate::content_store::LocalStore::new(dir.path().join("slow"), 2),
+            in_flight: Arc::new(AtomicU64::new(0)),
+            peak: peak.clone(),
+        }));
+        
+        let bodies: Vec<Vec<u8>> = (0..24).map(|i| format!("burst {}", i).into_bytes()).collect();
+        let stores = futures::future::join_all(bodies.iter().map(|body| storage.store_content(body, Some("text/plain"), "session")));
+        tokio::pin!(stores);
+        let mut deepest_queue = 0;
+        let results = loop {
+            tokio::select! {
+                results = &mut stores => break results,
+                _ = tokio::time::sleep(std::time::Duration::from_millis(5)) => {
+                    deepest_queue = deepest_queue.max(storage.get_stats().write_queue_depth);
+                }
+            }
+        };
+        
+        assert!(results.iter().all(Result::is_ok));
+        assert_eq!(peak.load(Ordering::SeqCst), 3);
+        assert!(deepest_queue > 0);
+        assert_eq!(storage.get_stats().write_queue_depth, 0);
+        for body in &bodies {
+            assert_eq!(&storage.retrieve_content(&storage.content_hash(body)).await.unwrap(), body);
+        }
     }
 }
-
-#[derive(Debug, Serialize)]
-pub struct StorageStats {
-    pub content_count: usize,
-    pub cache_size: usize,
-    pub total_size: u64,
-    pub compressed_size: u64,
-    pub compression_ratio: f64,
-}
\ No newline at end of file
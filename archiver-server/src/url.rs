@@ -0,0 +1,145 @@
+use ::url::{ParseError, Url};
+use std::collections::HashSet;
+use std::sync::OnceLock;
+
+/// Session ID for URLs without a host, such as `data:` or `about:blank`.
+const DEFAULT_SESSION: &str = "default";
+
+/// Parses `raw` into its canonical form: lowercase scheme and host, no
+/// default port, no fragment, and no query parameters matching
+/// `strip_params`. A pattern ending in `*` matches by prefix (`utm_*`).
+pub fn normalize(raw: &str, strip_params: &[String]) -> Result<Url, ParseError> {
+    // The parser already lowercases the host and drops default ports
+    let mut url = Url::parse(raw.trim())?;
+    url.set_fragment(None);
+    
+    if url.query().is_some() && !strip_params.is_empty() {
+        let pairs: Vec<(String, String)> = url.query_pairs().into_owned().collect();
+        let kept: Vec<&(String, String)> = pairs.iter()
+            .filter(|(name, _)| !strip_params.iter().any(|pattern| param_matches(pattern, name)))
+            .collect();
+        // Re-encoding could change untouched parameters, so only rewrite
+        // the query when something was dropped
+        if kept.is_empty() {
+            url.set_query(None);
+        } else if kept.len() < pairs.len() {
+            url.query_pairs_mut().clear().extend_pairs(kept);
+        }
+    }
+    Ok(url)
+}
+
+/// Groups URLs by authority: the host, plus the port when it isn't the
+/// scheme's default.
+pub fn session_id(url: &Url) -> String {
+    match (url.host_str(), url.port()) {
+        (Some(host), Some(port)) => format!("{}:{}", host, port),
+        (Some(host), None) => host.to_string(),
+        (None, _) => DEFAULT_SESSION.to_string(),
+    }
+}
+
+fn param_matches(pattern: &str, name: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => name.starts_with(prefix),
+        None => name == pattern,
+    }
+}
+
+/// Snapshot of https://publicsuffix.org/list/, used unless
+/// `ARCHIVER_PUBLIC_SUFFIX_LIST` names a fresher copy.
+const PUBLIC_SUFFIX_LIST: &str = include_str!("../data/public_suffix_list.dat");
+
+static SUFFIXES: OnceLock<SuffixList> = OnceLock::new();
+
+/// Public Suffix List rules: plain suffixes, `*.` wildcards (stored without
+/// the `*.`), and `!` exceptions to them.
+struct SuffixList {
+    rules: HashSet<String>,
+    wildcards: HashSet<String>,
+    exceptions: HashSet<String>,
+}
+
+impl SuffixList {
+    fn parse(text: &str) -> Self {
+        let mut list = SuffixList {
+            rules: HashSet::new(),
+            wildcards: HashSet::new(),
+            exceptions: HashSet::new(),
+        };
+        for line in text.lines() {
+            // A rule is the line's first word
+            let Some(rule) = line.split_whitespace().next().filter(|rule| !rule.starts_with("//")) else {
+                continue;
+            };
+            let rule = rule.to_lowercase();
+            if let Some(exception) = rule.strip_prefix('!') {
+                list.exceptions.insert(exception.to_string());
+            } else if let Some(wildcard) = rule.strip_prefix("*.") {
+                list.wildcards.insert(wildcard.to_string());
+            } else {
+                list.rules.insert(rule);
+            }
+        }
+        list
+    }
+    
+    fn get() -> &'static SuffixList {
+        SUFFIXES.get_or_init(|| {
+            if let Ok(path) = std::env::var("ARCHIVER_PUBLIC_SUFFIX_LIST") {
+                match std::fs::read_to_string(&path) {
+                    Ok(text) => return SuffixList::parse(&text),
+                    Err(e) => tracing::warn!("Failed to read public suffix list {}: {}; using the built-in one", path, e),
+                }
+            }
+            SuffixList::parse(PUBLIC_SUFFIX_LIST)
+        })
+    }
+    
+    /// Index of the label where `labels`' public suffix starts: the longest
+    /// matching rule wins, and with none, the last label is the suffix.
+    fn suffix_start(&self, labels: &[&str]) -> usize {
+        for start in 0..labels.len() {
+            let candidate = labels[start..].join(".");
+            if self.exceptions.contains(&candidate) {
+                return start + 1;
+            }
+            let parent_is_wildcard = start + 1 < labels.len()
+                && self.wildcards.contains(&labels[start + 1..].join("."));
+            if self.rules.contains(&candidate) || parent_is_wildcard {
+                return start;
+            }
+        }
+        labels.len().saturating_sub(1)
+    }
+}
+
+/// The registrable domain (eTLD+1) of `host` under the Public Suffix List,
+/// e.g. `example.co.uk` for `www.example.co.uk`. IP addresses are returned
+/// as they are; `None` when the host is itself a public suffix.
+pub fn registrable_domain(host: &str) -> Option<String> {
+    let host = host.trim_end_matches('.').to_lowercase();
+    if host.parse::<std::net::IpAddr>().is_ok() || host.starts_with('[') {
+        return Some(host);
+    }
+    let labels: Vec<&str> = host.split('.').collect();
+    if labels.iter().any(|label| label.is_empty()) {
+        return None;
+    }
+    let start = SuffixList::get().suffix_start(&labels);
+    (start > 0).then(|| labels[start - 1..].join("."))
+}
+
+/// Whether `request_url` belongs to a different site than `page_url`,
+/// comparing registrable domains. URLs without a host, or whose host is a
+/// public suffix, are compared by host.
+pub fn is_third_party(request_url: &str, page_url: &str) -> bool {
+    let site = |raw: &str| {
+        let host = Url::parse(raw).ok()?.host_str()?.to_string();
+        Some(registrable_domain(&host).unwrap_or(host))
+    };
+    match (site(request_url), site(page_url)) {
+        (Some(request), Some(page)) => request != page,
+        _ => false,
+    }
+}
@@ -0,0 +1,143 @@
+use crate::storage::PageFetchIndex;
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+/// Summaries waiting for delivery; more are dropped rather than stalling ingest.
+const QUEUE_CAPACITY: usize = 1024;
+const MAX_ATTEMPTS: u32 = 5;
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What the webhook receives for each stored page fetch.
+#[derive(Debug, Serialize)]
+pub struct PageSummary {
+    /// `None` for the default tenant.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+    pub session_id: String,
+    pub navigation_id: String,
+    pub page_url: String,
+    pub request_count: usize,
+    /// Request and response body and WebSocket payload hashes, each listed
+    /// once.
+    pub content_hashes: Vec<String>,
+}
+
+impl PageSummary {
+    pub fn new(tenant: Option<&str>, page_fetch: &PageFetchIndex) -> Self {
+        let mut content_hashes = Vec::new();
+        for request in &page_fetch.requests {
+            let response_hash = request.response.as_ref().and_then(|r| r.body_hash.as_ref());
+            for hash in request.request_body_hash.iter().chain(response_hash) {
+                if !content_hashes.contains(hash) {
+                    content_hashes.push(hash.clone());
+                }
+            }
+        }
+        for hash in page_fetch.frame_hashes() {
+            if !content_hashes.contains(hash) {
+                content_hashes.push(hash.clone());
+            }
+        }
+        PageSummary {
+            tenant: tenant.map(str::to_string),
+            session_id: page_fetch.session_id.clone(),
+            navigation_id: page_fetch.navigation_id.clone(),
+            page_url: page_fetch.page_url.clone(),
+            request_count: page_fetch.requests.len(),
+            content_hashes,
+        }
+    }
+}
+
+/// Delivery outcomes since startup, for `/metrics`.
+#[derive(Debug, Default)]
+pub struct WebhookCounters {
+    pub delivered: AtomicU64,
+    /// Summaries that failed every attempt.
+    pub failed: AtomicU64,
+    /// Summaries dropped because the queue was full.
+    pub dropped: AtomicU64,
+}
+
+/// Forwards page summaries to a URL from a background task, retrying with
+/// exponential backoff. Cloning shares the queue.
+#[derive(Clone)]
+pub struct Webhook {
+    queue: mpsc::Sender<PageSummary>,
+    counters: Arc<WebhookCounters>,
+}
+
+impl Webhook {
+    /// Starts the delivery task; call from within the runtime.
+    pub fn spawn(url: reqwest::Url) -> Result<Self, reqwest::Error> {
+        let client = reqwest::Client::builder()
+            .timeout(DELIVERY_TIMEOUT)
+            .build()?;
+        let (queue, receiver) = mpsc::channel(QUEUE_CAPACITY);
+        let counters = Arc::new(WebhookCounters::default());
+        tokio::spawn(deliver_all(client, url, receiver, counters.clone()));
+        Ok(Webhook { queue, counters })
+    }
+    
+    /// Queues `summary` without waiting; it's dropped and counted if the
+    /// queue is full.
+    pub fn send(&self, summary: PageSummary) {
+        if self.queue.try_send(summary).is_err() {
+            let dropped = self.counters.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+            tracing::warn!("Webhook queue full; dropped page summary ({} dropped so far)", dropped);
+        }
+    }
+    
+    pub fn counters(&self) -> &WebhookCounters {
+        &self.counters
+    }
+}
+
+/// Delivers summaries one at a time, so a slow endpoint backs up the queue
+/// instead of piling up requests.
+async fn deliver_all(
+    client: reqwest::Client,
+    url: reqwest::Url,
+    mut receiver: mpsc::Receiver<PageSummary>,
+    counters: Arc<WebhookCounters>,
+) {
+    while let Some(summary) = receiver.recv().await {
+        let body = match serde_json::to_vec(&summary) {
+            Ok(body) => body,
+            Err(e) => {
+                tracing::error!("Failed to encode page summary: {}", e);
+                continue;
+            }
+        };
+        let mut backoff = INITIAL_BACKOFF;
+        for attempt in 1..=MAX_ATTEMPTS {
+            let result = client.post(url.clone())
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(body.clone())
+                .send()
+                .await
+                .and_then(|response| response.error_for_status());
+            match result {
+                Ok(_) => {
+                    counters.delivered.fetch_add(1, Ordering::Relaxed);
+                    break;
+                }
+                Err(e) if attempt == MAX_ATTEMPTS => {
+                    counters.failed.fetch_add(1, Ordering::Relaxed);
+                    tracing::error!("Giving up on webhook delivery for {} after {} attempts: {}",
+                        summary.navigation_id, attempt, e);
+                }
+                Err(e) => {
+                    tracing::warn!("Webhook delivery attempt {} failed, retrying in {:?}: {}", attempt, backoff, e);
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                }
+            }
+        }
+    }
+}